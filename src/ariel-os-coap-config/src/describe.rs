@@ -0,0 +1,123 @@
+//! Human-readable descriptions of `peers.yml` records.
+
+use std::fmt::Write as _;
+
+use crate::Error;
+use crate::edn::bytes_to_edn;
+use crate::peers::{KnownScope, Peer, Scope, SinglePermission, TokenAlgorithm};
+use crate::render::{encode_aif, kccs_to_cbor};
+
+/// Describes who is granted which access by the records of a `peers.yml` file.
+///
+/// Scopes are described as they result from their AIF (RFC9237) encoding, which is what the
+/// device evaluates.
+///
+/// # Errors
+///
+/// This errs if a record's credential or scope can not be processed; [`render_peers()`] provides
+/// more thorough checks.
+///
+/// [`render_peers()`]: crate::render_peers
+pub fn describe_peers(peers: &[Peer]) -> Result<String, Error> {
+    let mut description = String::new();
+    for peer in peers {
+        let who = if let Some(authorization_server) = &peer.authorization_server {
+            let algorithm = match authorization_server.algorithm {
+                TokenAlgorithm::AesCcm16_128_256 => "AES-CCM-16-128-256",
+                TokenAlgorithm::Es256 => "ES256",
+            };
+            match &authorization_server.audience {
+                Some(audience) => format!(
+                    "Clients with {algorithm} tokens of an Authorization Server addressing this device as {audience:?}"
+                ),
+                None => format!("Clients with {algorithm} tokens of an Authorization Server"),
+            }
+        } else if let Some(kccs) = &peer.kccs {
+            format!("Peer {}", describe_kccs(&kccs_to_cbor(kccs)?))
+        } else if peer.from.is_some() {
+            "Any client, even unauthenticated".to_string()
+        } else if peer.group.is_some() {
+            "Members of the OSCORE group".to_string()
+        } else if peer.issuer.is_some() {
+            "Peers with a certificate or CWT of the trusted issuer".to_string()
+        } else {
+            "Incomplete record".to_string()
+        };
+        writeln!(description, "{who}:").expect("writing to String is infallible");
+
+        match &peer.scope {
+            None | Some(Scope::KnownScope(KnownScope::AllowAll))
+                if peer.authorization_server.is_some() =>
+            {
+                description.push_str("  as allowed by their tokens\n");
+            }
+            Some(Scope::Aif(aif)) if peer.authorization_server.is_some() => {
+                description.push_str("  as allowed by their tokens (of any AS), but at most:\n");
+                describe_aif(&encode_aif(aif)?, &mut description)?;
+            }
+            None => description.push_str("  nothing (no scope given)\n"),
+            Some(Scope::KnownScope(KnownScope::AllowAll)) => {
+                description.push_str("  all methods on all resources\n");
+            }
+            Some(Scope::Aif(aif)) => describe_aif(&encode_aif(aif)?, &mut description)?,
+        }
+    }
+    Ok(description)
+}
+
+/// Describes the entries of an encoded AIF scope, one per line.
+fn describe_aif(encoded: &[u8], description: &mut String) -> Result<(), Error> {
+    let entries: Vec<(String, u32)> =
+        minicbor::decode(encoded).map_err(|_| Error::new("scope is not valid AIF"))?;
+    for (path, mask) in entries {
+        let methods: Vec<_> = SinglePermission::ALL
+            .iter()
+            .filter(|permission| mask & permission.mask() != 0)
+            .map(|permission| format!("{permission:?}"))
+            .collect();
+        writeln!(description, "  {path}: {}", methods.join(", "))
+            .expect("writing to String is infallible");
+    }
+    Ok(())
+}
+
+/// Describes a KCCS by its subject and the key ID of its key, as far as they are present.
+fn describe_kccs(kccs: &[u8]) -> String {
+    let mut subject = None;
+    let mut kid = None;
+
+    let mut decoder = minicbor::Decoder::new(kccs);
+    // Any data that does not fit the expected structure is just not described.
+    let _ = (|| -> Result<(), minicbor::decode::Error> {
+        for _ in 0..decoder.map()?.unwrap_or(0) {
+            match decoder.u8()? {
+                // sub
+                2 => subject = Some(decoder.str()?.to_string()),
+                // cnf
+                8 => {
+                    decoder.map()?;
+                    // COSE_Key
+                    if decoder.u8()? != 1 {
+                        return Ok(());
+                    }
+                    for _ in 0..decoder.map()?.unwrap_or(0) {
+                        if decoder.i64()? == 2 {
+                            kid = Some(decoder.bytes()?.to_vec());
+                        } else {
+                            decoder.skip()?;
+                        }
+                    }
+                }
+                _ => decoder.skip()?,
+            }
+        }
+        Ok(())
+    })();
+
+    match (subject, kid) {
+        (Some(subject), Some(kid)) => format!("{subject:?} (key ID {})", bytes_to_edn(&kid)),
+        (Some(subject), None) => format!("{subject:?}"),
+        (None, Some(kid)) => format!("with key ID {}", bytes_to_edn(&kid)),
+        (None, None) => "with an unnamed credential".to_string(),
+    }
+}
@@ -0,0 +1,87 @@
+//! Conversions of items given in CBOR Diagnostic Notation (EDN).
+
+use crate::{Error, ensure};
+
+/// Converts an item given in CBOR Diagnostic Notation (EDN) into CBOR; `what` names the item in
+/// error messages.
+pub(crate) fn edn_to_cbor(edn: &str, what: &str) -> Result<Vec<u8>, Error> {
+    cbor_edn::StandaloneItem::parse(edn)
+        .map_err(|_| {
+            Error::new(format!(
+                "{what} is not valid CBOR Diagnostic Notation (EDN)"
+            ))
+        })?
+        .to_cbor()
+        .map_err(|_| {
+            Error::new(format!(
+                "{what} uses CBOR Diagnostic Notation (EDN) that is not expressible in CBOR"
+            ))
+        })
+}
+
+/// Converts a byte string given in CBOR Diagnostic Notation (EDN) into its bytes.
+pub(crate) fn edn_bytes(edn: &str, what: &str) -> Result<Vec<u8>, Error> {
+    minicbor::Decoder::new(&edn_to_cbor(edn, what)?)
+        .bytes()
+        .map(<[u8]>::to_vec)
+        .map_err(|_| Error::new(format!("{what} needs to be a byte string")))
+}
+
+/// Renders bytes as a byte string in CBOR Diagnostic Notation (EDN), e.g. `h'2b'`.
+#[must_use]
+pub fn bytes_to_edn(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("h'{hex}'")
+}
+
+/// Extracts the byte string parameters of a COSE_Key given in CBOR Diagnostic Notation, after
+/// checking its key type.
+pub(crate) fn cose_key_parameters(
+    edn: &str,
+    expected_kty: i64,
+) -> Result<std::collections::HashMap<i64, Vec<u8>>, Error> {
+    let cbor = edn_to_cbor(edn, "key")?;
+    let malformed = |_| Error::new("key is not a well-formed COSE_Key map");
+
+    let mut decoder = minicbor::Decoder::new(&cbor);
+    let entries = decoder
+        .map()
+        .map_err(malformed)?
+        .ok_or_else(|| Error::new("indefinite length maps are not supported in keys"))?;
+    let mut parameters = std::collections::HashMap::new();
+    for _ in 0..entries {
+        let label = decoder
+            .i64()
+            .map_err(|_| Error::new("COSE_Key labels need to be integers"))?;
+        match decoder.datatype().map_err(malformed)? {
+            minicbor::data::Type::Bytes => {
+                parameters.insert(label, decoder.bytes().map_err(malformed)?.to_vec());
+            }
+            _ if label == 1 => {
+                let kty = decoder
+                    .i64()
+                    .map_err(|_| Error::new("kty needs to be an integer"))?;
+                ensure!(
+                    kty == expected_kty,
+                    "key has a key type unsuitable for its use (expected kty {expected_kty})"
+                );
+            }
+            _ => decoder.skip().map_err(malformed)?,
+        }
+    }
+    Ok(parameters)
+}
+
+/// Extracts the P-256 coordinates `x` (-2) and `y` (-3) of an EC2 COSE_Key; `what` names the key
+/// in error messages.
+pub(crate) fn p256_coordinates(edn: &str, what: &str) -> Result<([u8; 32], [u8; 32]), Error> {
+    let key = cose_key_parameters(edn, 2)?;
+    let coordinate = |label| -> Result<[u8; 32], Error> {
+        key.get(&label)
+            .ok_or_else(|| Error::new(format!("{what} needs `x` (-2) and `y` (-3) parameters")))?
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::new("P-256 coordinates are 32 bytes long"))
+    };
+    Ok((coordinate(-2)?, coordinate(-3)?))
+}
@@ -0,0 +1,122 @@
+//! The CoAP server security configuration of Ariel OS, as given in a `peers.yml` file.
+//!
+//! This library is used by the build script of `ariel-os-coap` to compile the configuration into
+//! the firmware, and by the `ariel-os-coap-config` tool to check configurations on the host before
+//! building. The format of `peers.yml` is described in the example file in `tests/coap/`.
+
+mod describe;
+mod edn;
+pub mod limits;
+mod peers;
+mod render;
+
+pub use describe::describe_peers;
+pub use edn::bytes_to_edn;
+pub use peers::Peer;
+pub use render::render_peers;
+
+/// Error in a `peers.yml` file, describing what needs to be changed.
+#[derive(Debug)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Returns an [`Error`] with the formatted message unless the condition holds.
+macro_rules! ensure {
+    ($condition:expr, $($message:tt)+) => {
+        if !$condition {
+            return Err($crate::Error::new(format!($($message)+)));
+        }
+    };
+}
+pub(crate) use ensure;
+
+/// Parses the records of a `peers.yml` file.
+///
+/// This only checks the structure of the file; whether the records are usable is checked when
+/// they are rendered by [`render_peers()`].
+///
+/// # Errors
+///
+/// This errs if the file is not valid YAML, or does not have the structure of a `peers.yml` file.
+pub fn parse_peers(reader: impl std::io::Read) -> Result<Vec<Peer>, Error> {
+    serde_yml::from_reader(reader).map_err(|e| Error::new(format!("invalid peers.yml: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_peers() {
+        let peers = parse_peers(include_str!("../../../tests/coap/peers.yml").as_bytes()).unwrap();
+        let rendered = render_peers(&peers).unwrap();
+        assert!(rendered.contains("fn seed_peers()"));
+
+        let description = describe_peers(&peers).unwrap();
+        assert!(description.contains("Any client, even unauthenticated:\n"));
+        assert!(description.contains("  /poem: GET\n"));
+        assert!(description.contains(
+            "Peer \"42-50-31-FF-EF-37-32-39\" (key ID h'2b'):\n  all methods on all resources\n"
+        ));
+    }
+
+    #[test]
+    fn token_scope_limit() {
+        let peers = parse_peers(
+            "- as:\n    algorithm: AES-CCM-16-128-256\n    key: |\n      {1: 4, -1: h'0000000000000000000000000000000000000000000000000000000000000000'}\n  scope:\n    /led: GET\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let rendered = render_peers(&peers).unwrap();
+        assert!(rendered.contains(".with_token_scope_limit("));
+
+        let description = describe_peers(&peers).unwrap();
+        assert!(description.contains("but at most:\n  /led: GET\n"));
+    }
+
+    #[test]
+    fn unusable_peers() {
+        let peers = parse_peers(
+            "- kccs: |\n    {2: \"a\"}\n  from: unauthenticated\n  scope: allow-all\n".as_bytes(),
+        )
+        .unwrap();
+        assert!(render_peers(&peers).is_err());
+
+        let peers = parse_peers(
+            "- as:\n    algorithm: AES-CCM-16-128-256\n    key: |\n      {1: 4, -1: h'0000000000000000000000000000000000000000000000000000000000000000'}\n  scope:\n    /led: GET\n- as:\n    algorithm: ES256\n    key: |\n      {1: 2}\n    audience: \"d01\"\n  scope:\n    /led: PUT\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert!(
+            render_peers(&peers)
+                .unwrap_err()
+                .to_string()
+                .contains("Only a single AS can have a `scope`")
+        );
+
+        let peers = parse_peers("- kccs: |\n    {2: \"a\"}\n".as_bytes()).unwrap();
+        assert!(
+            render_peers(&peers)
+                .unwrap_err()
+                .to_string()
+                .contains("needs a `scope`")
+        );
+    }
+}
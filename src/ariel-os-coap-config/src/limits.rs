@@ -0,0 +1,29 @@
+//! Limits of the firmware on what `peers.yml` can configure.
+//!
+//! These are the single source of the limits: [`render_peers()`](crate::render_peers) checks the
+//! configuration against them, and emits them into the rendered code, where `ariel-os-coap` sizes
+//! its storage with them and checks that they match what coapcore is built with.
+
+/// Maximum number of peers with a `kccs` record.
+pub const MAX_PEERS: usize = 8;
+
+/// Maximum length of a KCCS of a peer.
+///
+/// This is limited by the size of a storage item along with its (deliberately short) key.
+pub const MAX_KCCS_LEN: usize = 112;
+
+/// Maximum length of an encoded AIF scope.
+///
+/// `ariel-os-coap` holds scopes in coapcore's `GenericAifValue` of this length, and stores them
+/// along with the peers; scopes need to fit into a storage item along with their key as well.
+pub const MAX_AIF_LEN: usize = 64;
+
+/// Maximum length of the audience in signed tokens, as in coapcore's `seccfg::MAX_AUD_SIZE`.
+pub const MAX_AUD_SIZE: usize = 8;
+
+/// Maximum number of other members of an OSCORE group, as in coapcore's `group::MAX_MEMBERS`.
+pub const MAX_GROUP_MEMBERS: usize = 4;
+
+/// Maximum length of a KCCS of an OSCORE group member, as in coapcore's
+/// `group::MAX_CREDENTIAL_LEN`.
+pub const MAX_GROUP_KCCS_LEN: usize = 128;
@@ -0,0 +1,124 @@
+//! Checks CoAP server security configurations (`peers.yml`) and generates credentials for them.
+
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use ariel_os_coap_config::bytes_to_edn;
+use clap::{Parser, Subcommand};
+use p256::elliptic_curve::sec1::ToEncodedPoint as _;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Checks that a `peers.yml` file is usable, and describes which access it grants.
+    Check {
+        /// The `peers.yml` file.
+        peers_yml: PathBuf,
+    },
+    /// Generates a P-256 key pair, and prints a KCCS credential containing its public key.
+    ///
+    /// The KCCS can be used in `kccs: ...` records of `peers.yml` (for clients) and in the
+    /// credentials of CoAP clients such as aiocoap (for the device or other clients).
+    GenerateKey {
+        /// File to which the private key is written as a COSE_Key (e.g. `client.cosekey`).
+        ///
+        /// The file is only readable by the current user, and is not overwritten if it exists.
+        #[arg(long)]
+        output: PathBuf,
+        /// Subject of the KCCS.
+        #[arg(long, default_value = "")]
+        subject: String,
+        /// Key ID by which the credential is indicated, as hexadecimal digits (e.g. `2b`).
+        #[arg(long)]
+        kid: Option<String>,
+    },
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Check { peers_yml } => check(&peers_yml),
+        Command::GenerateKey {
+            output,
+            subject,
+            kid,
+        } => generate_key(&output, &subject, kid.as_deref()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn check(peers_yml: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(peers_yml)
+        .map_err(|e| format!("{e} while opening {}", peers_yml.display()))?;
+    let peers = ariel_os_coap_config::parse_peers(file)?;
+    // Rendering performs all checks the build does.
+    ariel_os_coap_config::render_peers(&peers)?;
+    print!("{}", ariel_os_coap_config::describe_peers(&peers)?);
+    Ok(())
+}
+
+fn generate_key(
+    output: &Path,
+    subject: &str,
+    kid: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let kid = kid.map(parse_hex).transpose()?;
+
+    let secret = p256::SecretKey::random(&mut rand_core::OsRng);
+    let point = secret.public_key().to_encoded_point(false);
+    let (Some(x), Some(y)) = (point.x(), point.y()) else {
+        unreachable!("uncompressed points have both coordinates");
+    };
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(output)
+        .map_err(|e| format!("{e} while creating {}", output.display()))?;
+    // Same format as `key` entries of peers.yml and aiocoap's private key files
+    writeln!(
+        file,
+        "{{1: 2, -1: 1, -4: {}}}",
+        bytes_to_edn(&secret.to_bytes())
+    )?;
+
+    let kid = kid
+        .map(|kid| format!("2: {}, ", bytes_to_edn(&kid)))
+        .unwrap_or_default();
+    println!(
+        "{{2: {subject:?}, 8: {{1: {{1: 2, {kid}-1: 1, -2: {}, -3: {}}}}}}}",
+        bytes_to_edn(x),
+        bytes_to_edn(y)
+    );
+    Ok(())
+}
+
+/// Parses hexadecimal digits into bytes.
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("`{hex}` is not an even number of hexadecimal digits");
+    if hex.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|start| {
+            hex.get(start..start + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
@@ -0,0 +1,142 @@
+//! The records of a `peers.yml` file.
+
+use serde::Deserialize;
+
+/// Second-level item for deserializing a `peers.yml`
+///
+/// (The top level is a list thereof).
+#[derive(Debug, Deserialize)]
+pub struct Peer {
+    pub(crate) kccs: Option<String>,
+    pub(crate) from: Option<KnownSource>,
+    pub(crate) scope: Option<Scope>,
+    #[serde(rename = "as")]
+    pub(crate) authorization_server: Option<AuthorizationServer>,
+    pub(crate) group: Option<OscoreGroup>,
+    /// Key of an issuer whose X.509 certificates and CWTs are accepted, as an EC2 COSE_Key in CBOR
+    /// Diagnostic Notation (EDN).
+    pub(crate) issuer: Option<String>,
+}
+
+/// A Group OSCORE group whose requests are processed with the record's scope.
+///
+/// All byte strings, credentials and keys are given in CBOR Diagnostic Notation (EDN).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct OscoreGroup {
+    /// Group Identifier (Gid) as a byte string.
+    pub(crate) id: String,
+    /// Master secret as a byte string.
+    pub(crate) secret: String,
+    /// Master salt as a byte string.
+    pub(crate) salt: Option<String>,
+    /// This device's Sender ID in the group as a byte string.
+    pub(crate) sender_id: String,
+    /// This device's credential in the group (a KCCS).
+    pub(crate) kccs: String,
+    /// This device's private key as a COSE_Key.
+    pub(crate) key: String,
+    /// The members whose requests are processed.
+    pub(crate) members: Vec<GroupMember>,
+}
+
+/// A member of an [`OscoreGroup`].
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct GroupMember {
+    /// The member's Sender ID as a byte string.
+    pub(crate) sender_id: String,
+    /// The member's credential (a KCCS).
+    pub(crate) kccs: String,
+}
+
+/// An ACE Authorization Server (AS) whose access tokens are accepted.
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuthorizationServer {
+    pub(crate) algorithm: TokenAlgorithm,
+    /// The AS's key as a COSE_Key in CBOR Diagnostic Notation (EDN).
+    pub(crate) key: String,
+    /// Audience value by which the AS addresses this device in signed tokens.
+    pub(crate) audience: Option<String>,
+    /// URI of the AS, sent to unauthorized clients in AS Request Creation Hints.
+    pub(crate) uri: Option<String>,
+}
+
+/// Algorithm by which tokens are protected.
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
+pub(crate) enum TokenAlgorithm {
+    /// Symmetrically encrypted tokens (COSE algorithm 31).
+    #[serde(rename = "AES-CCM-16-128-256")]
+    AesCcm16_128_256,
+    /// Signed tokens (COSE algorithm -7).
+    #[serde(rename = "ES256")]
+    Es256,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Scope {
+    KnownScope(KnownScope),
+    Aif(std::collections::HashMap<String, Permission>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Permission {
+    Set(Vec<SinglePermission>),
+    Single(SinglePermission),
+}
+
+#[derive(Debug, Deserialize, Copy, Clone)]
+#[allow(clippy::upper_case_acronyms, reason = "used to guide serde values")]
+#[repr(u8)]
+pub(crate) enum SinglePermission {
+    GET = coap_numbers::code::GET,
+    POST = coap_numbers::code::POST,
+    PUT = coap_numbers::code::PUT,
+    DELETE = coap_numbers::code::DELETE,
+    FETCH = coap_numbers::code::FETCH,
+    PATCH = coap_numbers::code::PATCH,
+    #[allow(non_camel_case_types, reason = "that's how that code is named")]
+    iPATCH = coap_numbers::code::IPATCH,
+}
+
+impl Permission {
+    pub(crate) fn mask(&self) -> u32 {
+        match self {
+            Permission::Set(p) => p.iter().fold(0, |old, value| old | value.mask()),
+            Permission::Single(p) => p.mask(),
+        }
+    }
+}
+
+impl SinglePermission {
+    /// All permissions, in the order of their bits in the `Tperm` representation.
+    pub(crate) const ALL: [Self; 7] = [
+        Self::GET,
+        Self::POST,
+        Self::PUT,
+        Self::DELETE,
+        Self::FETCH,
+        Self::PATCH,
+        Self::iPATCH,
+    ];
+
+    /// The `Tperm` unsigned integer representation of the REST-specific AIF model described in
+    /// RFC9237.
+    pub(crate) fn mask(self) -> u32 {
+        1 << (self as u8 - 1)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum KnownScope {
+    AllowAll,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum KnownSource {
+    Unauthenticated,
+}
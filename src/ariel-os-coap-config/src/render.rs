@@ -0,0 +1,326 @@
+//! Rendering of `peers.yml` records into the Rust code that the firmware is built with.
+
+use std::fmt::Write as _;
+
+use crate::edn::{cose_key_parameters, edn_bytes, edn_to_cbor, p256_coordinates};
+use crate::limits::{
+    MAX_AIF_LEN, MAX_AUD_SIZE, MAX_GROUP_KCCS_LEN, MAX_GROUP_MEMBERS, MAX_KCCS_LEN, MAX_PEERS,
+};
+use crate::peers::{KnownScope, KnownSource, OscoreGroup, Peer, Permission, Scope, TokenAlgorithm};
+use crate::{Error, ensure};
+
+/// Encodes the AIF (RFC9237) representation of a scope given as paths and their permissions.
+pub(crate) fn encode_aif(
+    aif: &std::collections::HashMap<String, Permission>,
+) -> Result<Vec<u8>, Error> {
+    let data: Vec<_> = aif
+        .iter()
+        .map(|(toid, tperm)| (toid, tperm.mask()))
+        .collect();
+    let mut bytes = vec![];
+    minicbor::encode(data, &mut bytes).map_err(|_| Error::new("scope is not encodable"))?;
+    ensure!(
+        bytes.len() <= MAX_AIF_LEN,
+        "scope exceeds the supported length of {MAX_AIF_LEN} bytes"
+    );
+    Ok(bytes)
+}
+
+/// Converts a KCCS given in CBOR Diagnostic Notation (EDN) into CBOR, checking its length.
+pub(crate) fn kccs_to_cbor(edn: &str) -> Result<Vec<u8>, Error> {
+    let kccs = edn_to_cbor(edn, "kccs")?;
+    ensure!(
+        kccs.len() <= MAX_KCCS_LEN,
+        "kccs exceeds the supported length of {MAX_KCCS_LEN} bytes"
+    );
+    Ok(kccs)
+}
+
+/// Renders the `ConfigBuilder` step that sets up an OSCORE group with the given scope.
+fn group_config(group: &OscoreGroup, union_scope: &str) -> Result<String, Error> {
+    ensure!(
+        group.members.len() <= MAX_GROUP_MEMBERS,
+        "an OSCORE group can have at most {MAX_GROUP_MEMBERS} other members"
+    );
+    let kccs = |edn: &str| -> Result<Vec<u8>, Error> {
+        let kccs = edn_to_cbor(edn, "kccs of the OSCORE group")?;
+        ensure!(
+            kccs.len() <= MAX_GROUP_KCCS_LEN,
+            "kccs in OSCORE group exceeds the supported length of {MAX_GROUP_KCCS_LEN} bytes"
+        );
+        Ok(kccs)
+    };
+
+    // kty: EC2
+    let key = cose_key_parameters(&group.key, 2)?;
+    let d: [u8; 32] = key
+        .get(&-4)
+        .ok_or_else(|| Error::new("the key of an OSCORE group needs a `d` (-4) parameter"))?
+        .as_slice()
+        .try_into()
+        .map_err(|_| Error::new("P-256 private keys are 32 bytes long"))?;
+
+    let salt = match group.salt.as_deref() {
+        Some(salt) => edn_bytes(salt, "salt")?,
+        None => vec![],
+    };
+    let mut config = format!(
+        "coapcore::group::OscoreGroup::new(&{:?}, &{:?}, &{salt:?}, &{:?}, &{:?}, {d:?}).expect(\"OSCORE group in peers.yml is unusable\")",
+        edn_bytes(&group.id, "id")?,
+        edn_bytes(&group.secret, "secret")?,
+        edn_bytes(&group.sender_id, "sender-id")?,
+        kccs(&group.kccs)?,
+    );
+    for member in &group.members {
+        write!(
+            config,
+            ".with_member(&{:?}, &{:?}).expect(\"OSCORE group member in peers.yml is unusable\")",
+            edn_bytes(&member.sender_id, "sender-id")?,
+            kccs(&member.kccs)?,
+        )
+        .expect("writing to String is infallible");
+    }
+    Ok(format!(".with_oscore_group({config}, {union_scope})"))
+}
+
+/// Renders the records of a `peers.yml` file into the Rust code that is included in
+/// ariel-os-coap's `stored` module.
+///
+/// # Errors
+///
+/// This errs if any record is not usable, naming the problem.
+#[allow(clippy::too_many_lines, reason = "a single pass over all record kinds")]
+pub fn render_peers(peers: &[Peer]) -> Result<String, Error> {
+    let mut unauthenticated_scope = None;
+    let mut chain_once_per_kccs = String::new();
+    let mut kccs_count = 0;
+    let mut token_config = String::new();
+    let mut algorithms = vec![];
+    let mut request_creation_hints = None;
+    let mut has_oscore_group = false;
+    let mut trusted_issuer = None;
+    let mut token_scope_limit = false;
+    for peer in peers {
+        if let Some(authorization_server) = &peer.authorization_server {
+            ensure!(
+                peer.kccs.is_none()
+                    && peer.from.is_none()
+                    && peer.group.is_none()
+                    && peer.issuer.is_none(),
+                "An `as: ...` record can not have a `kccs`, `from`, `group` or `issuer` key; the scope is given by the AS in its tokens."
+            );
+            // The scope of an AS record limits the scopes of all tokens.
+            if let Some(Scope::Aif(aif)) = &peer.scope {
+                ensure!(
+                    !token_scope_limit,
+                    "Only a single AS can have a `scope`, which limits the tokens of all AS."
+                );
+                token_scope_limit = true;
+                let bytes = encode_aif(aif)?;
+                write!(
+                    token_config,
+                    ".with_token_scope_limit(coapcore::scope::GenericAifValue::parse(&{bytes:?}).unwrap())"
+                )
+                .expect("writing to String is infallible");
+            }
+            ensure!(
+                !algorithms.contains(&authorization_server.algorithm),
+                "Only a single AS per algorithm is usable."
+            );
+            algorithms.push(authorization_server.algorithm);
+
+            match authorization_server.algorithm {
+                TokenAlgorithm::AesCcm16_128_256 => {
+                    // kty: Symmetric
+                    let key = cose_key_parameters(&authorization_server.key, 4)?;
+                    let k: [u8; 32] = key
+                        .get(&-1)
+                        .ok_or_else(|| Error::new("symmetric AS key needs a `k` (-1) parameter"))?
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| Error::new("AES-CCM-16-128-256 keys are 32 bytes long"))?;
+                    write!(token_config, ".with_aif_symmetric_as_aesccm256({k:?})")
+                        .expect("writing to String is infallible");
+                }
+                TokenAlgorithm::Es256 => {
+                    let (x, y) = p256_coordinates(&authorization_server.key, "ES256 AS key")?;
+                    let audience = authorization_server.audience.as_deref().ok_or_else(|| {
+                        Error::new("An ES256 AS needs the `audience` of this device in its tokens")
+                    })?;
+                    ensure!(
+                        audience.len() <= MAX_AUD_SIZE,
+                        "audience exceeds the supported length of {MAX_AUD_SIZE} bytes"
+                    );
+                    write!(
+                        token_config,
+                        ".with_aif_asymmetric_es256({x:?}, {y:?}, heapless::String::try_from({audience:?}).unwrap())"
+                    )
+                    .expect("writing to String is infallible");
+                }
+            }
+
+            if let Some(uri) = &authorization_server.uri {
+                ensure!(
+                    request_creation_hints.is_none(),
+                    "Only a single AS can have a `uri`."
+                );
+                // AS Request Creation Hints (RFC9200 Section 5.3)
+                let audience = &authorization_server.audience;
+                let mut hints = minicbor::Encoder::new(vec![]);
+                hints
+                    .map(if audience.is_some() { 2 } else { 1 })
+                    .and_then(|hints| hints.u8(1))
+                    .and_then(|hints| hints.str(uri))
+                    .expect("writing to Vec is infallible");
+                if let Some(audience) = audience {
+                    hints
+                        .u8(5)
+                        .and_then(|hints| hints.str(audience))
+                        .expect("writing to Vec is infallible");
+                }
+                request_creation_hints = Some(hints.into_writer());
+            }
+
+            continue;
+        }
+
+        let scope = peer.scope.as_ref().ok_or_else(|| {
+            Error::new(
+                "Every `kccs: ...`, `from: unauthenticated`, `group: ...` or `issuer: ...` record needs a `scope`.",
+            )
+        })?;
+
+        // The unauthenticated scope is compiled in as a `UnionScope`, whereas the scopes of
+        // credentials are only used to populate the peers stored on the device at first startup.
+        let (union_scope, stored_scope) = match scope {
+            Scope::KnownScope(KnownScope::AllowAll) => (
+                "coapcore::scope::UnionScope::AllowAll".to_string(),
+                "super::StoredScope::AllowAll".to_string(),
+            ),
+            Scope::Aif(aif) => {
+                let bytes = encode_aif(aif)?;
+                (
+                    format!(
+                        "coapcore::scope::UnionScope::AifValue(coapcore::scope::GenericAifValue::parse(&{bytes:?}).unwrap())"
+                    ),
+                    format!("super::StoredScope::aif(&{bytes:?})"),
+                )
+            }
+        };
+
+        if let Some(group) = &peer.group {
+            ensure!(
+                peer.kccs.is_none() && peer.from.is_none() && peer.issuer.is_none(),
+                "A `group: ...` record can not have a `kccs`, `from` or `issuer` key; the credentials of the members are listed in the group."
+            );
+            ensure!(
+                !has_oscore_group,
+                "Only a single `group: ...` record is usable."
+            );
+            has_oscore_group = true;
+
+            token_config.push_str(&group_config(group, &union_scope)?);
+            continue;
+        }
+
+        if let Some(issuer) = &peer.issuer {
+            ensure!(
+                peer.kccs.is_none() && peer.from.is_none(),
+                "An `issuer: ...` record can not have a `kccs` or `from` key."
+            );
+            ensure!(
+                trusted_issuer.is_none(),
+                "Only a single `issuer: ...` record is usable."
+            );
+
+            let (x, y) = p256_coordinates(issuer, "issuer key")?;
+            trusted_issuer = Some(format!(
+                "Some((coapcore::credentials::TrustedIssuer::new(&{x:?}, &{y:?}).expect(\"issuer key in peers.yml is not on the P-256 curve\"), {union_scope}))"
+            ));
+            continue;
+        }
+
+        match (&peer.kccs, &peer.from) {
+            (Some(kccs), None) => {
+                let kccs = kccs_to_cbor(kccs)?;
+                kccs_count += 1;
+
+                write!(
+                    chain_once_per_kccs,
+                    ".chain(core::iter::once((&{kccs:?}[..], {stored_scope})))"
+                )
+                .expect("writing to String is infallible");
+            }
+            (None, Some(KnownSource::Unauthenticated)) => {
+                ensure!(
+                    unauthenticated_scope.is_none(),
+                    "Only a single `from: unauthenticated` record is usable.",
+                );
+
+                unauthenticated_scope = Some(format!("Some({union_scope})"));
+            }
+            _ => {
+                return Err(Error::new(
+                    "Every peer record needs to have either a `kccs: ...`, a `from: unauthenticated`, a `group: ...`, an `issuer: ...` or an `as: ...` key.",
+                ));
+            }
+        }
+    }
+
+    ensure!(
+        kccs_count <= MAX_PEERS,
+        "peers.yml lists more than the {MAX_PEERS} supported `kccs` records"
+    );
+
+    let unauthenticated_scope = unauthenticated_scope.unwrap_or("None".to_string());
+    let trusted_issuer = trusted_issuer.unwrap_or("None".to_string());
+
+    let parses_tokens = !algorithms.is_empty();
+    if let Some(hints) = request_creation_hints {
+        write!(token_config, ".with_request_creation_hints(&{hints:?})")
+            .expect("writing to String is infallible");
+    }
+
+    Ok(format!(
+        "
+        pub(super) fn seed_peers() -> impl Iterator<Item=(&'static [u8], super::StoredScope)> {{
+            core::iter::empty()
+                {chain_once_per_kccs}
+        }}
+
+        pub(super) fn unauthenticated_scope() -> Option<coapcore::scope::UnionScope<MAX_AIF_LEN>> {{
+            {unauthenticated_scope}
+        }}
+
+        pub(super) fn trusted_issuer() -> Option<(coapcore::credentials::TrustedIssuer, coapcore::scope::UnionScope<MAX_AIF_LEN>)> {{
+            {trusted_issuer}
+        }}
+
+        pub(super) const PARSES_TOKENS: bool = {parses_tokens};
+
+        pub(super) const HAS_OSCORE_GROUP: bool = {has_oscore_group};
+
+        pub(super) const MAX_PEERS: usize = {MAX_PEERS};
+        pub(super) const MAX_KCCS_LEN: usize = {MAX_KCCS_LEN};
+        pub(super) const MAX_AIF_LEN: usize = {MAX_AIF_LEN};
+
+        const _: () = assert!(
+            coapcore::seccfg::MAX_AUD_SIZE == {MAX_AUD_SIZE},
+            \"ariel-os-coap-config checks audiences against a different length than coapcore supports\"
+        );
+        const _: () = assert!(
+            coapcore::group::MAX_MEMBERS == {MAX_GROUP_MEMBERS},
+            \"ariel-os-coap-config checks OSCORE groups against a different size than coapcore supports\"
+        );
+        const _: () = assert!(
+            coapcore::group::MAX_CREDENTIAL_LEN == {MAX_GROUP_KCCS_LEN},
+            \"ariel-os-coap-config checks group members' KCCS against a different length than coapcore supports\"
+        );
+
+        pub(super) fn token_config() -> coapcore::seccfg::ConfigBuilder<MAX_AIF_LEN> {{
+            coapcore::seccfg::ConfigBuilder::default()
+                {token_config}
+        }}
+    "
+    ))
+}
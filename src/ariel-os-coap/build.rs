@@ -1,74 +1,188 @@
-use serde::Deserialize;
 use std::fmt::Write;
 
-/// Second-level item for deserializing a `peers.yml`
+/// Generates the list of additional server sockets and multicast groups from
+/// `CONFIG_COAP_ADDITIONAL_PORTS` and `CONFIG_COAP_MULTICAST_GROUPS`.
 ///
-/// (The top level is a list thereof).
-#[derive(Deserialize)]
-struct Peer {
-    kccs: Option<String>,
-    from: Option<KnownSource>,
-    scope: Scope,
-}
+/// Both variables hold comma separated lists; the multicast groups are IPv6 or IPv4 addresses.
+fn write_socket_config() {
+    build::rerun_if_env_changed("CONFIG_COAP_ADDITIONAL_PORTS");
+    build::rerun_if_env_changed("CONFIG_COAP_MULTICAST_GROUPS");
+
+    let list = |name| {
+        std::env::var(name)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+
+    let ports = list("CONFIG_COAP_ADDITIONAL_PORTS")
+        .into_iter()
+        .map(|port| {
+            port.parse::<u16>().unwrap_or_else(|_| {
+                panic!("invalid port in CONFIG_COAP_ADDITIONAL_PORTS: `{port}`")
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut groups = String::new();
+    for group in list("CONFIG_COAP_MULTICAST_GROUPS") {
+        let address = group.parse::<std::net::IpAddr>().unwrap_or_else(|_| {
+            panic!("invalid address in CONFIG_COAP_MULTICAST_GROUPS: `{group}`")
+        });
+        assert!(
+            address.is_multicast(),
+            "CONFIG_COAP_MULTICAST_GROUPS lists `{address}`, which is not a multicast address"
+        );
+        match address {
+            std::net::IpAddr::V4(address) => write!(
+                groups,
+                "core::net::IpAddr::V4(core::net::Ipv4Addr::from_bits({})), ",
+                address.to_bits()
+            ),
+            std::net::IpAddr::V6(address) => write!(
+                groups,
+                "core::net::IpAddr::V6(core::net::Ipv6Addr::from_bits({})), ",
+                address.to_bits()
+            ),
+        }
+        .expect("writing to String is infallible");
+    }
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-enum Scope {
-    KnownScope(KnownScope),
-    Aif(std::collections::HashMap<String, Permission>),
-}
+    let sockets_data = format!(
+        "
+        pub(super) const ADDITIONAL_PORTS: [u16; {}] = {ports:?};
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-enum Permission {
-    Set(Vec<SinglePermission>),
-    Single(SinglePermission),
-}
+        pub(super) const MULTICAST_GROUPS: &[core::net::IpAddr] = &[{groups}];
+    ",
+        ports.len()
+    );
 
-#[derive(Debug, Deserialize, Copy, Clone)]
-#[allow(clippy::upper_case_acronyms, reason = "used to guide serde values")]
-#[repr(u8)]
-enum SinglePermission {
-    GET = coap_numbers::code::GET,
-    POST = coap_numbers::code::POST,
-    PUT = coap_numbers::code::PUT,
-    DELETE = coap_numbers::code::DELETE,
-    FETCH = coap_numbers::code::FETCH,
-    PATCH = coap_numbers::code::PATCH,
-    #[allow(non_camel_case_types, reason = "that's how that code is named")]
-    iPATCH = coap_numbers::code::IPATCH,
+    let sockets_file = build::out_dir().join("sockets.rs");
+    std::fs::write(sockets_file, sockets_data).unwrap();
 }
 
-impl Permission {
-    fn mask(&self) -> u32 {
-        match self {
-            Permission::Set(p) => p.iter().fold(0, |old, value| old | value.mask()),
-            Permission::Single(p) => p.mask(),
-        }
+/// Longest endpoint name accepted by RFC9176 Section 5.
+const MAX_RD_ENDPOINT_LEN: usize = 63;
+
+/// Generates the Resource Directory configuration from `CONFIG_COAP_RD_ADDRESS`,
+/// `CONFIG_COAP_RD_ENDPOINT` and `CONFIG_COAP_RD_LIFETIME`.
+///
+/// The address is a unicast IP address, optionally with a port; without one, the default CoAP
+/// port is used.
+fn write_rd_config() {
+    build::rerun_if_env_changed("CONFIG_COAP_RD_ADDRESS");
+    build::rerun_if_env_changed("CONFIG_COAP_RD_ENDPOINT");
+    build::rerun_if_env_changed("CONFIG_COAP_RD_LIFETIME");
+
+    let address = std::env::var("CONFIG_COAP_RD_ADDRESS")
+        .expect("the coap-rd feature needs the RD's address in CONFIG_COAP_RD_ADDRESS");
+    let address = address.trim();
+    let address = address
+        .parse::<std::net::SocketAddr>()
+        .or_else(|_| {
+            address
+                .parse::<std::net::IpAddr>()
+                .map(|ip| std::net::SocketAddr::new(ip, 5683))
+        })
+        .unwrap_or_else(|_| panic!("invalid address in CONFIG_COAP_RD_ADDRESS: `{address}`"));
+    assert!(
+        !address.ip().is_multicast(),
+        "CONFIG_COAP_RD_ADDRESS is `{address}`, but discovering an RD through multicast is not supported"
+    );
+    let ip = match address.ip() {
+        std::net::IpAddr::V4(ip) => format!(
+            "core::net::IpAddr::V4(core::net::Ipv4Addr::from_bits({}))",
+            ip.to_bits()
+        ),
+        std::net::IpAddr::V6(ip) => format!(
+            "core::net::IpAddr::V6(core::net::Ipv6Addr::from_bits({}))",
+            ip.to_bits()
+        ),
+    };
+
+    let endpoint = std::env::var("CONFIG_COAP_RD_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty());
+    if let Some(endpoint) = &endpoint {
+        assert!(
+            endpoint.len() <= MAX_RD_ENDPOINT_LEN,
+            "CONFIG_COAP_RD_ENDPOINT is longer than {MAX_RD_ENDPOINT_LEN} bytes"
+        );
     }
+
+    let lifetime = std::env::var("CONFIG_COAP_RD_LIFETIME").map_or(90_000, |lifetime| {
+        lifetime
+            .trim()
+            .parse::<u32>()
+            .unwrap_or_else(|_| panic!("invalid lifetime in CONFIG_COAP_RD_LIFETIME: `{lifetime}`"))
+    });
+    assert!(
+        lifetime >= 60,
+        "CONFIG_COAP_RD_LIFETIME must be at least 60 seconds"
+    );
+
+    let rd_data = format!(
+        "
+        pub(super) const ADDRESS: core::net::SocketAddr = core::net::SocketAddr::new({ip}, {port});
+
+        pub(super) const ENDPOINT: Option<&str> = {endpoint:?};
+
+        pub(super) const LIFETIME: u32 = {lifetime};
+    ",
+        port = address.port(),
+    );
+
+    let rd_file = build::out_dir().join("rd.rs");
+    std::fs::write(rd_file, rd_data).unwrap();
 }
 
-impl SinglePermission {
-    /// The `Tperm` unsigned integer representation of the REST-specific AIF model described in
-    /// RFC9237.
-    fn mask(self) -> u32 {
-        1 << (self as u8 - 1)
+/// Longest DNS label (RFC1035 Section 2.3.4).
+const MAX_MDNS_HOSTNAME_LEN: usize = 63;
+
+/// Generates the mDNS configuration from `CONFIG_COAP_MDNS_HOSTNAME`.
+fn write_mdns_config() {
+    build::rerun_if_env_changed("CONFIG_COAP_MDNS_HOSTNAME");
+
+    let hostname = std::env::var("CONFIG_COAP_MDNS_HOSTNAME")
+        .ok()
+        .filter(|hostname| !hostname.is_empty());
+    if let Some(hostname) = &hostname {
+        assert!(
+            hostname.len() <= MAX_MDNS_HOSTNAME_LEN,
+            "CONFIG_COAP_MDNS_HOSTNAME is longer than {MAX_MDNS_HOSTNAME_LEN} bytes"
+        );
+        assert!(
+            hostname
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-'),
+            "CONFIG_COAP_MDNS_HOSTNAME is `{hostname}`, but may only contain letters, digits and hyphens"
+        );
     }
-}
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
-enum KnownScope {
-    AllowAll,
-}
+    let mdns_data = format!(
+        "
+        pub(super) const HOSTNAME: Option<&str> = {hostname:?};
+    "
+    );
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
-enum KnownSource {
-    Unauthenticated,
+    let mdns_file = build::out_dir().join("mdns.rs");
+    std::fs::write(mdns_file, mdns_data).unwrap();
 }
 
 fn main() {
+    write_socket_config();
+
+    if build::cargo_feature("coap-rd") {
+        write_rd_config();
+    }
+
+    if build::cargo_feature("coap-mdns") {
+        write_mdns_config();
+    }
+
     if !build::cargo_feature("coap-server-config-storage") {
         return;
     }
@@ -88,76 +202,10 @@ fn main() {
         })
         .expect("no peers.yml usable in specified location");
 
-    let peers: Vec<Peer> = serde_yml::from_reader(peers_file).expect("failed to parse peers.yml");
-
-    let mut unauthenticated_scope = None;
-    let mut chain_once_per_kccs = String::new();
-    for peer in peers {
-        // FIXME: Should we pre-parse the KCCS and have the parsed credentials as const in flash? Or
-        // just parsed enough that there is no CBOR parsing but credential and material point to
-        // overlapping slices?
-        let scope = match peer.scope {
-            Scope::KnownScope(KnownScope::AllowAll) => {
-                "coapcore::scope::UnionScope::AllowAll".to_string()
-            }
-            Scope::Aif(aif) => {
-                let data: Vec<_> = aif
-                    .into_iter()
-                    .map(|(toid, tperm)| (toid, tperm.mask()))
-                    .collect();
-                let mut bytes = vec![];
-                minicbor::encode(data, &mut bytes).unwrap();
-                format!(
-                    "coapcore::scope::UnionScope::AifValue(coapcore::scope::AifValue::parse(&{bytes:?}).unwrap())"
-                )
-            }
-        };
-
-        match (peer.kccs, peer.from) {
-            (Some(kccs), None) => {
-                let kccs = cbor_edn::StandaloneItem::parse(&kccs)
-                    .expect("data in kccs is not valid CBOR Diagnostic Notation (EDN)")
-                    .to_cbor()
-                    .expect("CBOR Diagnostic Notation (EDN) is not expressible in CBOR");
-
-                write!(
-                    chain_once_per_kccs,
-                    ".chain(core::iter::once((lakers::Credential::parse_ccs(
-                            &{kccs:?}).unwrap(),
-                            {scope},
-                            )))"
-                )
-                .expect("writing to String is infallible");
-            }
-            (None, Some(KnownSource::Unauthenticated)) => {
-                assert!(
-                    unauthenticated_scope.is_none(),
-                    "Only a single `from: unauthenticated` record is usable.",
-                );
-
-                unauthenticated_scope = Some(format!("Some({scope})"));
-            }
-            _ => {
-                panic!(
-                    "Every peer record needs to have either a `kccs: ...` or a `from: unauthenticated` key."
-                )
-            }
-        }
-    }
-
-    let unauthenticated_scope = unauthenticated_scope.unwrap_or("None".to_string());
-
-    let peers_data = format!(
-        "
-        pub(super) fn kccs() -> impl Iterator<Item=(lakers::Credential, coapcore::scope::UnionScope)> {{
-            core::iter::empty()
-                {chain_once_per_kccs}
-        }}
-
-        pub(super) fn unauthenticated_scope() -> Option<coapcore::scope::UnionScope> {{
-            {unauthenticated_scope}
-        }}
-    ");
+    // Errors can be investigated in more detail with `ariel-os-coap-config check`.
+    let peers_data = ariel_os_coap_config::parse_peers(peers_file)
+        .and_then(|peers| ariel_os_coap_config::render_peers(&peers))
+        .unwrap_or_else(|e| panic!("{} is not usable: {e}", peers_yml.display()));
 
     let peers_file = build::out_dir().join("peers.rs");
     std::fs::write(peers_file, peers_data).unwrap();
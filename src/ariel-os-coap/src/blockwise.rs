@@ -0,0 +1,946 @@
+//! Block-wise transfers ([RFC7959](https://www.rfc-editor.org/rfc/rfc7959)) for representations
+//! that do not fit into a single message.
+//!
+//! On the server side, [`BlockReadResource`] serves a [`BlockSource`] in blocks (Block2), and
+//! [`BlockWriteResource`] passes uploaded blocks (Block1) to a [`BlockSink`]. On the client side,
+//! [`get_blockwise()`] and [`send_blockwise()`] run the corresponding exchanges on any
+//! [`coap_request::Stack`], such as the one returned by [`coap_client()`](crate::coap_client);
+//! [`get_blockwise_oscore()`] and [`send_blockwise_oscore()`] run them through a
+//! [`coapcore::OscoreEdhocClient`] instead.
+//!
+//! Block options are protected by OSCORE like any other inner option; resources served by the
+//! CoAP server thus work the same whether or not requests are protected.
+
+use coap_message::{
+    Code as _, MessageOption as _, MinimalWritableMessage, MutableWritableMessage,
+    OptionNumber as _, ReadableMessage,
+};
+use coap_message_utils::Error as CoAPError;
+
+use crate::util::{internal, reject_critical};
+
+/// Largest block size exponent used in responses: blocks of 512 bytes leave room for the
+/// overhead of OSCORE in the server's message buffers.
+const MAX_RESPONSE_SZX: u8 = 5;
+
+/// Largest block size in bytes.
+const MAX_BLOCK_SIZE: usize = 1024;
+
+/// A Block1 or Block2 option value (RFC7959 Section 2.2).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlockValue {
+    num: u32,
+    more: bool,
+    szx: u8,
+}
+
+impl BlockValue {
+    /// Creates a block value for the `num`-th block of size `2^(szx + 4)`.
+    ///
+    /// Returns `None` if the size exponent is out of range (above 6), or the number is too large
+    /// to be expressed.
+    #[must_use]
+    pub fn new(num: u32, more: bool, szx: u8) -> Option<Self> {
+        (szx <= 6 && num < (1 << 20)).then_some(Self { num, more, szx })
+    }
+
+    /// Parses the numeric value of a Block1 or Block2 option.
+    ///
+    /// Returns `None` on the reserved size exponent 7 and on oversized values.
+    #[must_use]
+    pub fn from_option_value(value: u32) -> Option<Self> {
+        #[expect(clippy::cast_possible_truncation, reason = "masked to 3 bits")]
+        let szx = (value & 0x7) as u8;
+        Self::new(value >> 4, value & 0x8 != 0, szx)
+    }
+
+    /// Returns the numeric value of the option.
+    #[must_use]
+    pub fn to_option_value(self) -> u32 {
+        (self.num << 4) | (u32::from(self.more) << 3) | u32::from(self.szx)
+    }
+
+    /// The block number.
+    #[must_use]
+    pub fn num(self) -> u32 {
+        self.num
+    }
+
+    /// Whether more blocks follow.
+    #[must_use]
+    pub fn more(self) -> bool {
+        self.more
+    }
+
+    /// The block size in bytes.
+    #[must_use]
+    pub fn size(self) -> usize {
+        16 << self.szx
+    }
+
+    /// Position of the block's first byte in the complete representation.
+    #[must_use]
+    pub fn start(self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    /// Returns the same block in a size of at most `2^(szx + 4)`, starting at the same position.
+    fn limited_to(self, szx: u8) -> Self {
+        if self.szx <= szx {
+            self
+        } else {
+            Self {
+                num: self.num << (self.szx - szx),
+                more: self.more,
+                szx,
+            }
+        }
+    }
+}
+
+/// Reads the option with the given number as a [`BlockValue`].
+///
+/// # Errors
+///
+/// Produces a Bad Option error if the value is invalid.
+fn block_option(option: &impl coap_message::MessageOption) -> Result<BlockValue, CoAPError> {
+    option
+        .value_uint::<u32>()
+        .and_then(BlockValue::from_option_value)
+        .ok_or_else(|| CoAPError::bad_option(option.number()))
+}
+
+/// Data that can be read piecewise by a [`BlockReadResource`].
+pub trait BlockSource {
+    /// Copies data starting at `offset` into `buffer`, and returns the number of bytes copied.
+    ///
+    /// Copying fewer bytes than fit into the buffer indicates the end of the data.
+    ///
+    /// # Errors
+    ///
+    /// Any error is sent to the client.
+    fn read(&mut self, offset: usize, buffer: &mut [u8]) -> Result<usize, CoAPError>;
+
+    /// Content format to indicate in responses, if any.
+    fn content_format(&self) -> Option<u16> {
+        None
+    }
+}
+
+/// A resource that serves a [`BlockSource`] to GET requests, block-wise if needed.
+pub struct BlockReadResource<S: BlockSource>(pub S);
+
+impl<S: BlockSource> coap_handler::Handler for BlockReadResource<S> {
+    type RequestData = BlockValue;
+    type ExtractRequestError = CoAPError;
+    type BuildResponseError<M: MinimalWritableMessage> = CoAPError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        let mut block = BlockValue::new(0, false, MAX_RESPONSE_SZX).expect("value is in range");
+        for option in request.options() {
+            match option.number() {
+                coap_numbers::option::URI_PATH => (),
+                coap_numbers::option::BLOCK2 => block = block_option(&option)?,
+                number => reject_critical(number)?,
+            }
+        }
+
+        let code: u8 = request.code().into();
+        if code != coap_numbers::code::GET {
+            return Err(CoAPError::method_not_allowed());
+        }
+
+        Ok(block.limited_to(MAX_RESPONSE_SZX))
+    }
+
+    fn estimate_length(&mut self, request: &Self::RequestData) -> usize {
+        request.size() + 16
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        // One byte more than the block, to find out whether more data follows.
+        let mut buffer = [0u8; MAX_BLOCK_SIZE + 1];
+        let buffer = buffer
+            .get_mut(..=request.size())
+            .expect("block size is limited");
+        let read = self.0.read(request.start(), buffer)?;
+        if read == 0 && request.num() > 0 {
+            return Err(CoAPError::bad_option(coap_numbers::option::BLOCK2));
+        }
+        let more = read > request.size();
+        let block = BlockValue { more, ..request };
+
+        response.set_code(M::Code::new(coap_numbers::code::CONTENT).map_err(internal)?);
+        if let Some(content_format) = self.0.content_format() {
+            response
+                .add_option_uint(
+                    M::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT).map_err(internal)?,
+                    content_format,
+                )
+                .map_err(internal)?;
+        }
+        if more || block.num() > 0 {
+            response
+                .add_option_uint(
+                    M::OptionNumber::new(coap_numbers::option::BLOCK2).map_err(internal)?,
+                    block.to_option_value(),
+                )
+                .map_err(internal)?;
+        }
+        response
+            .set_payload(buffer.get(..read.min(request.size())).unwrap_or_default())
+            .map_err(internal)?;
+        Ok(())
+    }
+}
+
+/// Destination of data uploaded to a [`BlockWriteResource`].
+pub trait BlockSink {
+    /// Accepts `data` that is located at `offset` in the complete representation.
+    ///
+    /// Blocks arrive in sequence; a block at offset 0 starts a new upload (possibly abandoning a
+    /// previous one). `last` is set on the final block.
+    ///
+    /// # Errors
+    ///
+    /// Any error is sent to the client. The block is not considered received, so the client may
+    /// send it again (e.g., after a 5.03 Service Unavailable error), or restart the upload.
+    fn write(&mut self, offset: usize, data: &[u8], last: bool) -> Result<(), CoAPError>;
+}
+
+/// Outcome of processing an uploaded block.
+#[doc(hidden)]
+#[derive(Debug)]
+pub enum BlockWriteOutcome {
+    /// The block was accepted and more are expected.
+    Continue(BlockValue),
+    /// The upload is complete.
+    Done(Option<BlockValue>),
+    /// The block did not follow the previous one.
+    Incomplete,
+}
+
+/// A resource that accepts PUT or POST requests, block-wise if needed, and passes their payload
+/// to a [`BlockSink`].
+///
+/// Only one upload is processed at a time.
+pub struct BlockWriteResource<S: BlockSink> {
+    sink: S,
+    /// Offset at which the next block is expected, if an upload is in progress.
+    expected: Option<usize>,
+}
+
+impl<S: BlockSink> BlockWriteResource<S> {
+    /// Creates a resource passing uploads to `sink`.
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            expected: None,
+        }
+    }
+}
+
+impl<S: BlockSink> coap_handler::Handler for BlockWriteResource<S> {
+    type RequestData = BlockWriteOutcome;
+    type ExtractRequestError = CoAPError;
+    type BuildResponseError<M: MinimalWritableMessage> = CoAPError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        let mut block = None;
+        for option in request.options() {
+            match option.number() {
+                coap_numbers::option::URI_PATH | coap_numbers::option::CONTENT_FORMAT => (),
+                coap_numbers::option::BLOCK1 => block = Some(block_option(&option)?),
+                number => reject_critical(number)?,
+            }
+        }
+
+        let code: u8 = request.code().into();
+        if !matches!(code, coap_numbers::code::PUT | coap_numbers::code::POST) {
+            return Err(CoAPError::method_not_allowed());
+        }
+
+        let payload = request.payload();
+        let Some(block) = block else {
+            self.expected = None;
+            self.sink.write(0, payload, true)?;
+            return Ok(BlockWriteOutcome::Done(None));
+        };
+
+        if block.more() && payload.len() != block.size() {
+            return Err(CoAPError::bad_option(coap_numbers::option::BLOCK1));
+        }
+        if block.num() != 0 && self.expected != Some(block.start()) {
+            return Ok(BlockWriteOutcome::Incomplete);
+        }
+
+        self.sink.write(block.start(), payload, !block.more())?;
+        self.expected = block.more().then(|| block.start() + payload.len());
+
+        Ok(if block.more() {
+            BlockWriteOutcome::Continue(block)
+        } else {
+            BlockWriteOutcome::Done(Some(block))
+        })
+    }
+
+    fn estimate_length(&mut self, _request: &Self::RequestData) -> usize {
+        16
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        let (code, block) = match request {
+            BlockWriteOutcome::Continue(block) => (coap_numbers::code::CONTINUE, Some(block)),
+            BlockWriteOutcome::Done(block) => (coap_numbers::code::CHANGED, block),
+            BlockWriteOutcome::Incomplete => (coap_numbers::code::REQUEST_ENTITY_INCOMPLETE, None),
+        };
+        response.set_code(M::Code::new(code).map_err(internal)?);
+        if let Some(block) = block {
+            response
+                .add_option_uint(
+                    M::OptionNumber::new(coap_numbers::option::BLOCK1).map_err(internal)?,
+                    block.to_option_value(),
+                )
+                .map_err(internal)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error of a block-wise exchange run by [`get_blockwise()`] or [`send_blockwise()`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BlockwiseError<T> {
+    /// The stack could not send a request or receive its response.
+    Transport(T),
+    /// A request could not be built (e.g. because the path is too long).
+    Request,
+    /// The server responded with an unsuccessful code.
+    Response(u8),
+    /// The server's block options did not match the requests.
+    Protocol,
+}
+
+/// Adds the Uri-Path options of `path` (e.g. `"/fw/0"`) to a request.
+pub(crate) fn add_path<M: MinimalWritableMessage>(
+    request: &mut M,
+    path: &str,
+) -> Result<(), M::UnionError> {
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        request.add_option(
+            M::OptionNumber::new(coap_numbers::option::URI_PATH)?,
+            segment.as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Finds a block option in a response.
+fn response_block(response: &impl ReadableMessage, number: u16) -> Result<Option<BlockValue>, ()> {
+    response
+        .options()
+        .find(|o| o.number() == number)
+        .map(|o| block_option(&o).map_err(|_| ()))
+        .transpose()
+}
+
+/// Builds the GET request for one block of a [`get_blockwise()`] exchange.
+fn build_block2_request<M: MinimalWritableMessage>(
+    request: &mut M,
+    path: &str,
+    block: BlockValue,
+) -> Result<(), M::UnionError> {
+    request.set_code(M::Code::new(coap_numbers::code::GET)?);
+    add_path(request, path)?;
+    // Also sent with the first request, so that the server does not pick a block size that
+    // exceeds the client's message buffers.
+    request.add_option_uint(
+        M::OptionNumber::new(coap_numbers::option::BLOCK2)?,
+        block.to_option_value(),
+    )?;
+    Ok(())
+}
+
+/// Passes the payload of the response to a request for the `requested` block to `sink`, and
+/// returns the next block to request, if any.
+fn process_block2_response<E>(
+    response: &impl ReadableMessage,
+    requested: BlockValue,
+    sink: &mut impl FnMut(usize, &[u8]),
+) -> Result<Option<BlockValue>, BlockwiseError<E>> {
+    let code: u8 = response.code().into();
+    if code != coap_numbers::code::CONTENT {
+        return Err(BlockwiseError::Response(code));
+    }
+    let Some(block) = response_block(response, coap_numbers::option::BLOCK2)
+        .map_err(|()| BlockwiseError::Protocol)?
+    else {
+        // The whole representation fit into a single response.
+        sink(0, response.payload());
+        return Ok(None);
+    };
+    // Servers may pick smaller blocks than requested, but not larger ones (RFC7959 Section 2.4).
+    if block.start() != requested.start() || block.szx > requested.szx {
+        return Err(BlockwiseError::Protocol);
+    }
+    sink(block.start(), response.payload());
+    Ok(block
+        .more()
+        .then(|| BlockValue::new(block.num() + 1, false, block.szx))
+        .flatten())
+}
+
+/// Runs a [`get_blockwise()`] exchange, sending each request through `request`.
+async fn run_get_blockwise<E>(
+    mut request: impl AsyncFnMut(BlockValue) -> Result<Option<BlockValue>, BlockwiseError<E>>,
+) -> Result<(), BlockwiseError<E>> {
+    let mut block = BlockValue::new(0, false, MAX_RESPONSE_SZX).expect("value is in range");
+    while let Some(next) = request(block).await? {
+        block = next;
+    }
+    Ok(())
+}
+
+/// One GET request of a [`get_blockwise()`] exchange.
+struct Block2Request<'a, F> {
+    path: &'a str,
+    block: BlockValue,
+    sink: &'a mut F,
+}
+
+impl<S: coap_request::Stack + ?Sized, F: FnMut(usize, &[u8])> coap_request::Request<S>
+    for Block2Request<'_, F>
+{
+    /// The next block to request, if any.
+    type Output = Result<Option<BlockValue>, BlockwiseError<S::TransportError>>;
+    type Carry = ();
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        build_block2_request(request, self.path, self.block)
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        _carry: (),
+    ) -> Self::Output {
+        process_block2_response(response, self.block, self.sink)
+    }
+}
+
+/// Fetches the resource at `path` with GET requests, following Block2 responses until the
+/// complete representation has been passed to `sink` (along with the offset of each piece).
+///
+/// # Errors
+///
+/// This errs if a request fails, or the server responds with anything other than Content.
+/// Pieces received up to then have been passed to the sink already.
+pub async fn get_blockwise<S: coap_request::Stack>(
+    stack: &mut S,
+    path: &str,
+    mut sink: impl FnMut(usize, &[u8]),
+) -> Result<(), BlockwiseError<S::TransportError>> {
+    run_get_blockwise(async |block| {
+        stack
+            .request(Block2Request {
+                path,
+                block,
+                sink: &mut sink,
+            })
+            .await
+            .map_err(BlockwiseError::Transport)?
+    })
+    .await
+}
+
+/// Like [`get_blockwise()`], but protects the requests with OSCORE through `client`, sending them
+/// through `transport` to the server presenting the `peer` credential.
+///
+/// # Errors
+///
+/// This errs like [`get_blockwise()`], and with [`BlockwiseError::Transport`] if a request can
+/// not be protected or its response can not be unprotected.
+pub async fn get_blockwise_oscore<Crypto, CryptoFactory, T>(
+    client: &mut coapcore::OscoreEdhocClient<Crypto, CryptoFactory>,
+    transport: &mut T,
+    peer: &lakers::Credential,
+    path: &str,
+    mut sink: impl FnMut(usize, &[u8]),
+) -> Result<(), BlockwiseError<coapcore::ClientError<T::Error>>>
+where
+    Crypto: lakers::Crypto,
+    CryptoFactory: Fn() -> Crypto,
+    T: coapcore::ClientTransport,
+{
+    run_get_blockwise(async |block| {
+        let mut built = Ok(());
+        let next = client
+            .request(
+                transport,
+                peer,
+                |request| built = build_block2_request(request, path, block).map_err(|_| ()),
+                |response| process_block2_response(response, block, &mut sink),
+            )
+            .await
+            .map_err(BlockwiseError::Transport)?;
+        built.map_err(|()| BlockwiseError::Request)?;
+        next
+    })
+    .await
+}
+
+/// Builds the request for one block of a [`send_blockwise()`] exchange.
+fn build_block1_request<M: MinimalWritableMessage>(
+    request: &mut M,
+    code: u8,
+    path: &str,
+    block: BlockValue,
+    data: &[u8],
+) -> Result<(), M::UnionError> {
+    request.set_code(M::Code::new(code)?);
+    add_path(request, path)?;
+    request.add_option_uint(
+        M::OptionNumber::new(coap_numbers::option::BLOCK1)?,
+        block.to_option_value(),
+    )?;
+    request.set_payload(data)?;
+    Ok(())
+}
+
+/// Returns the code and the Block1 option of the response to a [`send_blockwise()`] request.
+fn process_block1_response<E>(
+    response: &impl ReadableMessage,
+) -> Result<(u8, Option<BlockValue>), BlockwiseError<E>> {
+    Ok((
+        response.code().into(),
+        response_block(response, coap_numbers::option::BLOCK1)
+            .map_err(|()| BlockwiseError::Protocol)?,
+    ))
+}
+
+/// Runs a [`send_blockwise()`] exchange, sending the request for each block (along with its
+/// data) through `request`.
+async fn run_send_blockwise<E>(
+    payload: &[u8],
+    mut request: impl AsyncFnMut(
+        BlockValue,
+        &[u8],
+    ) -> Result<(u8, Option<BlockValue>), BlockwiseError<E>>,
+) -> Result<u8, BlockwiseError<E>> {
+    let mut szx = MAX_RESPONSE_SZX;
+    let mut offset = 0;
+    loop {
+        let size = 16usize << szx;
+        let data = payload.get(offset..).unwrap_or_default();
+        let (data, more) = match data.split_at_checked(size) {
+            Some((data, rest)) => (data, !rest.is_empty()),
+            None => (data, false),
+        };
+        let num = u32::try_from(offset / size).map_err(|_| BlockwiseError::Request)?;
+        let block = BlockValue::new(num, more, szx).ok_or(BlockwiseError::Request)?;
+
+        let (response_code, server_block) = request(block, data).await?;
+
+        if !more {
+            // Success codes are those of class 2.
+            return if response_code >> 5 == 2 {
+                Ok(response_code)
+            } else {
+                Err(BlockwiseError::Response(response_code))
+            };
+        }
+        if response_code != coap_numbers::code::CONTINUE {
+            return Err(BlockwiseError::Response(response_code));
+        }
+        offset += data.len();
+        // The server may ask for smaller blocks in its response (RFC7959 Section 2.5).
+        if let Some(server_block) = server_block {
+            szx = szx.min(server_block.szx);
+        }
+    }
+}
+
+/// One request of a [`send_blockwise()`] exchange.
+struct Block1Request<'a> {
+    code: u8,
+    path: &'a str,
+    block: BlockValue,
+    data: &'a [u8],
+}
+
+impl<S: coap_request::Stack + ?Sized> coap_request::Request<S> for Block1Request<'_> {
+    /// The server's response code and Block1 option.
+    type Output = Result<(u8, Option<BlockValue>), BlockwiseError<S::TransportError>>;
+    type Carry = ();
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        build_block1_request(request, self.code, self.path, self.block, self.data)
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        _carry: (),
+    ) -> Self::Output {
+        process_block1_response(response)
+    }
+}
+
+/// Sends `payload` to the resource at `path` in a request with the given `code` (typically PUT or
+/// POST), in blocks (Block1) of up to 512 bytes.
+///
+/// On success, returns the code of the server's final response.
+///
+/// # Errors
+///
+/// This errs if a request fails, or the server responds with an unsuccessful code.
+pub async fn send_blockwise<S: coap_request::Stack>(
+    stack: &mut S,
+    code: u8,
+    path: &str,
+    payload: &[u8],
+) -> Result<u8, BlockwiseError<S::TransportError>> {
+    run_send_blockwise(payload, async |block, data| {
+        stack
+            .request(Block1Request {
+                code,
+                path,
+                block,
+                data,
+            })
+            .await
+            .map_err(BlockwiseError::Transport)?
+    })
+    .await
+}
+
+/// Like [`send_blockwise()`], but protects the requests with OSCORE through `client`, sending
+/// them through `transport` to the server presenting the `peer` credential.
+///
+/// # Errors
+///
+/// This errs like [`send_blockwise()`], and with [`BlockwiseError::Transport`] if a request can
+/// not be protected or its response can not be unprotected.
+pub async fn send_blockwise_oscore<Crypto, CryptoFactory, T>(
+    client: &mut coapcore::OscoreEdhocClient<Crypto, CryptoFactory>,
+    transport: &mut T,
+    peer: &lakers::Credential,
+    code: u8,
+    path: &str,
+    payload: &[u8],
+) -> Result<u8, BlockwiseError<coapcore::ClientError<T::Error>>>
+where
+    Crypto: lakers::Crypto,
+    CryptoFactory: Fn() -> Crypto,
+    T: coapcore::ClientTransport,
+{
+    run_send_blockwise(payload, async |block, data| {
+        let mut built = Ok(());
+        let response = client
+            .request(
+                transport,
+                peer,
+                |request| {
+                    built = build_block1_request(request, code, path, block, data).map_err(|_| ());
+                },
+                process_block1_response,
+            )
+            .await
+            .map_err(BlockwiseError::Transport)?;
+        built.map_err(|()| BlockwiseError::Request)?;
+        response
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use coap_message::{MinimalWritableMessage as _, ReadableMessage as _};
+    use coap_message_implementations::inmemory_write;
+    use coap_message_utils::Error as CoAPError;
+    use embassy_futures::block_on;
+
+    use super::{
+        BlockReadResource, BlockSink, BlockSource, BlockValue, BlockWriteResource, BlockwiseError,
+        build_block1_request, build_block2_request, process_block1_response,
+        process_block2_response, run_get_blockwise, run_send_blockwise,
+    };
+
+    /// Test data that does not fit into a single block.
+    fn data() -> [u8; 1100] {
+        core::array::from_fn(|i| i.to_le_bytes()[0])
+    }
+
+    type Collected = heapless::Vec<u8, 2048>;
+
+    struct Source([u8; 1100]);
+
+    impl BlockSource for Source {
+        fn read(&mut self, offset: usize, buffer: &mut [u8]) -> Result<usize, CoAPError> {
+            let data = self.0.get(offset..).unwrap_or_default();
+            let len = data.len().min(buffer.len());
+            buffer
+                .get_mut(..len)
+                .unwrap()
+                .copy_from_slice(data.get(..len).unwrap());
+            Ok(len)
+        }
+    }
+
+    struct Sink(Collected, bool);
+
+    impl BlockSink for Sink {
+        fn write(&mut self, offset: usize, data: &[u8], last: bool) -> Result<(), CoAPError> {
+            if offset == 0 {
+                self.0.clear();
+            }
+            assert_eq!(offset, self.0.len());
+            self.0.extend_from_slice(data).unwrap();
+            self.1 = last;
+            Ok(())
+        }
+    }
+
+    /// Passes a request built by `build` to `handler`, and its response to `process`.
+    fn exchange<H: coap_handler::Handler<ExtractRequestError = CoAPError>, R>(
+        handler: &mut H,
+        build: impl FnOnce(&mut inmemory_write::Message<'_>),
+        process: impl FnOnce(&inmemory_write::Message<'_>) -> R,
+    ) -> Result<R, CoAPError> {
+        let mut request_code = 0;
+        let mut request_buffer = [0; 1100];
+        let mut request = inmemory_write::Message::new(&mut request_code, &mut request_buffer[..]);
+        build(&mut request);
+
+        let request_data = handler.extract_request_data(&request)?;
+
+        let mut response_code = 0;
+        let mut response_buffer = [0; 1100];
+        let mut response =
+            inmemory_write::Message::new(&mut response_code, &mut response_buffer[..]);
+        handler
+            .build_response(&mut response, request_data)
+            .map_err(|_| CoAPError::internal_server_error())?;
+        Ok(process(&response))
+    }
+
+    /// Builds a Content response carrying the given Block2 option.
+    fn block2_response<R>(
+        block: BlockValue,
+        process: impl FnOnce(&inmemory_write::Message<'_>) -> R,
+    ) -> R {
+        let mut code = 0;
+        let mut buffer = [0; 64];
+        let mut response = inmemory_write::Message::new(&mut code, &mut buffer[..]);
+        response.set_code(coap_numbers::code::CONTENT);
+        response
+            .add_option_uint(coap_numbers::option::BLOCK2, block.to_option_value())
+            .unwrap();
+        response.set_payload(&[0; 16]).unwrap();
+        process(&response)
+    }
+
+    #[test]
+    fn block_value_encoding() {
+        let block = BlockValue::new(3, true, 2).unwrap();
+        assert_eq!(block.to_option_value(), 0x3a);
+        assert_eq!(BlockValue::from_option_value(0x3a), Some(block));
+        assert_eq!(block.size(), 64);
+        assert_eq!(block.start(), 192);
+
+        let last = BlockValue::from_option_value(0x16).unwrap();
+        assert_eq!((last.num(), last.more(), last.size()), (1, false, 1024));
+
+        // Reserved size exponent, and block numbers not fitting into 20 bits.
+        assert_eq!(BlockValue::from_option_value(0x7), None);
+        assert_eq!(BlockValue::new(0, false, 7), None);
+        assert_eq!(BlockValue::new(1 << 20, false, 0), None);
+        assert!(BlockValue::new((1 << 20) - 1, false, 0).is_some());
+    }
+
+    #[test]
+    fn limited_to_keeps_the_start() {
+        let block = BlockValue::new(1, true, 6).unwrap();
+        let limited = block.limited_to(4);
+        assert_eq!(limited, BlockValue::new(4, true, 4).unwrap());
+        assert_eq!(limited.start(), block.start());
+        assert_eq!(block.limited_to(6), block);
+        assert_eq!(limited.limited_to(5), limited);
+    }
+
+    #[test]
+    fn block2_sequence() {
+        let mut resource = BlockReadResource(Source(data()));
+        let mut collected = Collected::new();
+        let mut requested = heapless::Vec::<u32, 8>::new();
+
+        let result: Result<(), BlockwiseError<()>> = block_on(run_get_blockwise(async |block| {
+            requested.push(block.num()).unwrap();
+            exchange(
+                &mut resource,
+                |request| build_block2_request(request, "/data", block).unwrap(),
+                |response| {
+                    process_block2_response(response, block, &mut |offset, data: &[u8]| {
+                        assert_eq!(offset, collected.len());
+                        collected.extend_from_slice(data).unwrap();
+                    })
+                },
+            )
+            .unwrap()
+        }));
+
+        result.unwrap();
+        assert_eq!(requested, [0, 1, 2]);
+        assert_eq!(collected, data());
+    }
+
+    #[test]
+    fn block2_szx_negotiation() {
+        let requested = BlockValue::new(0, false, 5).unwrap();
+        let mut sink = |_: usize, _: &[u8]| ();
+
+        // The server may pick a smaller block size, which is kept for the following requests ...
+        let smaller = BlockValue::new(0, true, 4).unwrap();
+        let next = block2_response(smaller, |response| {
+            process_block2_response::<()>(response, requested, &mut sink)
+        });
+        assert_eq!(next.unwrap(), BlockValue::new(1, false, 4));
+
+        // ... but not a larger one, nor a block at a different position.
+        for block in [
+            BlockValue::new(0, true, 6).unwrap(),
+            BlockValue::new(1, true, 5).unwrap(),
+        ] {
+            let next = block2_response(block, |response| {
+                process_block2_response::<()>(response, requested, &mut sink)
+            });
+            assert!(matches!(next, Err(BlockwiseError::Protocol)), "{block:?}");
+        }
+
+        // Requests for large blocks are served in the server's maximum size.
+        let mut resource = BlockReadResource(Source(data()));
+        let large = BlockValue::new(0, false, 6).unwrap();
+        let next = exchange(
+            &mut resource,
+            |request| build_block2_request(request, "/data", large).unwrap(),
+            |response| process_block2_response::<()>(response, large, &mut sink),
+        )
+        .unwrap();
+        assert_eq!(next.unwrap(), BlockValue::new(1, false, 5));
+    }
+
+    #[test]
+    fn block1_sequence() {
+        let data = data();
+        let mut resource = BlockWriteResource::new(Sink(Collected::new(), false));
+        let mut sizes = heapless::Vec::<usize, 8>::new();
+
+        let result: Result<u8, BlockwiseError<()>> =
+            block_on(run_send_blockwise(&data, async |block, data| {
+                sizes.push(data.len()).unwrap();
+                exchange(
+                    &mut resource,
+                    |request| {
+                        build_block1_request(
+                            request,
+                            coap_numbers::code::PUT,
+                            "/data",
+                            block,
+                            data,
+                        )
+                        .unwrap();
+                    },
+                    process_block1_response,
+                )
+                .unwrap()
+            }));
+
+        assert_eq!(result.unwrap(), coap_numbers::code::CHANGED);
+        assert_eq!(sizes, [512, 512, 76]);
+        assert_eq!(resource.sink.0, data);
+        assert!(resource.sink.1);
+    }
+
+    #[test]
+    fn block1_follows_the_server_szx() {
+        let mut sizes = heapless::Vec::<usize, 8>::new();
+
+        let result: Result<u8, BlockwiseError<()>> =
+            block_on(run_send_blockwise(&data(), async |block, data| {
+                sizes.push(data.len()).unwrap();
+                let code = if block.more() {
+                    coap_numbers::code::CONTINUE
+                } else {
+                    coap_numbers::code::CHANGED
+                };
+                Ok((code, BlockValue::new(block.num(), block.more(), 4)))
+            }));
+
+        assert_eq!(result.unwrap(), coap_numbers::code::CHANGED);
+        // 512 bytes, then blocks of 256 bytes from offset 512 on.
+        assert_eq!(sizes, [512, 256, 256, 76]);
+    }
+
+    #[test]
+    fn block1_rejects_out_of_order_and_mismatched_blocks() {
+        let data = data();
+        let mut resource = BlockWriteResource::new(Sink(Collected::new(), false));
+        let mut put = |block: BlockValue, len: usize| {
+            exchange(
+                &mut resource,
+                |request| {
+                    let data = data.get(..len).unwrap();
+                    build_block1_request(request, coap_numbers::code::PUT, "/data", block, data)
+                        .unwrap();
+                },
+                |response| response.code(),
+            )
+        };
+
+        // A block other than the first one while no upload is in progress, or after skipping a
+        // block.
+        let incomplete = coap_numbers::code::REQUEST_ENTITY_INCOMPLETE;
+        assert_eq!(
+            put(BlockValue::new(1, true, 2).unwrap(), 64).unwrap(),
+            incomplete
+        );
+        assert_eq!(
+            put(BlockValue::new(0, true, 2).unwrap(), 64).unwrap(),
+            coap_numbers::code::CONTINUE
+        );
+        assert_eq!(
+            put(BlockValue::new(2, true, 2).unwrap(), 64).unwrap(),
+            incomplete
+        );
+
+        // Blocks other than the last one need to be of the indicated size.
+        assert!(put(BlockValue::new(1, true, 2).unwrap(), 63).is_err());
+        assert_eq!(
+            put(BlockValue::new(1, false, 2).unwrap(), 63).unwrap(),
+            coap_numbers::code::CHANGED
+        );
+
+        assert_eq!(resource.sink.0, data.get(..127).unwrap());
+        assert!(resource.sink.1);
+    }
+}
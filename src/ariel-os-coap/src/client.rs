@@ -0,0 +1,389 @@
+//! A CoAP client that can be used from any executor or thread.
+//!
+//! The client of [`embedded_nal_coap`] can only be used from the task that runs the CoAP server.
+//! A [`Client`] instead passes each request through a slot shared with that task: The request is
+//! built by the caller, sent by the CoAP task, and its response is processed by the caller again.
+//! There is one slot per concurrent request the stack supports; further requests wait for a slot
+//! to become free.
+//!
+//! From a thread, requests can be run through `ariel_os::asynch::blocker::block_on()` (with the
+//! `threading` laze module).
+//!
+//! Requests are sent unprotected. To protect them with OSCORE (establishing security contexts
+//! through EDHOC), a [`RequestingClient`] serves as the [`coapcore::ClientTransport`] of a
+//! [`coapcore::OscoreEdhocClient`], which the application creates with its own credential.
+//! Messages of such a client need to fit into the options and payload passed through a slot
+//! ([`MESSAGE_BUFFER_SIZE`] bytes).
+
+use core::{cell::RefCell, net::SocketAddr};
+
+use coap_message::{MessageOption as _, MinimalWritableMessage, ReadableMessage};
+use coap_message_implementations::{inmemory, inmemory_write};
+use coap_request::Stack as _;
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    channel::Channel,
+    mutex::Mutex as AsyncMutex,
+    once_lock::OnceLock,
+    signal::Signal,
+};
+
+use crate::CONCURRENT_REQUESTS;
+
+/// Space for the options and payload of requests and responses passed through a slot.
+///
+/// This needs to hold the blocks of block-wise transfers (512 bytes) along with their options,
+/// and the larger messages of OSCORE and EDHOC.
+pub const MESSAGE_BUFFER_SIZE: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_CLIENT_MESSAGE_BUFFER_SIZE",
+    1024,
+    "size of the options and payload of a CoAP client request or response (in bytes)"
+);
+
+static SLOTS: [Slot; CONCURRENT_REQUESTS] = [const { Slot::new() }; CONCURRENT_REQUESTS];
+
+static FREE_SLOTS: Channel<CriticalSectionRawMutex, &'static Slot, CONCURRENT_REQUESTS> =
+    Channel::new();
+
+/// Slots holding requests to be sent by the CoAP task.
+static REQUESTS: Channel<CriticalSectionRawMutex, &'static Slot, CONCURRENT_REQUESTS> =
+    Channel::new();
+
+/// Set once the CoAP task forwards requests.
+pub(crate) static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Error of a request sent through a [`Client`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The request could not be built, e.g., because it is too large.
+    Request,
+    /// The request could not be sent, or no response was received.
+    Transport,
+    /// The response was too large to be passed on.
+    Response,
+}
+
+/// Who is using a slot that was taken.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Phase {
+    /// The requester sets up its request, or reads the response.
+    Held,
+    /// The CoAP task sends the request, and writes the response.
+    Forwarding,
+    /// The requester stopped waiting while the CoAP task was forwarding; the CoAP task gives the
+    /// slot back once it is done with it.
+    Abandoned,
+}
+
+struct SlotState {
+    phase: Phase,
+    remote: Option<SocketAddr>,
+}
+
+/// The request of a slot, and later its response.
+struct SlotMessage {
+    code: u8,
+    len: usize,
+    /// Options and payload.
+    buffer: [u8; MESSAGE_BUFFER_SIZE],
+}
+
+struct Slot {
+    state: Mutex<CriticalSectionRawMutex, RefCell<SlotState>>,
+    /// Accessed by whoever the phase says is using the slot.
+    ///
+    /// This is behind an async mutex (only locked for short by the phase handover) rather than
+    /// the state's, so that messages are written and read in place, without holding a critical
+    /// section.
+    message: AsyncMutex<CriticalSectionRawMutex, SlotMessage>,
+    /// Signaled by the CoAP task once it processed the request.
+    done: Signal<CriticalSectionRawMutex, Result<(), Error>>,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(SlotState {
+                phase: Phase::Held,
+                remote: None,
+            })),
+            message: AsyncMutex::new(SlotMessage {
+                code: 0,
+                len: 0,
+                buffer: [0; MESSAGE_BUFFER_SIZE],
+            }),
+            done: Signal::new(),
+        }
+    }
+
+    /// Makes the slot available to other requests.
+    fn release(&'static self) {
+        // There is room for all slots.
+        let _ = FREE_SLOTS.try_send(self);
+    }
+}
+
+/// A slot taken by a [`Client`], which is given back when dropped.
+///
+/// While the CoAP task forwards the request, it is the CoAP task that gives it back.
+struct TakenSlot(&'static Slot);
+
+impl TakenSlot {
+    async fn take() -> Self {
+        let slot = FREE_SLOTS.receive().await;
+        // A previous holder may have stopped waiting after the CoAP task was done.
+        slot.done.reset();
+        Self(slot)
+    }
+}
+
+impl Drop for TakenSlot {
+    fn drop(&mut self) {
+        let forwarding = self.0.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            if state.phase == Phase::Forwarding {
+                state.phase = Phase::Abandoned;
+                true
+            } else {
+                false
+            }
+        });
+        if !forwarding {
+            self.0.release();
+        }
+    }
+}
+
+/// The system's CoAP client, see [`coap_client()`](crate::coap_client).
+pub struct Client {
+    _private: (),
+}
+
+impl Client {
+    /// Returns a [`coap_request::Stack`] that sends requests to `remote`.
+    #[must_use]
+    pub fn to(&self, remote: SocketAddr) -> RequestingClient {
+        RequestingClient { remote }
+    }
+}
+
+/// A [`Client`] that sends requests to a single server.
+pub struct RequestingClient {
+    remote: SocketAddr,
+}
+
+impl coap_request::Stack for RequestingClient {
+    type RequestUnionError =
+        <inmemory_write::Message<'static> as MinimalWritableMessage>::UnionError;
+    type RequestMessage<'a>
+        = inmemory_write::Message<'a>
+    where
+        Self: 'a;
+    type ResponseMessage<'a>
+        = inmemory::Message<'a>
+    where
+        Self: 'a;
+    type TransportError = Error;
+
+    async fn request<Req: coap_request::Request<Self>>(
+        &mut self,
+        mut request: Req,
+    ) -> Result<Req::Output, Self::TransportError> {
+        let slot = TakenSlot::take().await;
+
+        // The request is built right into the slot.
+        let carry = {
+            let mut slot_message = slot.0.message.lock().await;
+            let slot_message = &mut *slot_message;
+            let mut message =
+                inmemory_write::Message::new(&mut slot_message.code, &mut slot_message.buffer[..]);
+            let carry = request
+                .build_request(&mut message)
+                .await
+                .map_err(|_| Error::Request)?;
+            slot_message.len = message.finish();
+            carry
+        };
+
+        slot.0.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            state.phase = Phase::Forwarding;
+            state.remote = Some(self.remote);
+        });
+        // There is room for all slots, as each is queued at most once until the CoAP task gives
+        // it back.
+        let _ = REQUESTS.try_send(slot.0);
+
+        slot.0.done.wait().await?;
+
+        let slot_message = slot.0.message.lock().await;
+        let response = inmemory::Message::new(
+            slot_message.code,
+            slot_message
+                .buffer
+                .get(..slot_message.len)
+                .unwrap_or_default(),
+        );
+        Ok(request.process_response(&response, carry).await)
+    }
+}
+
+impl coapcore::ClientTransport for RequestingClient {
+    type Error = Error;
+
+    async fn exchange(
+        &mut self,
+        request: &inmemory_write::Message<'_>,
+        response: &mut inmemory_write::Message<'_>,
+    ) -> Result<(), Self::Error> {
+        self.request(Exchange { request, response }).await?
+    }
+}
+
+/// Copies a message of a [`coapcore::OscoreEdhocClient`] into a request, and the response back.
+struct Exchange<'a, 'b, 'c> {
+    request: &'a inmemory_write::Message<'b>,
+    response: &'a mut inmemory_write::Message<'c>,
+}
+
+impl<S: coap_request::Stack + ?Sized> coap_request::Request<S> for Exchange<'_, '_, '_> {
+    type Output = Result<(), Error>;
+    type Carry = ();
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        request.set_code(coap_message::Code::new(self.request.code())?);
+        for option in self.request.options() {
+            request.add_option(
+                coap_message::OptionNumber::new(option.number())?,
+                option.value(),
+            )?;
+        }
+        request.set_payload(self.request.payload())?;
+        Ok(())
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        _carry: (),
+    ) -> Self::Output {
+        self.response.set_code(response.code().into());
+        for option in response.options() {
+            self.response
+                .add_option(option.number(), option.value())
+                .map_err(|_| Error::Response)?;
+        }
+        self.response
+            .set_payload(response.payload())
+            .map_err(|_| Error::Response)
+    }
+}
+
+/// Sends the requests of [`Client`]s through the CoAP task's client.
+///
+/// This needs to run alongside the CoAP server; it processes as many requests concurrently as
+/// the client supports.
+pub(crate) async fn forward_requests(
+    client: &embedded_nal_coap::CoAPRuntimeClient<'_, CONCURRENT_REQUESTS>,
+) -> ! {
+    for slot in &SLOTS {
+        // There is room for all slots.
+        let _ = FREE_SLOTS.try_send(slot);
+    }
+    let _ = CLIENT.init(Client { _private: () });
+
+    let forwarders: [_; CONCURRENT_REQUESTS] = core::array::from_fn(|_| forward(client));
+    embassy_futures::select::select_array(forwarders).await.0
+}
+
+async fn forward(client: &embedded_nal_coap::CoAPRuntimeClient<'_, CONCURRENT_REQUESTS>) -> ! {
+    loop {
+        let slot = REQUESTS.receive().await;
+        let remote = slot.state.lock(|state| {
+            let state = state.borrow();
+            state.remote.filter(|_| state.phase == Phase::Forwarding)
+        });
+
+        let result = match remote {
+            Some(remote) => match client.to(remote).request(Forward { slot }).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(())) => Err(Error::Response),
+                Err(_) => Err(Error::Transport),
+            },
+            // The request was abandoned before it was sent.
+            None => Err(Error::Transport),
+        };
+
+        // Signaled under the lock, so that the requester can not give the slot back in between.
+        let abandoned = slot.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            let abandoned = state.phase == Phase::Abandoned;
+            state.phase = Phase::Held;
+            if !abandoned {
+                slot.done.signal(result);
+            }
+            abandoned
+        });
+        if abandoned {
+            slot.release();
+        }
+    }
+}
+
+/// Copies a request from a slot into the CoAP task's client, and its response back.
+struct Forward {
+    slot: &'static Slot,
+}
+
+impl<S: coap_request::Stack + ?Sized> coap_request::Request<S> for Forward {
+    /// Whether the response fit into the slot.
+    type Output = Result<(), ()>;
+    type Carry = ();
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        let slot_message = self.slot.message.lock().await;
+        let message = inmemory::Message::new(
+            slot_message.code,
+            slot_message
+                .buffer
+                .get(..slot_message.len)
+                .unwrap_or_default(),
+        );
+        request.set_code(coap_message::Code::new(message.code())?);
+        for option in message.options() {
+            request.add_option(
+                coap_message::OptionNumber::new(option.number())?,
+                option.value(),
+            )?;
+        }
+        request.set_payload(message.payload())?;
+        Ok(())
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        _carry: (),
+    ) -> Self::Output {
+        let mut slot_message = self.slot.message.lock().await;
+        let slot_message = &mut *slot_message;
+        let mut message =
+            inmemory_write::Message::new(&mut slot_message.code, &mut slot_message.buffer[..]);
+        message.set_code(response.code().into());
+        for option in response.options() {
+            message
+                .add_option(option.number(), option.value())
+                .map_err(|_| ())?;
+        }
+        message.set_payload(response.payload()).map_err(|_| ())?;
+        slot_message.len = message.finish();
+        Ok(())
+    }
+}
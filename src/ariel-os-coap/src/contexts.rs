@@ -0,0 +1,183 @@
+//! Persistence of OSCORE security contexts in ariel-os storage
+//!
+//! Security contexts established with the server are kept across reboots, so that clients can
+//! continue using them instead of running EDHOC or ACE again. The [`coapcore::persist`] module
+//! describes what is stored when; this module applies the handler's requests to storage, one slot
+//! per context, and restores the stored contexts at startup.
+
+use core::cell::RefCell;
+
+use ariel_os_debug::log::{debug, error, info};
+use coapcore::persist::{
+    InvalidRecord, MAX_CLAIMS_LEN, MAX_MATERIAL_LEN, PersistenceAction, PersistenceRequest, SLOTS,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+
+/// Signals [`persist_contexts()`] that the handler may have changes to persist.
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Reports that a request or notification was processed, after which security contexts may need
+/// to be persisted.
+pub(crate) fn changed() {
+    CHANGED.signal(());
+}
+
+/// Storage keys of a context slot's material, claims and sequence number.
+///
+/// These are kept short to leave room for the material in the storage item.
+fn context_keys(
+    slot: usize,
+) -> (
+    heapless::String<16>,
+    heapless::String<16>,
+    heapless::String<16>,
+) {
+    use core::fmt::Write as _;
+
+    let mut material_key = heapless::String::new();
+    let mut claims_key = heapless::String::new();
+    let mut sequence_key = heapless::String::new();
+    write!(material_key, "coap.ctx/{slot}").expect("fits by construction");
+    write!(claims_key, "coap.claims/{slot}").expect("fits by construction");
+    write!(sequence_key, "coap.seq/{slot}").expect("fits by construction");
+    (material_key, claims_key, sequence_key)
+}
+
+/// Operations of the [`coapcore::OscoreEdhocHandler`] used to persist security contexts.
+pub(crate) trait Persistable {
+    fn restore_context(
+        &mut self,
+        slot: usize,
+        material: &[u8],
+        claims: &[u8],
+        sequence_number: u64,
+    ) -> Result<(), InvalidRecord>;
+    fn next_persistence_request(&mut self) -> Option<PersistenceRequest>;
+    fn persisted(&mut self, request: PersistenceRequest);
+}
+
+impl<
+    H: coap_handler::Handler,
+    Crypto: lakers::Crypto,
+    CryptoFactory: Fn() -> Crypto,
+    SSC: coapcore::seccfg::ServerSecurityConfig,
+    RNG: rand_core::RngCore + rand_core::CryptoRng,
+    TP: coapcore::time::TimeProvider,
+    AL: coapcore::metrics::AccessLog,
+> Persistable for coapcore::OscoreEdhocHandler<H, Crypto, CryptoFactory, SSC, RNG, TP, AL>
+{
+    fn restore_context(
+        &mut self,
+        slot: usize,
+        material: &[u8],
+        claims: &[u8],
+        sequence_number: u64,
+    ) -> Result<(), InvalidRecord> {
+        self.restore_context(slot, material, claims, sequence_number)
+    }
+
+    fn next_persistence_request(&mut self) -> Option<PersistenceRequest> {
+        self.next_persistence_request()
+    }
+
+    fn persisted(&mut self, request: PersistenceRequest) {
+        self.persisted(request);
+    }
+}
+
+/// Restores the stored security contexts into the handler.
+///
+/// This needs to run before the server processes any request.
+pub(crate) async fn restore<H: Persistable>(handler: &RefCell<H>) {
+    for slot in 0..SLOTS {
+        let (material_key, claims_key, sequence_key) = context_keys(slot);
+        let material: Option<heapless::Vec<u8, MAX_MATERIAL_LEN>> =
+            ariel_os_storage::get(&material_key)
+                .await
+                .expect("flash error prevents startup");
+        let claims: Option<heapless::Vec<u8, MAX_CLAIMS_LEN>> = ariel_os_storage::get(&claims_key)
+            .await
+            .expect("flash error prevents startup");
+        let sequence_number: Option<u64> = ariel_os_storage::get(&sequence_key)
+            .await
+            .expect("flash error prevents startup");
+
+        let restored = match (&material, &claims, sequence_number) {
+            (None, None, None) => continue,
+            (Some(material), Some(claims), Some(sequence_number)) => handler
+                .borrow_mut()
+                .restore_context(slot, material, claims, sequence_number)
+                .is_ok(),
+            _ => false,
+        };
+        if restored {
+            info!("Restored security context {}.", slot);
+        } else {
+            error!("Discarding invalid security context {}.", slot);
+            apply(slot, &PersistenceAction::Remove)
+                .await
+                .expect("flash error prevents startup");
+        }
+    }
+
+    // Restoring may leave slots to be cleaned up.
+    apply_requests(handler).await;
+}
+
+/// Applies a single action to the storage items of `slot`.
+async fn apply(slot: usize, action: &PersistenceAction) -> Result<(), impl core::fmt::Debug> {
+    let (material_key, claims_key, sequence_key) = context_keys(slot);
+
+    let mut storage = ariel_os_storage::lock().await;
+    // Sequence numbers are advanced most often, and are a single item; all other changes span
+    // all items of the slot, which need to change atomically.
+    if let PersistenceAction::Advance { sequence_number } = action {
+        return storage.insert(&sequence_key, *sequence_number).await;
+    }
+
+    let mut txn = storage.transaction().await?;
+    match action {
+        PersistenceAction::Store {
+            material,
+            claims,
+            sequence_number,
+        } => {
+            txn.insert(&material_key, material.clone()).await?;
+            txn.insert(&claims_key, claims.clone()).await?;
+            txn.insert(&sequence_key, *sequence_number).await?;
+        }
+        _ => {
+            txn.remove(&material_key).await?;
+            txn.remove(&claims_key).await?;
+            txn.remove(&sequence_key).await?;
+        }
+    }
+    txn.commit().await
+}
+
+/// Applies the handler's persistence requests until there are none left, or one fails.
+async fn apply_requests<H: Persistable>(handler: &RefCell<H>) {
+    loop {
+        // Not holding on to the handler while storage is accessed
+        let request = handler.borrow_mut().next_persistence_request();
+        let Some(request) = request else {
+            return;
+        };
+        if apply(request.slot(), request.action()).await.is_err() {
+            error!("Failed to persist security context, retrying with the next change.");
+            return;
+        }
+        debug!("Persisted security context {}.", request.slot());
+        handler.borrow_mut().persisted(request);
+    }
+}
+
+/// Persists security contexts as requested by the handler.
+///
+/// This needs to run alongside the CoAP server, as the handler can not access storage itself.
+pub(crate) async fn persist_contexts<H: Persistable>(handler: &RefCell<H>) -> ! {
+    loop {
+        CHANGED.wait().await;
+        apply_requests(handler).await;
+    }
+}
@@ -8,26 +8,122 @@
 #![no_std]
 #![deny(missing_docs)]
 
+/// Runs the future `$run` with the never returning future `$background` alongside it, producing
+/// the output of `$run`.
+///
+/// This is a macro rather than a function because the never type can not be named in a stable
+/// function signature.
+macro_rules! alongside {
+    ($run:expr, $background:expr $(,)?) => {
+        async {
+            match embassy_futures::select::select($run, $background).await {
+                embassy_futures::select::Either::First(result) => result,
+                embassy_futures::select::Either::Second(never) => never,
+            }
+        }
+    };
+}
+
 // Moving work from https://github.com/embassy-rs/embassy/pull/2519 in here for the time being
 mod udp_nal;
 
+mod observe;
+
+pub mod blockwise;
+pub mod client;
+
+#[cfg(feature = "coap-server-config-storage")]
+mod contexts;
+#[cfg(feature = "coap-mdns")]
+mod mdns;
+#[cfg(feature = "coap-metrics")]
+pub mod metrics;
+#[cfg(feature = "coap-ota")]
+mod ota;
+#[cfg(feature = "coap-rd")]
+mod rd;
 #[cfg(feature = "coap-server-config-storage")]
 mod stored;
+#[cfg(feature = "coap-system-resources")]
+mod system;
+#[cfg(feature = "coap-tcp")]
+mod tcp;
+mod util;
+
+pub mod time;
+
+pub use observe::notify;
 
-use ariel_os_debug::log::info;
-use ariel_os_embassy::cell::SameExecutorCell;
+use core::{
+    cell::RefCell,
+    net::{Ipv6Addr, SocketAddr},
+};
+
+use ariel_os_debug::log::{error, info};
 use coap_handler_implementations::ReportingHandlerBuilder;
 use embassy_net::udp::{PacketMetadata, UdpSocket};
-use embassy_sync::watch::Watch;
-use static_cell::StaticCell;
+use static_cell::{ConstStaticCell, StaticCell};
+
+/// Number of requests that can be processed concurrently on each socket, both by the CoAP server
+/// and the CoAP client.
+const CONCURRENT_REQUESTS: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_CONCURRENT_REQUESTS",
+    3,
+    "number of concurrent CoAP requests per socket"
+);
 
-const CONCURRENT_REQUESTS: usize = 3;
+/// UDP port of the CoAP server's main socket, which is also used by the CoAP client.
+const PORT: u16 =
+    ariel_os_utils::u16_from_env_or!("CONFIG_COAP_PORT", 5683, "UDP port of the CoAP server");
 
-static CLIENT_READY: Watch<
-    embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex,
-    SameExecutorCell<&'static embedded_nal_coap::CoAPRuntimeClient<'static, CONCURRENT_REQUESTS>>,
-    1,
-> = Watch::new();
+const RX_BUFFER_SIZE: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_RX_BUFFER_SIZE",
+    1500,
+    "size of the receive buffer of each CoAP socket (in bytes)"
+);
+const TX_BUFFER_SIZE: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_TX_BUFFER_SIZE",
+    1500,
+    "size of the transmit buffer of each CoAP socket (in bytes)"
+);
+const RX_PACKETS: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_RX_PACKETS",
+    2,
+    "number of datagrams each CoAP socket can hold in its receive buffer"
+);
+const TX_PACKETS: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_TX_PACKETS",
+    2,
+    "number of datagrams each CoAP socket can hold in its transmit buffer"
+);
+
+/// Additional sockets and multicast groups, as configured through `CONFIG_COAP_ADDITIONAL_PORTS`
+/// and `CONFIG_COAP_MULTICAST_GROUPS`.
+mod sockets {
+    include!(concat!(env!("OUT_DIR"), "/sockets.rs"));
+}
+
+/// Number of sockets the CoAP server listens on.
+const SOCKET_COUNT: usize = 1 + sockets::ADDITIONAL_PORTS.len();
+
+/// Buffers of a CoAP server socket.
+struct SocketBuffers {
+    rx_meta: [PacketMetadata; RX_PACKETS],
+    rx: [u8; RX_BUFFER_SIZE],
+    tx_meta: [PacketMetadata; TX_PACKETS],
+    tx: [u8; TX_BUFFER_SIZE],
+}
+
+impl SocketBuffers {
+    const fn new() -> Self {
+        Self {
+            rx_meta: [PacketMetadata::EMPTY; RX_PACKETS],
+            rx: [0; RX_BUFFER_SIZE],
+            tx_meta: [PacketMetadata::EMPTY; TX_PACKETS],
+            tx: [0; TX_BUFFER_SIZE],
+        }
+    }
+}
 
 #[cfg(feature = "coap-server-config-demokeys")]
 mod demo_setup {
@@ -114,38 +210,54 @@ pub async fn coap_run(handler: impl coap_handler::Handler + coap_handler::Report
 ///
 /// This can only be run once, as it sets up a system wide CoAP handler.
 async fn coap_run_impl(handler: impl coap_handler::Handler + coap_handler::Reporting) -> ! {
-    static COAP: StaticCell<embedded_nal_coap::CoAPShared<CONCURRENT_REQUESTS>> = StaticCell::new();
+    static COAP: StaticCell<[embedded_nal_coap::CoAPShared<CONCURRENT_REQUESTS>; SOCKET_COUNT]> =
+        StaticCell::new();
+    // The buffer sizes default to a likely good starting point for "we process any message
+    // immediately anyway", and can be trimmed through the CONFIG_COAP_* variables.
+    static BUFFERS: ConstStaticCell<[SocketBuffers; SOCKET_COUNT]> =
+        ConstStaticCell::new([const { SocketBuffers::new() }; SOCKET_COUNT]);
 
     let stack = ariel_os_embassy::net::network_stack().await.unwrap();
 
-    // There's no strong need to wait this early (it matters that we wait before populating
-    // CLIENT_READY), but this is a convenient place in the code (we have a `stack` now, to populate
-    // CLIENT_READY after the server, we'd have to poll the server and `wait_config_up` in parallel), and
+    // There's no strong need to wait this early (it matters that we wait before making the client
+    // available), but this is a convenient place in the code (we have a `stack` now, to make the
+    // client available after the server, we'd have to poll the server and `wait_config_up` in parallel), and
     // it's not like we'd expect requests to come in before everything is up. (Not even a loopback
     // request, because we shouldn't hand out a client early).
     stack.wait_config_up().await;
 
-    // FIXME trim to CoAP requirements (those values are just a likely good starting point for "we
-    // process any message immediately anyway")
-    let mut rx_meta = [PacketMetadata::EMPTY; 2];
-    let mut rx_buffer = [0; 1500];
-    let mut tx_meta = [PacketMetadata::EMPTY; 2];
-    let mut tx_buffer = [0; 1500];
-
-    let socket = UdpSocket::new(
-        stack,
-        &mut rx_meta,
-        &mut rx_buffer,
-        &mut tx_meta,
-        &mut tx_buffer,
-    );
+    let mut buffers = BUFFERS.take().iter_mut();
+    let binding: [_; SOCKET_COUNT] = core::array::from_fn(|index| {
+        let buffers = buffers
+            .next()
+            .expect("there is one set of buffers per socket");
+        let port = index
+            .checked_sub(1)
+            .and_then(|index| sockets::ADDITIONAL_PORTS.get(index).copied())
+            .unwrap_or(PORT);
+        let socket = UdpSocket::new(
+            stack,
+            &mut buffers.rx_meta,
+            &mut buffers.rx,
+            &mut buffers.tx_meta,
+            &mut buffers.tx,
+        );
+        let local_any = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+        udp_nal::UnconnectedUdp::bind_multiple(socket, local_any)
+    });
 
     info!("Starting up CoAP server");
 
-    let local_any = "[::]:5683".parse().unwrap();
-    let mut unconnected = udp_nal::UnconnectedUdp::bind_multiple(socket, local_any)
+    let unconnected = embassy_futures::join::join_array(binding)
         .await
-        .unwrap();
+        .map(Result::unwrap);
+
+    // Requests sent to the groups arrive at any socket bound to their destination port.
+    for group in sockets::MULTICAST_GROUPS {
+        if stack.join_multicast_group(*group).is_err() {
+            error!("Could not join a CoAP multicast group.");
+        }
+    }
 
     cfg_if::cfg_if! {
         if #[cfg(feature = "coap-server-config-storage")] {
@@ -154,6 +266,13 @@ async fn coap_run_impl(handler: impl coap_handler::Handler + coap_handler::Repor
             let security_config = demo_setup::build_demo_ssc();
         } else if #[cfg(feature = "coap-server-config-unprotected")] {
             let security_config = coapcore::seccfg::AllowAll;
+
+            // Without any access control, anyone could reboot the device or read its metrics.
+            #[cfg(all(
+                any(feature = "coap-system-resources", feature = "coap-metrics"),
+                not(feature = "doc")
+            ))]
+            compile_error!("The coap-system-resources and coap-metrics features can not be used with coap-server-config-unprotected.");
         } else {
             // We could pick another policy too to get 4.04 errors, but "there may be something but
             // I won't tell you" is just as good an answer, and may prune some more branches even.
@@ -164,40 +283,135 @@ async fn coap_run_impl(handler: impl coap_handler::Handler + coap_handler::Repor
         }
     }
 
+    #[cfg(feature = "coap-server-config-storage")]
+    let handler = handler.at_with_attributes(&["ariel", "peers"], &[], stored::PeersAdmin);
+    #[cfg(feature = "coap-ota")]
+    let handler = handler
+        .at_with_attributes(&["ariel", "ota", "manifest"], &[], ota::ManifestResource)
+        .at_with_attributes(&["ariel", "ota", "image"], &[], ota::image_resource());
+    #[cfg(feature = "coap-system-resources")]
+    let handler = handler
+        .at_with_attributes(&["ariel", "build"], &[], system::SystemResource::Build)
+        .at_with_attributes(&["ariel", "id"], &[], system::SystemResource::DeviceId)
+        .at_with_attributes(&["ariel", "reboot"], &[], system::SystemResource::Reboot);
+    #[cfg(all(feature = "coap-system-resources", feature = "threading"))]
+    let handler =
+        handler.at_with_attributes(&["ariel", "threads"], &[], system::SystemResource::Threads);
+    #[cfg(all(feature = "coap-system-resources", feature = "storage"))]
+    let handler = handler.at_with_attributes(
+        &["ariel", "storage"],
+        &[],
+        system::SystemResource::StorageKeys,
+    );
+    #[cfg(feature = "coap-metrics")]
+    let handler = handler.at_with_attributes(&["ariel", "metrics"], &[], metrics::MetricsResource);
+
     // FIXME: Should we allow users to override that? After all, this is just convenience and may
     // be limiting in special applications.
     let handler = handler.with_wkc();
-    let mut handler = coapcore::OscoreEdhocHandler::new(
+    // The links registered at the Resource Directory are those of the `.well-known/core`
+    // resource.
+    #[cfg(feature = "coap-rd")]
+    let (handler, rd_links) = {
+        let mut handler = handler;
+        let links = rd::render_links(&mut handler);
+        (handler, links)
+    };
+    let handler = coapcore::OscoreEdhocHandler::new(
         handler,
         security_config,
         || lakers_crypto_rustcrypto::Crypto::new(ariel_os_random::crypto_rng()),
         ariel_os_random::crypto_rng(),
-        coapcore::time::TimeUnknown,
+        time::WallClock,
     );
+    #[cfg(feature = "coap-metrics")]
+    let handler = handler.with_access_log(metrics::AccessLogger);
+    let handler = RefCell::new(handler);
+    // Security contexts from before the last reboot are available from the first request on.
+    #[cfg(feature = "coap-server-config-storage")]
+    contexts::restore(&handler).await;
+    // The handler and the socket are shared with the sender of notifications of observed
+    // resources.
+    let transport = observe::Transport::new();
 
     info!("Server is ready.");
 
-    let coap = COAP.init_with(embedded_nal_coap::CoAPShared::new);
-    let (client, server) = coap.split();
-    #[expect(
-        clippy::items_after_statements,
-        reason = "This is the item's place in the workflow."
-    )]
-    static CLIENT: StaticCell<embedded_nal_coap::CoAPRuntimeClient<'static, CONCURRENT_REQUESTS>> =
-        StaticCell::new();
-
-    CLIENT_READY
-        .sender()
-        .send(SameExecutorCell::new_async(&*CLIENT.init(client)).await);
+    let coap: &'static [_; SOCKET_COUNT] =
+        COAP.init_with(|| core::array::from_fn(|_| embedded_nal_coap::CoAPShared::new()));
+    // Only the main socket's client is used; the others' just go unused.
+    let mut client = None;
+    let mut coap = coap.iter();
+    let servers: [_; SOCKET_COUNT] = core::array::from_fn(|_| {
+        let (socket_client, server) = coap
+            .next()
+            .expect("there is one CoAP state per socket")
+            .split();
+        client.get_or_insert(socket_client);
+        server
+    });
+    let client = client.expect("there is at least one socket");
 
-    server
-        .run(
-            &mut unconnected,
-            &mut handler,
-            &mut ariel_os_random::fast_rng(),
+    let mut observing_sockets = unconnected
+        .each_ref()
+        .map(|socket| observe::ObservingSocket::new(socket, &transport));
+    let mut shared_handlers: [_; SOCKET_COUNT] =
+        core::array::from_fn(|_| observe::SharedHandler::new(&handler, &transport));
+    let mut rngs: [_; SOCKET_COUNT] = core::array::from_fn(|_| ariel_os_random::fast_rng());
+    let mut observing_sockets = observing_sockets.iter_mut();
+    let mut shared_handlers = shared_handlers.iter_mut();
+    let mut rngs = rngs.iter_mut();
+    let runs = servers.map(|server| {
+        server.run(
+            observing_sockets
+                .next()
+                .expect("there is one socket per server"),
+            shared_handlers
+                .next()
+                .expect("there is one handler per server"),
+            rngs.next().expect("there is one RNG per server"),
         )
-        .await
-        .expect("UDP error");
+    });
+    let run = async { embassy_futures::select::select_array(runs).await.0 };
+    let run = alongside!(
+        run,
+        observe::send_notifications(&handler, &unconnected, &transport)
+    );
+
+    // Requests of `coap_client()` users are handed to the client by a loop running alongside the
+    // server.
+    let run = alongside!(run, client::forward_requests(&client));
+
+    // Changes made through the peers resource are stored by a loop running alongside the server.
+    #[cfg(feature = "coap-server-config-storage")]
+    let run = alongside!(run, stored::persist_peers());
+
+    // Security contexts are stored by a loop running alongside the server.
+    #[cfg(feature = "coap-server-config-storage")]
+    let run = alongside!(run, contexts::persist_contexts(&handler));
+
+    // Firmware updates are written to flash by a loop running alongside the server.
+    #[cfg(feature = "coap-ota")]
+    let run = alongside!(run, ota::process_updates());
+
+    // Reboots and storage listings requested through the system resources are performed by a loop
+    // running alongside the server.
+    #[cfg(feature = "coap-system-resources")]
+    let run = alongside!(run, system::run());
+
+    // Requests over TCP are served by loops running alongside the server.
+    #[cfg(feature = "coap-tcp")]
+    let run = alongside!(run, tcp::run(stack, &handler));
+
+    // The registration at the Resource Directory is kept up by a loop running alongside the
+    // server.
+    #[cfg(feature = "coap-rd")]
+    let run = alongside!(run, rd::run(rd_links, stack));
+
+    // The server is advertised on the local link by a responder running alongside it.
+    #[cfg(feature = "coap-mdns")]
+    let run = alongside!(run, mdns::run(stack));
+
+    run.await.expect("UDP error");
     unreachable!("embassy-net's sockets do not get closed (but embedded-nal-coap can't know that)");
 }
 
@@ -206,35 +420,33 @@ async fn coap_run_impl(handler: impl coap_handler::Handler + coap_handler::Repor
 /// This asynchronously blocks until [`coap_run()`] has been called (which happens at startup
 /// when the corresponding feature `coap-server` is not active), and the CoAP stack is operational.
 ///
-/// # Panics
+/// The client can be used from any executor: requests are passed to the task running the CoAP
+/// server, which sends them and passes their responses back. Threads can send requests by running
+/// them in `ariel_os::asynch::blocker::block_on()`.
 ///
-/// This is currently only available from the thread that hosts the network stack, and panics
-/// otherwise. This restriction will be lifted in the future (by generalization in
-/// [`embedded_nal_coap`] to allow different mutexes).
-pub async fn coap_client()
--> &'static embedded_nal_coap::CoAPRuntimeClient<'static, CONCURRENT_REQUESTS> {
-    let mut receiver = CLIENT_READY
-        .receiver()
-        .expect("Too many CoAP clients are waiting for the network to come up.");
-    receiver
-        .get()
-        .await
-        .get_async()
-        .await // Not an actual await, just a convenient way to see which executor is running
-        .expect("CoAP client can currently only be used from the thread the network is bound to")
+/// # Migrating from earlier versions
+///
+/// This used to return the `embedded_nal_coap::CoAPRuntimeClient` of the CoAP task, which could
+/// only be used from the executor that task runs on. Code calling
+/// `coap_client().await.to(remote).request(…)` keeps working unchanged, as
+/// [`Client::to()`](client::Client::to) returns a [`coap_request::Stack`] as well. Code naming
+/// the type needs to use [`client::Client`] instead, and requests and responses now need to fit
+/// into [`client::MESSAGE_BUFFER_SIZE`] (set through `CONFIG_COAP_CLIENT_MESSAGE_BUFFER_SIZE`).
+pub async fn coap_client() -> &'static client::Client {
+    client::CLIENT.get().await
 }
 
 /// Auto-started CoAP server that serves two purposes:
 ///
 /// * It provides the backend for the CoAP client operation (which leaves message sending to that
 ///   task).
-/// * It runs any CoAP server components provided by the OS (none yet).
+/// * It runs any CoAP server components provided by the OS (e.g., the system resources of the
+///   `coap-system-resources` feature), which [`coap_run_impl()`] adds to any handler.
 #[cfg(not(feature = "coap-server"))]
 #[ariel_os_macros::task(autostart)]
 async fn coap_run() {
     use coap_handler_implementations::new_dispatcher;
 
-    // FIXME: Provide an "all system components" constructor in this crate.
     let handler = new_dispatcher();
     coap_run_impl(handler).await;
 }
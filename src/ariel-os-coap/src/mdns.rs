@@ -0,0 +1,818 @@
+//! Advertisement of the CoAP server through DNS-Based Service Discovery
+//! ([RFC6763](https://www.rfc-editor.org/rfc/rfc6763)) over Multicast DNS
+//! ([RFC6762](https://www.rfc-editor.org/rfc/rfc6762)).
+//!
+//! A responder on UDP port 5353 (listening on the groups 224.0.0.251 and ff02::fb) answers
+//! queries for
+//!
+//! * `_coap._udp.local` (PTR) with the service instance `<hostname>._coap._udp.local`,
+//! * `_services._dns-sd._udp.local` (PTR) with `_coap._udp.local`,
+//! * the service instance (SRV and an empty TXT), pointing to the server's main port
+//!   (`CONFIG_COAP_PORT`) on `<hostname>.local`,
+//! * `<hostname>.local` (A and AAAA) with the addresses of the network configuration.
+//!
+//! The hostname is set in `CONFIG_COAP_MDNS_HOSTNAME`, or otherwise derived from the device ID
+//! (`ariel-` followed by the ID in hexadecimal digits, truncated to a DNS label).
+//!
+//! All records are announced twice whenever the network configuration comes up. Queries are
+//! answered through multicast, unless they ask for a unicast response, or are sent from a port
+//! other than 5353 (legacy unicast, RFC6762 Section 6.7). Probing for conflicting names is not
+//! implemented, and known answers are not suppressed: devices need distinct hostnames, which the
+//! names derived from device IDs are.
+
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ariel_os_debug::log::{debug, error, info};
+use ariel_os_embassy::net::NetworkStack;
+use embassy_futures::select::{Either, select};
+use embassy_net::{
+    IpAddress, IpEndpoint,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Timer};
+
+/// The mDNS configuration, as set through the `CONFIG_COAP_MDNS_*` variables.
+mod config {
+    include!(concat!(env!("OUT_DIR"), "/mdns.rs"));
+}
+
+/// UDP port of mDNS.
+const MDNS_PORT: u16 = 5353;
+
+/// Multicast groups of mDNS.
+const MDNS_GROUP_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const MDNS_GROUPS: [IpAddr; 2] = [IpAddr::V4(MDNS_GROUP_V4), IpAddr::V6(MDNS_GROUP_V6)];
+
+/// Longest DNS label (RFC1035 Section 2.3.4), and thus hostname.
+const MAX_HOSTNAME_LEN: usize = 63;
+
+/// Largest message received or sent; larger queries are dropped.
+const MAX_MESSAGE_LEN: usize = 512;
+
+/// Number of datagrams the socket can hold in each direction.
+const PACKETS: usize = 2;
+
+/// Largest number of labels of a name in a query that is compared to the advertised names.
+const MAX_LABELS: usize = 8;
+
+/// Largest number of compression pointers followed in a name.
+const MAX_POINTERS: usize = 8;
+
+/// Number and interval of the announcements sent when the network configuration comes up
+/// (RFC6762 Section 8.3).
+const ANNOUNCEMENTS: usize = 2;
+const ANNOUNCEMENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time to live of records referring to the host, and of other records (RFC6762 Section 10).
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/// Largest time to live in responses to legacy unicast queries (RFC6762 Section 6.7).
+const LEGACY_TTL: u32 = 10;
+
+/// Length of the DNS message header.
+const HEADER_LEN: usize = 12;
+
+/// Flags of a response: QR and AA.
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+/// Resource record types.
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// Bit of the class of a record that replaces cached records of the same name and type.
+const CACHE_FLUSH: u16 = 0x8000;
+/// Bit of the class of a question that asks for a unicast response.
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+const SERVICE: &[&[u8]] = &[b"_coap", b"_udp", b"local"];
+const SERVICES: &[&[u8]] = &[b"_services", b"_dns-sd", b"_udp", b"local"];
+const LOCAL: &[&[u8]] = &[b"local"];
+
+type Hostname = heapless::String<MAX_HOSTNAME_LEN>;
+
+/// Advertises the CoAP server on the local link.
+///
+/// This needs to run alongside the CoAP server.
+pub(crate) async fn run(stack: NetworkStack) -> ! {
+    let hostname = hostname();
+
+    let mut rx_meta = [PacketMetadata::EMPTY; PACKETS];
+    let mut rx_buffer = [0; MAX_MESSAGE_LEN * PACKETS];
+    let mut tx_meta = [PacketMetadata::EMPTY; PACKETS];
+    let mut tx_buffer = [0; MAX_MESSAGE_LEN * PACKETS];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if socket.bind(MDNS_PORT).is_err() {
+        error!("Could not bind the mDNS port, not advertising the CoAP server.");
+        loop {
+            core::future::pending::<()>().await;
+        }
+    }
+
+    let mut query = [0; MAX_MESSAGE_LEN];
+    let mut response = [0; MAX_MESSAGE_LEN];
+    loop {
+        stack.wait_config_up().await;
+        // The groups are joined with every network configuration, and left when it goes down.
+        for group in MDNS_GROUPS {
+            if stack.join_multicast_group(group).is_err() {
+                error!("Could not join an mDNS multicast group.");
+            }
+        }
+        info!(
+            "Advertising the CoAP server as {}.local.",
+            hostname.as_str()
+        );
+
+        for _ in 0..ANNOUNCEMENTS {
+            let records = Records::new(&hostname, stack);
+            if let Some(len) = records.announcement(&mut response) {
+                let announcement = response.get(..len).unwrap_or_default();
+                if records.ipv4.is_some() {
+                    let _ = socket
+                        .send_to(
+                            announcement,
+                            IpEndpoint::new(MDNS_GROUP_V4.into(), MDNS_PORT),
+                        )
+                        .await;
+                }
+                if records.ipv6.is_some() {
+                    let _ = socket
+                        .send_to(
+                            announcement,
+                            IpEndpoint::new(MDNS_GROUP_V6.into(), MDNS_PORT),
+                        )
+                        .await;
+                }
+            }
+            Timer::after(ANNOUNCEMENT_INTERVAL).await;
+        }
+
+        loop {
+            let (len, meta) =
+                match select(socket.recv_from(&mut query), stack.wait_config_down()).await {
+                    Either::First(Ok(received)) => received,
+                    // Truncated queries are dropped.
+                    Either::First(Err(_)) => continue,
+                    Either::Second(()) => {
+                        for group in MDNS_GROUPS {
+                            let _ = stack.leave_multicast_group(group);
+                        }
+                        break;
+                    }
+                };
+            let Some(query) = query.get(..len) else {
+                continue;
+            };
+
+            let records = Records::new(&hostname, stack);
+            // Queries from other ports come from simple resolvers, which expect a conventional
+            // unicast response.
+            let legacy = meta.endpoint.port != MDNS_PORT;
+            let Some((len, unicast)) = records.respond(query, legacy, &mut response) else {
+                continue;
+            };
+            let destination = if unicast || legacy {
+                meta.endpoint
+            } else {
+                match meta.endpoint.addr {
+                    IpAddress::Ipv4(_) => IpEndpoint::new(MDNS_GROUP_V4.into(), MDNS_PORT),
+                    IpAddress::Ipv6(_) => IpEndpoint::new(MDNS_GROUP_V6.into(), MDNS_PORT),
+                }
+            };
+            if socket
+                .send_to(response.get(..len).unwrap_or_default(), destination)
+                .await
+                .is_err()
+            {
+                debug!("Sending an mDNS response failed.");
+            }
+        }
+    }
+}
+
+/// Returns the hostname from `CONFIG_COAP_MDNS_HOSTNAME`, or one derived from the device ID.
+fn hostname() -> Hostname {
+    crate::util::device_name(config::HOSTNAME)
+}
+
+/// A domain name: an optional first label followed by fixed labels.
+#[derive(Copy, Clone)]
+struct Name<'a> {
+    first: Option<&'a [u8]>,
+    rest: &'static [&'static [u8]],
+}
+
+impl<'a> Name<'a> {
+    fn labels(self) -> impl Iterator<Item = &'a [u8]> {
+        self.first.into_iter().chain(self.rest.iter().copied())
+    }
+
+    /// Compares the name to the `labels` of a name in a query, which are case insensitive.
+    fn is(self, labels: &[&[u8]]) -> bool {
+        self.labels().count() == labels.len()
+            && self
+                .labels()
+                .zip(labels)
+                .all(|(own, other)| own.eq_ignore_ascii_case(other))
+    }
+}
+
+/// One of the advertised resource records.
+#[derive(Copy, Clone)]
+enum Record {
+    /// `_coap._udp.local` PTR `<hostname>._coap._udp.local`
+    ServicePtr,
+    /// `_services._dns-sd._udp.local` PTR `_coap._udp.local`
+    ServicesPtr,
+    /// `<hostname>._coap._udp.local` SRV
+    Srv,
+    /// `<hostname>._coap._udp.local` TXT
+    Txt,
+    /// `<hostname>.local` A
+    A,
+    /// `<hostname>.local` AAAA
+    Aaaa,
+}
+
+impl Record {
+    const ALL: [Self; 6] = [
+        Self::ServicePtr,
+        Self::ServicesPtr,
+        Self::Srv,
+        Self::Txt,
+        Self::A,
+        Self::Aaaa,
+    ];
+
+    fn record_type(self) -> u16 {
+        match self {
+            Self::ServicePtr | Self::ServicesPtr => TYPE_PTR,
+            Self::Srv => TYPE_SRV,
+            Self::Txt => TYPE_TXT,
+            Self::A => TYPE_A,
+            Self::Aaaa => TYPE_AAAA,
+        }
+    }
+
+    /// Whether the record is the only one of its name and type on the link, rather than shared
+    /// with other devices (RFC6762 Section 2).
+    fn is_unique(self) -> bool {
+        !matches!(self, Self::ServicePtr | Self::ServicesPtr)
+    }
+
+    fn ttl(self) -> u32 {
+        match self {
+            Self::Srv | Self::A | Self::Aaaa => HOST_TTL,
+            Self::ServicePtr | Self::ServicesPtr | Self::Txt => SERVICE_TTL,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+/// A set of [`Record`]s.
+#[derive(Copy, Clone, Default)]
+struct RecordSet(u8);
+
+impl RecordSet {
+    fn insert(&mut self, record: Record) {
+        self.0 |= record.bit();
+    }
+
+    fn contains(self, record: Record) -> bool {
+        self.0 & record.bit() != 0
+    }
+
+    fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    fn iter(self) -> impl Iterator<Item = Record> {
+        Record::ALL
+            .into_iter()
+            .filter(move |record| self.contains(*record))
+    }
+}
+
+/// The advertised records, with the addresses of the current network configuration.
+struct Records<'a> {
+    hostname: &'a str,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+}
+
+impl<'a> Records<'a> {
+    fn new(hostname: &'a str, stack: NetworkStack) -> Self {
+        Self {
+            hostname,
+            ipv4: stack.config_v4().map(|config| config.address.address()),
+            ipv6: stack.config_v6().map(|config| config.address.address()),
+        }
+    }
+
+    fn instance(&self) -> Name<'a> {
+        Name {
+            first: Some(self.hostname.as_bytes()),
+            rest: SERVICE,
+        }
+    }
+
+    fn host(&self) -> Name<'a> {
+        Name {
+            first: Some(self.hostname.as_bytes()),
+            rest: LOCAL,
+        }
+    }
+
+    fn owner(&self, record: Record) -> Name<'a> {
+        match record {
+            Record::ServicePtr => Name {
+                first: None,
+                rest: SERVICE,
+            },
+            Record::ServicesPtr => Name {
+                first: None,
+                rest: SERVICES,
+            },
+            Record::Srv | Record::Txt => self.instance(),
+            Record::A | Record::Aaaa => self.host(),
+        }
+    }
+
+    /// The records that are currently available: addresses only exist while configured.
+    fn available(&self) -> RecordSet {
+        let mut available = RecordSet::default();
+        for record in Record::ALL {
+            let present = match record {
+                Record::A => self.ipv4.is_some(),
+                Record::Aaaa => self.ipv6.is_some(),
+                _ => true,
+            };
+            if present {
+                available.insert(record);
+            }
+        }
+        available
+    }
+
+    /// Writes an unsolicited response announcing all available records into `buffer`, and
+    /// returns its length.
+    fn announcement(&self, buffer: &mut [u8]) -> Option<usize> {
+        let mut writer = Writer::new(buffer);
+        let answers = self.available();
+        writer.header(0, 0, answers.iter().count(), 0)?;
+        for record in answers.iter() {
+            self.write(&mut writer, record, false)?;
+        }
+        Some(writer.len)
+    }
+
+    /// Writes the response to a `query` into `buffer`.
+    ///
+    /// Returns the length of the response and whether it was asked to be sent by unicast, or
+    /// `None` if the query is not to be answered.
+    fn respond(&self, query: &[u8], legacy: bool, buffer: &mut [u8]) -> Option<(usize, bool)> {
+        let (&[id_high, id_low, flags, _, count_high, count_low, ..], _) =
+            query.split_first_chunk::<HEADER_LEN>()?;
+        // Only standard queries (QR and OPCODE zero) are answered.
+        if flags & 0xf8 != 0 {
+            return None;
+        }
+        let question_count = u16::from_be_bytes([count_high, count_low]);
+
+        let available = self.available();
+        let mut answers = RecordSet::default();
+        let mut unicast = false;
+        let mut offset = HEADER_LEN;
+        for _ in 0..question_count {
+            let (labels, end) = read_name(query, offset)?;
+            let question_type = read_u16(query, end)?;
+            let question_class = read_u16(query, end + 2)?;
+            offset = end + 4;
+
+            unicast |= question_class & UNICAST_RESPONSE != 0;
+            let Some(labels) = labels else {
+                continue;
+            };
+            if question_class & !UNICAST_RESPONSE != CLASS_IN {
+                continue;
+            }
+            for record in available.iter() {
+                if (question_type == TYPE_ANY || question_type == record.record_type())
+                    && self.owner(record).is(&labels)
+                {
+                    answers.insert(record);
+                }
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+
+        // Records the querier is going to need next (RFC6763 Section 12).
+        let mut additionals = RecordSet::default();
+        if answers.contains(Record::ServicePtr) {
+            additionals.insert(Record::Srv);
+            additionals.insert(Record::Txt);
+        }
+        if answers.contains(Record::ServicePtr) || answers.contains(Record::Srv) {
+            additionals.insert(Record::A);
+            additionals.insert(Record::Aaaa);
+        }
+        let additionals = RecordSet(additionals.0 & available.0 & !answers.0);
+
+        let mut writer = Writer::new(buffer);
+        if legacy {
+            // Legacy unicast responses repeat the query's ID and questions.
+            writer.header(
+                u16::from_be_bytes([id_high, id_low]),
+                question_count,
+                answers.iter().count(),
+                additionals.iter().count(),
+            )?;
+            writer.bytes(query.get(HEADER_LEN..offset)?)?;
+        } else {
+            writer.header(0, 0, answers.iter().count(), additionals.iter().count())?;
+        }
+        for record in answers.iter().chain(additionals.iter()) {
+            self.write(&mut writer, record, legacy)?;
+        }
+        Some((writer.len, unicast))
+    }
+
+    /// Writes a resource record.
+    ///
+    /// In responses to legacy unicast queries, records are not marked to flush caches, and have a
+    /// short time to live.
+    fn write(&self, writer: &mut Writer<'_>, record: Record, legacy: bool) -> Option<()> {
+        writer.name(self.owner(record))?;
+        writer.u16(record.record_type())?;
+        let class = if record.is_unique() && !legacy {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+        writer.u16(class)?;
+        writer.u32(if legacy {
+            record.ttl().min(LEGACY_TTL)
+        } else {
+            record.ttl()
+        })?;
+
+        let length_offset = writer.len;
+        writer.u16(0)?;
+        match record {
+            Record::ServicePtr => writer.name(self.instance())?,
+            Record::ServicesPtr => writer.name(Name {
+                first: None,
+                rest: SERVICE,
+            })?,
+            Record::Srv => {
+                // Priority and weight
+                writer.u16(0)?;
+                writer.u16(0)?;
+                writer.u16(crate::PORT)?;
+                writer.name(self.host())?;
+            }
+            // A single empty string (RFC6763 Section 6.1)
+            Record::Txt => writer.bytes(&[0])?,
+            Record::A => writer.bytes(&self.ipv4?.octets())?,
+            Record::Aaaa => writer.bytes(&self.ipv6?.octets())?,
+        }
+        let length = u16::try_from(writer.len - length_offset - 2).ok()?;
+        writer.set_u16(length_offset, length)
+    }
+}
+
+/// Reads the name starting at `offset` of a `message`, following compression pointers.
+///
+/// Returns the labels of the name (or `None` if there are too many of them), and the offset
+/// after the name; `None` if the name is malformed.
+fn read_name(
+    message: &[u8],
+    mut offset: usize,
+) -> Option<(Option<heapless::Vec<&[u8], MAX_LABELS>>, usize)> {
+    let mut labels = Some(heapless::Vec::new());
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let length = *message.get(offset)?;
+        match length {
+            0 => return Some((labels, end.unwrap_or(offset + 1))),
+            1..=63 => {
+                let start = offset + 1;
+                offset = start + usize::from(length);
+                let label = message.get(start..offset)?;
+                if labels
+                    .as_mut()
+                    .is_some_and(|labels| labels.push(label).is_err())
+                {
+                    labels = None;
+                }
+            }
+            0xc0..=0xff => {
+                let low = *message.get(offset + 1)?;
+                end.get_or_insert(offset + 2);
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                offset = usize::from(u16::from_be_bytes([length & 0x3f, low]));
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    let bytes = message.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Writes a DNS message into a buffer.
+struct Writer<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn new(buffer: &'b mut [u8]) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Option<()> {
+        let end = self.len.checked_add(data.len())?;
+        self.buffer.get_mut(self.len..end)?.copy_from_slice(data);
+        self.len = end;
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) -> Option<()> {
+        self.buffer
+            .get_mut(offset..offset + 2)?
+            .copy_from_slice(&value.to_be_bytes());
+        Some(())
+    }
+
+    /// Writes a name without compression.
+    fn name(&mut self, name: Name<'_>) -> Option<()> {
+        for label in name.labels() {
+            self.bytes(&[u8::try_from(label.len()).ok()?])?;
+            self.bytes(label)?;
+        }
+        self.bytes(&[0])
+    }
+
+    fn header(
+        &mut self,
+        id: u16,
+        questions: u16,
+        answers: usize,
+        additionals: usize,
+    ) -> Option<()> {
+        self.u16(id)?;
+        self.u16(FLAGS_RESPONSE)?;
+        self.u16(questions)?;
+        self.u16(u16::try_from(answers).ok()?)?;
+        // Authority records
+        self.u16(0)?;
+        self.u16(u16::try_from(additionals).ok()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv4Addr;
+
+    use super::{
+        CLASS_IN, HEADER_LEN, MAX_LABELS, MAX_MESSAGE_LEN, Records, TYPE_A, TYPE_AAAA, TYPE_ANY,
+        TYPE_PTR, UNICAST_RESPONSE, read_name, read_u16,
+    };
+
+    type Message = heapless::Vec<u8, MAX_MESSAGE_LEN>;
+
+    const SERVICE: [&[u8]; 3] = [b"_coap", b"_udp", b"local"];
+    /// Offset of the first name in a message.
+    const FIRST_NAME: u16 = 12;
+    /// Offset of the `local` label of [`SERVICE`] when that is the first name in a message.
+    const SERVICE_LOCAL: u16 = FIRST_NAME + 11;
+
+    fn records() -> Records<'static> {
+        Records {
+            hostname: "ariel-test",
+            ipv4: Some(Ipv4Addr::new(192, 0, 2, 1)),
+            ipv6: None,
+        }
+    }
+
+    /// Starts a message with the given ID and number of questions.
+    fn query(id: u16, questions: u16) -> Message {
+        let mut message = Message::new();
+        message.extend_from_slice(&id.to_be_bytes()).unwrap();
+        message.extend_from_slice(&[0, 0]).unwrap();
+        message.extend_from_slice(&questions.to_be_bytes()).unwrap();
+        message.extend_from_slice(&[0; 6]).unwrap();
+        message
+    }
+
+    /// Appends the `labels` of a name, ended by a compression pointer to `pointer` if given.
+    fn push_name(message: &mut Message, labels: &[&[u8]], pointer: Option<u16>) {
+        for label in labels {
+            message.push(u8::try_from(label.len()).unwrap()).unwrap();
+            message.extend_from_slice(label).unwrap();
+        }
+        match pointer {
+            Some(offset) => message
+                .extend_from_slice(&(0xc000 | offset).to_be_bytes())
+                .unwrap(),
+            None => message.push(0).unwrap(),
+        }
+    }
+
+    /// Appends the type and class of a question.
+    fn push_question(message: &mut Message, question_type: u16, class: u16) {
+        message
+            .extend_from_slice(&question_type.to_be_bytes())
+            .unwrap();
+        message.extend_from_slice(&class.to_be_bytes()).unwrap();
+    }
+
+    fn is_name(labels: Option<heapless::Vec<&[u8], MAX_LABELS>>, expected: &[&[u8]]) -> bool {
+        labels.is_some_and(|labels| labels.as_slice() == expected)
+    }
+
+    /// Returns the numbers of answers and additional records of a response.
+    fn counts(response: &[u8]) -> (Option<u16>, Option<u16>) {
+        (read_u16(response, 6), read_u16(response, 10))
+    }
+
+    #[test]
+    fn read_name_follows_pointers() {
+        let mut message = query(0, 0);
+        push_name(&mut message, &SERVICE, None);
+        let host = message.len();
+        push_name(&mut message, &[b"host"], Some(SERVICE_LOCAL));
+        let (labels, end) = read_name(&message, host).unwrap();
+        assert!(is_name(labels, &[b"host", b"local"]));
+        assert_eq!(end, message.len());
+
+        // A pointer to a name that ends in a pointer
+        let alias = message.len();
+        push_name(
+            &mut message,
+            &[b"alias"],
+            Some(u16::try_from(host).unwrap()),
+        );
+        let (labels, end) = read_name(&message, alias).unwrap();
+        assert!(is_name(labels, &[b"alias", b"host", b"local"]));
+        assert_eq!(end, message.len());
+    }
+
+    #[test]
+    fn read_name_rejects_pointer_loops() {
+        let mut message = query(0, 0);
+        push_name(&mut message, &[], Some(FIRST_NAME));
+        assert!(read_name(&message, HEADER_LEN).is_none());
+
+        let mut message = query(0, 0);
+        push_name(&mut message, &[b"a"], Some(FIRST_NAME));
+        assert!(read_name(&message, HEADER_LEN).is_none());
+    }
+
+    #[test]
+    fn read_name_rejects_truncated_names() {
+        let names: [&[u8]; 6] = [
+            // Label longer than the message
+            &[5, b'l', b'o'],
+            // Missing root label
+            &[2, b'l', b'o'],
+            // Pointer without its second byte
+            &[1, b'a', 0xc0],
+            // Pointer past the end of the message
+            &[0xc0, 0xff],
+            // Reserved label types
+            &[0x40, 0],
+            &[0x80, 0],
+        ];
+        for name in names {
+            let mut message = query(0, 0);
+            message.extend_from_slice(name).unwrap();
+            assert!(read_name(&message, HEADER_LEN).is_none());
+        }
+    }
+
+    #[test]
+    fn read_name_skips_long_names() {
+        let mut message = query(0, 0);
+        push_name(&mut message, &[b"a".as_slice(); MAX_LABELS + 1], None);
+        let (labels, end) = read_name(&message, HEADER_LEN).unwrap();
+        assert!(labels.is_none());
+        assert_eq!(end, message.len());
+    }
+
+    #[test]
+    fn respond_to_service_query() {
+        let mut message = query(0, 1);
+        push_name(&mut message, &SERVICE, None);
+        push_question(&mut message, TYPE_PTR, CLASS_IN);
+
+        let mut response = [0; MAX_MESSAGE_LEN];
+        let (len, unicast) = records().respond(&message, false, &mut response).unwrap();
+        assert!(!unicast);
+        let response = response.get(..len).unwrap();
+        assert_eq!(read_u16(response, 4), Some(0));
+        // The PTR record, followed by SRV, TXT and A (as there is no IPv6 address)
+        assert_eq!(counts(response), (Some(1), Some(3)));
+        let (labels, _) = read_name(response, HEADER_LEN).unwrap();
+        assert!(is_name(labels, &SERVICE));
+    }
+
+    #[test]
+    fn respond_to_compressed_questions() {
+        let mut message = query(0x1234, 2);
+        push_name(&mut message, &SERVICE, None);
+        push_question(&mut message, TYPE_PTR, CLASS_IN | UNICAST_RESPONSE);
+        // Names are compared case insensitively.
+        push_name(&mut message, &[b"ARIEL-Test"], Some(SERVICE_LOCAL));
+        push_question(&mut message, TYPE_A, CLASS_IN);
+
+        let mut response = [0; MAX_MESSAGE_LEN];
+        let (len, unicast) = records().respond(&message, false, &mut response).unwrap();
+        assert!(unicast);
+        // PTR and A, followed by SRV and TXT
+        assert_eq!(counts(response.get(..len).unwrap()), (Some(2), Some(2)));
+
+        // Legacy unicast responses repeat the ID and the questions.
+        let (len, _) = records().respond(&message, true, &mut response).unwrap();
+        let response = response.get(..len).unwrap();
+        assert_eq!(read_u16(response, 0), Some(0x1234));
+        assert_eq!(read_u16(response, 4), Some(2));
+        assert_eq!(
+            response.get(HEADER_LEN..message.len()),
+            message.get(HEADER_LEN..)
+        );
+    }
+
+    #[test]
+    fn respond_ignores_unusable_questions() {
+        let mut response = [0; MAX_MESSAGE_LEN];
+
+        // Overly long names are not answered, but do not keep later questions from being read.
+        let mut message = query(0, 2);
+        push_name(&mut message, &[b"a".as_slice(); MAX_LABELS + 1], None);
+        push_question(&mut message, TYPE_ANY, CLASS_IN);
+        push_name(&mut message, &SERVICE, None);
+        push_question(&mut message, TYPE_PTR, CLASS_IN);
+        let (len, _) = records().respond(&message, false, &mut response).unwrap();
+        assert_eq!(counts(response.get(..len).unwrap()), (Some(1), Some(3)));
+
+        // There is no record to answer with.
+        let mut message = query(0, 1);
+        push_name(&mut message, &[b"ariel-test", b"local"], None);
+        push_question(&mut message, TYPE_AAAA, CLASS_IN);
+        assert!(records().respond(&message, false, &mut response).is_none());
+
+        // Pointer loop
+        let mut message = query(0, 1);
+        push_name(&mut message, &[], Some(FIRST_NAME));
+        push_question(&mut message, TYPE_ANY, CLASS_IN);
+        assert!(records().respond(&message, false, &mut response).is_none());
+
+        // Truncated question
+        let mut message = query(0, 1);
+        push_name(&mut message, &SERVICE, None);
+        message.extend_from_slice(&TYPE_PTR.to_be_bytes()).unwrap();
+        assert!(records().respond(&message, false, &mut response).is_none());
+
+        // Responses are not answered.
+        let mut message = query(0, 1);
+        if let Some(flags) = message.get_mut(2) {
+            *flags = 0x84;
+        }
+        push_name(&mut message, &SERVICE, None);
+        push_question(&mut message, TYPE_PTR, CLASS_IN);
+        assert!(records().respond(&message, false, &mut response).is_none());
+    }
+}
@@ -0,0 +1,241 @@
+//! Counters and access logging of the CoAP server.
+//!
+//! The counters kept by the server's [`coapcore::OscoreEdhocHandler`] (see [`coapcore::metrics`])
+//! are available through [`snapshot()`], and to CoAP clients at `/ariel/metrics` (`GET`): a CBOR
+//! map with the text keys
+//!
+//! * `"requests"`: a map from request codes (e.g. 1 for GET) to the number of requests with that
+//!   code, where all codes other than the methods GET to iPATCH are counted in 0,
+//! * `"not-allowed"`, `"freshness-challenges"`, `"edhoc-completed"`, `"edhoc-failed"`,
+//!   `"oscore-rejected"`, `"replays-rejected"` and `"contexts-evicted"`: the counters of the same
+//!   name in [`Metrics`].
+//!
+//! Like any other resource, it is only accessible to peers whose scope allows the request (see
+//! the `coap-server-config-*` features).
+//!
+//! Requests checked against the peer's authorization are logged at the debug level, and passed to
+//! the function set through [`set_access_log()`], if any.
+
+use core::cell::Cell;
+
+use ariel_os_debug::log::debug;
+use coap_message::{
+    Code as _, MessageOption as _, MinimalWritableMessage, MutableWritableMessage,
+    OptionNumber as _, ReadableMessage,
+};
+use coap_message_utils::Error as CoAPError;
+use coapcore::metrics::AccessLog;
+pub use coapcore::metrics::{Metrics, Outcome, Peer};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use minicbor::encode::write::Cursor;
+
+use crate::util::{internal, reject_critical};
+
+/// Longest path reported to the access log; longer paths are truncated.
+const MAX_PATH_LEN: usize = 64;
+
+/// Largest CBOR document produced by the metrics resource.
+const MAX_RESPONSE_LEN: usize = 256;
+
+/// Counters as of the most recently processed request, once there was one.
+static METRICS: Mutex<CriticalSectionRawMutex, Cell<Option<Metrics>>> = Mutex::new(Cell::new(None));
+
+static ACCESS_LOG: Mutex<CriticalSectionRawMutex, Cell<Option<fn(&AccessRecord<'_>)>>> =
+    Mutex::new(Cell::new(None));
+
+/// Returns the counters of the CoAP server, as of the most recently processed request.
+pub fn snapshot() -> Metrics {
+    METRICS.lock(Cell::get).unwrap_or_default()
+}
+
+/// Updates the counters returned by [`snapshot()`] from the handler.
+pub(crate) fn update(metrics: &Metrics) {
+    METRICS.lock(|cell| cell.set(Some(*metrics)));
+}
+
+/// A request that was checked against the peer's authorization; see [`set_access_log()`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct AccessRecord<'a> {
+    /// The peer the request was received from.
+    pub peer: Peer<'a>,
+    /// Code of the request (e.g. 1 for GET); for requests protected with OSCORE, this is the code
+    /// of the decrypted request.
+    pub code: u8,
+    /// Path of the requested resource (e.g. `/ariel/id`), truncated to 64 bytes, and with
+    /// segments that are not valid UTF-8 shown as `?`.
+    pub path: &'a str,
+    /// Whether the request was allowed.
+    pub outcome: Outcome,
+}
+
+/// Sets a function to which every request checked against the peer's authorization is reported,
+/// replacing any previously set function.
+///
+/// The function is called while the request is processed, so it should not take long.
+pub fn set_access_log(log: fn(&AccessRecord<'_>)) {
+    ACCESS_LOG.lock(|cell| cell.set(Some(log)));
+}
+
+/// The access log installed on the CoAP server's handler.
+pub(crate) struct AccessLogger;
+
+impl AccessLog for AccessLogger {
+    fn record<M: ReadableMessage>(&mut self, peer: Peer<'_>, request: &M, outcome: Outcome) {
+        let mut path = heapless::String::<MAX_PATH_LEN>::new();
+        for option in request.options() {
+            if option.number() != coap_numbers::option::URI_PATH {
+                continue;
+            }
+            let segment = core::str::from_utf8(option.value()).unwrap_or("?");
+            if path
+                .push('/')
+                .and_then(|()| path.push_str(segment))
+                .is_err()
+            {
+                break;
+            }
+        }
+        if path.is_empty() {
+            // Fits by construction
+            let _ = path.push('/');
+        }
+
+        let record = AccessRecord {
+            peer,
+            code: request.code().into(),
+            path: path.as_str(),
+            outcome,
+        };
+        log_access(&record);
+
+        if let Some(log) = ACCESS_LOG.lock(Cell::get) {
+            log(&record);
+        }
+    }
+}
+
+/// Logs a record of the access log at the debug level.
+#[allow(
+    unused_variables,
+    reason = "the record is only used for logging, which may be disabled"
+)]
+fn log_access(record: &AccessRecord<'_>) {
+    let result = match record.outcome {
+        Outcome::Allowed => "allowed",
+        Outcome::NotAllowed => "not allowed",
+        Outcome::NotFresh => "challenged for freshness",
+    };
+    match record.peer {
+        Peer::Unprotected => debug!(
+            "Unprotected request {} to {}: {}",
+            record.code, record.path, result
+        ),
+        Peer::Oscore { recipient_id } => debug!(
+            "OSCORE request {} through context {} to {}: {}",
+            record.code,
+            ariel_os_debug::log::Hex(recipient_id),
+            record.path,
+            result
+        ),
+        Peer::GroupMember { index } => debug!(
+            "Group OSCORE request {} from member {} to {}: {}",
+            record.code, index, record.path, result
+        ),
+    }
+}
+
+/// Encodes the counters as described in the [module documentation](self).
+fn encode(
+    metrics: &Metrics,
+    encoder: &mut minicbor::Encoder<Cursor<&mut [u8]>>,
+) -> Result<(), CoAPError> {
+    encoder
+        .map(8)
+        .and_then(|e| e.str("requests"))
+        .and_then(|e| e.map(8))
+        .map_err(internal)?;
+    for code in 0..8 {
+        encoder
+            .u8(code)
+            .and_then(|e| e.u32(metrics.requests(code)))
+            .map_err(internal)?;
+    }
+    encoder
+        .str("not-allowed")
+        .and_then(|e| e.u32(metrics.not_allowed))
+        .and_then(|e| e.str("freshness-challenges"))
+        .and_then(|e| e.u32(metrics.freshness_challenges))
+        .and_then(|e| e.str("edhoc-completed"))
+        .and_then(|e| e.u32(metrics.edhoc_completed))
+        .and_then(|e| e.str("edhoc-failed"))
+        .and_then(|e| e.u32(metrics.edhoc_failed))
+        .and_then(|e| e.str("oscore-rejected"))
+        .and_then(|e| e.u32(metrics.oscore_rejected))
+        .and_then(|e| e.str("replays-rejected"))
+        .and_then(|e| e.u32(metrics.replays_rejected))
+        .and_then(|e| e.str("contexts-evicted"))
+        .and_then(|e| e.u32(metrics.contexts_evicted))
+        .map_err(internal)?;
+    Ok(())
+}
+
+/// The `/ariel/metrics` resource; see the [module documentation](self).
+#[derive(Copy, Clone)]
+pub(crate) struct MetricsResource;
+
+impl coap_handler::Handler for MetricsResource {
+    type RequestData = ();
+    type ExtractRequestError = CoAPError;
+    type BuildResponseError<M: MinimalWritableMessage> = CoAPError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        for option in request.options() {
+            match option.number() {
+                coap_numbers::option::URI_PATH => (),
+                number => reject_critical(number)?,
+            }
+        }
+
+        let code: u8 = request.code().into();
+        if code == coap_numbers::code::GET {
+            Ok(())
+        } else {
+            Err(CoAPError::method_not_allowed())
+        }
+    }
+
+    fn estimate_length(&mut self, _request: &Self::RequestData) -> usize {
+        MAX_RESPONSE_LEN
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        _request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        let mut buffer = [0u8; MAX_RESPONSE_LEN];
+        let mut encoder = minicbor::Encoder::new(Cursor::new(&mut buffer[..]));
+        encode(&snapshot(), &mut encoder)?;
+        let written = encoder.into_writer().position();
+
+        response.set_code(M::Code::new(coap_numbers::code::CONTENT).map_err(internal)?);
+        response
+            .add_option_uint(
+                M::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT).map_err(internal)?,
+                60u8, // application/cbor
+            )
+            .map_err(internal)?;
+        response
+            .set_payload(
+                buffer
+                    .get(..written)
+                    .ok_or_else(CoAPError::internal_server_error)?,
+            )
+            .map_err(internal)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+//! Observation of resources by CoAP clients ([RFC7641](https://www.rfc-editor.org/rfc/rfc7641)).
+//!
+//! The observations themselves are kept by the [`coapcore::OscoreEdhocHandler`] (see
+//! [`coapcore::observe`]); this module provides the transport side: It tells the handler where
+//! requests come from, sends the notifications the handler builds, and ends observations whose
+//! notifications are rejected.
+//!
+//! Notifications are sent as non-confirmable messages; every few notifications, one is sent as
+//! confirmable instead. Confirmable notifications are not retransmitted: If one is still
+//! unacknowledged when the next confirmable notification is due, the client is considered gone,
+//! and the observation ends.
+
+use core::cell::{Cell, RefCell};
+
+use ariel_os_debug::log::{debug, info};
+use coap_message_implementations::inmemory_write::Message;
+use coapcore::observe::{MAX_OBSERVATIONS, MAX_TOKEN_LEN, Notification, Origin};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embedded_nal_async as nal;
+
+use crate::udp_nal::UnconnectedUdp;
+
+/// Space for the options and payload of a notification.
+const NOTIFICATION_BUFFER_SIZE: usize = 1024;
+
+/// Length of the fixed CoAP header.
+const HEADER_LEN: usize = 4;
+
+/// CoAP message types.
+const TYPE_CON: u8 = 0;
+const TYPE_NON: u8 = 1;
+const TYPE_ACK: u8 = 2;
+const TYPE_RST: u8 = 3;
+
+/// Number of changes that can be reported through [`notify()`] before the CoAP task processes
+/// them.
+const CHANGES_QUEUE_LEN: usize = 4;
+
+static CHANGES: Channel<CriticalSectionRawMutex, &'static str, CHANGES_QUEUE_LEN> = Channel::new();
+
+/// Sends a notification to all clients observing the resource at `path` (e.g.
+/// `"/sensors/temp"`).
+///
+/// The notifications are built by the CoAP task, which queries the resource again for each
+/// observer, just as if the observer had sent its request again. Changes reported while the
+/// previous ones are still being processed may be coalesced or dropped; the observers will still
+/// get notified of the latest state once the task catches up.
+pub fn notify(path: &'static str) {
+    if CHANGES.try_send(path).is_err() {
+        debug!("Notification queue full, dropping change of {}", path);
+    }
+}
+
+/// Operations of the [`coapcore::OscoreEdhocHandler`] used to implement observation.
+pub(crate) trait Observable: coap_handler::Handler {
+    fn set_request_origin(&mut self, origin: Origin);
+    fn notify(&mut self, path: &str);
+    fn cancel_observation(&mut self, origin: &Origin);
+    fn build_notification(&mut self, response: &mut Message<'_>) -> Option<Notification>;
+    #[cfg(feature = "coap-metrics")]
+    fn metrics(&self) -> &coapcore::metrics::Metrics;
+}
+
+impl<
+    H: coap_handler::Handler,
+    Crypto: lakers::Crypto,
+    CryptoFactory: Fn() -> Crypto,
+    SSC: coapcore::seccfg::ServerSecurityConfig,
+    RNG: rand_core::RngCore + rand_core::CryptoRng,
+    TP: coapcore::time::TimeProvider,
+    AL: coapcore::metrics::AccessLog,
+> Observable for coapcore::OscoreEdhocHandler<H, Crypto, CryptoFactory, SSC, RNG, TP, AL>
+{
+    fn set_request_origin(&mut self, origin: Origin) {
+        self.set_request_origin(origin);
+    }
+
+    fn notify(&mut self, path: &str) {
+        self.notify(path);
+    }
+
+    fn cancel_observation(&mut self, origin: &Origin) {
+        self.cancel_observation(origin);
+    }
+
+    fn build_notification(&mut self, response: &mut Message<'_>) -> Option<Notification> {
+        self.build_notification(response)
+    }
+
+    #[cfg(feature = "coap-metrics")]
+    fn metrics(&self) -> &coapcore::metrics::Metrics {
+        self.metrics()
+    }
+}
+
+/// A notification that was sent recently.
+struct Sent {
+    message_id: u16,
+    origin: Origin,
+    /// Set for confirmable notifications until they are acknowledged.
+    awaiting_ack: bool,
+}
+
+/// State shared between the server's socket, its handler, and the notification sender.
+///
+/// All of them run in the same task, so plain cells suffice.
+pub(crate) struct Transport {
+    /// Origin of the most recently received request.
+    origin: RefCell<Option<Origin>>,
+    /// Recently sent notifications, to match acknowledgements and resets to.
+    sent: RefCell<heapless::Deque<Sent, MAX_OBSERVATIONS>>,
+    /// Observations whose notifications were rejected, to be removed from the handler.
+    rejected: RefCell<heapless::Vec<Origin, MAX_OBSERVATIONS>>,
+    next_message_id: Cell<u16>,
+}
+
+impl Transport {
+    pub(crate) fn new() -> Self {
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "any starting point for message IDs is as good as any other"
+        )]
+        let first_message_id =
+            rand_core::RngCore::next_u32(&mut ariel_os_random::fast_rng()) as u16;
+        Self {
+            origin: RefCell::new(None),
+            sent: RefCell::new(heapless::Deque::new()),
+            rejected: RefCell::new(heapless::Vec::new()),
+            next_message_id: Cell::new(first_message_id),
+        }
+    }
+
+    /// Processes an empty acknowledgement or reset message, returning true if it was in response
+    /// to a notification (and thus is not of interest to the CoAP server).
+    fn process_empty(&self, message_type: u8, message_id: u16) -> bool {
+        let mut sent = self.sent.borrow_mut();
+        let Some(notification) = sent.iter_mut().find(|s| s.message_id == message_id) else {
+            return false;
+        };
+        if message_type == TYPE_RST {
+            debug!("Notification was rejected, ending observation.");
+            // If this is full, the observation ends with the next confirmable notification.
+            let _ = self.rejected.borrow_mut().push(notification.origin.clone());
+        }
+        notification.awaiting_ack = false;
+        true
+    }
+
+    /// Records a sent notification, and returns false if the observation is to be ended because
+    /// an earlier confirmable notification was not acknowledged.
+    fn record_sent(&self, message_id: u16, notification: &Notification) -> bool {
+        let mut sent = self.sent.borrow_mut();
+        if notification.confirmable
+            && sent
+                .iter()
+                .any(|s| s.awaiting_ack && s.origin == notification.origin)
+        {
+            return false;
+        }
+        if sent.is_full() {
+            sent.pop_front();
+        }
+        let _ = sent.push_back(Sent {
+            message_id,
+            origin: notification.origin.clone(),
+            awaiting_ack: notification.confirmable,
+        });
+        true
+    }
+}
+
+/// A [`coap_handler::Handler`] that is shared between the server and the notification sender.
+pub(crate) struct SharedHandler<'a, H> {
+    handler: &'a RefCell<H>,
+    transport: &'a Transport,
+}
+
+impl<'a, H> SharedHandler<'a, H> {
+    pub(crate) fn new(handler: &'a RefCell<H>, transport: &'a Transport) -> Self {
+        Self { handler, transport }
+    }
+}
+
+impl<H: Observable> coap_handler::Handler for SharedHandler<'_, H> {
+    type RequestData = H::RequestData;
+    type ExtractRequestError = H::ExtractRequestError;
+    type BuildResponseError<M: coap_message::MinimalWritableMessage> = H::BuildResponseError<M>;
+
+    fn extract_request_data<M: coap_message::ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        let mut handler = self.handler.borrow_mut();
+        if let Some(origin) = self.transport.origin.take() {
+            handler.set_request_origin(origin);
+        }
+        let extracted = handler.extract_request_data(request);
+        #[cfg(feature = "coap-metrics")]
+        crate::metrics::update(handler.metrics());
+        extracted
+    }
+
+    fn estimate_length(&mut self, request: &Self::RequestData) -> usize {
+        self.handler.borrow_mut().estimate_length(request)
+    }
+
+    fn build_response<M: coap_message::MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        // Responses may use up sender sequence numbers or establish security contexts.
+        #[cfg(feature = "coap-server-config-storage")]
+        crate::contexts::changed();
+        let mut handler = self.handler.borrow_mut();
+        let built = handler.build_response(response, request);
+        #[cfg(feature = "coap-metrics")]
+        crate::metrics::update(handler.metrics());
+        built
+    }
+}
+
+/// The server's socket, recording the origin of requests and filtering out responses to
+/// notifications.
+pub(crate) struct ObservingSocket<'a, 's> {
+    socket: &'a UnconnectedUdp<'s>,
+    transport: &'a Transport,
+}
+
+impl<'a, 's> ObservingSocket<'a, 's> {
+    pub(crate) fn new(socket: &'a UnconnectedUdp<'s>, transport: &'a Transport) -> Self {
+        Self { socket, transport }
+    }
+}
+
+impl nal::UnconnectedUdp for ObservingSocket<'_, '_> {
+    type Error = crate::udp_nal::Error;
+
+    async fn send(
+        &mut self,
+        local: core::net::SocketAddr,
+        remote: core::net::SocketAddr,
+        buf: &[u8],
+    ) -> Result<(), Self::Error> {
+        self.socket.send_shared(local, remote, buf).await
+    }
+
+    async fn receive_into(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<(usize, core::net::SocketAddr, core::net::SocketAddr), Self::Error> {
+        loop {
+            let (len, local, remote) = self.socket.receive_into_shared(buf).await?;
+
+            let Some(&[first, code, mid_high, mid_low]) = buf.first_chunk::<HEADER_LEN>() else {
+                // Not CoAP; the server will discard it.
+                return Ok((len, local, remote));
+            };
+            let message_type = (first >> 4) & 0x3;
+            let token_len = usize::from(first & 0xf);
+
+            if code == 0 && matches!(message_type, TYPE_ACK | TYPE_RST) {
+                if self
+                    .transport
+                    .process_empty(message_type, u16::from_be_bytes([mid_high, mid_low]))
+                {
+                    continue;
+                }
+            } else if code != 0 && code >> 5 == 0 {
+                // Requests are the codes 0.01 to 0.31.
+                let token = buf
+                    .get(HEADER_LEN..HEADER_LEN + token_len)
+                    .filter(|_| HEADER_LEN + token_len <= len)
+                    .and_then(|token| heapless::Vec::<u8, MAX_TOKEN_LEN>::from_slice(token).ok());
+                self.transport.origin.replace(token.map(|token| Origin {
+                    remote,
+                    local,
+                    token,
+                }));
+            }
+
+            return Ok((len, local, remote));
+        }
+    }
+}
+
+/// Sends notifications whenever [`notify()`] is called.
+///
+/// Each notification is sent through the socket the observation was registered on.
+pub(crate) async fn send_notifications<H: Observable>(
+    handler: &RefCell<H>,
+    sockets: &[UnconnectedUdp<'_>],
+    transport: &Transport,
+) -> ! {
+    // The fixed header and the token are placed in front of the options and payload as built by
+    // the handler.
+    const PREFIX_LEN: usize = HEADER_LEN + MAX_TOKEN_LEN;
+
+    loop {
+        let path = CHANGES.receive().await;
+
+        {
+            let mut handler = handler.borrow_mut();
+            while let Some(origin) = transport.rejected.borrow_mut().pop() {
+                handler.cancel_observation(&origin);
+            }
+            handler.notify(path);
+        }
+
+        loop {
+            let mut datagram = [0u8; PREFIX_LEN + NOTIFICATION_BUFFER_SIZE];
+            let (prefix, tail) = datagram.split_at_mut(PREFIX_LEN);
+            let mut code = 0;
+            let mut message = Message::new(&mut code, tail);
+            let Some(notification) = handler.borrow_mut().build_notification(&mut message) else {
+                break;
+            };
+            let tail_len = message.finish();
+            #[cfg(feature = "coap-server-config-storage")]
+            crate::contexts::changed();
+
+            let message_id = transport.next_message_id.get();
+            transport.next_message_id.set(message_id.wrapping_add(1));
+            if !transport.record_sent(message_id, &notification) {
+                info!("Observer did not acknowledge notification, ending observation.");
+                handler
+                    .borrow_mut()
+                    .cancel_observation(&notification.origin);
+                continue;
+            }
+
+            let token = notification.origin.token.as_slice();
+            let start = PREFIX_LEN - HEADER_LEN - token.len();
+            let message_type = if notification.confirmable {
+                TYPE_CON
+            } else {
+                TYPE_NON
+            };
+            let [mid_high, mid_low] = message_id.to_be_bytes();
+            #[expect(
+                clippy::cast_possible_truncation,
+                reason = "token length is limited to MAX_TOKEN_LEN"
+            )]
+            let first = 0x40 | (message_type << 4) | token.len() as u8;
+            #[expect(
+                clippy::indexing_slicing,
+                reason = "start is within the prefix by construction"
+            )]
+            let (header, token_space) = prefix[start..].split_at_mut(HEADER_LEN);
+            header.copy_from_slice(&[first, code, mid_high, mid_low]);
+            token_space.copy_from_slice(token);
+
+            #[expect(
+                clippy::indexing_slicing,
+                reason = "the message can not exceed the buffer it was written into"
+            )]
+            let datagram = &datagram[start..PREFIX_LEN + tail_len];
+            let Some(socket) = sockets
+                .iter()
+                .find(|socket| socket.port() == notification.origin.local.port())
+            else {
+                debug!("No socket is bound to the port of the observation.");
+                continue;
+            };
+            if socket
+                .send_shared(
+                    notification.origin.local,
+                    notification.origin.remote,
+                    datagram,
+                )
+                .await
+                .is_err()
+            {
+                debug!("Sending notification failed.");
+            }
+        }
+    }
+}
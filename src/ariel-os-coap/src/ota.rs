@@ -0,0 +1,255 @@
+//! Firmware updates through CoAP, see [`ariel_os_ota`].
+//!
+//! An update is sent in two steps:
+//!
+//! * The signed manifest is sent in a `PUT` request to `/ariel/ota/manifest`. It is checked right
+//!   away; the response indicates whether it is accepted.
+//! * The image is sent in a (typically block-wise) `PUT` request to `/ariel/ota/image`.
+//!
+//! Once the image is complete and matches the manifest, the device reboots, and the bootloader
+//! swaps in the new firmware. A `GET` request to `/ariel/ota/manifest` reports the progress as a
+//! CBOR unsigned integer (see [`Status`]).
+//!
+//! Flash is written by a loop running alongside the CoAP server, as resource handlers can not wait
+//! for flash operations. Blocks arriving faster than they can be written are answered with 5.03
+//! Service Unavailable, and need to be sent again.
+
+use core::cell::Cell;
+
+use ariel_os_debug::log::{error, info};
+use ariel_os_ota::manifest::{Manifest, ManifestError};
+use coap_message::{
+    Code as _, MessageOption as _, MinimalWritableMessage, MutableWritableMessage,
+    OptionNumber as _, ReadableMessage,
+};
+use coap_message_utils::Error as CoAPError;
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    blockwise::{BlockSink, BlockWriteResource},
+    util::{internal, reject_critical},
+};
+
+/// Largest block of the image that can be queued for writing.
+const MAX_BLOCK_SIZE: usize = 1024;
+
+/// Number of blocks that can be queued for writing.
+const COMMANDS_QUEUE_LEN: usize = 2;
+
+/// Time given to the final response to reach the client before rebooting.
+const REBOOT_DELAY: Duration = Duration::from_secs(1);
+
+/// Progress of an update, as reported by `GET /ariel/ota/manifest`.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Status {
+    /// No update was started since boot.
+    Idle = 0,
+    /// A manifest was accepted, and the image is being received.
+    Receiving = 1,
+    /// The image is complete and being checked.
+    Installing = 2,
+    /// Writing or checking the image failed; the update needs to be started over.
+    Failed = 3,
+    /// The update is installed, and the device is about to reboot.
+    Rebooting = 4,
+}
+
+enum Command {
+    Begin(Manifest),
+    Write {
+        offset: u32,
+        data: heapless::Vec<u8, MAX_BLOCK_SIZE>,
+        last: bool,
+    },
+}
+
+static COMMANDS: Channel<CriticalSectionRawMutex, Command, COMMANDS_QUEUE_LEN> = Channel::new();
+
+static STATUS: Mutex<CriticalSectionRawMutex, Cell<Status>> = Mutex::new(Cell::new(Status::Idle));
+
+/// Sequence number of the running firmware, once it has been read from storage.
+static SEQUENCE_NUMBER: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> =
+    Mutex::new(Cell::new(None));
+
+fn status() -> Status {
+    STATUS.lock(Cell::get)
+}
+
+fn set_status(status: Status) {
+    STATUS.lock(|cell| cell.set(status));
+}
+
+/// Writes the image, and installs it once it is complete.
+///
+/// This needs to run alongside the CoAP server. It also rolls back an installed update that
+/// does not confirm itself in time (see [`ariel_os_ota::roll_back_unless_confirmed()`]).
+pub(crate) async fn process_updates() -> ! {
+    let sequence_number = ariel_os_ota::sequence_number().await;
+    SEQUENCE_NUMBER.lock(|cell| cell.set(Some(sequence_number)));
+
+    embassy_futures::join::join(
+        ariel_os_ota::roll_back_unless_confirmed(),
+        process_commands(),
+    )
+    .await
+    .1
+}
+
+async fn process_commands() -> ! {
+    loop {
+        let result = match COMMANDS.receive().await {
+            Command::Begin(manifest) => ariel_os_ota::lock().await.begin(manifest),
+            Command::Write { offset, data, last } => {
+                let result = ariel_os_ota::lock().await.write(offset, &data).await;
+                if result.is_ok() && last {
+                    set_status(Status::Installing);
+                    ariel_os_ota::finish().await
+                } else {
+                    result
+                }
+            }
+        };
+
+        if result.is_err() {
+            error!("Firmware update failed.");
+            set_status(Status::Failed);
+        } else if status() == Status::Installing {
+            info!("Firmware update installed, rebooting.");
+            set_status(Status::Rebooting);
+            Timer::after(REBOOT_DELAY).await;
+            ariel_os_power::reboot();
+        }
+    }
+}
+
+/// CoAP resource accepting the manifest of an update.
+pub(crate) struct ManifestResource;
+
+pub(crate) enum ManifestRequest {
+    Status,
+    Accepted,
+}
+
+impl ManifestResource {
+    fn accept(envelope: &[u8]) -> Result<(), CoAPError> {
+        let manifest = Manifest::verify(envelope, &ariel_os_ota::trusted_key()).map_err(|e| {
+            if e == ManifestError::BadSignature {
+                info!("Rejected firmware manifest with bad signature.");
+                CoAPError::unauthorized()
+            } else {
+                CoAPError::bad_request()
+            }
+        })?;
+
+        let current = SEQUENCE_NUMBER
+            .lock(Cell::get)
+            .ok_or_else(CoAPError::service_unavailable)?;
+        if manifest.sequence_number <= current {
+            info!(
+                "Rejected firmware manifest with sequence number {} (running {}).",
+                manifest.sequence_number, current
+            );
+            return Err(CoAPError::bad_request());
+        }
+        if matches!(status(), Status::Installing | Status::Rebooting) {
+            return Err(CoAPError::service_unavailable());
+        }
+
+        COMMANDS
+            .try_send(Command::Begin(manifest))
+            .map_err(|_| CoAPError::service_unavailable())?;
+        set_status(Status::Receiving);
+        info!(
+            "Accepted firmware manifest with sequence number {}.",
+            manifest.sequence_number
+        );
+        Ok(())
+    }
+}
+
+impl coap_handler::Handler for ManifestResource {
+    type RequestData = ManifestRequest;
+    type ExtractRequestError = CoAPError;
+    type BuildResponseError<M: MinimalWritableMessage> = CoAPError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        for option in request.options() {
+            match option.number() {
+                coap_numbers::option::URI_PATH | coap_numbers::option::CONTENT_FORMAT => (),
+                number => reject_critical(number)?,
+            }
+        }
+
+        let code: u8 = request.code().into();
+        match code {
+            coap_numbers::code::GET => Ok(ManifestRequest::Status),
+            coap_numbers::code::PUT => {
+                Self::accept(request.payload()).map(|()| ManifestRequest::Accepted)
+            }
+            _ => Err(CoAPError::method_not_allowed()),
+        }
+    }
+
+    fn estimate_length(&mut self, _request: &Self::RequestData) -> usize {
+        8
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        match request {
+            ManifestRequest::Status => {
+                response.set_code(M::Code::new(coap_numbers::code::CONTENT).map_err(internal)?);
+                response
+                    .add_option_uint(
+                        M::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT)
+                            .map_err(internal)?,
+                        60u8, // application/cbor
+                    )
+                    .map_err(internal)?;
+                // Small unsigned integers are encoded in CBOR as the plain byte.
+                response.set_payload(&[status() as u8]).map_err(internal)?;
+            }
+            ManifestRequest::Accepted => {
+                response.set_code(M::Code::new(coap_numbers::code::CHANGED).map_err(internal)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Passes the uploaded image on to [`process_updates()`].
+pub(crate) struct ImageSink;
+
+impl BlockSink for ImageSink {
+    fn write(&mut self, offset: usize, data: &[u8], last: bool) -> Result<(), CoAPError> {
+        match status() {
+            Status::Receiving => (),
+            // The update needs to be started (over) by sending a manifest.
+            Status::Idle | Status::Failed => return Err(CoAPError::bad_request()),
+            Status::Installing | Status::Rebooting => {
+                return Err(CoAPError::service_unavailable());
+            }
+        }
+        let offset = u32::try_from(offset).map_err(|_| CoAPError::bad_request())?;
+        let data = heapless::Vec::from_slice(data).map_err(|()| CoAPError::bad_request())?;
+        COMMANDS
+            .try_send(Command::Write { offset, data, last })
+            .map_err(|_| CoAPError::service_unavailable())
+    }
+}
+
+/// CoAP resource accepting the image of an update.
+pub(crate) fn image_resource() -> BlockWriteResource<ImageSink> {
+    BlockWriteResource::new(ImageSink)
+}
@@ -0,0 +1,332 @@
+//! Registration at a Resource Directory (RD, [RFC9176](https://www.rfc-editor.org/rfc/rfc9176)).
+//!
+//! The links of the CoAP server's resources (as listed in its `/.well-known/core` document) are
+//! registered at the RD configured in `CONFIG_COAP_RD_ADDRESS`:
+//!
+//! * The RD's registration interface is looked up with a `GET` request to
+//!   `/.well-known/core?rt=core.rd`.
+//! * The links are registered with a `POST` request to that interface; the RD responds with the
+//!   location of the registration resource.
+//! * The registration is refreshed with an empty `POST` request to that location before its
+//!   lifetime expires.
+//!
+//! When refreshing fails, or the network configuration goes down, the device registers again (as
+//! soon as the network is up again). Registrations are sent without OSCORE protection, and
+//! discovering the RD through multicast or router advertisements is not supported.
+
+use core::fmt::Write as _;
+
+use ariel_os_debug::log::{debug, error, info};
+use ariel_os_embassy::net::NetworkStack;
+use coap_message::{
+    Code as _, MessageOption as _, MinimalWritableMessage, OptionNumber as _, ReadableMessage,
+};
+use coap_message_implementations::inmemory_write::Message;
+use coap_request::Stack as _;
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Timer};
+
+use crate::{blockwise::add_path, client::RequestingClient};
+
+/// The RD configuration, as set through the `CONFIG_COAP_RD_*` variables.
+mod config {
+    include!(concat!(env!("OUT_DIR"), "/rd.rs"));
+}
+
+/// Largest link-format document that is registered.
+///
+/// This leaves room for the options of the registration request in the client's message buffer.
+const MAX_LINKS_LEN: usize = crate::client::MESSAGE_BUFFER_SIZE - 128;
+
+/// Longest endpoint name accepted by RFC9176 Section 5.
+const MAX_ENDPOINT_LEN: usize = 63;
+
+/// Longest path of the registration interface or a registration resource.
+const MAX_PATH_LEN: usize = 64;
+
+/// Time after which a failed registration is tried again.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Content-Format of link-format documents (`application/link-format`).
+const LINK_FORMAT: u16 = 40;
+
+/// The link-format document registered at the RD.
+pub(crate) type Links = heapless::Vec<u8, MAX_LINKS_LEN>;
+
+type Path = heapless::String<MAX_PATH_LEN>;
+
+/// Error of an interaction with the RD.
+enum Error {
+    /// The request could not be sent, or no response was received.
+    Transport,
+    /// The RD responded with an unexpected code.
+    Response(u8),
+    /// The RD's response could not be processed.
+    Protocol,
+}
+
+/// Renders the `/.well-known/core` document of the `handler`, which is what gets registered.
+///
+/// Returns `None` if the document is not available, or is too large to be registered.
+pub(crate) fn render_links(handler: &mut impl coap_handler::Handler) -> Option<Links> {
+    let mut request_code = 0;
+    let mut request_buffer = [0u8; 32];
+    let mut request = Message::new(&mut request_code, &mut request_buffer[..]);
+    request.set_code(coap_numbers::code::GET);
+    add_path(&mut request, "/.well-known/core").ok()?;
+
+    let extracted = handler.extract_request_data(&request).ok()?;
+    let mut code = 0;
+    let mut buffer = [0u8; crate::client::MESSAGE_BUFFER_SIZE];
+    let mut response = Message::new(&mut code, &mut buffer[..]);
+    handler.build_response(&mut response, extracted).ok()?;
+
+    if response.code() != coap_numbers::code::CONTENT {
+        return None;
+    }
+    // A document that does not fit into a single response is too large anyway.
+    let incomplete = response
+        .options()
+        .filter(|option| option.number() == coap_numbers::option::BLOCK2)
+        .any(|option| {
+            option
+                .value_uint::<u32>()
+                .is_none_or(|value| value & 0x08 != 0)
+        });
+    if incomplete {
+        return None;
+    }
+    Links::from_slice(response.payload()).ok()
+}
+
+/// Keeps the `links` registered at the RD.
+///
+/// This needs to run alongside the CoAP server.
+pub(crate) async fn run(links: Option<Links>, stack: NetworkStack) -> ! {
+    let Some(links) = links else {
+        error!("Links of the CoAP server do not fit into an RD registration, not registering.");
+        loop {
+            core::future::pending::<()>().await;
+        }
+    };
+    let endpoint = endpoint();
+    let mut client = crate::coap_client().await.to(config::ADDRESS);
+
+    loop {
+        stack.wait_config_up().await;
+
+        match register(&mut client, &endpoint, &links).await {
+            Ok(location) => {
+                info!("Registered at the RD as {}.", endpoint.as_str());
+                keep_refreshed(&mut client, &location, stack).await;
+            }
+            Err(Error::Response(code)) => {
+                error!("RD rejected the registration with code {}.", code);
+                Timer::after(RETRY_DELAY).await;
+            }
+            Err(Error::Transport | Error::Protocol) => {
+                error!("Registering at the RD failed.");
+                Timer::after(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Returns the endpoint name from `CONFIG_COAP_RD_ENDPOINT`, or one derived from the device ID.
+fn endpoint() -> heapless::String<MAX_ENDPOINT_LEN> {
+    crate::util::device_name(config::ENDPOINT)
+}
+
+/// Looks up the RD's registration interface, and registers the `links` there.
+///
+/// Returns the location of the registration resource.
+async fn register(
+    client: &mut RequestingClient,
+    endpoint: &str,
+    links: &[u8],
+) -> Result<Path, Error> {
+    let interface = client
+        .request(Lookup)
+        .await
+        .map_err(|_| Error::Transport)??;
+    debug!("RD registration interface is at {}", interface.as_str());
+    client
+        .request(Register {
+            interface: &interface,
+            endpoint,
+            links,
+        })
+        .await
+        .map_err(|_| Error::Transport)?
+}
+
+/// Refreshes the registration at `location` until refreshing fails or the network configuration
+/// goes down.
+async fn keep_refreshed(client: &mut RequestingClient, location: &str, stack: NetworkStack) {
+    loop {
+        if let Either::Second(()) =
+            select(Timer::after(refresh_interval()), stack.wait_config_down()).await
+        {
+            info!("Network configuration went down, registering at the RD again once it is up.");
+            return;
+        }
+
+        match client.request(Update { location }).await {
+            Ok(Ok(())) => debug!("Refreshed the RD registration."),
+            Ok(Err(Error::Response(coap_numbers::code::NOT_FOUND))) => {
+                info!("RD registration expired, registering again.");
+                return;
+            }
+            Ok(Err(_)) | Err(_) => {
+                error!("Refreshing the RD registration failed, registering again.");
+                return;
+            }
+        }
+    }
+}
+
+/// Returns the time after which a registration is refreshed, leaving a quarter of its lifetime
+/// for the refresh to arrive.
+fn refresh_interval() -> Duration {
+    Duration::from_secs(u64::from(config::LIFETIME) / 4 * 3)
+}
+
+/// Parses the target of the first link in a link-format document, if it is a path.
+fn first_link_path(document: &[u8]) -> Option<Path> {
+    let document = core::str::from_utf8(document).ok()?;
+    let (target, _) = document.trim_start().strip_prefix('<')?.split_once('>')?;
+    if !target.starts_with('/') {
+        return None;
+    }
+    Path::try_from(target).ok()
+}
+
+/// Adds the Uri-Query option `name=value` to the `request`.
+fn add_query<M: MinimalWritableMessage>(
+    request: &mut M,
+    name: &str,
+    value: impl core::fmt::Display,
+) -> Result<(), M::UnionError> {
+    let mut query = heapless::String::<{ MAX_ENDPOINT_LEN + 8 }>::new();
+    // The longest values are endpoint names, which fit.
+    let _ = write!(query, "{name}={value}");
+    request.add_option(
+        M::OptionNumber::new(coap_numbers::option::URI_QUERY)?,
+        query.as_bytes(),
+    )
+}
+
+/// Lookup of the RD's registration interface (RFC9176 Section 4.3).
+struct Lookup;
+
+impl<S: coap_request::Stack + ?Sized> coap_request::Request<S> for Lookup {
+    /// The path of the registration interface.
+    type Output = Result<Path, Error>;
+    type Carry = ();
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        request.set_code(coap_message::Code::new(coap_numbers::code::GET)?);
+        add_path(request, "/.well-known/core")?;
+        add_query(request, "rt", "core.rd")?;
+        Ok(())
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        _carry: (),
+    ) -> Self::Output {
+        let code: u8 = response.code().into();
+        if code != coap_numbers::code::CONTENT {
+            return Err(Error::Response(code));
+        }
+        first_link_path(response.payload()).ok_or(Error::Protocol)
+    }
+}
+
+/// Registration of the links (RFC9176 Section 5).
+struct Register<'a> {
+    interface: &'a str,
+    endpoint: &'a str,
+    links: &'a [u8],
+}
+
+impl<S: coap_request::Stack + ?Sized> coap_request::Request<S> for Register<'_> {
+    /// The location of the registration resource.
+    type Output = Result<Path, Error>;
+    type Carry = ();
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        request.set_code(coap_message::Code::new(coap_numbers::code::POST)?);
+        add_path(request, self.interface)?;
+        request.add_option_uint(
+            coap_message::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT)?,
+            LINK_FORMAT,
+        )?;
+        add_query(request, "ep", self.endpoint)?;
+        add_query(request, "lt", config::LIFETIME)?;
+        request.set_payload(self.links)?;
+        Ok(())
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        _carry: (),
+    ) -> Self::Output {
+        let code: u8 = response.code().into();
+        if code != coap_numbers::code::CREATED {
+            return Err(Error::Response(code));
+        }
+        let mut location = Path::new();
+        for option in response
+            .options()
+            .filter(|option| option.number() == coap_numbers::option::LOCATION_PATH)
+        {
+            let segment = core::str::from_utf8(option.value()).map_err(|_| Error::Protocol)?;
+            location.push('/').map_err(|()| Error::Protocol)?;
+            location.push_str(segment).map_err(|()| Error::Protocol)?;
+        }
+        if location.is_empty() {
+            return Err(Error::Protocol);
+        }
+        Ok(location)
+    }
+}
+
+/// Refresh of a registration (RFC9176 Section 5.3.1).
+struct Update<'a> {
+    location: &'a str,
+}
+
+impl<S: coap_request::Stack + ?Sized> coap_request::Request<S> for Update<'_> {
+    type Output = Result<(), Error>;
+    type Carry = ();
+
+    async fn build_request(
+        &mut self,
+        request: &mut S::RequestMessage<'_>,
+    ) -> Result<(), S::RequestUnionError> {
+        request.set_code(coap_message::Code::new(coap_numbers::code::POST)?);
+        add_path(request, self.location)?;
+        Ok(())
+    }
+
+    async fn process_response(
+        &mut self,
+        response: &S::ResponseMessage<'_>,
+        _carry: (),
+    ) -> Self::Output {
+        let code: u8 = response.code().into();
+        if code != coap_numbers::code::CHANGED {
+            return Err(Error::Response(code));
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,146 @@
 //! Credential and key configuration backed by ariel-os storage
+//!
+//! The peers (KCCS credentials along with their scopes) are kept in storage, one slot per peer;
+//! at first startup, they are populated from the `peers.yml` file the firmware was built with.
+//! At runtime, they are mirrored in RAM (as policy decisions need to be made synchronously), and
+//! can be managed through the [`PeersAdmin`] resource.
+//!
+//! Access tokens of ACE Authorization Servers listed in `peers.yml` are processed by a
+//! [`ConfigBuilder`](coapcore::seccfg::ConfigBuilder) built from that file, as are requests of
+//! the Group OSCORE group configured there.
 
-use ariel_os_debug::log::{debug, info};
+use core::cell::RefCell;
+
+use ariel_os_debug::log::{debug, error, info};
 use cbor_macro::cbo;
+use coap_message::{
+    Code as _, MessageOption as _, MinimalWritableMessage, MutableWritableMessage,
+    OptionNumber as _, ReadableMessage,
+};
+use coap_message_utils::Error as CoAPError;
 use coapcore::seccfg::ServerSecurityConfig;
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embedded_storage_async::nor_flash::NorFlash;
+use minicbor::encode::write::{Cursor, Write as _};
+use serde::{Deserialize, Serialize};
+
+use crate::util::{internal, reject_critical};
 
 mod flash_peers {
     include!(concat!(env!("OUT_DIR"), "/peers.rs"));
 }
 
+// The limits are set by ariel-os-coap-config, which checks `peers.yml` against them.
+use flash_peers::{MAX_AIF_LEN, MAX_KCCS_LEN, MAX_PEERS};
+
+/// AIF value holding the scopes from `peers.yml`, from stored peers and from tokens.
+type AifValue = coapcore::scope::GenericAifValue<MAX_AIF_LEN>;
+/// Scope of the claims of [`StoredPolicy`].
+type UnionScope = coapcore::scope::UnionScope<MAX_AIF_LEN>;
+
+const _: () = assert!(
+    MAX_AIF_LEN < MAX_KCCS_LEN,
+    "scopes need to fit into a storage item along with their key"
+);
+/// Maximum length of the encoded claims of a persisted security context.
+///
+/// Like a KCCS, they need to fit into a storage item along with their key; contexts whose claims
+/// are longer are not persisted.
+const MAX_STORED_CLAIMS_LEN: usize = MAX_KCCS_LEN;
+
+/// Storage key marking that the peers from `peers.yml` have been stored.
+const PEERS_SEEDED_KEY: &str = "ariel-os-coap.peers-seeded";
+
+// Storing all peers takes two operations per peer, which must fit into a single transaction.
+const _: () = assert!(
+    2 * MAX_PEERS <= ariel_os_storage::MAX_TRANSACTION_OPS,
+    "all peers need to fit into a single storage transaction"
+);
+
+/// Storage keys of a peer slot's KCCS and scope.
+///
+/// These are kept short to leave room for the KCCS in the storage item.
+fn peer_keys(slot: usize) -> (heapless::String<16>, heapless::String<16>) {
+    use core::fmt::Write as _;
+
+    let mut kccs_key = heapless::String::new();
+    let mut scope_key = heapless::String::new();
+    write!(kccs_key, "coap.peer/{slot}").expect("fits by construction");
+    write!(scope_key, "coap.scope/{slot}").expect("fits by construction");
+    (kccs_key, scope_key)
+}
+
+/// A scope as stored along with a peer's credential.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) enum StoredScope {
+    AllowAll,
+    Aif(heapless::Vec<u8, MAX_AIF_LEN>),
+}
+
+impl StoredScope {
+    /// Constructs an AIF scope that was checked to fit at build time.
+    fn aif(encoded: &[u8]) -> Self {
+        Self::Aif(heapless::Vec::from_slice(encoded).expect("length checked at build time"))
+    }
+
+    /// Returns true if `encoded` is this scope as encoded by
+    /// [`StoredPolicy::encode_claims()`](ServerSecurityConfig::encode_claims).
+    fn is_encoded_as(&self, encoded: &[u8]) -> bool {
+        match self {
+            // CBOR `true`
+            Self::AllowAll => encoded == [0xf5],
+            Self::Aif(aif) => aif == encoded,
+        }
+    }
+
+    fn to_union_scope(&self) -> UnionScope {
+        match self {
+            Self::AllowAll => UnionScope::AllowAll,
+            // Scopes are validated before they are stored, but flash content is not to be trusted
+            // blindly.
+            Self::Aif(encoded) => AifValue::parse(encoded).map_or(UnionScope::DenyAll, Into::into),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Peer {
+    kccs: heapless::Vec<u8, MAX_KCCS_LEN>,
+    scope: StoredScope,
+}
+
+/// The RAM copy of the stored peers.
+struct Peers {
+    slots: [Option<Peer>; MAX_PEERS],
+    /// Bit mask of slots that were changed and not persisted yet.
+    dirty: u8,
+    /// Number of times the peer in each slot was revoked or had its scope changed since startup.
+    ///
+    /// Claims granted to a peer record the revision of its slot, and are revoked once it changes.
+    revisions: [u16; MAX_PEERS],
+}
+
+impl Peers {
+    /// Revokes all claims granted to the peer in slot `index` so far.
+    fn revoke(&mut self, index: usize) {
+        if let Some(revision) = self.revisions.get_mut(index) {
+            *revision = revision.wrapping_add(1);
+        }
+    }
+}
+
+static PEERS: Mutex<CriticalSectionRawMutex, RefCell<Peers>> = Mutex::new(RefCell::new(Peers {
+    slots: [const { None }; MAX_PEERS],
+    dirty: 0,
+    revisions: [0; MAX_PEERS],
+}));
+
+/// Signals [`persist_peers()`] that [`PEERS`] has dirty slots.
+static PEERS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
 pub async fn server_security_config() -> impl ServerSecurityConfig {
     StoredPolicy::load().await
 }
@@ -16,14 +149,60 @@ pub async fn server_security_config() -> impl ServerSecurityConfig {
 // don't have the async context to access any storage at CoAP time.
 struct StoredPolicy {
     own_edhoc_credential: (lakers::Credential, lakers::BytesP256ElemLen),
+    /// Authorization servers and OSCORE group from `peers.yml`, to which token processing and
+    /// group requests are delegated.
+    tokens: coapcore::seccfg::ConfigBuilder<MAX_AIF_LEN>,
+    /// Issuer from `peers.yml` whose certificates and CWTs are accepted as peer credentials.
+    trusted_issuer: Option<(coapcore::credentials::TrustedIssuer, UnionScope)>,
 }
 
 impl ServerSecurityConfig for StoredPolicy {
-    // FIXME: Decide based on peers.rs input (but so far tokens are not implemented)
-    const PARSES_TOKENS: bool = false;
+    const PARSES_TOKENS: bool = flash_peers::PARSES_TOKENS;
     const HAS_EDHOC: bool = true;
+    const HAS_OSCORE_GROUP: bool = flash_peers::HAS_OSCORE_GROUP;
     type GeneralClaims = StoredClaims;
 
+    fn decrypt_symmetric_token<'buf>(
+        &self,
+        headers: &coapcore::ace::HeaderMap,
+        aad: &[u8],
+        ciphertext_buffer: &'buf mut [u8],
+    ) -> Result<(StoredClaims, coapcore::ace::CwtClaimsSet<'buf>), coapcore::CredentialError> {
+        self.tokens
+            .decrypt_symmetric_token(headers, aad, ciphertext_buffer)
+            .map(|(claims, claims_set)| (claims.into(), claims_set))
+    }
+
+    fn verify_asymmetric_token<'b>(
+        &self,
+        headers: &coapcore::ace::HeaderMap,
+        signed_data: &[u8],
+        signature: &[u8],
+        signed_payload: &'b [u8],
+    ) -> Result<(StoredClaims, coapcore::ace::CwtClaimsSet<'b>), coapcore::CredentialError> {
+        self.tokens
+            .verify_asymmetric_token(headers, signed_data, signature, signed_payload)
+            .map(|(claims, claims_set)| (claims.into(), claims_set))
+    }
+
+    fn render_not_allowed<M: coap_message::MutableWritableMessage>(
+        &self,
+        message: &mut M,
+    ) -> Result<(), coapcore::seccfg::NotAllowedRenderingFailed> {
+        if Self::PARSES_TOKENS {
+            // Sends the AS Request Creation Hints, if configured.
+            self.tokens.render_not_allowed(message)
+        } else {
+            Err(coapcore::seccfg::NotAllowedRenderingFailed)
+        }
+    }
+
+    fn oscore_group(&self) -> Option<(&coapcore::group::OscoreGroup, StoredClaims)> {
+        self.tokens
+            .oscore_group()
+            .map(|(group, claims)| (group, claims.into()))
+    }
+
     fn own_edhoc_credential(&self) -> Option<(lakers::Credential, lakers::BytesP256ElemLen)> {
         Some(self.own_edhoc_credential)
     }
@@ -32,13 +211,45 @@ impl ServerSecurityConfig for StoredPolicy {
         &self,
         id_cred_x: lakers::IdCred,
     ) -> Option<(lakers::Credential, StoredClaims)> {
-        for (credential, scope) in flash_peers::kccs() {
-            if credential.by_kid().is_ok_and(|by_kid| by_kid == id_cred_x)
-                || credential
-                    .by_value()
-                    .is_ok_and(|by_value| by_value == id_cred_x)
-            {
-                return Some((credential, StoredClaims { scope }));
+        let found = PEERS.lock(|peers| {
+            let peers = peers.borrow();
+            peers.slots.iter().enumerate().find_map(|(index, peer)| {
+                let peer = peer.as_ref()?;
+                let credential = lakers::Credential::parse_ccs(&peer.kccs).ok()?;
+                (credential.by_kid().is_ok_and(|by_kid| by_kid == id_cred_x)
+                    || credential
+                        .by_value()
+                        .is_ok_and(|by_value| by_value == id_cred_x))
+                .then(|| {
+                    (
+                        credential,
+                        StoredClaims {
+                            scope: peer.scope.to_union_scope(),
+                            time_constraint: coapcore::time::TimeConstraint::unbounded(),
+                            peer: peers
+                                .revisions
+                                .get(index)
+                                .map(|revision| (index, *revision)),
+                        },
+                    )
+                })
+            })
+        });
+        if found.is_some() {
+            return found;
+        }
+
+        if let Some((issuer, scope)) = &self.trusted_issuer {
+            if let Some((credential, time_constraint)) = issuer.expand(&id_cred_x) {
+                debug!("Peer presented a credential signed by the trusted issuer.");
+                return Some((
+                    credential,
+                    StoredClaims {
+                        scope: scope.clone(),
+                        time_constraint,
+                        peer: None,
+                    },
+                ));
             }
         }
 
@@ -55,7 +266,117 @@ impl ServerSecurityConfig for StoredPolicy {
     }
 
     fn nosec_authorization(&self) -> Option<Self::GeneralClaims> {
-        flash_peers::unauthenticated_scope().map(|scope| StoredClaims { scope })
+        flash_peers::unauthenticated_scope().map(|scope| StoredClaims {
+            scope,
+            time_constraint: coapcore::time::TimeConstraint::unbounded(),
+            peer: None,
+        })
+    }
+
+    /// Encodes the claims as `[scope, exp]`, where the scope is `true` (all allowed), `false`
+    /// (nothing allowed) or an AIF array, and `exp` is `null` for unbounded claims.
+    ///
+    /// The scope of a token limited by the local policy of `peers.yml` is encoded as `{1: aif}`
+    /// with only the token's AIF array; the policy is applied again when the claims are decoded.
+    ///
+    /// Claims granted to a stored peer are encoded as `[scope, exp, slot]`; they are only restored
+    /// while that slot still holds a peer with the same scope.
+    fn encode_claims(&self, claims: &Self::GeneralClaims, buffer: &mut [u8]) -> Option<usize> {
+        let (buffer, _) = buffer.split_at_mut(buffer.len().min(MAX_STORED_CLAIMS_LEN));
+        let mut encoder = minicbor::Encoder::new(Cursor::new(buffer));
+        encoder
+            .array(if claims.peer.is_some() { 3 } else { 2 })
+            .ok()?;
+        let scope_written = match &claims.scope {
+            UnionScope::AllowAll => encoder.bool(true).is_ok(),
+            UnionScope::DenyAll => encoder.bool(false).is_ok(),
+            UnionScope::AifValue(aif) => encoder.writer_mut().write_all(aif.encoded()).is_ok(),
+            UnionScope::Intersection(aif, limit) => {
+                // Only intersections with the current policy are restored as such.
+                self.tokens
+                    .token_scope_limit()
+                    .is_some_and(|policy| policy.encoded() == limit.encoded())
+                    && encoder.map(1).and_then(|encoder| encoder.u8(1)).is_ok()
+                    && encoder.writer_mut().write_all(aif.encoded()).is_ok()
+            }
+        };
+        if !scope_written {
+            return None;
+        }
+        match claims.time_constraint.expiry() {
+            Some(exp) => encoder.u64(exp),
+            None => encoder.null(),
+        }
+        .ok()?;
+        if let Some((index, _)) = claims.peer {
+            encoder.u64(index as u64).ok()?;
+        }
+        Some(encoder.into_writer().position())
+    }
+
+    fn decode_claims(&self, encoded: &[u8]) -> Option<Self::GeneralClaims> {
+        use minicbor::data::Type;
+
+        let mut decoder = minicbor::Decoder::new(encoded);
+        let with_peer = match decoder.array().ok()? {
+            Some(2) => false,
+            Some(3) => true,
+            _ => return None,
+        };
+        let aif = |decoder: &mut minicbor::Decoder<'_>| {
+            let start = decoder.position();
+            decoder.skip().ok()?;
+            AifValue::parse(encoded.get(start..decoder.position())?).ok()
+        };
+        let scope_start = decoder.position();
+        let scope = match decoder.datatype().ok()? {
+            Type::Bool => {
+                if decoder.bool().ok()? {
+                    UnionScope::AllowAll
+                } else {
+                    UnionScope::DenyAll
+                }
+            }
+            Type::Map => {
+                if decoder.map().ok()? != Some(1) || decoder.u8().ok()? != 1 {
+                    return None;
+                }
+                let token = aif(&mut decoder)?;
+                // Contexts of tokens are not restored if the policy was removed since.
+                UnionScope::Intersection(token, self.tokens.token_scope_limit()?.clone())
+            }
+            _ => aif(&mut decoder)?.into(),
+        };
+        let scope_end = decoder.position();
+        let time_constraint = if decoder.datatype().ok()? == Type::Null {
+            decoder.null().ok()?;
+            coapcore::time::TimeConstraint::unbounded()
+        } else {
+            coapcore::time::TimeConstraint::until(decoder.u64().ok()?)
+        };
+        let peer = if with_peer {
+            let encoded_scope = encoded.get(scope_start..scope_end)?;
+            let index = usize::try_from(decoder.u64().ok()?).ok()?;
+            // Contexts of peers that were revoked or changed while they were persisted are not
+            // restored.
+            let revision = PEERS.lock(|peers| {
+                let peers = peers.borrow();
+                peers
+                    .slots
+                    .get(index)?
+                    .as_ref()
+                    .filter(|peer| peer.scope.is_encoded_as(encoded_scope))?;
+                peers.revisions.get(index).copied()
+            })?;
+            Some((index, revision))
+        } else {
+            None
+        };
+        Some(StoredClaims {
+            scope,
+            time_constraint,
+            peer,
+        })
     }
 }
 
@@ -112,29 +433,430 @@ impl StoredPolicy {
             lakers::Credential::parse_ccs(&credential).expect("Processable by construction");
         let own_edhoc_credential = (credential, key);
 
+        load_peers().await;
+
         Self {
             own_edhoc_credential,
+            tokens: flash_peers::token_config(),
+            trusted_issuer: flash_peers::trusted_issuer(),
         }
     }
 }
 
+/// Loads the stored peers into [`PEERS`], storing those from `peers.yml` at first startup.
+async fn load_peers() {
+    let seeded: Option<bool> = ariel_os_storage::get(PEERS_SEEDED_KEY)
+        .await
+        .expect("flash error prevents startup");
+
+    if seeded.is_none() {
+        PEERS.lock(|peers| {
+            let mut peers = peers.borrow_mut();
+            let mut dirty = 0;
+            for (index, (slot, (kccs, scope))) in peers
+                .slots
+                .iter_mut()
+                .zip(flash_peers::seed_peers())
+                .enumerate()
+            {
+                *slot = Some(Peer {
+                    kccs: heapless::Vec::from_slice(kccs).expect("length checked at build time"),
+                    scope,
+                });
+                dirty |= 1 << index;
+            }
+            peers.dirty = dirty;
+        });
+        store_dirty_peers(true)
+            .await
+            .expect("flash error prevents startup");
+        info!("Stored peers from peers.yml.");
+        return;
+    }
+
+    for index in 0..MAX_PEERS {
+        let (kccs_key, scope_key) = peer_keys(index);
+        let kccs: Option<heapless::Vec<u8, MAX_KCCS_LEN>> = ariel_os_storage::get(&kccs_key)
+            .await
+            .expect("flash error prevents startup");
+        let scope: Option<StoredScope> = ariel_os_storage::get(&scope_key)
+            .await
+            .expect("flash error prevents startup");
+        if let (Some(kccs), Some(scope)) = (kccs, scope) {
+            debug!("Loaded peer {}: {=[u8]:02x}", index, kccs); // :02x could be :cbor
+            PEERS.lock(|peers| {
+                if let Some(slot) = peers.borrow_mut().slots.get_mut(index) {
+                    *slot = Some(Peer { kccs, scope });
+                }
+            });
+        }
+    }
+}
+
+/// Writes the dirty slots of [`PEERS`] to storage.
+///
+/// If `seeding`, the marker that `peers.yml` has been applied is stored after them.
+///
+/// On error, the slots are marked dirty again.
+async fn store_dirty_peers(seeding: bool) -> Result<(), impl core::fmt::Debug> {
+    let (dirty, slots) = PEERS.lock(|peers| {
+        let mut peers = peers.borrow_mut();
+        (core::mem::take(&mut peers.dirty), peers.slots.clone())
+    });
+
+    let mut storage = ariel_os_storage::lock().await;
+    let result = store_peers(&mut storage, &slots, dirty, seeding).await;
+
+    if result.is_err() {
+        PEERS.lock(|peers| peers.borrow_mut().dirty |= dirty);
+    }
+    result
+}
+
+/// Writes the `dirty` slots to `storage` in a single transaction.
+///
+/// If `seeding`, the marker that `peers.yml` has been applied is stored in a separate step
+/// afterwards: should power be lost before, the peers are stored again at the next startup.
+async fn store_peers<F: NorFlash>(
+    storage: &mut ariel_os_storage::Storage<F>,
+    slots: &[Option<Peer>; MAX_PEERS],
+    dirty: u8,
+    seeding: bool,
+) -> Result<(), impl core::fmt::Debug + use<F>> {
+    let stored = async {
+        let mut txn = storage.transaction().await?;
+        for (index, slot) in slots.iter().enumerate() {
+            if dirty & (1 << index) == 0 {
+                continue;
+            }
+            let (kccs_key, scope_key) = peer_keys(index);
+            match slot {
+                Some(Peer { kccs, scope }) => {
+                    txn.insert(&kccs_key, kccs.clone()).await?;
+                    txn.insert(&scope_key, scope.clone()).await?;
+                }
+                None => {
+                    txn.remove(&kccs_key).await?;
+                    txn.remove(&scope_key).await?;
+                }
+            }
+        }
+        txn.commit().await
+    }
+    .await;
+
+    match stored {
+        Ok(()) if seeding => storage.insert(PEERS_SEEDED_KEY, true).await,
+        stored => stored,
+    }
+}
+
+/// Persists changes made through the [`PeersAdmin`] resource.
+///
+/// This needs to run alongside the CoAP server, as resource handlers can not access storage
+/// themselves.
+pub(crate) async fn persist_peers() -> ! {
+    loop {
+        PEERS_CHANGED.wait().await;
+        match store_dirty_peers(false).await {
+            Ok(()) => debug!("Stored updated peers."),
+            Err(_) => error!("Failed to store peers, retrying with the next change."),
+        }
+    }
+}
+
+/// CoAP resource for managing the stored peers.
+///
+/// Access to it is governed by the server access policy like to any other resource; it is
+/// typically only granted to an administrator's credential.
+///
+/// Peers are represented in CBOR, as an array of the slot number, the KCCS (as a byte string),
+/// and the scope (`true` for "allow all", or an AIF array as in `peers.yml`).
+///
+/// * `GET` lists all peers.
+/// * `POST` with a `[kccs, scope]` array adds a peer, or updates the scope of a peer with the same
+///   KCCS. It responds with the slot number.
+/// * `DELETE` with the slot number as its query (e.g., `?3`) revokes a peer.
+///
+/// Revoking a peer or changing its scope discards the security contexts established with it,
+/// including those persisted across reboots.
+pub(crate) struct PeersAdmin;
+
+pub(crate) enum PeersAdminRequest {
+    List,
+    Added(u8),
+    Removed,
+}
+
+impl PeersAdmin {
+    fn add(payload: &[u8]) -> Result<u8, CoAPError> {
+        let mut decoder = minicbor::Decoder::new(payload);
+        if decoder.array().map_err(|_| CoAPError::bad_request())? != Some(2) {
+            return Err(CoAPError::bad_request());
+        }
+        let kccs = decoder.bytes().map_err(|_| CoAPError::bad_request())?;
+        let scope = if decoder.datatype().map_err(|_| CoAPError::bad_request())?
+            == minicbor::data::Type::Bool
+        {
+            if !decoder.bool().map_err(|_| CoAPError::bad_request())? {
+                return Err(CoAPError::bad_request());
+            }
+            StoredScope::AllowAll
+        } else {
+            let start = decoder.position();
+            decoder.skip().map_err(|_| CoAPError::bad_request())?;
+            let aif = payload
+                .get(start..decoder.position())
+                .ok_or_else(CoAPError::bad_request)?;
+            AifValue::parse(aif).map_err(|_| CoAPError::bad_request())?;
+            StoredScope::Aif(heapless::Vec::from_slice(aif).map_err(|()| CoAPError::bad_request())?)
+        };
+        if decoder.position() != payload.len() {
+            return Err(CoAPError::bad_request());
+        }
+
+        lakers::Credential::parse_ccs(kccs).map_err(|_| CoAPError::bad_request())?;
+        let kccs = heapless::Vec::from_slice(kccs).map_err(|()| CoAPError::bad_request())?;
+
+        PEERS.lock(|peers| {
+            let mut peers = peers.borrow_mut();
+            let index = peers
+                .slots
+                .iter()
+                .position(|slot| slot.as_ref().is_some_and(|peer| peer.kccs == kccs))
+                .or_else(|| peers.slots.iter().position(Option::is_none))
+                // FIXME: 5.03 would be more precise
+                .ok_or_else(CoAPError::internal_server_error)?;
+            let replaced = peers.slots.get_mut(index).and_then(|slot| {
+                slot.replace(Peer {
+                    kccs,
+                    scope: scope.clone(),
+                })
+            });
+            // Security contexts established with the old scope are discarded.
+            if replaced.is_some_and(|peer| peer.scope != scope) {
+                peers.revoke(index);
+            }
+            peers.dirty |= 1 << index;
+            PEERS_CHANGED.signal(());
+            info!("Peer {} added through CoAP.", index);
+            #[expect(clippy::cast_possible_truncation, reason = "bounded by MAX_PEERS")]
+            let index = index as u8;
+            Ok(index)
+        })
+    }
+
+    fn remove(index: usize) -> Result<(), CoAPError> {
+        PEERS.lock(|peers| {
+            let mut peers = peers.borrow_mut();
+            peers
+                .slots
+                .get_mut(index)
+                .and_then(Option::take)
+                .ok_or_else(CoAPError::not_found)?;
+            peers.revoke(index);
+            peers.dirty |= 1 << index;
+            PEERS_CHANGED.signal(());
+            info!("Peer {} revoked through CoAP.", index);
+            Ok(())
+        })
+    }
+
+    fn list(encoder: &mut minicbor::Encoder<Cursor<&mut [u8]>>) -> Result<(), CoAPError> {
+        PEERS.lock(|peers| {
+            let peers = peers.borrow();
+            let count = peers.slots.iter().flatten().count();
+            encoder.array(count as u64).map_err(internal)?;
+            for (index, peer) in peers.slots.iter().enumerate() {
+                let Some(peer) = peer else {
+                    continue;
+                };
+                encoder
+                    .array(3)
+                    .and_then(|e| e.u64(index as u64))
+                    .and_then(|e| e.bytes(&peer.kccs))
+                    .map_err(internal)?;
+                match &peer.scope {
+                    StoredScope::AllowAll => {
+                        encoder.bool(true).map_err(internal)?;
+                    }
+                    // The AIF array is already encoded CBOR.
+                    StoredScope::Aif(aif) => {
+                        encoder.writer_mut().write_all(aif).map_err(internal)?;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+impl coap_handler::Handler for PeersAdmin {
+    type RequestData = PeersAdminRequest;
+    type ExtractRequestError = CoAPError;
+    type BuildResponseError<M: MinimalWritableMessage> = CoAPError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        let mut slot = None;
+        for option in request.options() {
+            match option.number() {
+                coap_numbers::option::URI_PATH => (),
+                coap_numbers::option::URI_QUERY => {
+                    slot = Some(
+                        core::str::from_utf8(option.value())
+                            .ok()
+                            .and_then(|query| query.parse::<usize>().ok())
+                            .ok_or_else(|| {
+                                CoAPError::bad_option(coap_numbers::option::URI_QUERY)
+                            })?,
+                    );
+                }
+                number => reject_critical(number)?,
+            }
+        }
+
+        match (request.code().into(), slot) {
+            (coap_numbers::code::GET, None) => Ok(PeersAdminRequest::List),
+            (coap_numbers::code::POST, None) => {
+                Self::add(request.payload()).map(PeersAdminRequest::Added)
+            }
+            (coap_numbers::code::DELETE, Some(slot)) => {
+                Self::remove(slot).map(|()| PeersAdminRequest::Removed)
+            }
+            (coap_numbers::code::GET | coap_numbers::code::POST, Some(_)) => {
+                Err(CoAPError::bad_option(coap_numbers::option::URI_QUERY))
+            }
+            (coap_numbers::code::DELETE, None) => Err(CoAPError::bad_request()),
+            _ => Err(CoAPError::method_not_allowed()),
+        }
+    }
+
+    fn estimate_length(&mut self, _request: &Self::RequestData) -> usize {
+        1024
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        let code = match request {
+            PeersAdminRequest::List => coap_numbers::code::CONTENT,
+            PeersAdminRequest::Added(_) => coap_numbers::code::CHANGED,
+            PeersAdminRequest::Removed => coap_numbers::code::DELETED,
+        };
+        response.set_code(M::Code::new(code).map_err(internal)?);
+
+        let mut buffer = [0u8; 1024];
+        let mut encoder = minicbor::Encoder::new(Cursor::new(&mut buffer[..]));
+        match request {
+            PeersAdminRequest::List => Self::list(&mut encoder)?,
+            PeersAdminRequest::Added(slot) => {
+                encoder.u8(slot).map_err(internal)?;
+            }
+            PeersAdminRequest::Removed => return Ok(()),
+        }
+        let written = encoder.into_writer().position();
+
+        response
+            .add_option_uint(
+                M::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT).map_err(internal)?,
+                60u8, // application/cbor
+            )
+            .map_err(internal)?;
+        response
+            .set_payload(
+                buffer
+                    .get(..written)
+                    .ok_or_else(CoAPError::internal_server_error)?,
+            )
+            .map_err(internal)?;
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct StoredClaims {
-    scope: coapcore::scope::UnionScope,
+    scope: UnionScope,
+    time_constraint: coapcore::time::TimeConstraint,
+    /// Slot and revision of the stored peer these claims were granted to.
+    peer: Option<(usize, u16)>,
+}
+
+impl From<coapcore::seccfg::ConfigBuilderClaims<MAX_AIF_LEN>> for StoredClaims {
+    fn from(claims: coapcore::seccfg::ConfigBuilderClaims<MAX_AIF_LEN>) -> Self {
+        Self {
+            scope: claims.scope,
+            time_constraint: claims.time_constraint,
+            peer: None,
+        }
+    }
 }
 
 impl coapcore::GeneralClaims for StoredClaims {
-    type Scope = coapcore::scope::UnionScope;
+    type Scope = UnionScope;
 
     fn scope(&self) -> &Self::Scope {
         &self.scope
     }
 
     fn time_constraint(&self) -> coapcore::time::TimeConstraint {
-        coapcore::time::TimeConstraint::unbounded()
+        self.time_constraint
     }
 
     fn is_important(&self) -> bool {
         false
     }
+
+    fn is_revoked(&self) -> bool {
+        self.peer.is_some_and(|(index, revision)| {
+            PEERS.lock(|peers| peers.borrow().revisions.get(index) != Some(&revision))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ariel_os_storage::{Storage, mock_flash::MockFlash};
+    use embassy_futures::block_on;
+
+    use super::{MAX_KCCS_LEN, MAX_PEERS, PEERS_SEEDED_KEY, Peer, StoredScope, peer_keys};
+
+    #[test]
+    fn seeds_max_peers() {
+        let mut flash = MockFlash::<1024, 4>::new(16);
+        let range = flash.range();
+        block_on(async {
+            let mut storage = Storage::new(&mut flash, range);
+            let slots: [Option<Peer>; MAX_PEERS] = core::array::from_fn(|index| {
+                #[expect(clippy::cast_possible_truncation, reason = "bounded by MAX_PEERS")]
+                let kccs = [index as u8; MAX_KCCS_LEN];
+                Some(Peer {
+                    kccs: heapless::Vec::from_slice(&kccs).unwrap(),
+                    scope: StoredScope::AllowAll,
+                })
+            });
+
+            super::store_peers(&mut storage, &slots, u8::MAX, true)
+                .await
+                .unwrap();
+
+            for (index, slot) in slots.iter().enumerate() {
+                let (kccs_key, scope_key) = peer_keys(index);
+                let kccs: Option<heapless::Vec<u8, MAX_KCCS_LEN>> =
+                    storage.get(&kccs_key).await.unwrap();
+                let scope: Option<StoredScope> = storage.get(&scope_key).await.unwrap();
+                let peer = slot.as_ref().unwrap();
+                assert_eq!(kccs.as_ref(), Some(&peer.kccs), "peer {index}");
+                assert_eq!(scope.as_ref(), Some(&peer.scope), "peer {index}");
+            }
+            assert_eq!(
+                storage.get::<bool>(PEERS_SEEDED_KEY).await.unwrap(),
+                Some(true)
+            );
+        });
+    }
 }
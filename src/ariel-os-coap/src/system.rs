@@ -0,0 +1,239 @@
+//! Resources exposing the state of the system, and allowing to administer it.
+//!
+//! The resources are mounted below `/ariel/`; like any other resource, they are only accessible
+//! to peers whose scope allows the request (see the `coap-server-config-*` features):
+//!
+//! * `/ariel/build` (`GET`): a CBOR map with the text keys `"os"` and `"board"`, see
+//!   [`ariel_os_buildinfo`].
+//! * `/ariel/id` (`GET`): the device ID as a CBOR byte string, see [`ariel_os_identity`].
+//! * `/ariel/threads` (`GET`, with threading enabled): a CBOR array holding `[thread ID,
+//!   priority, stack size, stack used]` for each thread, see
+//!   [`ariel_os_threads::stack_usage()`].
+//! * `/ariel/storage` (`GET`, with storage enabled): a CBOR array of the keys in the global
+//!   storage, see [`ariel_os_storage::keys()`].
+//! * `/ariel/reboot` (`POST`): reboots the device after responding.
+//!
+//! Resource handlers can not wait for flash operations, so storage keys are listed by a loop
+//! running alongside the CoAP server: a request is answered from the list taken after the
+//! previous request (or at startup), and triggers taking a new one.
+
+use ariel_os_debug::log::info;
+use coap_message::{
+    Code as _, MessageOption as _, MinimalWritableMessage, MutableWritableMessage,
+    OptionNumber as _, ReadableMessage,
+};
+use coap_message_utils::Error as CoAPError;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use minicbor::encode::write::Cursor;
+
+use crate::util::{internal, reject_critical};
+
+/// Time given to the response to reach the client before rebooting.
+const REBOOT_DELAY: Duration = Duration::from_secs(1);
+
+/// Largest CBOR document produced by any of the resources.
+const MAX_RESPONSE_LEN: usize = 1024;
+
+static REBOOT_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Performs the system operations requested through the resources.
+///
+/// This needs to run alongside the CoAP server.
+pub(crate) async fn run() -> ! {
+    #[cfg(feature = "storage")]
+    embassy_futures::join::join(reboot_on_request(), storage::list_keys())
+        .await
+        .0;
+    #[cfg(not(feature = "storage"))]
+    reboot_on_request().await;
+}
+
+async fn reboot_on_request() -> ! {
+    REBOOT_REQUESTED.wait().await;
+    info!("Rebooting as requested through CoAP.");
+    Timer::after(REBOOT_DELAY).await;
+    ariel_os_power::reboot();
+}
+
+#[cfg(feature = "storage")]
+mod storage {
+    use core::cell::RefCell;
+
+    use ariel_os_debug::log::error;
+    use arrayvec::{ArrayString, ArrayVec};
+    use embassy_sync::{
+        blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+        signal::Signal,
+    };
+
+    /// Largest number of storage keys listed.
+    ///
+    /// This is chosen so that a list of keys of maximum length fits into a response.
+    const MAX_LISTED_KEYS: usize = 12;
+
+    pub(super) type Keys =
+        ArrayVec<ArrayString<{ ariel_os_storage::MAX_KEY_LEN }>, MAX_LISTED_KEYS>;
+
+    /// Keys listed by [`list_keys()`], if listing succeeded.
+    pub(super) static KEYS: Mutex<CriticalSectionRawMutex, RefCell<Option<Keys>>> =
+        Mutex::new(RefCell::new(None));
+
+    pub(super) static KEYS_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+    pub(super) async fn list_keys() -> ! {
+        loop {
+            let keys = ariel_os_storage::keys::<MAX_LISTED_KEYS>().await;
+            if keys.is_err() {
+                error!("Listing storage keys failed.");
+            }
+            KEYS.lock(|cell| *cell.borrow_mut() = keys.ok());
+            KEYS_REQUESTED.wait().await;
+        }
+    }
+}
+
+/// Returns the index, priority and stack usage of the thread with the given index, if it exists.
+#[cfg(feature = "threading")]
+fn thread_usage(index: usize) -> Option<(usize, usize, ariel_os_threads::StackUsage)> {
+    #[expect(clippy::cast_possible_truncation, reason = "bounded by THREAD_COUNT")]
+    let thread_id = ariel_os_threads::ThreadId::new(index as u8);
+    let priority = ariel_os_threads::get_priority(thread_id)?;
+    let usage = ariel_os_threads::stack_usage(thread_id)?;
+    Some((index, usize::from(priority), usage))
+}
+
+/// One of the system resources; see the [module documentation](self) for what they do.
+#[derive(Copy, Clone)]
+pub(crate) enum SystemResource {
+    Build,
+    DeviceId,
+    #[cfg(feature = "threading")]
+    Threads,
+    #[cfg(feature = "storage")]
+    StorageKeys,
+    Reboot,
+}
+
+impl SystemResource {
+    fn encode(self, encoder: &mut minicbor::Encoder<Cursor<&mut [u8]>>) -> Result<(), CoAPError> {
+        match self {
+            Self::Build => {
+                encoder
+                    .map(2)
+                    .and_then(|e| e.str("os"))
+                    .and_then(|e| e.str(ariel_os_buildinfo::OS_NAME))
+                    .and_then(|e| e.str("board"))
+                    .and_then(|e| e.str(ariel_os_buildinfo::BOARD))
+                    .map_err(internal)?;
+            }
+            Self::DeviceId => {
+                let id =
+                    ariel_os_identity::device_id_bytes().map_err(|_| CoAPError::not_found())?;
+                encoder.bytes(id.as_ref()).map_err(internal)?;
+            }
+            #[cfg(feature = "threading")]
+            Self::Threads => {
+                use ariel_os_threads::THREAD_COUNT;
+
+                // Collected first, so that the threads are only looked up once.
+                let threads: arrayvec::ArrayVec<_, THREAD_COUNT> =
+                    (0..THREAD_COUNT).filter_map(thread_usage).collect();
+                encoder.array(threads.len() as u64).map_err(internal)?;
+                for (index, priority, usage) in threads {
+                    encoder
+                        .array(4)
+                        .and_then(|e| e.u64(index as u64))
+                        .and_then(|e| e.u64(priority as u64))
+                        .and_then(|e| e.u64(usage.size as u64))
+                        .and_then(|e| e.u64(usage.used as u64))
+                        .map_err(internal)?;
+                }
+            }
+            #[cfg(feature = "storage")]
+            Self::StorageKeys => {
+                storage::KEYS.lock(|keys| {
+                    let keys = keys.borrow();
+                    let keys = keys.as_ref().ok_or_else(CoAPError::service_unavailable)?;
+                    encoder.array(keys.len() as u64).map_err(internal)?;
+                    for key in keys {
+                        encoder.str(key).map_err(internal)?;
+                    }
+                    Ok(())
+                })?;
+            }
+            Self::Reboot => (),
+        }
+        Ok(())
+    }
+}
+
+impl coap_handler::Handler for SystemResource {
+    type RequestData = Self;
+    type ExtractRequestError = CoAPError;
+    type BuildResponseError<M: MinimalWritableMessage> = CoAPError;
+
+    fn extract_request_data<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+    ) -> Result<Self::RequestData, Self::ExtractRequestError> {
+        for option in request.options() {
+            match option.number() {
+                coap_numbers::option::URI_PATH => (),
+                number => reject_critical(number)?,
+            }
+        }
+
+        let code: u8 = request.code().into();
+        match (*self, code) {
+            (Self::Reboot, coap_numbers::code::POST) => {
+                REBOOT_REQUESTED.signal(());
+                Ok(Self::Reboot)
+            }
+            (Self::Reboot, _) => Err(CoAPError::method_not_allowed()),
+            #[cfg(feature = "storage")]
+            (Self::StorageKeys, coap_numbers::code::GET) => {
+                storage::KEYS_REQUESTED.signal(());
+                Ok(Self::StorageKeys)
+            }
+            (resource, coap_numbers::code::GET) => Ok(resource),
+            _ => Err(CoAPError::method_not_allowed()),
+        }
+    }
+
+    fn estimate_length(&mut self, _request: &Self::RequestData) -> usize {
+        MAX_RESPONSE_LEN
+    }
+
+    fn build_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        request: Self::RequestData,
+    ) -> Result<(), Self::BuildResponseError<M>> {
+        if let Self::Reboot = request {
+            response.set_code(M::Code::new(coap_numbers::code::CHANGED).map_err(internal)?);
+            return Ok(());
+        }
+
+        let mut buffer = [0u8; MAX_RESPONSE_LEN];
+        let mut encoder = minicbor::Encoder::new(Cursor::new(&mut buffer[..]));
+        request.encode(&mut encoder)?;
+        let written = encoder.into_writer().position();
+
+        response.set_code(M::Code::new(coap_numbers::code::CONTENT).map_err(internal)?);
+        response
+            .add_option_uint(
+                M::OptionNumber::new(coap_numbers::option::CONTENT_FORMAT).map_err(internal)?,
+                60u8, // application/cbor
+            )
+            .map_err(internal)?;
+        response
+            .set_payload(
+                buffer
+                    .get(..written)
+                    .ok_or_else(CoAPError::internal_server_error)?,
+            )
+            .map_err(internal)?;
+        Ok(())
+    }
+}
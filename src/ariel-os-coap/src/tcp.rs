@@ -0,0 +1,257 @@
+//! CoAP over TCP ([RFC8323](https://www.rfc-editor.org/rfc/rfc8323)).
+//!
+//! The server accepts connections on `CONFIG_COAP_TCP_PORT`, and processes the requests arriving
+//! on them with the same handler as requests over UDP (including its OSCORE and EDHOC
+//! processing). Up to `CONFIG_COAP_TCP_CONNECTIONS` connections are served at the same time.
+//!
+//! Of the signaling messages, only those needed to keep a connection going are supported: A
+//! Capabilities and Settings Message (CSM) announcing the largest message accepted is sent when a
+//! connection is opened, pings are answered, and releases and aborts close the connection.
+//! Observations can not be registered over TCP, and neither TLS nor WebSockets are supported.
+
+use core::cell::RefCell;
+
+use ariel_os_debug::log::{debug, info};
+use ariel_os_embassy::net::NetworkStack;
+use coap_message::{MinimalWritableMessage as _, error::RenderableOnMinimal as _};
+use coap_message_implementations::{inmemory, inmemory_write::Message};
+use embassy_net::tcp::TcpSocket;
+use embassy_time::Duration;
+use embedded_io_async::{Read as _, Write as _};
+
+use crate::observe::{Observable, SharedHandler, Transport};
+
+/// TCP port of the CoAP server.
+const PORT: u16 =
+    ariel_os_utils::u16_from_env_or!("CONFIG_COAP_TCP_PORT", 5683, "TCP port of the CoAP server");
+
+/// Number of connections served at the same time.
+const CONNECTIONS: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_TCP_CONNECTIONS",
+    1,
+    "number of concurrent CoAP over TCP connections"
+);
+
+/// Largest size of the options and payload of a message, as announced in the CSM.
+///
+/// This defaults to the size every peer needs to accept (RFC8323 Section 5.3.1).
+const MAX_MESSAGE_SIZE: usize = ariel_os_utils::usize_from_env_or!(
+    "CONFIG_COAP_TCP_MAX_MESSAGE_SIZE",
+    1152,
+    "largest size of the options and payload of a CoAP over TCP message (in bytes)"
+);
+
+/// Size of the receive and transmit buffer of each connection's socket.
+const SOCKET_BUFFER_SIZE: usize = 1024;
+
+/// Time after which a connection without any traffic is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Longest token accepted.
+const MAX_TOKEN_LEN: usize = 8;
+
+/// Longest header: the length and token length, two bytes of extended length, the code, and the
+/// token.
+const MAX_HEADER_LEN: usize = 1 + 2 + 1 + MAX_TOKEN_LEN;
+
+/// Signaling codes (RFC8323 Section 11.1).
+const CSM: u8 = 0xe1;
+const PING: u8 = 0xe2;
+const PONG: u8 = 0xe3;
+const RELEASE: u8 = 0xe4;
+const ABORT: u8 = 0xe5;
+
+/// The Max-Message-Size option of CSMs.
+const MAX_MESSAGE_SIZE_OPTION: u16 = 2;
+
+type Token = heapless::Vec<u8, MAX_TOKEN_LEN>;
+
+/// Serves requests over TCP with the `handler` the UDP server uses.
+///
+/// This needs to run alongside the CoAP server.
+pub(crate) async fn run<H: Observable>(stack: NetworkStack, handler: &RefCell<H>) -> ! {
+    let connections: [_; CONNECTIONS] = core::array::from_fn(|_| serve_connections(stack, handler));
+    embassy_futures::select::select_array(connections).await.0
+}
+
+/// Accepts connections one after the other, and serves each until it is closed.
+async fn serve_connections<H: Observable>(stack: NetworkStack, handler: &RefCell<H>) -> ! {
+    let mut rx_buffer = [0; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0; SOCKET_BUFFER_SIZE];
+    // Requests over TCP are not given an origin, so they do not register observations, whose
+    // notifications are only sent over UDP.
+    let transport = Transport::new();
+    let mut handler = SharedHandler::new(handler, &transport);
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(IDLE_TIMEOUT));
+        if socket.accept(PORT).await.is_err() {
+            continue;
+        }
+        debug!("Accepted CoAP over TCP connection.");
+
+        if serve(&mut socket, &mut handler).await.is_err() {
+            info!("CoAP over TCP connection failed, closing it.");
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+/// Serves a connection until the peer closes it.
+async fn serve(
+    socket: &mut TcpSocket<'_>,
+    handler: &mut impl coap_handler::Handler,
+) -> Result<(), ()> {
+    let mut incoming = [0u8; MAX_MESSAGE_SIZE];
+    let mut outgoing = [0u8; MAX_MESSAGE_SIZE];
+
+    let max_message_size = u32::try_from(MAX_MESSAGE_SIZE).unwrap_or(u32::MAX);
+    send(socket, &mut outgoing, &[], |csm| {
+        csm.set_code(CSM);
+        // This fits into any buffer that can hold a request.
+        let _ = csm.add_option_uint(MAX_MESSAGE_SIZE_OPTION, max_message_size);
+    })
+    .await?;
+
+    loop {
+        let Some((code, token, len)) = receive(socket, &mut incoming).await? else {
+            return Ok(());
+        };
+        let body = incoming.get(..len).ok_or(())?;
+
+        match code {
+            PING => send(socket, &mut outgoing, &token, |pong| pong.set_code(PONG)).await?,
+            RELEASE | ABORT => return Ok(()),
+            // Requests are the codes 0.01 to 0.31.
+            code if code != 0 && code >> 5 == 0 => {
+                let request = inmemory::Message::new(code, body);
+                send(socket, &mut outgoing, &token, |response| {
+                    respond(handler, &request, response);
+                })
+                .await?;
+            }
+            // Empty messages only keep the connection alive; CSMs and pongs need no reaction, and
+            // responses are not expected as no requests are sent.
+            _ => (),
+        }
+    }
+}
+
+/// Builds the `handler`'s response to the `request`.
+fn respond(
+    handler: &mut impl coap_handler::Handler,
+    request: &inmemory::Message<'_>,
+    response: &mut Message<'_>,
+) {
+    let rendered = match handler.extract_request_data(request) {
+        Ok(extracted) => match handler.build_response(response, extracted) {
+            Ok(()) => return,
+            Err(e) => e.render(response).is_ok(),
+        },
+        Err(e) => e.render(response).is_ok(),
+    };
+    if !rendered {
+        response.set_code(coap_numbers::code::INTERNAL_SERVER_ERROR);
+    }
+}
+
+/// Receives a message into `buffer`, and returns its code, its token, and the length of its
+/// options and payload.
+///
+/// Returns `None` if the peer closed the connection.
+async fn receive(
+    socket: &mut TcpSocket<'_>,
+    buffer: &mut [u8],
+) -> Result<Option<(u8, Token, usize)>, ()> {
+    let mut first = [0u8; 1];
+    if socket.read(&mut first).await.map_err(|_| ())? == 0 {
+        return Ok(None);
+    }
+    let [first] = first;
+
+    let len = match first >> 4 {
+        13 => {
+            let mut extended = [0u8; 1];
+            read(socket, &mut extended).await?;
+            usize::from(u8::from_be_bytes(extended)) + 13
+        }
+        14 => {
+            let mut extended = [0u8; 2];
+            read(socket, &mut extended).await?;
+            usize::from(u16::from_be_bytes(extended)) + 269
+        }
+        15 => {
+            let mut extended = [0u8; 4];
+            read(socket, &mut extended).await?;
+            usize::try_from(u32::from_be_bytes(extended))
+                .ok()
+                .and_then(|len| len.checked_add(65805))
+                .ok_or(())?
+        }
+        len => usize::from(len),
+    };
+
+    let mut code = [0u8; 1];
+    read(socket, &mut code).await?;
+    let [code] = code;
+
+    let mut token = [0u8; MAX_TOKEN_LEN];
+    let token = token.get_mut(..usize::from(first & 0xf)).ok_or(())?;
+    read(socket, token).await?;
+
+    // Peers were told the largest message size accepted in the CSM.
+    read(socket, buffer.get_mut(..len).ok_or(())?).await?;
+
+    Ok(Some((code, Token::from_slice(token)?, len)))
+}
+
+/// Fills `buffer` from the socket.
+async fn read(socket: &mut TcpSocket<'_>, buffer: &mut [u8]) -> Result<(), ()> {
+    socket.read_exact(buffer).await.map_err(|_| ())
+}
+
+/// Sends a message with the `token`, whose code, options and payload are set by `build`.
+async fn send(
+    socket: &mut TcpSocket<'_>,
+    buffer: &mut [u8],
+    token: &[u8],
+    build: impl FnOnce(&mut Message<'_>),
+) -> Result<(), ()> {
+    let mut code = 0;
+    let mut message = Message::new(&mut code, buffer);
+    build(&mut message);
+    let len = message.finish();
+
+    let header = header(len, code, token)?;
+    socket.write_all(&header).await.map_err(|_| ())?;
+    socket
+        .write_all(buffer.get(..len).ok_or(())?)
+        .await
+        .map_err(|_| ())
+}
+
+/// Encodes the header of a message with options and payload of length `len` (RFC8323 Section
+/// 3.2).
+fn header(len: usize, code: u8, token: &[u8]) -> Result<heapless::Vec<u8, MAX_HEADER_LEN>, ()> {
+    let token_len = u8::try_from(token.len())
+        .ok()
+        .filter(|token_len| usize::from(*token_len) <= MAX_TOKEN_LEN)
+        .ok_or(())?;
+
+    let mut header = heapless::Vec::new();
+    if let Some(len) = u8::try_from(len).ok().filter(|len| *len < 13) {
+        header.push((len << 4) | token_len).map_err(|_| ())?;
+    } else if let Ok(extended) = u8::try_from(len - 13) {
+        header.push((13 << 4) | token_len).map_err(|_| ())?;
+        header.push(extended).map_err(|_| ())?;
+    } else {
+        let extended = u16::try_from(len - 269).map_err(|_| ())?;
+        header.push((14 << 4) | token_len).map_err(|_| ())?;
+        header.extend_from_slice(&extended.to_be_bytes())?;
+    }
+    header.push(code).map_err(|_| ())?;
+    header.extend_from_slice(token)?;
+    Ok(header)
+}
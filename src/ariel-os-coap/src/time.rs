@@ -0,0 +1,89 @@
+//! Wall clock used by the CoAP server to evaluate the time constraints of authorization tokens.
+//!
+//! The system has no notion of the current date by itself; the monotonic [`embassy_time`] clock
+//! only counts time since boot. This module keeps an anchor that relates that clock to Unix time,
+//! which can come from two kinds of sources:
+//!
+//! * A source trusted to know the precise time (SNTP, a battery backed RTC, …), reported through
+//!   [`set_wall_clock()`]. This provides both a lower and an upper bound on the current time.
+//! * Tokens issued by a trusted authorization server: Their issue time (`iat`) is in the past, so
+//!   the current time can not be earlier than that. This only ever raises the lower bound.
+//!
+//! Tokens are only rejected as expired when their expiry is before the lower bound; without any
+//! anchor, all tokens are considered current.
+
+use core::cell::Cell;
+
+use ariel_os_debug::log::debug;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::Instant;
+
+/// Offsets between the embassy-time clock and Unix time, in seconds.
+#[derive(Copy, Clone)]
+struct Anchor {
+    /// Earliest Unix time that could have been at boot.
+    lower: Option<u64>,
+    /// Latest Unix time that could have been at boot.
+    ///
+    /// Only ever set together with `lower`.
+    upper: Option<u64>,
+}
+
+static ANCHOR: Mutex<CriticalSectionRawMutex, Cell<Anchor>> = Mutex::new(Cell::new(Anchor {
+    lower: None,
+    upper: None,
+}));
+
+/// Seconds since boot, rounded down.
+fn uptime() -> u64 {
+    Instant::now().as_secs()
+}
+
+/// Sets the current wall clock time from a trusted source.
+///
+/// `unix_seconds` is the current time in seconds since the Unix epoch. This replaces any previous
+/// knowledge about the current time, including any obtained from tokens, so it should only be
+/// called with time obtained from a source that is trusted with authorization decisions.
+pub fn set_wall_clock(unix_seconds: u64) {
+    // The uptime is rounded down, so boot might have been up to a second earlier than calculated.
+    let at_boot = unix_seconds.saturating_sub(uptime());
+    debug!("Wall clock set to {} s since epoch", unix_seconds);
+    ANCHOR.lock(|anchor| {
+        anchor.set(Anchor {
+            lower: Some(at_boot.saturating_sub(1)),
+            upper: Some(at_boot),
+        });
+    });
+}
+
+/// Time provider backed by [`embassy_time`] and the global anchor.
+pub(crate) struct WallClock;
+
+impl coapcore::time::TimeProvider for WallClock {
+    fn now(&mut self) -> (u64, Option<u64>) {
+        let anchor = ANCHOR.lock(Cell::get);
+        let uptime = uptime();
+        (
+            anchor.lower.map_or(0, |lower| lower.saturating_add(uptime)),
+            anchor.upper.map(|upper| upper.saturating_add(uptime + 1)),
+        )
+    }
+
+    fn past_trusted(&mut self, timestamp: u64) {
+        let at_boot = timestamp.saturating_sub(uptime());
+        ANCHOR.lock(|cell| {
+            let mut anchor = cell.get();
+            if anchor.lower.is_some_and(|lower| lower >= at_boot) {
+                return;
+            }
+            anchor.lower = Some(at_boot);
+            if anchor.upper.is_some_and(|upper| upper < at_boot) {
+                // The token's issuer and the wall clock source disagree; as the token is what is
+                // being used, its time takes precedence, and nothing is known about the upper
+                // bound any more.
+                anchor.upper = None;
+            }
+            cell.set(anchor);
+        });
+    }
+}
@@ -129,10 +129,17 @@ impl<'a> UnconnectedUdp<'a> {
     }
 }
 
-impl nal::UnconnectedUdp for UnconnectedUdp<'_> {
-    type Error = Error;
-    async fn send(
-        &mut self,
+impl UnconnectedUdp<'_> {
+    /// Returns the port the socket is bound to.
+    pub fn port(&self) -> u16 {
+        self.socket.with(|s, _| s.endpoint().port)
+    }
+
+    /// Sends a datagram like [`nal::UnconnectedUdp::send`] does, but through a shared reference.
+    ///
+    /// This allows sending while another task is waiting for the socket to receive.
+    pub async fn send_shared(
+        &self,
         local: SocketAddr,
         remote: SocketAddr,
         buf: &[u8],
@@ -141,12 +148,14 @@ impl nal::UnconnectedUdp for UnconnectedUdp<'_> {
         // information, so the underlying layers won't even have a *chance* to care if we don't
         // check here.
         debug_assert!(
-            local.port() == 0 || local.port() == self.socket.with(|s, _| s.endpoint().port),
+            local.port() == 0 || local.port() == self.port(),
             "Port of local address, when given, must match bound port."
         );
 
         let remote_endpoint = udp::UdpMetadata {
-            local_address: if is_unspec_ip(local) {
+            // Responses to requests sent to a multicast group are sent from a unicast address
+            // (RFC7252 Section 8.1), which the stack picks like for an unspecified address.
+            local_address: if is_unspec_ip(local) || local.ip().is_multicast() {
                 None
             } else {
                 // A conversion of the addr part only might be cheaper, but would also mean we need
@@ -158,8 +167,11 @@ impl nal::UnconnectedUdp for UnconnectedUdp<'_> {
         poll_fn(move |cx| self.socket.poll_send_to(buf, remote_endpoint, cx)).await?;
         Ok(())
     }
-    async fn receive_into(
-        &mut self,
+
+    /// Receives a datagram like [`nal::UnconnectedUdp::receive_into`] does, but through a shared
+    /// reference.
+    pub async fn receive_into_shared(
+        &self,
         buf: &mut [u8],
     ) -> Result<(usize, SocketAddr, SocketAddr), Error> {
         // FIXME: The truncation is an issue -- we may need to change poll_recv_from to poll_recv
@@ -171,9 +183,27 @@ impl nal::UnconnectedUdp for UnconnectedUdp<'_> {
                 addr: metadata
                     .local_address
                     .expect("Local address is always populated on receive"),
-                port: self.socket.with(|s, _| s.endpoint().port),
+                port: self.port(),
             }),
             sockaddr_smol2nal(metadata.endpoint),
         ))
     }
 }
+
+impl nal::UnconnectedUdp for UnconnectedUdp<'_> {
+    type Error = Error;
+    async fn send(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        self.send_shared(local, remote, buf).await
+    }
+    async fn receive_into(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<(usize, SocketAddr, SocketAddr), Error> {
+        self.receive_into_shared(buf).await
+    }
+}
@@ -0,0 +1,50 @@
+//! Helpers shared by the resources of the CoAP server.
+
+use coap_message_utils::Error as CoAPError;
+
+/// Maps any error while building a response to an internal server error.
+pub(crate) fn internal<E>(_: E) -> CoAPError {
+    CoAPError::internal_server_error()
+}
+
+/// Rejects an option that a resource does not process, if it is critical.
+///
+/// Odd option numbers are critical (RFC7252 Section 5.4.6); elective options may be ignored.
+///
+/// # Errors
+///
+/// Produces a Bad Option error for critical options.
+pub(crate) fn reject_critical(number: u16) -> Result<(), CoAPError> {
+    if number & 1 == 1 {
+        Err(CoAPError::bad_option(number))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the `configured` name of the device, or one derived from its device ID (`ariel-`
+/// followed by the ID in hexadecimal digits, truncated to `N` bytes).
+///
+/// The length of a configured name is checked by the build script.
+#[cfg(any(feature = "coap-rd", feature = "coap-mdns"))]
+pub(crate) fn device_name<const N: usize>(configured: Option<&str>) -> heapless::String<N> {
+    use core::fmt::Write as _;
+
+    let mut name = heapless::String::new();
+    if let Some(configured) = configured {
+        let _ = name.push_str(configured);
+        return name;
+    }
+
+    let _ = name.push_str("ariel");
+    if let Ok(id) = ariel_os_identity::device_id_bytes() {
+        let _ = name.push('-');
+        for byte in id.as_ref() {
+            // Overly long IDs are truncated.
+            if write!(name, "{byte:02x}").is_err() {
+                break;
+            }
+        }
+    }
+    name
+}
@@ -0,0 +1,77 @@
+use std::{env, fmt::Write as _, path::PathBuf};
+
+/// Default time after which an unconfirmed update is rolled back, in seconds.
+const DEFAULT_CONFIRM_TIMEOUT_SECS: u64 = 300;
+
+fn main() {
+    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    let public_key = public_key_from_env();
+    let confirm_timeout =
+        env::var("CONFIG_OTA_CONFIRM_TIMEOUT").map_or(DEFAULT_CONFIRM_TIMEOUT_SECS, |timeout| {
+            timeout.trim().parse().unwrap_or_else(|_| {
+                panic!("invalid CONFIG_OTA_CONFIRM_TIMEOUT: `{timeout}` (expected seconds)")
+            })
+        });
+
+    let mut key_bytes = String::new();
+    for byte in &public_key {
+        write!(key_bytes, "{byte:#04x}, ").unwrap();
+    }
+    std::fs::write(
+        out.join("config.rs"),
+        format!(
+            "pub(super) const PUBLIC_KEY: &[u8] = &[{key_bytes}];\n\
+             pub(super) const CONFIRM_TIMEOUT_SECS: u64 = {confirm_timeout};\n"
+        ),
+    )
+    .unwrap();
+
+    println!("cargo:rerun-if-env-changed=CARGO_CFG_CONTEXT");
+    println!("cargo:rerun-if-env-changed=CONFIG_OTA_PUBLIC_KEY");
+    println!("cargo:rerun-if-env-changed=CONFIG_OTA_CONFIRM_TIMEOUT");
+}
+
+/// Parses the key update manifests are signed with from `CONFIG_OTA_PUBLIC_KEY`.
+///
+/// The variable holds the SEC1 encoded P-256 public key in hex, e.g., as printed by
+/// `openssl ec -pubout -outform DER | tail -c 65 | xxd -p -c 65`.
+fn public_key_from_env() -> Vec<u8> {
+    let Ok(key) = env::var("CONFIG_OTA_PUBLIC_KEY") else {
+        assert!(
+            !is_in_current_contexts(&["ariel-os"]),
+            "CONFIG_OTA_PUBLIC_KEY needs to be set to the key that update manifests are signed with"
+        );
+        // Platform-independent tooling does not verify anything.
+        return Vec::new();
+    };
+
+    let key = key.trim();
+    assert!(
+        key.len() % 2 == 0 && key.chars().all(|c| c.is_ascii_hexdigit()),
+        "CONFIG_OTA_PUBLIC_KEY needs to be hex encoded"
+    );
+    let key = key
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect::<Vec<_>>();
+    assert!(
+        matches!(
+            (key.len(), key.first()),
+            (65, Some(0x04)) | (33, Some(0x02 | 0x03))
+        ),
+        "CONFIG_OTA_PUBLIC_KEY needs to be a SEC1 encoded P-256 public key"
+    );
+    key
+}
+
+/// Returns whether any of the current `cfg` contexts is one of the given contexts.
+fn is_in_current_contexts(contexts: &[&str]) -> bool {
+    let Ok(context_var) = std::env::var("CARGO_CFG_CONTEXT") else {
+        return false;
+    };
+
+    // Contexts cannot include commas.
+    context_var.split(',').any(|c| contexts.contains(&c))
+}
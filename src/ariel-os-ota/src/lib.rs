@@ -0,0 +1,28 @@
+//! Provides firmware updates.
+//!
+//! An update consists of a signed [manifest](manifest::Manifest) and the firmware image it
+//! describes. The image is written into a second flash slot (the DFU slot) by an [`Updater`],
+//! checked against the manifest, and then swapped into place by the bootloader at the next boot.
+//!
+//! The new firmware needs to [`confirm()`] itself once it has checked that it works (e.g., once it
+//! has network connectivity); otherwise, the bootloader swaps the previous firmware back in at the
+//! next boot. To make sure that next boot happens, the system reboots by itself if the firmware
+//! is not confirmed within `CONFIG_OTA_CONFIRM_TIMEOUT` seconds (300 by default) after startup.
+//!
+//! This requires an [`embassy-boot`](embassy_boot) bootloader, and a memory layout that places
+//! the application into its active partition and provides the linker symbols
+//! `__bootloader_dfu_start`, `__bootloader_dfu_end`, `__bootloader_state_start` and
+//! `__bootloader_state_end`; the `coap-ota` laze module is only available where such a layout is
+//! provided (signalled by `has_bootloader`).
+
+#![cfg_attr(not(test), no_std)]
+#![deny(missing_docs)]
+
+pub mod manifest;
+#[cfg(not(test))]
+mod system;
+mod updater;
+
+#[cfg(not(test))]
+pub use system::*;
+pub use updater::{Error, Updater};
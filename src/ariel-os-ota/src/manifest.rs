@@ -0,0 +1,311 @@
+//! Signed update manifests.
+//!
+//! A manifest describes a firmware image by its digest and size, and carries a sequence number
+//! that needs to increase with every release, so that older (possibly vulnerable) images can not
+//! be installed again.
+//!
+//! Its format is modeled after [SUIT](https://datatracker.ietf.org/doc/draft-ietf-suit-manifest/),
+//! but reduced to what a single-image device needs: The manifest is a CBOR map, signed as the
+//! payload of a `COSE_Sign1` object ([RFC9052](https://www.rfc-editor.org/rfc/rfc9052)) with
+//! ES256:
+//!
+//! ```cddl
+//! manifest = {
+//!     1 => 1,                          ; manifest version
+//!     2 => uint,                       ; sequence number
+//!     3 => [-16, bstr .size 32],       ; SHA-256 digest of the image
+//!     14 => uint,                      ; image size in bytes
+//! }
+//! ```
+
+use minicbor::{Decoder, data::Type, encode::write::Cursor};
+use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier as _};
+
+/// Maximum length of the encoded manifest (the `COSE_Sign1` payload).
+pub const MAX_MANIFEST_LEN: usize = 128;
+
+/// Maximum length of the encoded protected header of the `COSE_Sign1` object.
+const MAX_PROTECTED_LEN: usize = 16;
+
+/// COSE algorithm identifier of ES256.
+const COSE_ALG_ES256: i64 = -7;
+/// COSE algorithm identifier of SHA-256.
+const COSE_ALG_SHA256: i64 = -16;
+/// COSE header parameter holding the algorithm.
+const COSE_HEADER_ALG: u64 = 1;
+/// CBOR tag of a `COSE_Sign1` object.
+const COSE_SIGN1_TAG: u64 = 18;
+
+const KEY_VERSION: u64 = 1;
+const KEY_SEQUENCE_NUMBER: u64 = 2;
+const KEY_IMAGE_DIGEST: u64 = 3;
+const KEY_IMAGE_SIZE: u64 = 14;
+
+/// Error verifying a manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestError {
+    /// The envelope or manifest is not well-formed, or lacks mandatory entries.
+    Malformed,
+    /// The manifest uses a version or algorithm that is not supported.
+    Unsupported,
+    /// The signature does not match the trusted key.
+    BadSignature,
+}
+
+impl From<minicbor::decode::Error> for ManifestError {
+    fn from(_: minicbor::decode::Error) -> Self {
+        Self::Malformed
+    }
+}
+
+/// A verified update manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Manifest {
+    /// Sequence number of the release.
+    pub sequence_number: u64,
+    /// Size of the image in bytes.
+    pub image_size: u32,
+    /// SHA-256 digest of the image.
+    pub image_digest: [u8; 32],
+}
+
+impl Manifest {
+    /// Checks the signature of a `COSE_Sign1` envelope against `key`, and parses the manifest it
+    /// carries.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the envelope is not signed by `key`, or if the manifest can not be used.
+    pub fn verify(envelope: &[u8], key: &VerifyingKey) -> Result<Self, ManifestError> {
+        let mut decoder = Decoder::new(envelope);
+        if decoder.datatype()? == Type::Tag && decoder.tag()?.as_u64() != COSE_SIGN1_TAG {
+            return Err(ManifestError::Malformed);
+        }
+        if decoder.array()? != Some(4) {
+            return Err(ManifestError::Malformed);
+        }
+        let protected = decoder.bytes()?;
+        // Unprotected headers are not used.
+        decoder.skip()?;
+        let payload = decoder.bytes()?;
+        let signature = decoder.bytes()?;
+        if decoder.position() != envelope.len() {
+            return Err(ManifestError::Malformed);
+        }
+
+        check_protected(protected)?;
+        if protected.len() > MAX_PROTECTED_LEN || payload.len() > MAX_MANIFEST_LEN {
+            return Err(ManifestError::Unsupported);
+        }
+
+        let mut buffer = [0u8; 32 + MAX_PROTECTED_LEN + MAX_MANIFEST_LEN];
+        let to_be_signed = sig_structure(protected, payload, &mut buffer)?;
+        let signature =
+            Signature::from_slice(signature).map_err(|_| ManifestError::BadSignature)?;
+        key.verify(to_be_signed, &signature)
+            .map_err(|_| ManifestError::BadSignature)?;
+
+        // Only look into the manifest once it is known to be authentic.
+        Self::parse(payload)
+    }
+
+    /// Parses the (already authenticated) manifest.
+    fn parse(manifest: &[u8]) -> Result<Self, ManifestError> {
+        let mut decoder = Decoder::new(manifest);
+        let Some(entries) = decoder.map()? else {
+            return Err(ManifestError::Malformed);
+        };
+
+        let mut version = None;
+        let mut sequence_number = None;
+        let mut image_size = None;
+        let mut image_digest = None;
+        for _ in 0..entries {
+            match decoder.u64()? {
+                KEY_VERSION => version = Some(decoder.u64()?),
+                KEY_SEQUENCE_NUMBER => sequence_number = Some(decoder.u64()?),
+                KEY_IMAGE_SIZE => image_size = Some(decoder.u32()?),
+                KEY_IMAGE_DIGEST => {
+                    if decoder.array()? != Some(2) {
+                        return Err(ManifestError::Malformed);
+                    }
+                    if decoder.i64()? != COSE_ALG_SHA256 {
+                        return Err(ManifestError::Unsupported);
+                    }
+                    image_digest = Some(
+                        <[u8; 32]>::try_from(decoder.bytes()?)
+                            .map_err(|_| ManifestError::Malformed)?,
+                    );
+                }
+                // Unknown entries might change the meaning of the manifest, so they can not just
+                // be ignored.
+                _ => return Err(ManifestError::Unsupported),
+            }
+        }
+        if decoder.position() != manifest.len() {
+            return Err(ManifestError::Malformed);
+        }
+
+        if version.ok_or(ManifestError::Malformed)? != 1 {
+            return Err(ManifestError::Unsupported);
+        }
+        Ok(Self {
+            sequence_number: sequence_number.ok_or(ManifestError::Malformed)?,
+            image_size: image_size.ok_or(ManifestError::Malformed)?,
+            image_digest: image_digest.ok_or(ManifestError::Malformed)?,
+        })
+    }
+}
+
+/// Checks that the protected header selects ES256, and nothing else.
+fn check_protected(protected: &[u8]) -> Result<(), ManifestError> {
+    let mut decoder = Decoder::new(protected);
+    if decoder.map()? != Some(1) || decoder.u64()? != COSE_HEADER_ALG {
+        return Err(ManifestError::Unsupported);
+    }
+    if decoder.i64()? != COSE_ALG_ES256 || decoder.position() != protected.len() {
+        return Err(ManifestError::Unsupported);
+    }
+    Ok(())
+}
+
+/// Builds the `Sig_structure` of a `COSE_Sign1` object without external AAD.
+fn sig_structure<'b>(
+    protected: &[u8],
+    payload: &[u8],
+    buffer: &'b mut [u8],
+) -> Result<&'b [u8], ManifestError> {
+    let mut encoder = minicbor::Encoder::new(Cursor::new(buffer));
+    encoder
+        .array(4)
+        .and_then(|e| e.str("Signature1"))
+        .and_then(|e| e.bytes(protected))
+        .and_then(|e| e.bytes(&[]))
+        .and_then(|e| e.bytes(payload))
+        .map_err(|_| ManifestError::Unsupported)?;
+    let cursor = encoder.into_writer();
+    let len = cursor.position();
+    cursor
+        .into_inner()
+        .get(..len)
+        .ok_or(ManifestError::Unsupported)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use p256::ecdsa::{SigningKey, signature::Signer as _};
+
+    use super::*;
+
+    pub(crate) fn signing_key() -> SigningKey {
+        SigningKey::from_slice(&[0x17; 32]).unwrap()
+    }
+
+    /// Encodes a manifest with the given entries (as raw CBOR map contents).
+    pub(crate) fn manifest(entries: usize, body: &[u8]) -> Vec<u8> {
+        let mut manifest = vec![0xa0 | u8::try_from(entries).unwrap()];
+        manifest.extend_from_slice(body);
+        manifest
+    }
+
+    pub(crate) fn valid_manifest(sequence_number: u8, digest: &[u8; 32], size: u16) -> Vec<u8> {
+        let mut body = vec![
+            0x01,
+            0x01,
+            0x02,
+            sequence_number,
+            0x03,
+            0x82,
+            0x2f,
+            0x58,
+            0x20,
+        ];
+        body.extend_from_slice(digest);
+        body.extend_from_slice(&[0x0e, 0x19]);
+        body.extend_from_slice(&size.to_be_bytes());
+        manifest(4, &body)
+    }
+
+    /// Wraps `payload` in a `COSE_Sign1` object signed by `key`.
+    pub(crate) fn sign(payload: &[u8], key: &SigningKey) -> Vec<u8> {
+        let protected = [0xa1, 0x01, 0x26];
+        let mut buffer = [0u8; 256];
+        let to_be_signed = sig_structure(&protected, payload, &mut buffer).unwrap();
+        let signature: Signature = key.sign(to_be_signed);
+
+        let mut envelope = vec![0xd2, 0x84, 0x43];
+        envelope.extend_from_slice(&protected);
+        envelope.push(0xa0);
+        envelope.push(0x58);
+        envelope.push(u8::try_from(payload.len()).unwrap());
+        envelope.extend_from_slice(payload);
+        envelope.extend_from_slice(&[0x58, 0x40]);
+        envelope.extend_from_slice(&signature.to_bytes());
+        envelope
+    }
+
+    #[test]
+    fn accepts_signed_manifest() {
+        let key = signing_key();
+        let envelope = sign(&valid_manifest(5, &[0xaa; 32], 0x1234), &key);
+
+        let manifest = Manifest::verify(&envelope, key.verifying_key()).unwrap();
+        assert_eq!(
+            manifest,
+            Manifest {
+                sequence_number: 5,
+                image_size: 0x1234,
+                image_digest: [0xaa; 32],
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_other_key() {
+        let envelope = sign(&valid_manifest(5, &[0xaa; 32], 16), &signing_key());
+        let other = SigningKey::from_slice(&[0x42; 32]).unwrap();
+
+        assert_eq!(
+            Manifest::verify(&envelope, other.verifying_key()),
+            Err(ManifestError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_manifest() {
+        let key = signing_key();
+        let mut envelope = sign(&valid_manifest(5, &[0xaa; 32], 16), &key);
+        // Flip a bit in the digest.
+        *envelope.get_mut(30).unwrap() ^= 1;
+
+        assert_eq!(
+            Manifest::verify(&envelope, key.verifying_key()),
+            Err(ManifestError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_entries() {
+        let key = signing_key();
+        let mut body = valid_manifest(5, &[0xaa; 32], 16);
+        *body.first_mut().unwrap() += 1;
+        body.extend_from_slice(&[0x18, 0x63, 0x00]);
+        let envelope = sign(&body, &key);
+
+        assert_eq!(
+            Manifest::verify(&envelope, key.verifying_key()),
+            Err(ManifestError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn rejects_incomplete_manifest() {
+        let key = signing_key();
+        let envelope = sign(&manifest(2, &[0x01, 0x01, 0x02, 0x05]), &key);
+
+        assert_eq!(
+            Manifest::verify(&envelope, key.verifying_key()),
+            Err(ManifestError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! The system's update slot and state, as laid out for the bootloader.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use ariel_os_debug::log::{error, info, warn};
+use ariel_os_storage::Flash;
+use embassy_embedded_hal::flash::partition::Partition;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    mutex::{Mutex, MutexGuard},
+    once_lock::OnceLock,
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use embedded_storage_async::nor_flash::{NorFlash as _, ReadNorFlash as _};
+use p256::ecdsa::VerifyingKey;
+use static_cell::ConstStaticCell;
+
+use crate::{Error, Updater};
+
+mod config {
+    include!(concat!(env!("OUT_DIR"), "/config.rs"));
+}
+
+/// A partition of the internal flash.
+pub type FlashPartition = Partition<'static, CriticalSectionRawMutex, Flash>;
+
+/// The updater of the system firmware.
+pub type SystemUpdater = Updater<'static, FlashPartition, FlashPartition>;
+
+/// Storage key of the sequence number of the running firmware.
+const SEQUENCE_NUMBER_KEY: &str = "ariel-os-ota.sequence";
+/// Storage key of the sequence number of an installed but not yet confirmed update.
+const PENDING_SEQUENCE_NUMBER_KEY: &str = "ariel-os-ota.pending";
+
+const ALIGNED_LEN: usize = if Flash::WRITE_SIZE > Flash::READ_SIZE {
+    Flash::WRITE_SIZE
+} else {
+    Flash::READ_SIZE
+};
+
+static UPDATER: OnceLock<Mutex<CriticalSectionRawMutex, SystemUpdater>> = OnceLock::new();
+
+static CONFIRMED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Set once an update was installed since startup.
+///
+/// The bootloader's state then reads as unconfirmed until the next boot, just as after booting
+/// into an update.
+static UPDATE_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Gets a partition from linker symbols.
+macro_rules! partition_from_linker {
+    ($flash:expr, $start:ident, $end:ident) => {{
+        unsafe extern "C" {
+            static $start: u32;
+            static $end: u32;
+        }
+
+        let range = ariel_os_storage::flash_range(&raw const $start, &raw const $end);
+        Partition::new($flash, range.start, range.end - range.start)
+    }};
+}
+
+/// Returns the key manifests need to be signed with, as configured through
+/// `CONFIG_OTA_PUBLIC_KEY`.
+///
+/// # Panics
+///
+/// Panics if the configured key is not a valid P-256 public key.
+pub fn trusted_key() -> VerifyingKey {
+    VerifyingKey::from_sec1_bytes(config::PUBLIC_KEY)
+        .expect("CONFIG_OTA_PUBLIC_KEY is not a valid P-256 public key")
+}
+
+/// Gets a [`MutexGuard`] of the system's [`Updater`].
+///
+/// Note: don't forget to drop the mutex guard returned by this.
+pub async fn lock() -> MutexGuard<'static, CriticalSectionRawMutex, SystemUpdater> {
+    static ALIGNED: ConstStaticCell<[u8; ALIGNED_LEN]> = ConstStaticCell::new([0; ALIGNED_LEN]);
+
+    if let Some(updater) = UPDATER.try_get() {
+        return updater.lock().await;
+    }
+
+    let flash = ariel_os_storage::flash().await;
+    UPDATER
+        .get_or_init(|| {
+            let dfu = partition_from_linker!(flash, __bootloader_dfu_start, __bootloader_dfu_end);
+            let state =
+                partition_from_linker!(flash, __bootloader_state_start, __bootloader_state_end);
+            Mutex::new(Updater::new(dfu, state, ALIGNED.take()))
+        })
+        .lock()
+        .await
+}
+
+/// Returns the sequence number of the running firmware.
+///
+/// This is 0 until the first update was confirmed.
+pub async fn sequence_number() -> u64 {
+    ariel_os_storage::get(SEQUENCE_NUMBER_KEY)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Checks the image written to the system's [`Updater`], and marks it for installation at the
+/// next boot.
+///
+/// See [`Updater::finish()`].
+pub async fn finish() -> Result<(), Error> {
+    let mut updater = lock().await;
+    let sequence_number = updater.manifest().ok_or(Error::NoManifest)?.sequence_number;
+    // Stored before the update is handed to the bootloader, so that the update can not be
+    // installed without it.
+    ariel_os_storage::insert(PENDING_SEQUENCE_NUMBER_KEY, sequence_number)
+        .await
+        .map_err(|_| Error::Storage)?;
+    if let Err(e) = updater.finish().await {
+        // Best effort; a stale value is replaced by the next update anyway.
+        let _ = ariel_os_storage::remove(PENDING_SEQUENCE_NUMBER_KEY).await;
+        return Err(e);
+    }
+    UPDATE_PENDING.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns whether the running firmware is confirmed.
+///
+/// See [`Updater::is_confirmed()`].
+pub async fn is_confirmed() -> Result<bool, Error> {
+    lock().await.is_confirmed().await
+}
+
+/// Confirms the running firmware after an update, so that the bootloader keeps it.
+///
+/// This should be called once the firmware has checked that it works as intended; calling it
+/// when there was no update does no harm.
+///
+/// # Errors
+///
+/// Fails with [`Error::UpdatePending`] after an update was installed by [`finish()`] since
+/// startup, as confirming then would cancel that update.
+pub async fn confirm() -> Result<(), Error> {
+    if UPDATE_PENDING.load(Ordering::Relaxed) {
+        warn!("ota: not confirming, an update is pending");
+        return Err(Error::UpdatePending);
+    }
+    let mut updater = lock().await;
+    if updater.is_confirmed().await? {
+        return Ok(());
+    }
+    updater.confirm().await?;
+    drop(updater);
+
+    if let Ok(Some(sequence_number)) =
+        ariel_os_storage::get::<u64>(PENDING_SEQUENCE_NUMBER_KEY).await
+    {
+        ariel_os_storage::insert(SEQUENCE_NUMBER_KEY, sequence_number)
+            .await
+            .map_err(|_| Error::Storage)?;
+        ariel_os_storage::remove(PENDING_SEQUENCE_NUMBER_KEY)
+            .await
+            .map_err(|_| Error::Storage)?;
+    }
+
+    info!("ota: firmware confirmed");
+    CONFIRMED.signal(());
+    Ok(())
+}
+
+/// Reboots into the previous firmware if the running firmware was installed by an update and
+/// does not [`confirm()`] itself within `CONFIG_OTA_CONFIRM_TIMEOUT` seconds.
+///
+/// This returns once the firmware is confirmed; it needs to be run by the system component
+/// that installs updates.
+pub async fn roll_back_unless_confirmed() {
+    match is_confirmed().await {
+        Ok(true) => return,
+        Ok(false) => (),
+        Err(_) => {
+            error!("ota: could not read the update state");
+            return;
+        }
+    }
+
+    warn!(
+        "ota: running an unconfirmed update, rolling back in {} s unless confirmed",
+        config::CONFIRM_TIMEOUT_SECS
+    );
+    let timeout = Timer::after(Duration::from_secs(config::CONFIRM_TIMEOUT_SECS));
+    if let embassy_futures::select::Either::Second(()) =
+        embassy_futures::select::select(CONFIRMED.wait(), timeout).await
+    {
+        warn!("ota: update was not confirmed, rebooting into the previous firmware");
+        ariel_os_power::reboot();
+    }
+}
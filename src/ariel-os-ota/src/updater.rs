@@ -0,0 +1,389 @@
+//! Writing an image into the update slot, and handing it over to the bootloader.
+
+use embassy_boot::{FirmwareState, FirmwareUpdaterError, State};
+use embedded_storage_async::nor_flash::{NorFlash, NorFlashErrorKind};
+use sha2::{Digest as _, Sha256};
+
+use crate::manifest::Manifest;
+
+/// Size of the chunks in which the image is written to and read from the slot.
+///
+/// This needs to be a multiple of the write and read sizes of the flash.
+const CHUNK_SIZE: usize = 256;
+
+/// Error while installing an update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Accessing the flash failed.
+    Flash(NorFlashErrorKind),
+    /// No manifest was set up through [`Updater::begin()`].
+    NoManifest,
+    /// The image does not fit into the update slot, or is larger than announced in the manifest.
+    TooLarge,
+    /// The image data did not arrive in sequence, or is incomplete.
+    OutOfSequence,
+    /// The image does not match the digest in the manifest.
+    DigestMismatch,
+    /// The state partition is in an unexpected state.
+    BadState,
+    /// Recording the sequence number of the update in storage failed.
+    Storage,
+    /// An update was installed, and the running firmware can not be confirmed until the system
+    /// rebooted into it.
+    UpdatePending,
+}
+
+impl From<FirmwareUpdaterError> for Error {
+    fn from(error: FirmwareUpdaterError) -> Self {
+        match error {
+            FirmwareUpdaterError::Flash(kind) => Self::Flash(kind),
+            _ => Self::BadState,
+        }
+    }
+}
+
+fn flash_error<E: embedded_storage_async::nor_flash::NorFlashError>(error: E) -> Error {
+    Error::Flash(error.kind())
+}
+
+/// Writes an image to the update (DFU) slot, and marks it for installation by the bootloader.
+///
+/// The layout of the slot and state partitions follows the conventions of [`embassy_boot`]:
+/// After [`finish()`](Self::finish) succeeded, the bootloader swaps the image into place at the
+/// next boot. Unless the new firmware [confirms](Self::confirm) itself, the bootloader swaps the
+/// previous image back in at the boot after that.
+pub struct Updater<'d, DFU: NorFlash, STATE: NorFlash> {
+    dfu: DFU,
+    state: FirmwareState<'d, STATE>,
+    manifest: Option<Manifest>,
+    /// Number of image bytes written to the slot.
+    written: u32,
+    /// End of the erased part of the slot.
+    erased: u32,
+    /// Image bytes received after the written ones.
+    chunk: heapless::Vec<u8, CHUNK_SIZE>,
+}
+
+impl<'d, DFU: NorFlash, STATE: NorFlash> Updater<'d, DFU, STATE> {
+    /// Creates an updater writing to the `dfu` slot and the `state` partition.
+    ///
+    /// `aligned` is a scratch buffer for the state partition, and needs to be as large as the
+    /// larger of its read and write sizes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the write or read size of the `dfu` flash does not evenly divide 256 bytes.
+    pub fn new(dfu: DFU, state: STATE, aligned: &'d mut [u8]) -> Self {
+        assert!(CHUNK_SIZE % DFU::WRITE_SIZE == 0 && CHUNK_SIZE % DFU::READ_SIZE == 0);
+        Self {
+            dfu,
+            state: FirmwareState::new(state, aligned),
+            manifest: None,
+            written: 0,
+            erased: 0,
+            chunk: heapless::Vec::new(),
+        }
+    }
+
+    /// Starts receiving the image described by `manifest`, abandoning any image received before.
+    ///
+    /// The manifest's signature needs to be verified already, and its sequence number checked.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the image does not fit into the slot.
+    pub fn begin(&mut self, manifest: Manifest) -> Result<(), Error> {
+        self.manifest = None;
+        if usize::try_from(manifest.image_size)
+            .ok()
+            .is_none_or(|size| size > self.dfu.capacity())
+        {
+            return Err(Error::TooLarge);
+        }
+        self.manifest = Some(manifest);
+        self.restart();
+        Ok(())
+    }
+
+    /// Returns the manifest of the image being received, if any.
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Writes `data` at `offset` of the image.
+    ///
+    /// Data needs to be written in sequence; writing at offset 0 again restarts the image.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no image is expected, if the data is not in sequence or exceeds the announced
+    /// image size, or if writing to flash fails.
+    pub async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error> {
+        let manifest = self.manifest.ok_or(Error::NoManifest)?;
+        if offset == 0 {
+            self.restart();
+        }
+        if offset != self.received() {
+            return Err(Error::OutOfSequence);
+        }
+        let end = u32::try_from(data.len())
+            .ok()
+            .and_then(|len| offset.checked_add(len))
+            .filter(|end| *end <= manifest.image_size)
+            .ok_or(Error::TooLarge)?;
+
+        let mut data = data;
+        while !data.is_empty() {
+            let take = data.len().min(CHUNK_SIZE - self.chunk.len());
+            let (head, tail) = data.split_at(take);
+            self.chunk
+                .extend_from_slice(head)
+                .expect("limited to the remaining capacity");
+            data = tail;
+            if self.chunk.is_full() {
+                self.flush_chunk().await?;
+            }
+        }
+        debug_assert_eq!(self.received(), end);
+        Ok(())
+    }
+
+    /// Checks the complete image against the manifest, and marks it for installation at the next
+    /// boot.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the image is incomplete or does not match the manifest's digest, or if accessing
+    /// the flash fails. The image needs to be sent again after any error.
+    pub async fn finish(&mut self) -> Result<(), Error> {
+        let manifest = self.manifest.take().ok_or(Error::NoManifest)?;
+        if self.received() != manifest.image_size {
+            return Err(Error::OutOfSequence);
+        }
+        if !self.chunk.is_empty() {
+            // Pad the last chunk to the write size with what erased flash reads as anyway.
+            while self.chunk.len() % DFU::WRITE_SIZE != 0 {
+                self.chunk
+                    .push(0xff)
+                    .expect("chunk size is a multiple of the write size");
+            }
+            self.flush_chunk().await?;
+        }
+
+        if self.digest(manifest.image_size).await? != manifest.image_digest {
+            return Err(Error::DigestMismatch);
+        }
+
+        self.state.mark_updated().await?;
+        Ok(())
+    }
+
+    /// Returns whether the running firmware is confirmed, i.e., whether it will keep running
+    /// after the next reboot.
+    ///
+    /// This is false after an update was installed but not confirmed yet (and also between
+    /// [`finish()`](Self::finish) and the next reboot).
+    ///
+    /// # Errors
+    ///
+    /// Fails if the state partition can not be read.
+    pub async fn is_confirmed(&mut self) -> Result<bool, Error> {
+        Ok(self.state.get_state().await? != State::Swap)
+    }
+
+    /// Confirms the running firmware, so that the bootloader keeps it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the state partition can not be written.
+    pub async fn confirm(&mut self) -> Result<(), Error> {
+        self.state.mark_booted().await?;
+        Ok(())
+    }
+
+    /// Number of image bytes received so far.
+    fn received(&self) -> u32 {
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "the chunk is limited to CHUNK_SIZE"
+        )]
+        let buffered = self.chunk.len() as u32;
+        self.written + buffered
+    }
+
+    /// Forgets any data received, so that the image can be written from the start.
+    fn restart(&mut self) {
+        self.written = 0;
+        // Pages written to need to be erased again.
+        self.erased = 0;
+        self.chunk.clear();
+    }
+
+    /// Writes the buffered chunk to the slot, erasing pages as needed.
+    async fn flush_chunk(&mut self) -> Result<(), Error> {
+        let start = self.written;
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "the chunk is limited to CHUNK_SIZE"
+        )]
+        let end = start + self.chunk.len() as u32;
+
+        #[expect(clippy::cast_possible_truncation, reason = "erase sizes are small")]
+        let erase_size = DFU::ERASE_SIZE as u32;
+        while self.erased < end {
+            self.dfu
+                .erase(self.erased, self.erased + erase_size)
+                .await
+                .map_err(flash_error)?;
+            self.erased += erase_size;
+        }
+
+        self.dfu
+            .write(start, &self.chunk)
+            .await
+            .map_err(flash_error)?;
+        self.written = end;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Calculates the SHA-256 digest of the first `len` bytes of the slot.
+    async fn digest(&mut self, len: u32) -> Result<[u8; 32], Error> {
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < len {
+            self.dfu
+                .read(offset, &mut buffer)
+                .await
+                .map_err(flash_error)?;
+            #[expect(clippy::cast_possible_truncation, reason = "CHUNK_SIZE fits in an u32")]
+            let take = (len - offset).min(CHUNK_SIZE as u32);
+            hasher.update(buffer.get(..take as usize).expect("at most CHUNK_SIZE"));
+            offset += take;
+        }
+        Ok(hasher.finalize().into())
+    }
+}
+
+#[cfg(test)]
+#[allow(
+    clippy::indexing_slicing,
+    reason = "out of bounds accesses fail the test"
+)]
+mod tests {
+    use ariel_os_storage::mock_flash::MockFlash;
+    use embassy_futures::block_on;
+    use sha2::{Digest as _, Sha256};
+
+    use super::{Error, Updater};
+    use crate::manifest::Manifest;
+
+    type TestFlash = MockFlash<1024, 4>;
+
+    /// Number of pages of the update slot.
+    const SLOT_PAGES: usize = 4;
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| u8::try_from(i * 7 % 251).unwrap())
+            .collect()
+    }
+
+    fn manifest_for(image: &[u8]) -> Manifest {
+        Manifest {
+            sequence_number: 1,
+            image_size: u32::try_from(image.len()).unwrap(),
+            image_digest: Sha256::digest(image).into(),
+        }
+    }
+
+    #[test]
+    fn installs_image() {
+        let mut dfu = TestFlash::new(SLOT_PAGES);
+        let mut state = TestFlash::new(1);
+        let image = image(2500);
+        block_on(async {
+            let mut aligned = [0; 4];
+            let mut updater = Updater::new(&mut dfu, &mut state, &mut aligned);
+            assert!(updater.is_confirmed().await.unwrap());
+
+            updater.begin(manifest_for(&image)).unwrap();
+            // Blocks that do not line up with the chunks or pages.
+            for (index, block) in image.chunks(100).enumerate() {
+                let offset = u32::try_from(index * 100).unwrap();
+                updater.write(offset, block).await.unwrap();
+            }
+            updater.finish().await.unwrap();
+
+            // Until the bootloader swapped in the image and the image confirmed itself.
+            assert!(!updater.is_confirmed().await.unwrap());
+            updater.confirm().await.unwrap();
+            assert!(updater.is_confirmed().await.unwrap());
+        });
+        assert_eq!(dfu.as_bytes().get(..image.len()).unwrap(), &image[..]);
+    }
+
+    #[test]
+    fn rejects_wrong_digest() {
+        let mut dfu = TestFlash::new(SLOT_PAGES);
+        let mut state = TestFlash::new(1);
+        let image = image(300);
+        block_on(async {
+            let mut aligned = [0; 4];
+            let mut updater = Updater::new(&mut dfu, &mut state, &mut aligned);
+
+            updater.begin(manifest_for(&image)).unwrap();
+            let mut tampered = image.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            updater.write(0, &tampered).await.unwrap();
+            assert_eq!(updater.finish().await, Err(Error::DigestMismatch));
+            assert!(updater.is_confirmed().await.unwrap());
+        });
+    }
+
+    #[test]
+    fn enforces_sequence_and_size() {
+        let mut dfu = TestFlash::new(SLOT_PAGES);
+        let mut state = TestFlash::new(1);
+        let image = image(600);
+        block_on(async {
+            let mut aligned = [0; 4];
+            let mut updater = Updater::new(&mut dfu, &mut state, &mut aligned);
+            assert_eq!(updater.write(0, &image).await, Err(Error::NoManifest));
+
+            let mut too_large = manifest_for(&image);
+            too_large.image_size = u32::try_from(SLOT_PAGES * 1024 + 1).unwrap();
+            assert_eq!(updater.begin(too_large), Err(Error::TooLarge));
+
+            updater.begin(manifest_for(&image)).unwrap();
+            updater.write(0, &image[..200]).await.unwrap();
+            assert_eq!(
+                updater.write(300, &image[300..]).await,
+                Err(Error::OutOfSequence)
+            );
+            assert_eq!(updater.write(200, &[0; 401]).await, Err(Error::TooLarge));
+
+            // Starting over at offset 0 rewrites the slot.
+            updater.write(0, &image[..400]).await.unwrap();
+            updater.write(400, &image[400..]).await.unwrap();
+            updater.finish().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn incomplete_image_is_not_installed() {
+        let mut dfu = TestFlash::new(SLOT_PAGES);
+        let mut state = TestFlash::new(1);
+        let image = image(600);
+        block_on(async {
+            let mut aligned = [0; 4];
+            let mut updater = Updater::new(&mut dfu, &mut state, &mut aligned);
+
+            updater.begin(manifest_for(&image)).unwrap();
+            updater.write(0, &image[..512]).await.unwrap();
+            assert_eq!(updater.finish().await, Err(Error::OutOfSequence));
+            assert!(updater.is_confirmed().await.unwrap());
+        });
+    }
+}
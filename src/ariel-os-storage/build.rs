@@ -27,17 +27,106 @@ fn main() {
     // Put the linker script somewhere the linker can find it
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
 
+    let partitions = partitions_from_env();
+
+    let mut partition_sections = String::new();
+    for (name, pages) in &partitions {
+        let size = pages * flash_page_size;
+        partition_sections.push_str(&format!(
+            "    .storage_{name} ALIGN({flash_page_size}) (NOLOAD): {{\n        \
+             __storage_{name}_start = .;\n        \
+             . += {size};\n        \
+             __storage_{name}_end = .;\n    \
+             }} > FLASH\n"
+        ));
+    }
+
     let mut storage_template = std::fs::read_to_string("storage.ld.in").unwrap();
     storage_template = storage_template.replace("${ALIGNMENT}", &format!("{flash_page_size}"));
     storage_template = storage_template.replace("${SIZE}", &format!("{storage_size_total}"));
+    storage_template = storage_template.replace("${PARTITIONS}", &partition_sections);
 
     std::fs::write(out.join("storage.x"), &storage_template).unwrap();
 
+    let partition_list = partitions
+        .iter()
+        .map(|(name, _)| format!("{name}(__storage_{name}_start, __storage_{name}_end)"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    std::fs::write(
+        out.join("partitions.rs"),
+        format!("define_partitions!({partition_list});\n"),
+    )
+    .unwrap();
+
     println!("cargo:rerun-if-env-changed=CARGO_CFG_CONTEXT");
+    println!("cargo:rerun-if-env-changed=CONFIG_STORAGE_PARTITIONS");
     println!("cargo:rerun-if-changed=storage.ld.in");
     println!("cargo:rustc-link-search={}", out.display());
 }
 
+/// Strict and reserved keywords of Rust (2024 edition) that are lowercase identifiers.
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "static", "struct", "super", "trait", "true", "try", "type", "typeof",
+    "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// Parses the named storage partitions from `CONFIG_STORAGE_PARTITIONS`.
+///
+/// The variable holds a comma-separated list of `name:pages` entries, e.g.,
+/// `config:2,credentials:2,logs:4`; the page count defaults to 2 when omitted.
+fn partitions_from_env() -> Vec<(String, u32)> {
+    let Ok(partitions) = std::env::var("CONFIG_STORAGE_PARTITIONS") else {
+        return Vec::new();
+    };
+
+    let mut parsed: Vec<(String, u32)> = Vec::new();
+    for entry in partitions
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+    {
+        let (name, pages) = match entry.split_once(':') {
+            Some((name, pages)) => (
+                name.trim(),
+                pages.trim().parse().unwrap_or_else(|_| {
+                    panic!("invalid page count for storage partition `{name}`: `{pages}`")
+                }),
+            ),
+            None => (entry, 2),
+        };
+
+        assert!(
+            name.starts_with(|c: char| c.is_ascii_lowercase())
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'),
+            "invalid storage partition name `{name}`: must be a lowercase identifier"
+        );
+        // Partitions become modules, so their names can not be keywords.
+        assert!(
+            !RUST_KEYWORDS.contains(&name),
+            "invalid storage partition name `{name}`: must not be a Rust keyword"
+        );
+        assert!(
+            !parsed.iter().any(|(n, _)| n == name),
+            "storage partition `{name}` declared twice"
+        );
+        // `sequential-storage` needs at least two flash pages.
+        assert!(
+            pages >= 2,
+            "storage partition `{name}` needs at least two pages"
+        );
+
+        parsed.push((name.to_owned(), pages));
+    }
+
+    parsed
+}
+
 /// Returns whether any of the current `cfg` contexts is one of the given contexts.
 fn is_in_current_contexts(contexts: &[&str]) -> bool {
     let Ok(context_var) = std::env::var("CARGO_CFG_CONTEXT") else {
@@ -3,19 +3,25 @@
 //! Currently the same type used for serializing must be used for deserializing.
 //! While not doing so won't cause unsafety, it might return garbage data, or panic.
 
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "mock-flash")), no_std)]
 #![deny(missing_docs)]
 // TODO: overhaul errors
 #![expect(clippy::missing_errors_doc)]
 
 mod postcard_value;
+mod shared_flash;
 mod storage;
+mod transaction;
+mod wear;
+
+#[cfg(any(test, feature = "mock-flash"))]
+pub mod mock_flash;
 
 use core::ops::Range;
 
 use ariel_os_hal::{
     OptionalPeripherals,
-    storage::{Flash, FlashError, init as flash_init},
+    storage::{FlashError, init as flash_init},
 };
 use embassy_sync::{
     blocking_mutex::raw::CriticalSectionRawMutex,
@@ -23,20 +29,29 @@ use embassy_sync::{
     once_lock::OnceLock,
 };
 
+#[doc(hidden)]
+pub use ariel_os_hal::storage::Flash;
+pub use shared_flash::SharedFlash;
 pub use storage::*;
+pub use transaction::*;
+pub use wear::{MAX_WEAR_PAGES, Stats, WearWarningHook};
 
-static STORAGE: OnceLock<Mutex<CriticalSectionRawMutex, Storage<Flash>>> = OnceLock::new();
+/// The flash, shared between the global storage and the storage partitions.
+static FLASH: OnceLock<Mutex<CriticalSectionRawMutex, Flash>> = OnceLock::new();
+
+static STORAGE: OnceLock<Mutex<CriticalSectionRawMutex, Storage<SharedFlash<Flash>>>> =
+    OnceLock::new();
 
 const MARKER_KEY: &str = "ARIEL_INIT_MARK";
 const MARKER_VALUE: u8 = 0;
 
-/// Gets a [`Range`] from the linker that can be used for a global [`Storage`].
+/// Converts linker symbol addresses into a [`Range`] that can be used for a [`Storage`].
 ///
-/// This expects two symbols `__storage_start` and `__storage_end`.
 /// This function is also the place to configure a platform dependent `OFFSET`,
 /// which configures an offset between the linker flash address map and the
 /// flash driver address map.
-fn flash_range_from_linker() -> Range<u32> {
+#[doc(hidden)]
+pub fn flash_range(start: *const u32, end: *const u32) -> Range<u32> {
     #[cfg(context = "nrf")]
     const OFFSET: usize = 0x0;
     #[cfg(context = "rp")]
@@ -47,13 +62,8 @@ fn flash_range_from_linker() -> Range<u32> {
     #[cfg(not(context = "ariel-os"))]
     const OFFSET: usize = 0x0;
 
-    unsafe extern "C" {
-        static __storage_start: u32;
-        static __storage_end: u32;
-    }
-
-    let start = &raw const __storage_start as usize - OFFSET;
-    let end = &raw const __storage_end as usize - OFFSET;
+    let start = start as usize - OFFSET;
+    let end = end as usize - OFFSET;
 
     #[expect(clippy::cast_possible_truncation)]
     let (start, end) = (start as u32, end as u32);
@@ -61,25 +71,79 @@ fn flash_range_from_linker() -> Range<u32> {
     start..end
 }
 
-fn init_(p: &mut OptionalPeripherals) {
+/// Gets a [`Range`] from the linker that can be used for a global [`Storage`].
+///
+/// This expects two symbols `__storage_start` and `__storage_end`.
+fn flash_range_from_linker() -> Range<u32> {
+    unsafe extern "C" {
+        static __storage_start: u32;
+        static __storage_end: u32;
+    }
+
+    flash_range(&raw const __storage_start, &raw const __storage_end)
+}
+
+async fn init_(p: &mut OptionalPeripherals) {
     use ariel_os_debug::log::info;
     let flash_range = flash_range_from_linker();
     info!("storage: using flash range {:?}", &flash_range);
 
-    let flash = flash_init(p);
+    let flash = FLASH.get_or_init(|| Mutex::new(flash_init(p)));
+    let flash = SharedFlash::new(flash).await;
     let _ = STORAGE.init(Mutex::new(Storage::new(flash, flash_range)));
 }
 
-/// Initializes the global storage.
+/// Ensures that a [`Storage`] is initialized, and completes any interrupted [`Transaction`].
+///
+/// # Panics
+///
+/// Panics when erasing the storage fails, or when an interrupted [`Transaction`] cannot be
+/// completed.
+async fn prepare<F: embedded_storage_async::nor_flash::NorFlash>(
+    storage: &mut Storage<F>,
+    name: &str,
+) {
+    // Use a marker to ensure that this storage is initialized, and erase it again if that was
+    // interrupted.
+    if !matches!(storage.erase_in_progress().await, Ok(false))
+        || Ok(Some(MARKER_VALUE)) != storage.get::<u8>(MARKER_KEY).await
+    {
+        ariel_os_debug::log::info!("storage: initializing {}", name);
+        storage.erase_all().await.unwrap();
+        storage.insert(MARKER_KEY, MARKER_VALUE).await.unwrap();
+    }
+
+    storage.recover_transaction().await.unwrap();
+
+    if storage.page_count() > MAX_WEAR_PAGES {
+        ariel_os_debug::log::warn!(
+            "storage: erase cycles of {} are only tracked for its first {} pages",
+            name,
+            MAX_WEAR_PAGES
+        );
+    }
+}
+
+/// Gets the flash the global storage and the storage partitions are on.
+///
+/// This is for system components that manage flash regions outside of any storage, e.g., firmware
+/// update slots.
+#[doc(hidden)]
+pub async fn flash() -> &'static Mutex<CriticalSectionRawMutex, Flash> {
+    FLASH.get().await
+}
+
+/// Initializes the global storage and the storage partitions.
 ///
 /// Note: this is automatically called by the Ariel OS initialization code.
 ///
 /// # Panics
 ///
-/// Panics when initializing the flash fails.
+/// Panics when initializing the flash fails, or when an interrupted [`Transaction`] cannot be
+/// completed.
 #[doc(hidden)]
 pub async fn init(p: &mut OptionalPeripherals) {
-    init_(p);
+    init_(p).await;
 
     // add some delay to give an attached debug probe time to parse the
     // defmt RTT header. Reading that header might touch flash memory, which
@@ -88,11 +152,9 @@ pub async fn init(p: &mut OptionalPeripherals) {
     #[cfg(context = "rp")]
     embassy_time::block_for(embassy_time::Duration::from_millis(10));
 
-    // Use a marker to ensure that this storage is initialized.
-    if Ok(Some(MARKER_VALUE)) != get::<u8>(MARKER_KEY).await {
-        ariel_os_debug::log::info!("storage: initializing");
-        erase_all().await.unwrap();
-    }
+    prepare(&mut *lock().await, "global storage").await;
+
+    partitions::init(FLASH.get().await).await;
 }
 
 /// Stores a key-value pair into flash memory.
@@ -110,6 +172,8 @@ where
 /// Note: Always [`get()`] the same value type that was [`insert()`]!
 ///
 /// If no value with the key is found, `None` is returned.
+/// This is also the case for zero-sized types, whose values cannot be told apart from removed
+/// items.
 pub async fn get<V>(key: &str) -> Result<Option<V>, sequential_storage::Error<FlashError>>
 where
     V: Serialize + for<'d> Deserialize<'d> + Into<PostcardValue<V>>,
@@ -128,10 +192,40 @@ where
 /// All items in flash have to be read and deserialized to find the items with the key.
 /// This is unlikely to be cached well.
 /// </div>
-// STM32 flash drivers do not implement `MultiwriteNorFlash`.
-#[cfg(not(context = "stm32"))]
 pub async fn remove(key: &str) -> Result<(), sequential_storage::Error<FlashError>> {
-    lock().await.remove(key).await
+    cfg_if::cfg_if! {
+        // STM32 flash drivers do not implement `MultiwriteNorFlash`.
+        if #[cfg(context = "stm32")] {
+            lock().await.remove_with_tombstone(key).await
+        } else {
+            lock().await.remove(key).await
+        }
+    }
+}
+
+/// Gets usage statistics of the global storage.
+///
+/// See [`Storage::stats()`] for details.
+pub async fn stats() -> Result<Stats, sequential_storage::Error<FlashError>> {
+    lock().await.stats().await
+}
+
+/// Lists the keys that currently have a value in the global storage, up to `N` of them.
+///
+/// See [`Storage::keys()`] for details.
+pub async fn keys<const N: usize>() -> Result<
+    arrayvec::ArrayVec<arrayvec::ArrayString<MAX_KEY_LEN>, N>,
+    sequential_storage::Error<FlashError>,
+> {
+    lock().await.keys().await
+}
+
+/// Sets a hook called when a page of the global storage is erased and its erase count reaches
+/// `threshold`.
+///
+/// See [`Storage::set_wear_warning()`] for details.
+pub async fn set_wear_warning(threshold: u32, hook: WearWarningHook) {
+    lock().await.set_wear_warning(threshold, hook);
 }
 
 /// Resets the flash in the entire flash range.
@@ -143,7 +237,8 @@ pub async fn erase_all() -> Result<(), sequential_storage::Error<FlashError>> {
 
 /// Gets a [`MutexGuard`] of the global [`Storage`] object.
 ///
-/// This can be used to implement atomic RMW (like counters).
+/// This can be used to implement atomic RMW (like counters), and to start a [`Transaction`]
+/// updating several keys atomically, even across power loss.
 /// *It is not needed for using the global [`get()`], [`insert()`] and [`remove()`] functions.*
 ///
 /// Note: don't forget to drop the mutex guard returned by this.
@@ -160,6 +255,77 @@ pub async fn erase_all() -> Result<(), sequential_storage::Error<FlashError>> {
 ///     s.insert("counter", value + 1).await.unwrap();
 /// }
 /// ```
-pub async fn lock() -> MutexGuard<'static, CriticalSectionRawMutex, storage::Storage<Flash>> {
+pub async fn lock()
+-> MutexGuard<'static, CriticalSectionRawMutex, storage::Storage<SharedFlash<Flash>>> {
     STORAGE.get().await.lock().await
 }
+
+/// Defines a module per storage partition, each holding its own [`Storage`].
+macro_rules! define_partitions {
+    ($($name:ident($start:ident, $end:ident)),* $(,)?) => {
+        $(
+            #[doc = concat!("The `", stringify!($name), "` storage partition.")]
+            pub mod $name {
+                use embassy_sync::{
+                    blocking_mutex::raw::CriticalSectionRawMutex,
+                    mutex::{Mutex, MutexGuard},
+                    once_lock::OnceLock,
+                };
+
+                use crate::{Flash, SharedFlash, Storage};
+
+                static STORAGE: OnceLock<
+                    Mutex<CriticalSectionRawMutex, Storage<SharedFlash<Flash>>>,
+                > = OnceLock::new();
+
+                pub(super) async fn init(flash: &'static Mutex<CriticalSectionRawMutex, Flash>) {
+                    unsafe extern "C" {
+                        static $start: u32;
+                        static $end: u32;
+                    }
+
+                    let flash_range = crate::flash_range(&raw const $start, &raw const $end);
+                    ariel_os_debug::log::info!(
+                        "storage: using flash range {:?} for partition {}",
+                        &flash_range,
+                        stringify!($name)
+                    );
+
+                    let flash = SharedFlash::new(flash).await;
+                    let _ = STORAGE.init(Mutex::new(Storage::new(flash, flash_range)));
+
+                    crate::prepare(&mut *lock().await, stringify!($name)).await;
+                }
+
+                /// Gets a [`MutexGuard`] of the [`Storage`] of this partition.
+                ///
+                /// Note: don't forget to drop the mutex guard returned by this.
+                pub async fn lock()
+                -> MutexGuard<'static, CriticalSectionRawMutex, Storage<SharedFlash<Flash>>> {
+                    STORAGE.get().await.lock().await
+                }
+            }
+        )*
+
+        pub(super) async fn init(
+            #[allow(unused_variables, reason = "unused when no partition is declared")]
+            flash: &'static embassy_sync::mutex::Mutex<
+                embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex,
+                crate::Flash,
+            >,
+        ) {
+            $($name::init(flash).await;)*
+        }
+    };
+}
+
+/// Named storage partitions, each with its own [`Storage`] and flash range.
+///
+/// Partitions are declared with the `CONFIG_STORAGE_PARTITIONS` environment variable, as a
+/// comma-separated list of `name:pages` entries (e.g., `config:2,credentials:2,logs:4`); a module
+/// named after each partition is generated here.
+/// Keeping data with different write patterns in separate partitions ensures that, e.g., a log
+/// frequently written to does not wear out the pages holding device credentials.
+pub mod partitions {
+    include!(concat!(env!("OUT_DIR"), "/partitions.rs"));
+}
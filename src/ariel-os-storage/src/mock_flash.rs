@@ -0,0 +1,353 @@
+//! A RAM-backed NOR flash, optionally persisted to a file, for testing [`Storage`] on the host.
+//!
+//! [`MockFlash`] follows the NOR flash semantics expected by [`sequential_storage`]: erasing sets
+//! all bytes of a page to `0xff`, and writing can only clear bits.
+//! On top of that, it can simulate a power loss after an arbitrary number of programmed or erased
+//! bytes, and counts erase cycles per page.
+//!
+//! [`Storage`]: crate::storage::Storage
+#![allow(
+    clippy::indexing_slicing,
+    reason = "accesses are bounds-checked by `check_range()` first"
+)]
+
+use std::path::PathBuf;
+
+use embedded_storage_async::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+/// Value of a byte after it has been erased.
+const ERASED: u8 = 0xff;
+
+/// Error returned by [`MockFlash`] operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockFlashError {
+    /// The operation accessed memory outside of the flash capacity.
+    OutOfBounds,
+    /// The operation was not aligned to the required granularity.
+    NotAligned,
+    /// Power was lost during or before the operation; see [`MockFlash::power_loss_after()`].
+    PowerLoss,
+}
+
+impl NorFlashError for MockFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::NotAligned => NorFlashErrorKind::NotAligned,
+            Self::PowerLoss => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// A RAM-backed NOR flash with power-loss injection and wear counters.
+///
+/// `PAGE_SIZE` is the erase granularity, `WRITE_SIZE` the write granularity.
+///
+/// Clones of a [`MockFlash`] share nothing; use `&mut MockFlash` to hand the same flash to
+/// several consecutive [`Storage`](crate::storage::Storage) instances, e.g., to simulate a reboot.
+#[derive(Debug, Clone)]
+pub struct MockFlash<const PAGE_SIZE: usize = 4096, const WRITE_SIZE: usize = 4> {
+    data: Vec<u8>,
+    erase_counts: Vec<u32>,
+    bytes_written: u64,
+    bytes_until_power_loss: Option<usize>,
+    powered: bool,
+    path: Option<PathBuf>,
+}
+
+impl<const PAGE_SIZE: usize, const WRITE_SIZE: usize> MockFlash<PAGE_SIZE, WRITE_SIZE> {
+    /// Creates a fully erased flash of `pages` pages.
+    #[must_use]
+    pub fn new(pages: usize) -> Self {
+        const {
+            assert!(PAGE_SIZE % WRITE_SIZE == 0);
+        }
+
+        Self {
+            data: vec![ERASED; pages * PAGE_SIZE],
+            erase_counts: vec![0; pages],
+            bytes_written: 0,
+            bytes_until_power_loss: None,
+            powered: true,
+            path: None,
+        }
+    }
+
+    /// Creates a flash of `pages` pages whose content is persisted to the file at `path`.
+    ///
+    /// If the file exists, the flash content is loaded from it; otherwise the flash starts out
+    /// erased.
+    /// The file is rewritten after every successful write or erase operation.
+    /// Wear counters are not persisted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file exists but cannot be read.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file exists but its size does not match the flash size.
+    pub fn with_file(pages: usize, path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let mut flash = Self::new(pages);
+
+        match std::fs::read(&path) {
+            Ok(data) => {
+                assert_eq!(
+                    data.len(),
+                    flash.data.len(),
+                    "flash file size does not match the flash size"
+                );
+                flash.data = data;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        flash.path = Some(path);
+        Ok(flash)
+    }
+
+    /// Returns the raw flash content.
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the range of flash addresses usable by a [`Storage`](crate::storage::Storage).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the flash is larger than the 32-bit address space.
+    #[must_use]
+    pub fn range(&self) -> core::ops::Range<u32> {
+        0..u32::try_from(self.data.len()).unwrap()
+    }
+
+    /// Makes power fail after `bytes` more bytes have been programmed or erased.
+    ///
+    /// The operation during which power fails is left incomplete: a write only programs its first
+    /// bytes, an erase only erases the first bytes of the page.
+    /// All operations return [`MockFlashError::PowerLoss`] until [`Self::power_cycle()`] is called.
+    pub fn power_loss_after(&mut self, bytes: usize) {
+        self.bytes_until_power_loss = Some(bytes);
+    }
+
+    /// Restores power after a simulated power loss, and disarms any pending power loss.
+    pub fn power_cycle(&mut self) {
+        self.bytes_until_power_loss = None;
+        self.powered = true;
+    }
+
+    /// Returns whether power was lost and has not been restored yet.
+    #[must_use]
+    pub fn has_lost_power(&self) -> bool {
+        !self.powered
+    }
+
+    /// Returns the number of times each page has been erased, indexed by page.
+    #[must_use]
+    pub fn erase_counts(&self) -> &[u32] {
+        &self.erase_counts
+    }
+
+    /// Returns the number of bytes written since the flash was created.
+    #[must_use]
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Consumes one byte of the power budget.
+    ///
+    /// Returns `false` if power is lost before that byte could be processed.
+    fn consume_byte(&mut self) -> bool {
+        match &mut self.bytes_until_power_loss {
+            Some(0) => {
+                self.powered = false;
+                false
+            }
+            Some(remaining) => {
+                *remaining -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn check_powered(&self) -> Result<(), MockFlashError> {
+        if self.powered {
+            Ok(())
+        } else {
+            Err(MockFlashError::PowerLoss)
+        }
+    }
+
+    fn check_range(&self, offset: u32, len: usize, align: usize) -> Result<usize, MockFlashError> {
+        let offset = offset as usize;
+        if offset % align != 0 || len % align != 0 {
+            return Err(MockFlashError::NotAligned);
+        }
+        if offset + len > self.data.len() {
+            return Err(MockFlashError::OutOfBounds);
+        }
+        Ok(offset)
+    }
+
+    fn persist(&self) {
+        if let Some(path) = &self.path {
+            std::fs::write(path, &self.data).expect("failed to persist the mock flash");
+        }
+    }
+}
+
+impl<const PAGE_SIZE: usize, const WRITE_SIZE: usize> ErrorType
+    for MockFlash<PAGE_SIZE, WRITE_SIZE>
+{
+    type Error = MockFlashError;
+}
+
+impl<const PAGE_SIZE: usize, const WRITE_SIZE: usize> ReadNorFlash
+    for MockFlash<PAGE_SIZE, WRITE_SIZE>
+{
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check_powered()?;
+        let start = self.check_range(offset, bytes.len(), Self::READ_SIZE)?;
+        bytes.copy_from_slice(&self.data[start..start + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl<const PAGE_SIZE: usize, const WRITE_SIZE: usize> NorFlash
+    for MockFlash<PAGE_SIZE, WRITE_SIZE>
+{
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = PAGE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.check_powered()?;
+        let len = to.checked_sub(from).ok_or(MockFlashError::OutOfBounds)? as usize;
+        let start = self.check_range(from, len, PAGE_SIZE)?;
+
+        for page_start in (start..start + len).step_by(PAGE_SIZE) {
+            self.erase_counts[page_start / PAGE_SIZE] += 1;
+            for address in page_start..page_start + PAGE_SIZE {
+                if !self.consume_byte() {
+                    self.persist();
+                    return Err(MockFlashError::PowerLoss);
+                }
+                self.data[address] = ERASED;
+            }
+        }
+
+        self.persist();
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check_powered()?;
+        let start = self.check_range(offset, bytes.len(), WRITE_SIZE)?;
+
+        for (address, byte) in (start..).zip(bytes) {
+            if !self.consume_byte() {
+                self.persist();
+                return Err(MockFlashError::PowerLoss);
+            }
+            // NOR flash can only clear bits.
+            self.data[address] &= byte;
+            self.bytes_written += 1;
+        }
+
+        self.persist();
+        Ok(())
+    }
+}
+
+impl<const PAGE_SIZE: usize, const WRITE_SIZE: usize> MultiwriteNorFlash
+    for MockFlash<PAGE_SIZE, WRITE_SIZE>
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+
+    use super::{MockFlash, MockFlashError};
+
+    #[test]
+    fn write_only_clears_bits() {
+        block_on(async {
+            let mut flash = MockFlash::<64, 4>::new(2);
+            flash.write(0, &[0x0f, 0xf0, 0xff, 0x00]).await.unwrap();
+            flash.write(0, &[0xff, 0x0f, 0x0f, 0xff]).await.unwrap();
+            assert_eq!(&flash.as_bytes()[..4], &[0x0f, 0x00, 0x0f, 0x00]);
+
+            flash.erase(0, 64).await.unwrap();
+            assert_eq!(&flash.as_bytes()[..4], &[0xff; 4]);
+            assert_eq!(flash.erase_counts(), &[1, 0]);
+        });
+    }
+
+    #[test]
+    fn power_loss_interrupts_write() {
+        block_on(async {
+            let mut flash = MockFlash::<64, 4>::new(2);
+            flash.power_loss_after(6);
+            assert_eq!(
+                flash.write(0, &[0; 8]).await,
+                Err(MockFlashError::PowerLoss)
+            );
+            assert_eq!(&flash.as_bytes()[..8], &[0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+
+            let mut buf = [0; 4];
+            assert_eq!(
+                flash.read(0, &mut buf).await,
+                Err(MockFlashError::PowerLoss)
+            );
+
+            flash.power_cycle();
+            flash.read(4, &mut buf).await.unwrap();
+            assert_eq!(buf, [0, 0, 0xff, 0xff]);
+        });
+    }
+
+    #[test]
+    fn rejects_unaligned_access() {
+        block_on(async {
+            let mut flash = MockFlash::<64, 4>::new(2);
+            assert_eq!(
+                flash.write(2, &[0; 4]).await,
+                Err(MockFlashError::NotAligned)
+            );
+            assert_eq!(flash.erase(0, 32).await, Err(MockFlashError::NotAligned));
+            assert_eq!(flash.erase(0, 192).await, Err(MockFlashError::OutOfBounds));
+        });
+    }
+
+    #[test]
+    fn persists_to_file() {
+        let path = std::env::temp_dir().join(format!(
+            "ariel-os-storage-mock-flash-{}.bin",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        block_on(async {
+            let mut flash = MockFlash::<64, 4>::with_file(2, &path).unwrap();
+            flash.write(64, &[1, 2, 3, 4]).await.unwrap();
+        });
+
+        let flash = MockFlash::<64, 4>::with_file(2, &path).unwrap();
+        assert_eq!(&flash.as_bytes()[64..68], &[1, 2, 3, 4]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
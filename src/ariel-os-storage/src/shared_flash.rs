@@ -0,0 +1,53 @@
+//! A flash shared between several [`Storage`](crate::storage::Storage) instances.
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_storage_async::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+/// A handle to a flash shared between several [`Storage`](crate::storage::Storage) instances,
+/// e.g., one per storage partition.
+///
+/// The flash is locked for the duration of each operation.
+/// As each [`Storage`](crate::storage::Storage) only accesses its own flash range, no offset
+/// is applied to addresses.
+pub struct SharedFlash<F: 'static> {
+    flash: &'static Mutex<CriticalSectionRawMutex, F>,
+    capacity: usize,
+}
+
+impl<F: ReadNorFlash> SharedFlash<F> {
+    /// Creates a new handle to the shared `flash`.
+    pub async fn new(flash: &'static Mutex<CriticalSectionRawMutex, F>) -> Self {
+        let capacity = flash.lock().await.capacity();
+        Self { flash, capacity }
+    }
+}
+
+impl<F: ErrorType> ErrorType for SharedFlash<F> {
+    type Error = F::Error;
+}
+
+impl<F: ReadNorFlash> ReadNorFlash for SharedFlash<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.lock().await.read(offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl<F: NorFlash> NorFlash for SharedFlash<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.flash.lock().await.erase(from, to).await
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.flash.lock().await.write(offset, bytes).await
+    }
+}
+
+impl<F: MultiwriteNorFlash> MultiwriteNorFlash for SharedFlash<F> {}
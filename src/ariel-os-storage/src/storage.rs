@@ -2,15 +2,15 @@
 //! a flash range and backend.
 use core::ops::Range;
 
-use arrayvec::ArrayString;
-use embedded_storage_async::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash};
+use arrayvec::{ArrayString, ArrayVec};
+use embedded_storage_async::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash};
 use sequential_storage::{
     cache::NoCache,
-    erase_all,
-    map::{Value, fetch_item, remove_item, store_item},
+    map::{Key, SerializationError, Value, fetch_all_items, fetch_item, remove_item, store_item},
 };
 
 pub use crate::postcard_value::PostcardValue;
+use crate::wear::{MAX_WEAR_PAGES, Stats, WearTrackingFlash, WearWarningHook};
 pub use serde::{Deserialize, Serialize};
 
 /// Maximum key length.
@@ -18,26 +18,168 @@ pub const MAX_KEY_LEN: usize = 64usize;
 /// Data buffer length.
 pub const DATA_BUFFER_SIZE: usize = 128usize;
 
+/// Key of the item persisting the erase counts of each page.
+const WEAR_KEY: &str = "ARIEL_WEAR";
+/// Number of page erases after which the erase counts are persisted.
+///
+/// Persisting them stores an item, which may itself require erasing a page; persisting only
+/// every few erases bounds that write amplification, at the cost of losing up to this many erases
+/// on reset or power loss.
+const WEAR_PERSIST_INTERVAL: u32 = 8;
+/// Maximum number of distinct keys tracked by [`Storage::stats()`] to tell live from stale items.
+const MAX_STATS_KEYS: usize = 64;
+/// Prefix of the keys of items used internally, which [`Storage::keys()`] does not list.
+const INTERNAL_KEY_PREFIX: &str = "ARIEL_";
+/// Key of the item stored after tombstones, which allows page rotation to drop them; see
+/// [`ItemKey`].
+const SWEEP_KEY: &str = "ARIEL_SWEEP";
+/// Value of the item stored under [`SWEEP_KEY`]; it must not be empty, or it would be taken for a
+/// tombstone.
+const SWEEP_VALUE: &[u8] = &[0];
+/// Key of the item marking that [`Storage::erase_all()`] is in progress.
+const ERASE_KEY: &str = "ARIEL_ERASE";
+/// Value of the item stored under [`ERASE_KEY`].
+const ERASE_VALUE: &[u8] = &[0];
+/// Size of the chunks in which pages are read when scanning them.
+const SCAN_CHUNK_SIZE: usize = 32;
+
+/// The key of an item, told apart by what the item holds.
+///
+/// Keys are serialized as their [`ArrayString`], so this is only a different view on the same
+/// items.
+///
+/// When a page is rotated, [`sequential_storage`] copies each of its items to the free page only
+/// if it is the newest item whose key compares equal to the item's key.
+/// The variants compare as follows, which makes that drop tombstones that are no longer needed:
+///
+/// * Values and tombstones of the same key are equal, so that the newest of them determines
+///   whether the key is present.
+/// * The sweep marker is equal to every tombstone, and is stored after each of them
+///   ([`Storage::sweep_tombstones()`]).
+///   A tombstone followed by the marker is thus never the newest item equal to itself, and is
+///   dropped when its page is rotated.
+///   This is sound because the values it shadowed are older: they are either on the same page,
+///   where they are dropped as well, or on pages that were rotated before.
+#[derive(Clone)]
+enum ItemKey {
+    Value(ArrayString<MAX_KEY_LEN>),
+    Tombstone(ArrayString<MAX_KEY_LEN>),
+    Sweep,
+}
+
+impl ItemKey {
+    fn name(&self) -> &str {
+        match self {
+            Self::Value(name) | Self::Tombstone(name) => name,
+            Self::Sweep => SWEEP_KEY,
+        }
+    }
+}
+
+impl PartialEq for ItemKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Value(a) | Self::Tombstone(a), Self::Value(b) | Self::Tombstone(b)) => a == b,
+            (Self::Sweep, key) | (key, Self::Sweep) => !matches!(key, Self::Value(_)),
+        }
+    }
+}
+
+// Not transitive (a value equals its tombstone, which equals the sweep marker, which does not equal
+// the value), but `sequential_storage` only ever compares found keys to the one searched for.
+impl Eq for ItemKey {}
+
+impl Key for ItemKey {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        match self {
+            Self::Value(name) | Self::Tombstone(name) => Key::serialize_into(name, buffer),
+            Self::Sweep => Key::serialize_into(
+                &ArrayString::<MAX_KEY_LEN>::from(SWEEP_KEY)
+                    .map_err(|_| SerializationError::InvalidData)?,
+                buffer,
+            ),
+        }
+    }
+
+    /// Deserializes the key from the data of an item, which is followed by the item's value.
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), SerializationError> {
+        let (name, len) = <ArrayString<MAX_KEY_LEN> as Key>::deserialize_from(buffer)?;
+        let key = if name.as_str() == SWEEP_KEY {
+            Self::Sweep
+        } else if buffer.len() == len {
+            Self::Tombstone(name)
+        } else {
+            Self::Value(name)
+        };
+        Ok((key, len))
+    }
+}
+
+/// A [`Value`] that reads back as absent, written to remove items on flash that cannot clear
+/// existing items in place.
+///
+/// A tombstone is an item with an empty value; being the most recent item for its key, it
+/// shadows any value stored before it.
+/// Values serialized to zero bytes are therefore indistinguishable from tombstones.
+/// Deserializing a [`Tombstone`] accepts any value, so that it can be used to check whether a key
+/// is present.
+pub(crate) struct Tombstone;
+
+impl Value<'_> for Tombstone {
+    fn serialize_into(&self, _buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        Ok(0)
+    }
+
+    fn deserialize_from(_buffer: &[u8]) -> Result<Self, SerializationError> {
+        Ok(Self)
+    }
+}
+
+/// A [`Value`] wrapper returning `None` when reading a [`Tombstone`].
+struct MaybeRemoved<V>(Option<V>);
+
+impl<'d, V: Value<'d>> Value<'d> for MaybeRemoved<V> {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        match &self.0 {
+            Some(value) => value.serialize_into(buffer),
+            None => Tombstone.serialize_into(buffer),
+        }
+    }
+
+    fn deserialize_from(buffer: &'d [u8]) -> Result<Self, SerializationError> {
+        if buffer.is_empty() {
+            Ok(Self(None))
+        } else {
+            V::deserialize_from(buffer).map(|value| Self(Some(value)))
+        }
+    }
+}
+
 /// Object holding an instance of a key-value pair storage.
 ///
 /// You should probably look into using the global instance accessible via
 /// `ariel_os_storage::storage::{get,insert,remove}`.
 pub struct Storage<F> {
-    flash: F,
+    flash: WearTrackingFlash<F>,
     storage_range: Range<u32>,
+    wear_warning: Option<(u32, WearWarningHook)>,
 }
 
 impl<F: NorFlash> Storage<F> {
     /// Creates a new [`Storage`] instance.
     pub const fn new(flash: F, storage_range: Range<u32>) -> Storage<F> {
         Self {
-            flash,
+            flash: WearTrackingFlash::new(flash, storage_range.start),
             storage_range,
+            wear_warning: None,
         }
     }
 
     /// Gets a [`Value`] from this [`Storage`] instance.
     ///
+    /// Values serialized to zero bytes are indistinguishable from removed items, and are
+    /// returned as `None`.
+    ///
     /// # Panics
     ///
     /// Currently panics if `key.len() > MAX_KEY_LEN`.
@@ -45,17 +187,18 @@ impl<F: NorFlash> Storage<F> {
         &mut self,
         key: &str,
     ) -> Result<Option<V>, sequential_storage::Error<<F as ErrorType>::Error>> {
-        let key = ArrayString::<MAX_KEY_LEN>::from(key).unwrap();
+        let key = ItemKey::Value(ArrayString::from(key).unwrap());
         let mut data_buffer = [0; DATA_BUFFER_SIZE];
 
-        fetch_item::<_, V, _>(
+        let value = fetch_item::<_, MaybeRemoved<V>, _>(
             &mut self.flash,
             self.storage_range.clone(),
             &mut NoCache::new(),
             &mut data_buffer,
             &key,
         )
-        .await
+        .await?;
+        Ok(value.and_then(|value| value.0))
     }
 
     /// Inserts a [`Value`] into this [`Storage`] instance.
@@ -68,15 +211,54 @@ impl<F: NorFlash> Storage<F> {
         key: &str,
         value: V,
     ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
-        let key = ArrayString::<MAX_KEY_LEN>::from(key).unwrap();
-        let mut data_buffer = [0; DATA_BUFFER_SIZE];
+        self.insert_with_buffer(key, &value, &mut [0; DATA_BUFFER_SIZE])
+            .await
+    }
+
+    /// Inserts a [`Value`], using `data_buffer` to serialize the item.
+    ///
+    /// # Panics
+    ///
+    /// Currently panics if `key.len() > MAX_KEY_LEN`.
+    pub(crate) async fn insert_with_buffer<'d, V: Value<'d>>(
+        &mut self,
+        key: &str,
+        value: &V,
+        data_buffer: &mut [u8],
+    ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
+        let key = ItemKey::Value(ArrayString::from(key).unwrap());
         store_item(
             &mut self.flash,
             self.storage_range.clone(),
             &mut NoCache::new(),
-            &mut data_buffer,
+            data_buffer,
+            &key,
+            value,
+        )
+        .await?;
+        self.persist_erase_counts(false).await
+    }
+
+    /// Gets the serialized value associated with the given key, using `data_buffer` to read
+    /// the item.
+    ///
+    /// Tombstones are returned as empty slices.
+    ///
+    /// # Panics
+    ///
+    /// Currently panics if `key.len() > MAX_KEY_LEN`.
+    pub(crate) async fn get_with_buffer<'b>(
+        &mut self,
+        key: &str,
+        data_buffer: &'b mut [u8],
+    ) -> Result<Option<&'b [u8]>, sequential_storage::Error<<F as ErrorType>::Error>> {
+        let key = ItemKey::Value(ArrayString::from(key).unwrap());
+        fetch_item::<_, &[u8], _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            data_buffer,
             &key,
-            &value,
         )
         .await
     }
@@ -98,6 +280,8 @@ impl<F: NorFlash> Storage<F> {
     /// Gets the last stored value from the flash that is associated with the given key.
     ///
     /// If no value with the key is found, `None` is returned.
+    /// This is also the case for zero-sized types (on any flash), whose values cannot be told
+    /// apart from removed items.
     ///
     /// # Panics
     ///
@@ -109,28 +293,437 @@ impl<F: NorFlash> Storage<F> {
     where
         V: Serialize + for<'d> Deserialize<'d> + Into<PostcardValue<V>>,
     {
-        let key = ArrayString::<MAX_KEY_LEN>::from(key).unwrap();
-        let mut data_buffer = [0; DATA_BUFFER_SIZE];
+        let postcard_value = self.get_raw::<PostcardValue<V>>(key).await?;
+        Ok(postcard_value.map(PostcardValue::into_inner))
+    }
+
+    /// Deletes an item from flash by storing a tombstone for it.
+    ///
+    /// Unlike [`Storage::remove()`], this works on any [`NorFlash`], as it does not need to
+    /// overwrite the existing items.
+    /// Additional calls to [`Storage::get()`] with the same key will return `None` until
+    /// a new one is stored again.
+    ///
+    /// The tombstone takes up space like any other item, until the page holding it is rotated,
+    /// or the storage is erased: page rotation drops the tombstone along with the values it
+    /// shadowed.
+    /// No tombstone is written if the key is not present.
+    ///
+    /// # Panics
+    ///
+    /// Currently panics if `key.len() > MAX_KEY_LEN`.
+    pub async fn remove_with_tombstone(
+        &mut self,
+        key: &str,
+    ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
+        // `Tombstone` accepts any value, which avoids deserializing it.
+        if self.get_raw::<Tombstone>(key).await?.is_none() {
+            return Ok(());
+        }
+        self.insert_raw(key, Tombstone).await?;
+        self.sweep_tombstones().await
+    }
+
+    /// Stores the sweep marker, which allows page rotation to drop the tombstones stored before
+    /// it; see [`ItemKey`].
+    pub(crate) async fn sweep_tombstones(
+        &mut self,
+    ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
+        self.insert_raw(SWEEP_KEY, SWEEP_VALUE).await
+    }
+
+    /// Resets the flash in the entire flash range of this [`Storage`] instance.
+    ///
+    /// If this is interrupted, e.g., by a power loss, items may be left partially erased;
+    /// [`Storage::erase_in_progress()`] then reports it until this is called again.
+    pub async fn erase_all(
+        &mut self,
+    ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
+        // Mark the erase as in progress, and erase the pages holding the marker last, so that it
+        // remains as long as other items do.
+        // A storage that cannot be written to (e.g., because it is corrupted) is erased without
+        // the marker.
+        let _ = self.insert_raw(ERASE_KEY, ERASE_VALUE).await;
+
+        // Keep the erase counts in RAM while the item persisting them is erased.
+        // If they cannot be read (e.g., because the storage is corrupted), or power is lost
+        // before they are persisted again, they are lost.
+        if let Ok(Some(counts)) = self.get::<[u32; MAX_WEAR_PAGES]>(WEAR_KEY).await {
+            self.flash.add_pending(&counts);
+        }
+
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "pages are smaller than 4 GiB"
+        )]
+        let page_size = F::ERASE_SIZE as u32;
+
+        for marked in [false, true] {
+            for page_start in self.storage_range.clone().step_by(F::ERASE_SIZE) {
+                if self.scan_page(page_start).await?.has_erase_marker == marked {
+                    self.flash
+                        .erase(page_start, page_start + page_size)
+                        .await
+                        .map_err(|e| sequential_storage::Error::Storage { value: e })?;
+                }
+            }
+        }
+        self.persist_erase_counts(true).await
+    }
+
+    /// Returns whether [`Storage::erase_all()`] was interrupted, e.g., by a power loss.
+    ///
+    /// Items may then have been erased only partially, and the storage should be erased again
+    /// before it is used.
+    pub async fn erase_in_progress(
+        &mut self,
+    ) -> Result<bool, sequential_storage::Error<<F as ErrorType>::Error>> {
+        for page_start in self.storage_range.clone().step_by(F::ERASE_SIZE) {
+            if self.scan_page(page_start).await?.partially_erased {
+                return Ok(true);
+            }
+        }
+        Ok(self
+            .get_with_buffer(ERASE_KEY, &mut [0; DATA_BUFFER_SIZE])
+            .await?
+            .is_some())
+    }
+
+    /// Sets a hook called when a page is erased and its erase count reaches `threshold`.
+    ///
+    /// The threshold should be chosen depending on the endurance of the flash, which is
+    /// typically 10k erase cycles for internal flash of microcontrollers, e.g., as 80 % of it.
+    ///
+    /// The hook is called when the erase counts are persisted, which happens only every few
+    /// erases (see [`Storage::erase_counts()`]); it may thus be called a few erases late, and
+    /// once for several erases of the same page.
+    pub fn set_wear_warning(&mut self, threshold: u32, hook: WearWarningHook) {
+        self.wear_warning = Some((threshold, hook));
+    }
+
+    /// Returns the number of times each page has been erased, indexed by page.
+    ///
+    /// Erase counts are persisted in the storage itself, as an internal item, once an operation
+    /// brought the number of erases not persisted yet to 8, and after [`Storage::erase_all()`].
+    /// This has the following limitations:
+    ///
+    /// * Up to 7 erases are not accounted for on each reset or power loss.
+    /// * All counts are lost if power is lost during [`Storage::erase_all()`], before they have
+    ///   been persisted again, or if the storage gets corrupted.
+    /// * Only the first [`MAX_WEAR_PAGES`] pages are tracked; counts of further pages are not
+    ///   reported.
+    pub async fn erase_counts(
+        &mut self,
+    ) -> Result<ArrayVec<u32, MAX_WEAR_PAGES>, sequential_storage::Error<<F as ErrorType>::Error>>
+    {
+        let counts = self.total_erase_counts().await?;
+        Ok(counts.into_iter().take(self.page_count()).collect())
+    }
+
+    /// Gets usage statistics of this [`Storage`] instance.
+    ///
+    /// <div class="warning">
+    /// This is slow!
+    ///
+    /// All items in flash have to be read, as well as the erased part of each page.
+    /// </div>
+    ///
+    /// Byte counts are estimates, as the end of the last item of a page cannot always be told
+    /// apart from erased flash.
+    /// Internal items (e.g., the one holding erase counts) are included in item counts.
+    /// Items are only told apart for the first `MAX_STATS_KEYS` keys; items of further keys are
+    /// all counted as live.
+    pub async fn stats(
+        &mut self,
+    ) -> Result<Stats, sequential_storage::Error<<F as ErrorType>::Error>> {
+        let (live_items, stale_items) = self.item_counts().await?;
+        let erased_bytes = self.erased_bytes().await?;
+        let total_bytes = self.storage_range.end - self.storage_range.start;
+
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "pages are smaller than 4 GiB"
+        )]
+        let page_size = F::ERASE_SIZE as u32;
+
+        Ok(Stats {
+            total_bytes,
+            used_bytes: total_bytes.saturating_sub(erased_bytes),
+            free_bytes: erased_bytes.saturating_sub(page_size),
+            live_items,
+            stale_items,
+            erase_counts: self.erase_counts().await?,
+        })
+    }
+
+    /// Lists the keys that currently have a value, up to `N` of them.
+    ///
+    /// <div class="warning">
+    /// This is slow!
+    ///
+    /// All items in flash have to be read.
+    /// </div>
+    ///
+    /// Keys are listed in the order they were first stored in, as far as pages have not been
+    /// rotated since.
+    /// Keys of internal items (starting with `ARIEL_`) are not listed; keys beyond the first `N`
+    /// are left out.
+    pub async fn keys<const N: usize>(
+        &mut self,
+    ) -> Result<
+        ArrayVec<ArrayString<MAX_KEY_LEN>, N>,
+        sequential_storage::Error<<F as ErrorType>::Error>,
+    > {
+        // Keys seen so far, and whether their last item holds a value.
+        let mut keys = ArrayVec::<(ArrayString<MAX_KEY_LEN>, bool), N>::new();
 
-        let postcard_value = fetch_item::<_, PostcardValue<V>, _>(
+        let mut data_buffer = [0; DATA_BUFFER_SIZE];
+        let mut iter = fetch_all_items::<ItemKey, _, _>(
             &mut self.flash,
             self.storage_range.clone(),
             &mut NoCache::new(),
             &mut data_buffer,
-            &key,
         )
         .await?;
-        Ok(postcard_value.map(PostcardValue::into_inner))
+
+        let mut data_buffer = [0; DATA_BUFFER_SIZE];
+        // Items are iterated from the oldest to the newest.
+        while let Some((key, value)) = iter.next::<&[u8]>(&mut data_buffer).await? {
+            let (ItemKey::Value(key) | ItemKey::Tombstone(key)) = key else {
+                continue;
+            };
+            if key.starts_with(INTERNAL_KEY_PREFIX) {
+                continue;
+            }
+            let has_value = !value.is_empty();
+            if let Some(entry) = keys.iter_mut().find(|(k, _)| *k == key) {
+                entry.1 = has_value;
+            } else {
+                // Further keys are left out, as documented.
+                let _ = keys.try_push((key, has_value));
+            }
+        }
+
+        Ok(keys
+            .into_iter()
+            .filter_map(|(key, has_value)| has_value.then_some(key))
+            .collect())
     }
 
-    /// Resets the flash in the entire flash range of this [`Storage`] instance.
-    pub async fn erase_all(
+    pub(crate) fn page_count(&self) -> usize {
+        (self.storage_range.end - self.storage_range.start) as usize / F::ERASE_SIZE
+    }
+
+    /// Returns the persisted erase counts with the pending ones added.
+    async fn total_erase_counts(
+        &mut self,
+    ) -> Result<[u32; MAX_WEAR_PAGES], sequential_storage::Error<<F as ErrorType>::Error>> {
+        let mut counts = self
+            .get::<[u32; MAX_WEAR_PAGES]>(WEAR_KEY)
+            .await?
+            .unwrap_or_default();
+        for (count, pending) in counts.iter_mut().zip(self.flash.pending()) {
+            *count = count.saturating_add(pending);
+        }
+        Ok(counts)
+    }
+
+    /// Persists the erase counts if [`WEAR_PERSIST_INTERVAL`] pages have been erased since they
+    /// were last persisted, or if `force`d and pages have been erased at all, and calls the wear
+    /// warning hook.
+    async fn persist_erase_counts(
         &mut self,
+        mut force: bool,
     ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
-        erase_all(&mut self.flash, self.storage_range.clone()).await
+        // Storing the erase counts may itself erase pages, hence the loop; those erases are only
+        // persisted once enough of them accumulated.
+        loop {
+            let pending = self.flash.pending();
+            let erases = pending
+                .iter()
+                .fold(0u32, |total, count| total.saturating_add(*count));
+            if erases == 0 || (!force && erases < WEAR_PERSIST_INTERVAL) {
+                return Ok(());
+            }
+            force = false;
+
+            let counts = self.total_erase_counts().await?;
+            store_item(
+                &mut self.flash,
+                self.storage_range.clone(),
+                &mut NoCache::new(),
+                &mut [0; DATA_BUFFER_SIZE],
+                &ItemKey::Value(ArrayString::from(WEAR_KEY).unwrap()),
+                &PostcardValue::from(counts),
+            )
+            .await?;
+            self.flash.remove_pending(&pending);
+
+            if let Some((threshold, hook)) = self.wear_warning {
+                for (page, (count, pending)) in counts.iter().zip(pending).enumerate() {
+                    if pending > 0 && *count >= threshold {
+                        hook(page, *count);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Counts live and stale items.
+    async fn item_counts(
+        &mut self,
+    ) -> Result<(u32, u32), sequential_storage::Error<<F as ErrorType>::Error>> {
+        // Hashes of the keys seen so far, and whether their last item holds a value.
+        let mut keys = ArrayVec::<(u32, bool), MAX_STATS_KEYS>::new();
+        let mut untracked = 0u32;
+        let mut total = 0u32;
+
+        let mut data_buffer = [0; DATA_BUFFER_SIZE];
+        let mut iter = fetch_all_items::<ItemKey, _, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut data_buffer,
+        )
+        .await?;
+
+        let mut data_buffer = [0; DATA_BUFFER_SIZE];
+        // Items are iterated from the oldest to the newest.
+        while let Some((key, value)) = iter.next::<&[u8]>(&mut data_buffer).await? {
+            total += 1;
+            let hash = fnv1a(key.name().as_bytes());
+            let has_value = !value.is_empty();
+            if let Some(entry) = keys.iter_mut().find(|(h, _)| *h == hash) {
+                entry.1 = has_value;
+            } else if keys.try_push((hash, has_value)).is_err() {
+                untracked += 1;
+            }
+        }
+
+        #[expect(clippy::cast_possible_truncation, reason = "bounded by MAX_STATS_KEYS")]
+        let live = keys.iter().filter(|(_, has_value)| *has_value).count() as u32 + untracked;
+        Ok((live, total - live))
+    }
+
+    /// Reads the page starting at `page_start`, looking for the erase marker and for leftovers
+    /// of an interrupted erase.
+    async fn scan_page(
+        &mut self,
+        page_start: u32,
+    ) -> Result<PageScan, sequential_storage::Error<<F as ErrorType>::Error>> {
+        const MARKER_LEN: usize = ERASE_KEY.len();
+        const {
+            assert!(SCAN_CHUNK_SIZE % F::READ_SIZE == 0);
+            assert!(F::WRITE_SIZE <= SCAN_CHUNK_SIZE);
+        };
+
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "pages are smaller than 4 GiB"
+        )]
+        let page_end = page_start + F::ERASE_SIZE as u32;
+
+        let mut start_erased = false;
+        let mut programmed = false;
+        let mut has_erase_marker = false;
+
+        // Each chunk is read after the end of the previous one, so that markers spanning two
+        // chunks are found.
+        let mut window = [0xff; MARKER_LEN + SCAN_CHUNK_SIZE];
+        let mut offset = page_start;
+        while offset < page_end {
+            #[expect(clippy::cast_possible_truncation, reason = "small constant")]
+            let chunk_size = (SCAN_CHUNK_SIZE as u32).min(page_end - offset);
+            window.copy_within(SCAN_CHUNK_SIZE.., 0);
+
+            let window_len = MARKER_LEN + chunk_size as usize;
+            let chunk = window.get_mut(MARKER_LEN..window_len).unwrap();
+            self.flash
+                .read(offset, chunk)
+                .await
+                .map_err(|e| sequential_storage::Error::Storage { value: e })?;
+
+            // Pages in use start with a programmed page state marker, so an erased start with
+            // programmed bytes after it is what an interrupted erase leaves.
+            let skip = if offset == page_start {
+                start_erased = chunk.iter().take(F::WRITE_SIZE).all(|byte| *byte == 0xff);
+                F::WRITE_SIZE
+            } else {
+                0
+            };
+            programmed |= chunk.iter().skip(skip).any(|byte| *byte != 0xff);
+
+            has_erase_marker |= window
+                .get(..window_len)
+                .unwrap()
+                .windows(MARKER_LEN)
+                .any(|bytes| bytes == ERASE_KEY.as_bytes());
+
+            offset += chunk_size;
+        }
+
+        Ok(PageScan {
+            partially_erased: start_erased && programmed,
+            has_erase_marker,
+        })
+    }
+
+    /// Counts the bytes left erased at the end of each page.
+    async fn erased_bytes(
+        &mut self,
+    ) -> Result<u32, sequential_storage::Error<<F as ErrorType>::Error>> {
+        const { assert!(SCAN_CHUNK_SIZE % F::READ_SIZE == 0) };
+
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "pages are smaller than 4 GiB"
+        )]
+        let page_size = F::ERASE_SIZE as u32;
+
+        let mut erased = 0;
+        for page_start in self.storage_range.clone().step_by(F::ERASE_SIZE) {
+            // Scan the page backwards, until a programmed byte is found.
+            let mut offset = page_start + page_size;
+            'page: while offset > page_start {
+                #[expect(clippy::cast_possible_truncation, reason = "small constant")]
+                let chunk_size = (SCAN_CHUNK_SIZE as u32).min(offset - page_start);
+                offset -= chunk_size;
+
+                let mut chunk = [0; SCAN_CHUNK_SIZE];
+                let chunk = chunk.get_mut(..chunk_size as usize).unwrap();
+                self.flash
+                    .read(offset, chunk)
+                    .await
+                    .map_err(|e| sequential_storage::Error::Storage { value: e })?;
+
+                for byte in chunk.iter().rev() {
+                    if *byte != 0xff {
+                        break 'page;
+                    }
+                    erased += 1;
+                }
+            }
+        }
+
+        Ok(erased)
     }
 }
 
+/// What [`Storage::scan_page()`] found in a page.
+struct PageScan {
+    /// Whether the page was left partially erased.
+    partially_erased: bool,
+    /// Whether the page holds the key of the erase marker.
+    has_erase_marker: bool,
+}
+
+/// 32-bit FNV-1a hash.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
 impl<F: MultiwriteNorFlash> Storage<F> {
     /// Deletes an item from flash.
     ///
@@ -144,6 +737,9 @@ impl<F: MultiwriteNorFlash> Storage<F> {
     /// This is unlikely to be cached well.
     /// </div>
     ///
+    /// See [`Storage::remove_with_tombstone()`] for flash not implementing
+    /// [`MultiwriteNorFlash`].
+    ///
     /// # Panics
     ///
     /// Currently panics if `key.len() > MAX_KEY_LEN`.
@@ -151,7 +747,7 @@ impl<F: MultiwriteNorFlash> Storage<F> {
         &mut self,
         key: &str,
     ) -> Result<(), sequential_storage::Error<<F as ErrorType>::Error>> {
-        let key = ArrayString::<MAX_KEY_LEN>::from(key).unwrap();
+        let key = ItemKey::Value(ArrayString::from(key).unwrap());
         let mut data_buffer = [0; DATA_BUFFER_SIZE];
         remove_item(
             &mut self.flash,
@@ -163,3 +759,264 @@ impl<F: MultiwriteNorFlash> Storage<F> {
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use embassy_futures::block_on;
+    use sequential_storage::{cache::NoCache, map::fetch_all_items};
+
+    use super::{ArrayString, DATA_BUFFER_SIZE, ItemKey, Storage, WEAR_PERSIST_INTERVAL};
+    use crate::mock_flash::MockFlash;
+
+    type TestFlash = MockFlash<1024, 4>;
+
+    /// Number of pages of the test flash.
+    const PAGES: usize = 4;
+    /// Upper bound of bytes any single operation under test programs or erases.
+    const MAX_CUTOFF: usize = 2 * PAGES * 1024;
+
+    /// Creates a flash whose pages have already been cycled through, so that further inserts
+    /// require garbage collection, and often enough for the erase counts to have been persisted.
+    fn populated_flash() -> TestFlash {
+        let mut flash = TestFlash::new(PAGES);
+        let range = flash.range();
+        block_on(async {
+            let mut storage = Storage::new(&mut flash, range);
+            storage.insert("flag", true).await.unwrap();
+            for i in 0..1000u32 {
+                storage.insert("counter", i).await.unwrap();
+            }
+            storage.insert("counter", 1u32).await.unwrap();
+        });
+        flash
+    }
+
+    /// Counts the items stored under `key`, including stale ones and tombstones.
+    async fn count_items(storage: &mut Storage<&mut TestFlash>, key: &str) -> usize {
+        let mut data_buffer = [0; DATA_BUFFER_SIZE];
+        let mut iter = fetch_all_items::<ItemKey, _, _>(
+            &mut storage.flash,
+            storage.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut data_buffer,
+        )
+        .await
+        .unwrap();
+
+        let mut data_buffer = [0; DATA_BUFFER_SIZE];
+        let mut count = 0;
+        while let Some((item_key, _)) = iter.next::<&[u8]>(&mut data_buffer).await.unwrap() {
+            if item_key.name() == key {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn insert_survives_power_loss() {
+        let populated = populated_flash();
+        let range = populated.range();
+
+        for cutoff in 0..=MAX_CUTOFF {
+            let mut flash = populated.clone();
+            flash.power_loss_after(cutoff);
+
+            let completed = block_on(async {
+                let result = Storage::new(&mut flash, range.clone())
+                    .insert("counter", 2u32)
+                    .await;
+                let completed = !flash.has_lost_power();
+                flash.power_cycle();
+
+                let mut storage = Storage::new(&mut flash, range.clone());
+                let counter = storage.get::<u32>("counter").await.unwrap();
+                if completed {
+                    assert!(result.is_ok());
+                    assert_eq!(counter, Some(2));
+                } else {
+                    assert!(
+                        matches!(counter, Some(1 | 2)),
+                        "cutoff {cutoff}: {counter:?}"
+                    );
+                }
+                assert_eq!(storage.get::<bool>("flag").await.unwrap(), Some(true));
+
+                storage.insert("counter", 3u32).await.unwrap();
+                assert_eq!(storage.get::<u32>("counter").await.unwrap(), Some(3));
+                completed
+            });
+
+            if completed {
+                return;
+            }
+        }
+        panic!("insert() did not complete within {MAX_CUTOFF} bytes");
+    }
+
+    #[test]
+    fn tombstone_removes_item() {
+        let mut flash = TestFlash::new(PAGES);
+        let range = flash.range();
+        block_on(async {
+            let mut storage = Storage::new(&mut flash, range);
+            storage.insert("removed", 42u32).await.unwrap();
+            storage.remove_with_tombstone("removed").await.unwrap();
+            assert_eq!(storage.get::<u32>("removed").await.unwrap(), None);
+
+            assert_eq!(count_items(&mut storage, "removed").await, 2);
+
+            // Rotating all pages drops the tombstone along with the value it shadowed.
+            for i in 0..400u32 {
+                storage.insert("counter", i).await.unwrap();
+            }
+            assert_eq!(storage.get::<u32>("removed").await.unwrap(), None);
+            assert_eq!(count_items(&mut storage, "removed").await, 0);
+
+            storage.insert("removed", 43u32).await.unwrap();
+            assert_eq!(storage.get::<u32>("removed").await.unwrap(), Some(43));
+
+            // Removing an absent key does not write anything.
+            let written = storage.flash.flash.bytes_written();
+            storage.remove_with_tombstone("absent").await.unwrap();
+            assert_eq!(storage.flash.flash.bytes_written(), written);
+        });
+    }
+
+    #[test]
+    fn zero_sized_values_read_as_removed() {
+        let mut flash = TestFlash::new(PAGES);
+        let range = flash.range();
+        block_on(async {
+            let mut storage = Storage::new(&mut flash, range);
+            storage.insert("unit", ()).await.unwrap();
+            // Serialized to zero bytes, the value is indistinguishable from a tombstone.
+            assert_eq!(storage.get::<()>("unit").await.unwrap(), None);
+            assert_eq!(count_items(&mut storage, "unit").await, 1);
+        });
+    }
+
+    #[test]
+    fn keys_lists_live_items() {
+        let mut flash = TestFlash::new(PAGES);
+        let range = flash.range();
+        block_on(async {
+            let mut storage = Storage::new(&mut flash, range);
+            storage.insert("first", 1u8).await.unwrap();
+            storage.insert("removed", 2u8).await.unwrap();
+            storage.insert("first", 3u8).await.unwrap();
+            storage.insert("second", 4u8).await.unwrap();
+            storage.insert("ARIEL_INTERNAL", 5u8).await.unwrap();
+            storage.remove_with_tombstone("removed").await.unwrap();
+
+            let keys = storage.keys::<8>().await.unwrap();
+            let keys: Vec<&str> = keys.iter().map(ArrayString::as_str).collect();
+            assert_eq!(keys, ["first", "second"]);
+
+            let keys = storage.keys::<1>().await.unwrap();
+            let keys: Vec<&str> = keys.iter().map(ArrayString::as_str).collect();
+            assert_eq!(keys, ["first"]);
+        });
+    }
+
+    /// Asserts that the erase counts persisted by a freshly opened `storage` lag behind the
+    /// actual ones by at most the erases between two persists.
+    async fn assert_erase_counts_persisted(storage: &mut Storage<&mut TestFlash>) {
+        let persisted = storage.erase_counts().await.unwrap();
+        let physical = storage.flash.flash.erase_counts();
+        assert_eq!(persisted.len(), physical.len());
+
+        let mut lost = 0;
+        for (persisted, physical) in persisted.iter().zip(physical) {
+            assert!(persisted <= physical, "{persisted} > {physical}");
+            lost += physical - persisted;
+        }
+        assert!(lost <= WEAR_PERSIST_INTERVAL, "{lost} erases lost");
+    }
+
+    #[test]
+    fn stats_track_items_and_wear() {
+        static WARNINGS: AtomicU32 = AtomicU32::new(0);
+
+        let mut flash = populated_flash();
+        let range = flash.range();
+        block_on(async {
+            let mut storage = Storage::new(&mut flash, range.clone());
+            let stats = storage.stats().await.unwrap();
+            assert_eq!(stats.total_bytes, 4096);
+            assert!(stats.free_bytes < stats.total_bytes - stats.used_bytes);
+            // "flag", "counter", and the erase counts.
+            assert_eq!(stats.live_items, 3);
+            assert!(stats.stale_items > 0);
+            assert_erase_counts_persisted(&mut storage).await;
+
+            // Erases not persisted yet are lost when the instance goes away mid-run.
+            for i in 0..100u32 {
+                storage.insert("counter", i).await.unwrap();
+            }
+            drop(storage);
+            let mut storage = Storage::new(&mut flash, range);
+            assert_erase_counts_persisted(&mut storage).await;
+
+            storage.set_wear_warning(1, |_, _| {
+                WARNINGS.fetch_add(1, Ordering::Relaxed);
+            });
+            storage.erase_all().await.unwrap();
+            assert_eq!(WARNINGS.load(Ordering::Relaxed), PAGES as u32);
+
+            let stats = storage.stats().await.unwrap();
+            assert_eq!(stats.live_items, 1);
+            assert_eq!(stats.stale_items, 0);
+            assert_eq!(
+                stats.erase_counts.as_slice(),
+                storage.flash.flash.erase_counts()
+            );
+        });
+    }
+
+    #[test]
+    fn erase_all_survives_power_loss() {
+        let populated = populated_flash();
+        let range = populated.range();
+
+        for cutoff in 0..=MAX_CUTOFF {
+            let mut flash = populated.clone();
+            flash.power_loss_after(cutoff);
+
+            let completed = block_on(async {
+                let result = Storage::new(&mut flash, range.clone()).erase_all().await;
+                let completed = !flash.has_lost_power();
+                flash.power_cycle();
+
+                let mut storage = Storage::new(&mut flash, range.clone());
+                let in_progress = storage.erase_in_progress().await.unwrap();
+                if completed {
+                    assert!(result.is_ok());
+                    assert!(!in_progress);
+                } else {
+                    // Unless reported, the interrupted erase must not have touched any item.
+                    if !in_progress {
+                        assert_eq!(storage.get::<u32>("counter").await.unwrap(), Some(1));
+                        assert_eq!(storage.get::<bool>("flag").await.unwrap(), Some(true));
+                    }
+                    storage.erase_all().await.unwrap();
+                    assert!(!storage.erase_in_progress().await.unwrap());
+                }
+                assert_eq!(storage.stats().await.unwrap().live_items, 1);
+                assert_eq!(storage.get::<u32>("counter").await.unwrap(), None);
+                assert_eq!(storage.get::<bool>("flag").await.unwrap(), None);
+
+                storage.insert("counter", 3u32).await.unwrap();
+                assert_eq!(storage.get::<u32>("counter").await.unwrap(), Some(3));
+                completed
+            });
+
+            if completed {
+                return;
+            }
+        }
+        panic!("erase_all() did not complete within {MAX_CUTOFF} bytes");
+    }
+}
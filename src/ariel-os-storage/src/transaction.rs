@@ -0,0 +1,397 @@
+//! Atomic multi-key updates of a [`Storage`].
+//!
+//! A transaction is written to flash in three steps:
+//!
+//! 1. Each operation is *staged* into its own slot item (`ARIEL_TXN/<index>`), tagged with the
+//!    transaction id.
+//! 2. A *commit record* (`ARIEL_TXN`) is stored, holding the transaction id and the number of
+//!    staged operations.
+//!    Storing this single item is what makes the transaction take effect.
+//! 3. The staged operations are *applied* to their actual keys, after which their slots are
+//!    removed, and the commit record is marked as applied.
+//!
+//! If power is lost before step 2, the staged operations are never applied and are discarded.
+//! If power is lost after step 2, [`Storage::recover_transaction()`] applies them again; applying
+//! an operation twice has the same effect as applying it once, and slots that were already
+//! removed belong to operations that were already applied.
+use core::fmt::Write as _;
+
+use arrayvec::ArrayString;
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash};
+use sequential_storage::map::{SerializationError, Value as _};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{DATA_BUFFER_SIZE, MAX_KEY_LEN, PostcardValue, Storage, Tombstone};
+
+/// Maximum number of operations in a single [`Transaction`].
+pub const MAX_TRANSACTION_OPS: usize = 16;
+
+/// Key of the commit record.
+const COMMIT_KEY: &str = "ARIEL_TXN";
+/// Maximum length of the key of a slot holding a staged operation.
+const SLOT_KEY_LEN: usize = COMMIT_KEY.len() + 4;
+/// Size of the buffer holding a staged operation: its slot key, the transaction id, and the key
+/// and value of the operation, each with their length prefix.
+const STAGED_BUFFER_SIZE: usize = SLOT_KEY_LEN + 2 + 5 + MAX_KEY_LEN + 2 + DATA_BUFFER_SIZE + 2;
+/// Length prefix of keys serialized by [`sequential_storage`].
+const KEY_LENGTH_PREFIX: usize = 2;
+
+type StorageError<F> = sequential_storage::Error<<F as ErrorType>::Error>;
+
+/// Commit record, the presence of which makes a transaction take effect.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct CommitRecord {
+    /// Id of the last committed transaction.
+    id: u32,
+    /// Number of operations staged by that transaction.
+    ops: u8,
+    /// Whether the operations have all been applied.
+    applied: bool,
+}
+
+/// A staged operation: the transaction id, the key, and the serialized value or `None` for
+/// removals.
+type StagedOp<'a> = (u32, &'a str, Option<&'a [u8]>);
+
+fn slot_key(index: usize) -> ArrayString<SLOT_KEY_LEN> {
+    let mut key = ArrayString::new();
+    write!(key, "{COMMIT_KEY}/{index}").unwrap();
+    key
+}
+
+/// A set of inserts and removals applied atomically to a [`Storage`].
+///
+/// Created by [`Storage::transaction()`].
+/// Operations are staged in flash as they are added, but do not take effect until
+/// [`Transaction::commit()`] is called; dropping the [`Transaction`] discards them.
+///
+/// Example:
+///
+/// ```ignore
+/// let mut s = storage::lock().await;
+/// let mut txn = s.transaction().await?;
+/// txn.insert("wifi.ssid", ssid).await?;
+/// txn.insert("wifi.password", password).await?;
+/// txn.commit().await?;
+/// ```
+pub struct Transaction<'s, F> {
+    storage: &'s mut Storage<F>,
+    id: u32,
+    ops: usize,
+}
+
+impl<F: NorFlash> Transaction<'_, F> {
+    /// Stages storing a key-value pair.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`sequential_storage::Error::SerializationError`] if the value does not fit into
+    /// an item together with its key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key.len() > MAX_KEY_LEN`, or if more than [`MAX_TRANSACTION_OPS`] operations
+    /// are staged.
+    pub async fn insert<'d, V>(&mut self, key: &str, value: V) -> Result<(), StorageError<F>>
+    where
+        V: Serialize + Deserialize<'d> + Into<PostcardValue<V>>,
+    {
+        assert!(key.len() <= MAX_KEY_LEN);
+
+        // Ensure the value fits into an item of the regular data buffer size once applied.
+        let max_len = DATA_BUFFER_SIZE.saturating_sub(key.len() + KEY_LENGTH_PREFIX);
+        let mut value_buffer = [0; DATA_BUFFER_SIZE];
+        let value_buffer = value_buffer.get_mut(..max_len).unwrap();
+        let value: PostcardValue<V> = value.into();
+        let len = value
+            .serialize_into(value_buffer)
+            .map_err(sequential_storage::Error::SerializationError)?;
+
+        self.stage(key, Some(value_buffer.get(..len).unwrap()))
+            .await
+    }
+
+    /// Stages deleting an item.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key.len() > MAX_KEY_LEN`, or if more than [`MAX_TRANSACTION_OPS`] operations
+    /// are staged.
+    pub async fn remove(&mut self, key: &str) -> Result<(), StorageError<F>> {
+        assert!(key.len() <= MAX_KEY_LEN);
+
+        self.stage(key, None).await
+    }
+
+    /// Atomically applies all staged operations.
+    ///
+    /// Once the commit record has been stored, the transaction takes effect even if power is
+    /// lost before this returns: the remaining operations are then applied by
+    /// [`Storage::recover_transaction()`].
+    pub async fn commit(self) -> Result<(), StorageError<F>> {
+        if self.ops == 0 {
+            return Ok(());
+        }
+
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "bounded by MAX_TRANSACTION_OPS"
+        )]
+        let record = CommitRecord {
+            id: self.id,
+            ops: self.ops as u8,
+            applied: false,
+        };
+        self.storage.insert(COMMIT_KEY, record).await?;
+        self.storage.apply_transaction(record).await
+    }
+
+    async fn stage(&mut self, key: &str, value: Option<&[u8]>) -> Result<(), StorageError<F>> {
+        assert!(
+            self.ops < MAX_TRANSACTION_OPS,
+            "too many operations in a storage transaction"
+        );
+
+        let mut staged_buffer = [0; STAGED_BUFFER_SIZE];
+        let staged: StagedOp<'_> = (self.id, key, value);
+        let staged = postcard::to_slice(&staged, &mut staged_buffer)
+            .map_err(|_| sequential_storage::Error::ItemTooBig)?;
+
+        self.storage
+            .insert_with_buffer(&slot_key(self.ops), &&*staged, &mut [0; STAGED_BUFFER_SIZE])
+            .await?;
+        self.ops += 1;
+
+        Ok(())
+    }
+}
+
+impl<F: NorFlash> Storage<F> {
+    /// Starts a [`Transaction`] on this [`Storage`] instance.
+    ///
+    /// A transaction previously committed but not fully applied is first applied by calling
+    /// [`Storage::recover_transaction()`].
+    pub async fn transaction(&mut self) -> Result<Transaction<'_, F>, StorageError<F>> {
+        let id = match self.recover_transaction_record().await? {
+            Some(record) => record.id.wrapping_add(1),
+            None => 0,
+        };
+
+        Ok(Transaction {
+            storage: self,
+            id,
+            ops: 0,
+        })
+    }
+
+    /// Finishes applying a committed [`Transaction`] that was interrupted, e.g., by a power loss.
+    ///
+    /// Operations staged by a transaction that was not committed are discarded.
+    ///
+    /// Note: when using the global storage, this is automatically called by the Ariel OS
+    /// initialization code.
+    pub async fn recover_transaction(&mut self) -> Result<(), StorageError<F>> {
+        self.recover_transaction_record().await.map(|_| ())
+    }
+
+    /// Applies the last committed transaction if needed, and returns its commit record.
+    async fn recover_transaction_record(
+        &mut self,
+    ) -> Result<Option<CommitRecord>, StorageError<F>> {
+        let record = self.get::<CommitRecord>(COMMIT_KEY).await?;
+        match record {
+            Some(record) if !record.applied => self.apply_transaction(record).await?,
+            // Discard operations staged by a transaction that was not committed.
+            _ => self.clear_slots().await?,
+        }
+        Ok(record)
+    }
+
+    async fn apply_transaction(&mut self, record: CommitRecord) -> Result<(), StorageError<F>> {
+        for index in 0..usize::from(record.ops) {
+            let mut staged_buffer = [0; STAGED_BUFFER_SIZE];
+            let Some(staged) = self
+                .get_with_buffer(&slot_key(index), &mut staged_buffer)
+                .await?
+                .filter(|staged| !staged.is_empty())
+            else {
+                // The slot was removed after applying it, before power was lost.
+                continue;
+            };
+            let (id, key, value): StagedOp<'_> = postcard::from_bytes(staged).map_err(|_| {
+                sequential_storage::Error::SerializationError(SerializationError::InvalidData)
+            })?;
+            if id != record.id {
+                // Left over from an earlier transaction; cannot happen unless the slot was
+                // overwritten outside of a transaction.
+                continue;
+            }
+
+            match value {
+                Some(value) => self.insert_raw(key, value).await?,
+                None => self.insert_raw(key, Tombstone).await?,
+            }
+        }
+
+        self.clear_slots().await?;
+
+        self.insert(
+            COMMIT_KEY,
+            CommitRecord {
+                applied: true,
+                ..record
+            },
+        )
+        .await
+    }
+
+    /// Removes the slots of all staged operations, which keeps them from being copied on every
+    /// page rotation.
+    ///
+    /// All slots are checked, as a transaction that was not committed may have staged more
+    /// operations than the last committed one.
+    async fn clear_slots(&mut self) -> Result<(), StorageError<F>> {
+        let mut cleared = false;
+        for index in 0..MAX_TRANSACTION_OPS {
+            let mut staged_buffer = [0; STAGED_BUFFER_SIZE];
+            let staged = self
+                .get_with_buffer(&slot_key(index), &mut staged_buffer)
+                .await?;
+            if staged.is_some_and(|staged| !staged.is_empty()) {
+                self.insert_raw(&slot_key(index), Tombstone).await?;
+                cleared = true;
+            }
+        }
+
+        if cleared {
+            self.sweep_tombstones().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::{MAX_TRANSACTION_OPS, slot_key};
+    use crate::{
+        mock_flash::MockFlash,
+        storage::{Storage, Tombstone},
+    };
+
+    type TestFlash = MockFlash<1024, 4>;
+
+    const PAGES: usize = 4;
+
+    #[test]
+    fn uncommitted_transaction_is_discarded() {
+        let mut flash = TestFlash::new(PAGES);
+        let range = flash.range();
+        block_on(async {
+            let mut storage = Storage::new(&mut flash, range);
+            storage.insert("ssid", 1u32).await.unwrap();
+
+            let mut txn = storage.transaction().await.unwrap();
+            txn.insert("ssid", 2u32).await.unwrap();
+            txn.insert("password", 2u32).await.unwrap();
+            drop(txn);
+
+            storage.recover_transaction().await.unwrap();
+            assert_eq!(storage.get::<u32>("ssid").await.unwrap(), Some(1));
+            assert_eq!(storage.get::<u32>("password").await.unwrap(), None);
+
+            let mut txn = storage.transaction().await.unwrap();
+            txn.insert("password", 3u32).await.unwrap();
+            txn.remove("ssid").await.unwrap();
+            txn.commit().await.unwrap();
+            assert_eq!(storage.get::<u32>("ssid").await.unwrap(), None);
+            assert_eq!(storage.get::<u32>("password").await.unwrap(), Some(3));
+
+            // Applied operations do not linger in their slots.
+            for index in 0..MAX_TRANSACTION_OPS {
+                let slot = storage
+                    .get_raw::<Tombstone>(&slot_key(index))
+                    .await
+                    .unwrap();
+                assert!(slot.is_none(), "slot {index}");
+            }
+        });
+    }
+
+    #[test]
+    fn smaller_commit_clears_interrupted_slots() {
+        let mut flash = TestFlash::new(PAGES);
+        let range = flash.range();
+        block_on(async {
+            let mut storage = Storage::new(&mut flash, range.clone());
+            let mut txn = storage.transaction().await.unwrap();
+            for index in 0..4u32 {
+                txn.insert("staged", index).await.unwrap();
+            }
+            drop(txn);
+        });
+        flash.power_cycle();
+
+        block_on(async {
+            let mut storage = Storage::new(&mut flash, range);
+            let mut txn = storage.transaction().await.unwrap();
+            txn.insert("committed", 1u32).await.unwrap();
+            txn.commit().await.unwrap();
+
+            assert_eq!(storage.get::<u32>("staged").await.unwrap(), None);
+            assert_eq!(storage.get::<u32>("committed").await.unwrap(), Some(1));
+            for index in 0..MAX_TRANSACTION_OPS {
+                let slot = storage
+                    .get_raw::<Tombstone>(&slot_key(index))
+                    .await
+                    .unwrap();
+                assert!(slot.is_none(), "slot {index}");
+            }
+        });
+    }
+
+    #[test]
+    fn commit_survives_power_loss() {
+        let mut populated = TestFlash::new(PAGES);
+        let range = populated.range();
+        block_on(async {
+            let mut storage = Storage::new(&mut populated, range.clone());
+            storage.insert("ssid", 1u32).await.unwrap();
+            storage.insert("password", 1u32).await.unwrap();
+        });
+
+        for cutoff in 0.. {
+            let mut flash = populated.clone();
+            flash.power_loss_after(cutoff);
+
+            let completed = block_on(async {
+                let result = async {
+                    let mut storage = Storage::new(&mut flash, range.clone());
+                    let mut txn = storage.transaction().await?;
+                    txn.insert("ssid", 2u32).await?;
+                    txn.insert("password", 2u32).await?;
+                    txn.commit().await
+                }
+                .await;
+                let completed = !flash.has_lost_power();
+                flash.power_cycle();
+
+                let mut storage = Storage::new(&mut flash, range.clone());
+                storage.recover_transaction().await.unwrap();
+                let ssid = storage.get::<u32>("ssid").await.unwrap();
+                let password = storage.get::<u32>("password").await.unwrap();
+                assert_eq!(ssid, password, "cutoff {cutoff}");
+                if completed {
+                    assert!(result.is_ok());
+                    assert_eq!(ssid, Some(2));
+                }
+                completed
+            });
+
+            if completed {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Usage statistics and wear tracking of a [`Storage`](crate::storage::Storage).
+use arrayvec::ArrayVec;
+use embedded_storage_async::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+/// Maximum number of flash pages whose erase cycles are tracked.
+///
+/// Pages beyond this are not tracked; a warning is logged when the global storage or a storage
+/// partition has more pages.
+pub const MAX_WEAR_PAGES: usize = 16;
+
+/// Usage statistics of a [`Storage`](crate::storage::Storage), as returned by
+/// [`Storage::stats()`](crate::storage::Storage::stats).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Size of the flash range, in bytes.
+    pub total_bytes: u32,
+    /// Bytes taken up by items, live and stale alike, and by page metadata.
+    pub used_bytes: u32,
+    /// Bytes that can still be written before garbage collection needs to run.
+    ///
+    /// This excludes the page that is always kept free for garbage collection.
+    pub free_bytes: u32,
+    /// Number of items holding the current value of their key.
+    pub live_items: u32,
+    /// Number of items superseded by a later item with the same key, or marking a removed key.
+    ///
+    /// These are dropped by garbage collection.
+    pub stale_items: u32,
+    /// Number of times each page has been erased, indexed by page.
+    pub erase_counts: ArrayVec<u32, MAX_WEAR_PAGES>,
+}
+
+/// Hook called when the erase count of a page reaches a threshold, as configured with
+/// [`Storage::set_wear_warning()`](crate::storage::Storage::set_wear_warning).
+///
+/// It is passed the index of the page in the storage range, and its erase count.
+pub type WearWarningHook = fn(page: usize, erase_count: u32);
+
+/// Flash wrapper counting erase cycles per page.
+///
+/// Erases are counted in RAM, until they are persisted by the [`Storage`] and taken out.
+///
+/// [`Storage`]: crate::storage::Storage
+pub(crate) struct WearTrackingFlash<F> {
+    pub(crate) flash: F,
+    base: u32,
+    pending: [u32; MAX_WEAR_PAGES],
+}
+
+impl<F> WearTrackingFlash<F> {
+    pub(crate) const fn new(flash: F, base: u32) -> Self {
+        Self {
+            flash,
+            base,
+            pending: [0; MAX_WEAR_PAGES],
+        }
+    }
+
+    /// Returns the erase counts that have not been persisted yet.
+    pub(crate) fn pending(&self) -> [u32; MAX_WEAR_PAGES] {
+        self.pending
+    }
+
+    /// Adds to the erase counts that have not been persisted yet.
+    pub(crate) fn add_pending(&mut self, counts: &[u32; MAX_WEAR_PAGES]) {
+        for (pending, count) in self.pending.iter_mut().zip(counts) {
+            *pending = pending.saturating_add(*count);
+        }
+    }
+
+    /// Takes out erase counts that have been persisted.
+    pub(crate) fn remove_pending(&mut self, counts: &[u32; MAX_WEAR_PAGES]) {
+        for (pending, count) in self.pending.iter_mut().zip(counts) {
+            *pending = pending.saturating_sub(*count);
+        }
+    }
+}
+
+impl<F: ErrorType> ErrorType for WearTrackingFlash<F> {
+    type Error = F::Error;
+}
+
+impl<F: ReadNorFlash> ReadNorFlash for WearTrackingFlash<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<F: NorFlash> NorFlash for WearTrackingFlash<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        // Count before erasing, as an interrupted erase still wears the page.
+        for page_start in (from..to).step_by(F::ERASE_SIZE) {
+            let page = (page_start.saturating_sub(self.base)) as usize / F::ERASE_SIZE;
+            if let Some(pending) = self.pending.get_mut(page) {
+                *pending = pending.saturating_add(1);
+            }
+        }
+
+        self.flash.erase(from, to).await
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.flash.write(offset, bytes).await
+    }
+}
+
+impl<F: MultiwriteNorFlash> MultiwriteNorFlash for WearTrackingFlash<F> {}
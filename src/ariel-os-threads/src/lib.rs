@@ -89,6 +89,10 @@ pub const SCHED_PRIO_LEVELS: usize = THREAD_COUNT;
 /// The maximum number of concurrent threads that can be created.
 pub const THREAD_COUNT: usize = 16;
 
+/// Value the unused stack of a thread is filled with when it is set up (see
+/// [`Thread::stack_paint_init()`](thread::Thread::stack_paint_init)), to measure its usage.
+const STACK_PAINT: u8 = 0xCC;
+
 /// Number of processor cores.
 pub const CORE_COUNT: usize = {
     #[cfg(not(feature = "multi-core"))]
@@ -748,6 +752,51 @@ pub fn set_priority(thread_id: ThreadId, prio: RunqueueId) {
     SCHEDULER.with_mut(|mut scheduler| scheduler.set_priority(thread_id, prio));
 }
 
+/// Stack usage of a thread, see [`stack_usage()`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StackUsage {
+    /// Size of the stack, in bytes.
+    pub size: usize,
+    /// Most bytes of the stack used at any time since the thread was created.
+    pub used: usize,
+}
+
+/// Returns the stack usage of a thread.
+///
+/// The stack of each thread is filled with a known pattern when it is created; the usage is how
+/// much of it has been overwritten since.
+/// Stack that happened to be written with the pattern itself is not accounted for, so this is
+/// an estimate.
+///
+/// Returns `None` if this is not a valid thread.
+pub fn stack_usage(thread_id: ThreadId) -> Option<StackUsage> {
+    let (lowest, highest) = SCHEDULER.with(|scheduler| {
+        scheduler.is_valid_tid(thread_id).then(|| {
+            let thread = scheduler.get_unchecked(thread_id);
+            (thread.stack_lowest, thread.stack_highest)
+        })
+    })?;
+
+    // Stacks grow downwards, so the untouched part is at the lowest addresses.
+    let unused = (lowest..highest)
+        .take_while(|addr| {
+            // SAFETY: the address lies within the stack of a valid thread, which is a `'static`
+            // buffer. The thread may be writing to it concurrently, which is why this reads
+            // volatile; that can only affect the estimate.
+            let byte = unsafe {
+                core::ptr::read_volatile(core::ptr::with_exposed_provenance::<u8>(*addr))
+            };
+            byte == STACK_PAINT
+        })
+        .count();
+
+    Some(StackUsage {
+        size: highest - lowest,
+        used: highest - lowest - unused,
+    })
+}
+
 /// Returns the current thread's stack limits (lowest, highest).
 pub fn current_stack_limits() -> Option<(usize, usize)> {
     SCHEDULER.with_mut(|mut scheduler| {
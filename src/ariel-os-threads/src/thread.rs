@@ -76,13 +76,10 @@ impl Thread {
     /// - must only be called before the stack is active (within `arch::setup_stack()`).
     #[allow(dead_code, reason = "not used in all configurations")]
     pub(crate) unsafe fn stack_paint_init(&mut self, sp: usize) {
-        // Byte that's used to pain stacks.
-        const STACK_PAINT_COLOR: u8 = 0xCC;
-
         for pos in self.stack_lowest..sp {
             // SAFETY: Writing to the slice that was passed to `setup_stack()` is fine
             unsafe {
-                core::ptr::write_volatile(pos as *mut u8, STACK_PAINT_COLOR);
+                core::ptr::write_volatile(pos as *mut u8, crate::STACK_PAINT);
             }
         }
     }
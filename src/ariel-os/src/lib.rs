@@ -35,6 +35,9 @@ pub use ariel_os_coap as coap;
 pub use ariel_os_debug as debug;
 #[doc(inline)]
 pub use ariel_os_identity as identity;
+#[cfg(feature = "coap-ota")]
+#[doc(inline)]
+pub use ariel_os_ota as ota;
 #[doc(inline)]
 pub use ariel_os_power as power;
 #[cfg(feature = "random")]
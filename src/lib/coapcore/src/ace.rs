@@ -246,6 +246,20 @@ pub struct CwtClaimsSet<'a> {
     pub(crate) scope: &'a [u8],
 }
 
+/// The claims of a CWT that is used as an EDHOC authentication credential.
+///
+/// Only the claims that are evaluated are listed; the issuer is implied by the key the CWT is
+/// verified with.
+#[derive(minicbor::Decode, Debug)]
+#[cbor(map)]
+#[non_exhaustive]
+struct CredentialClaimsSet<'a> {
+    #[n(4)]
+    exp: Option<u64>,
+    #[b(8)]
+    cnf: Cnf<'a>,
+}
+
 /// A single CWT Claims Set Confirmation value.
 ///
 /// All possible variants are in the [CWT Confirmation Methods
@@ -293,7 +307,8 @@ struct OscoreInputMaterial<'a> {
 }
 
 impl OscoreInputMaterial<'_> {
-    /// Produces an OSCORE context from the ACE OSCORE inputs.
+    /// Produces an OSCORE context from the ACE OSCORE inputs, along with the material to persist
+    /// it (if it is small enough to be persisted).
     ///
     /// FIXME: When this errs and panics could need some clean-up: the same kind of error produces
     /// a panic in some and an error in
@@ -307,12 +322,20 @@ impl OscoreInputMaterial<'_> {
         nonce1: &[u8],
         nonce2: &[u8],
         sender_id: &[u8],
-        recipient_id: &[u8],
-    ) -> Result<liboscore::PrimitiveContext, CredentialError> {
+        recipient_id: COwn,
+    ) -> Result<
+        (
+            liboscore::PrimitiveContext,
+            Option<crate::persist::ContextMaterial>,
+        ),
+        CredentialError,
+    > {
         // We don't process the algorithm fields
-        let hkdf = liboscore::HkdfAlg::from_number(5)
+        const HKDF: i32 = 5;
+        const AEAD: i32 = 10;
+        let hkdf = liboscore::HkdfAlg::from_number(HKDF)
             .map_err(|_| CredentialErrorDetail::UnsupportedAlgorithm)?;
-        let aead = liboscore::AeadAlg::from_number(10)
+        let aead = liboscore::AeadAlg::from_number(AEAD)
             .map_err(|_| CredentialErrorDetail::UnsupportedAlgorithm)?;
 
         // This is the only really custom part of ACE-OSCORE; the rest is just passing around
@@ -337,14 +360,24 @@ impl OscoreInputMaterial<'_> {
             None, // context ID field not processed
             aead,
             sender_id,
-            recipient_id,
+            recipient_id.as_slice(),
         )
         // Unknown HKDF is probably the only case here.
         .map_err(|_| CredentialErrorDetail::UnsupportedAlgorithm)?;
 
+        let material = crate::persist::ContextMaterial::new(
+            HKDF,
+            AEAD,
+            self.ms,
+            &combined_salt,
+            sender_id,
+            recipient_id,
+        );
+
         // It is fresh because it is derived from.
-        Ok(liboscore::PrimitiveContext::new_from_fresh_material(
-            immutables,
+        Ok((
+            liboscore::PrimitiveContext::new_from_fresh_material(immutables),
+            material,
         ))
     }
 }
@@ -400,6 +433,7 @@ impl AceCborAuthzInfoResponse {
 /// * the request's `payload`
 /// * a list of recognized `authorities` (Authorization Servers) to authenticate the token,
 ///   the output of which is also later used to parse the token's scope.
+/// * the `time` provider, which is informed of the token's issue time once the token is verified
 /// * a random nonce2
 /// * a callback that, once the peer's recipient ID is known, chooses an own recipient ID
 ///   (because it's up to the pool of security contexts to pick one, and the peers can not pick
@@ -424,9 +458,18 @@ impl AceCborAuthzInfoResponse {
 pub(crate) fn process_acecbor_authz_info<GC: crate::GeneralClaims>(
     payload: &[u8],
     authorities: &impl crate::seccfg::ServerSecurityConfig<GeneralClaims = GC>,
+    time: &mut impl crate::time::TimeProvider,
     nonce2: [u8; OWN_NONCE_LEN],
     server_recipient_id: impl FnOnce(&[u8]) -> COwn,
-) -> Result<(AceCborAuthzInfoResponse, liboscore::PrimitiveContext, GC), CredentialError> {
+) -> Result<
+    (
+        AceCborAuthzInfoResponse,
+        liboscore::PrimitiveContext,
+        Option<crate::persist::ContextMaterial>,
+        GC,
+    ),
+    CredentialError,
+> {
     trace!("Processing authz_info {=[u8]:02x}", payload); // :02x could be :cbor
 
     let decoded: UnprotectedAuthzInfoPost = minicbor::decode(payload)?;
@@ -462,6 +505,7 @@ pub(crate) fn process_acecbor_authz_info<GC: crate::GeneralClaims>(
 
     let (processed, parsed) =
         authorities.decrypt_symmetric_token(&headers, aad_encoded.as_ref(), buffer)?;
+    time.past_trusted(parsed.iat);
 
     // Currently disabled because no formatting is available while there; works with
     // <https://codeberg.org/chrysn/minicbor-adapters/pulls/1>
@@ -477,11 +521,11 @@ pub(crate) fn process_acecbor_authz_info<GC: crate::GeneralClaims>(
 
     let ace_server_recipientid = server_recipient_id(ace_client_recipientid);
 
-    let derived = osc.derive(
+    let (derived, material) = osc.derive(
         nonce1,
         &nonce2,
         ace_client_recipientid,
-        ace_server_recipientid.as_slice(),
+        ace_server_recipientid,
     )?;
 
     let response = AceCborAuthzInfoResponse {
@@ -489,12 +533,14 @@ pub(crate) fn process_acecbor_authz_info<GC: crate::GeneralClaims>(
         ace_server_recipientid,
     };
 
-    Ok((response, derived, processed))
+    Ok((response, derived, material, processed))
 }
 
 /// Verifies an ACE token sent in an EAD3 by the rules of the `authorities`, and produces both the
 /// decrypted claims and the extracted EDHOC specific credential.
 ///
+/// Once the token is verified, its issue time is passed on to the `time` provider.
+///
 /// # Errors
 ///
 /// This produces errors if the input (which is typically received from the network) is
@@ -506,6 +552,7 @@ pub(crate) fn process_acecbor_authz_info<GC: crate::GeneralClaims>(
 pub(crate) fn process_edhoc_token<GeneralClaims>(
     ead3: &[u8],
     authorities: &impl crate::seccfg::ServerSecurityConfig<GeneralClaims = GeneralClaims>,
+    time: &mut impl crate::time::TimeProvider,
 ) -> Result<(lakers::Credential, GeneralClaims), CredentialError> {
     let mut buffer = heapless::Vec::<u8, MAX_SUPPORTED_ACCESSTOKEN_LEN>::new();
 
@@ -538,6 +585,7 @@ pub(crate) fn process_edhoc_token<GeneralClaims>(
     } else {
         return Err(CredentialErrorDetail::UnsupportedExtension.into());
     };
+    time.past_trusted(parsed.iat);
 
     let Cnf {
         osc: None,
@@ -567,3 +615,61 @@ pub(crate) fn process_edhoc_token<GeneralClaims>(
 
     Ok((credential, processed))
 }
+
+/// Verifies a CWT that a peer presented as its EDHOC credential (`kcwt`) against the ES256 key
+/// of the issuer.
+///
+/// Returns the `x` coordinate of the P-256 key the CWT confirms, along with the CWT's expiry
+/// time, if any.
+///
+/// # Errors
+///
+/// This produces errors if the input (which is typically received from the network) is
+/// malformed, contains unsupported items, or is not signed by the issuer.
+pub(crate) fn verify_cwt_credential(
+    cwt: &[u8],
+    issuer: &p256::ecdsa::VerifyingKey,
+) -> Result<(lakers::BytesP256ElemLen, Option<u64>), CredentialError> {
+    use p256::ecdsa::signature::Verifier as _;
+
+    let sign1: SignedCwt = minicbor::decode(cwt)?;
+    let protected: HeaderMap = minicbor::decode(sign1.protected)?;
+    if sign1.unprotected.updated_with(&protected).alg != Some(crate::iana::cose_alg::ES256) {
+        return Err(CredentialErrorDetail::UnsupportedAlgorithm.into());
+    }
+
+    let aad = SigStructureForSignature1 {
+        context: "Signature1",
+        body_protected: sign1.protected,
+        external_aad: &[],
+        payload: sign1.payload,
+    };
+    let mut buffer = heapless::Vec::<u8, MAX_SUPPORTED_ACCESSTOKEN_LEN>::new();
+    minicbor::encode(&aad, minicbor_adapters::WriteToHeapless(&mut buffer))?;
+    let signature = p256::ecdsa::Signature::from_slice(sign1.signature)
+        .map_err(|_| CredentialErrorDetail::InconsistentDetails)?;
+    issuer
+        .verify(&buffer, &signature)
+        .map_err(|_| CredentialErrorDetail::VerifyFailed)?;
+
+    let claims: CredentialClaimsSet = minicbor::decode(sign1.payload)?;
+    let Cnf {
+        osc: None,
+        cose_key: Some(cose_key),
+    } = claims.cnf
+    else {
+        return Err(CredentialErrorDetail::InconsistentDetails.into());
+    };
+    // EC2 on P-256
+    if cose_key.parsed.kty != 2 || cose_key.parsed.crv != Some(1) {
+        return Err(CredentialErrorDetail::UnsupportedAlgorithm.into());
+    }
+    let x = cose_key
+        .parsed
+        .x
+        .ok_or(CredentialErrorDetail::InconsistentDetails)?
+        .try_into()
+        .map_err(|_| CredentialErrorDetail::InconsistentDetails)?;
+
+    Ok((x, claims.exp))
+}
@@ -0,0 +1,903 @@
+//! The client side of OSCORE/EDHOC.
+//!
+//! An [`OscoreEdhocClient`] runs EDHOC as an initiator against a server whose credential it was
+//! configured with, keeps the resulting OSCORE security contexts, and protects outgoing requests
+//! (and unprotects their responses) transparently.
+//!
+//! Unlike the server side, which is a [`coap_handler::Handler`] that any CoAP stack can drive,
+//! the client needs to send several messages in sequence (EDHOC message 1 before the first
+//! OSCORE request); the CoAP stack is abstracted into a [`ClientTransport`] for that purpose.
+//!
+//! The request that carries EDHOC message 3 is the first OSCORE request, using the EDHOC option
+//! ([RFC9668](https://www.rfc-editor.org/rfc/rfc9668)), which the [`OscoreEdhocHandler`] expects.
+//!
+//! The keys of established contexts are updated as described in the [`kudos`](crate::kudos)
+//! module.
+//!
+//! The EDHOC cipher suite is set through [`OscoreEdhocClient::with_cipher_suite()`], and determines
+//! the OSCORE algorithms (RFC9528 Appendix A.1); there is no negotiation with the server.
+//!
+//! [`OscoreEdhocHandler`]: crate::OscoreEdhocHandler
+
+use coap_message::{
+    MessageOption as _, MinimalWritableMessage, MutableWritableMessage, ReadableMessage,
+};
+use coap_message_implementations::inmemory_write::Message;
+use defmt_or_log::{debug, error, trace};
+
+use crate::helpers::COwn;
+use crate::kudos::{KeyUpdate, OscoreOption};
+use crate::persist::ContextMaterial;
+
+/// Number of security contexts an [`OscoreEdhocClient`] keeps at a time.
+const MAX_CLIENT_CONTEXTS: usize = 2;
+const _MAX_CLIENT_CONTEXTS_CHECK: () = assert!(MAX_CLIENT_CONTEXTS <= COwn::GENERATABLE_VALUES);
+
+/// Size of the buffers messages are built in.
+///
+/// This is sized to fit an EDHOC message 3 along with a small OSCORE request.
+pub const CLIENT_MESSAGE_BUFFER_SIZE: usize = 1152;
+
+/// A pool of client security contexts.
+type ClientContextPool<Crypto> =
+    crate::oluru::OrderedPool<ClientContextState<Crypto>, MAX_CLIENT_CONTEXTS, LEVEL_COUNT>;
+
+const LEVEL_ESTABLISHED: usize = 0;
+const LEVEL_EMPTY: usize = 1;
+const LEVEL_COUNT: usize = 2;
+
+/// A transport over which an [`OscoreEdhocClient`] exchanges messages with a single server.
+///
+/// Implementations send the request (which may be read through its [`ReadableMessage`]
+/// implementation) and write the server's response into `response`.
+pub trait ClientTransport {
+    /// Error that occurred while sending the request or awaiting the response.
+    type Error: core::fmt::Debug;
+
+    /// Sends a request and writes the corresponding response into `response`.
+    fn exchange(
+        &mut self,
+        request: &Message<'_>,
+        response: &mut Message<'_>,
+    ) -> impl core::future::Future<Output = Result<(), Self::Error>>;
+}
+
+/// Error returned by [`OscoreEdhocClient::request()`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ClientError<E> {
+    /// The transport failed to exchange a message.
+    Transport(E),
+    /// The EDHOC key exchange with the server failed.
+    Edhoc,
+    /// The server responded with an error to an EDHOC message.
+    EdhocRejected {
+        /// Code of the server's response.
+        code: u8,
+    },
+    /// The request could not be protected, or the response could not be unprotected.
+    Oscore,
+    /// A message did not fit into [`CLIENT_MESSAGE_BUFFER_SIZE`].
+    MessageTooLarge,
+}
+
+struct ClientContextState<Crypto: lakers::Crypto> {
+    /// Credential of the server, identifying the context.
+    peer: Option<lakers::Credential>,
+    stage: ClientContextStage<Crypto>,
+}
+
+impl<Crypto: lakers::Crypto> Default for ClientContextState<Crypto> {
+    fn default() -> Self {
+        Self {
+            peer: None,
+            stage: ClientContextStage::Empty,
+        }
+    }
+}
+
+#[expect(
+    clippy::large_enum_variant,
+    reason = "same trade-off as with the server's security context pool"
+)]
+enum ClientContextStage<Crypto: lakers::Crypto> {
+    Empty,
+    Oscore {
+        context: liboscore::PrimitiveContext,
+        /// EDHOC message 3, to be sent along with OSCORE requests until the server has responded
+        /// to one of them.
+        message_3: Option<lakers::EdhocMessageBuffer>,
+        own_id: COwn,
+        /// Input material of the context, if its keys can be updated.
+        material: Option<ContextMaterial>,
+        /// Set when the keys are to be updated with the next request.
+        key_update: bool,
+        _crypto: core::marker::PhantomData<Crypto>,
+    },
+}
+
+impl<Crypto: lakers::Crypto> crate::oluru::PriorityLevel for ClientContextState<Crypto> {
+    fn level(&self) -> usize {
+        match self.stage {
+            ClientContextStage::Empty => LEVEL_EMPTY,
+            ClientContextStage::Oscore { .. } => LEVEL_ESTABLISHED,
+        }
+    }
+}
+
+impl<Crypto: lakers::Crypto> ClientContextState<Crypto> {
+    fn is_for(&self, peer: &lakers::Credential) -> bool {
+        self.peer
+            .as_ref()
+            .is_some_and(|p| p.bytes.as_slice() == peer.bytes.as_slice())
+    }
+
+    fn own_id(&self) -> Option<COwn> {
+        match &self.stage {
+            ClientContextStage::Empty => None,
+            ClientContextStage::Oscore { own_id, .. } => Some(*own_id),
+        }
+    }
+}
+
+/// A CoAP client that protects its requests with OSCORE, establishing security contexts with
+/// EDHOC.
+///
+/// This is the client counterpart to [`OscoreEdhocHandler`](crate::OscoreEdhocHandler).
+pub struct OscoreEdhocClient<Crypto: lakers::Crypto, CryptoFactory: Fn() -> Crypto> {
+    pool: ClientContextPool<Crypto>,
+    own_credential: (lakers::Credential, lakers::BytesP256ElemLen),
+    crypto_factory: CryptoFactory,
+    cipher_suite: lakers::EDHOCSuite,
+}
+
+impl<Crypto: lakers::Crypto, CryptoFactory: Fn() -> Crypto>
+    OscoreEdhocClient<Crypto, CryptoFactory>
+{
+    /// Creates a new client presenting the given credential (with its private key) to servers.
+    ///
+    /// As with the [`OscoreEdhocHandler`](crate::OscoreEdhocHandler), the crypto factory can come
+    /// from the `lakers_crypto_rustcrypto::Crypto` or any more specialized hardware based
+    /// implementation.
+    ///
+    /// The client uses EDHOC cipher suite 2 unless set otherwise through
+    /// [`with_cipher_suite()`](Self::with_cipher_suite).
+    pub fn new(
+        own_credential: lakers::Credential,
+        own_key: lakers::BytesP256ElemLen,
+        crypto_factory: CryptoFactory,
+    ) -> Self {
+        Self {
+            pool: crate::oluru::OrderedPool::new(),
+            own_credential: (own_credential, own_key),
+            crypto_factory,
+            cipher_suite: lakers::EDHOCSuite::CipherSuite2,
+        }
+    }
+
+    /// Sets the EDHOC cipher suite offered to servers, which also determines the OSCORE
+    /// algorithms of the established contexts.
+    ///
+    /// A server that does not support the suite rejects EDHOC message 1, which makes
+    /// [`request()`](Self::request) fail with [`ClientError::EdhocRejected`]; there is no
+    /// fallback to other suites.
+    #[must_use]
+    pub fn with_cipher_suite(self, cipher_suite: lakers::EDHOCSuite) -> Self {
+        Self {
+            cipher_suite,
+            ..self
+        }
+    }
+
+    /// Sends a request protected with OSCORE to the server presenting the `peer` credential, and
+    /// processes its response.
+    ///
+    /// If no security context is established with that server, EDHOC is run first.
+    ///
+    /// `build_request` populates the request's code, options and payload; `process_response`
+    /// reads the unprotected response and produces the output of this function.
+    ///
+    /// # Errors
+    ///
+    /// This errs if the transport fails, if EDHOC fails, or if the response cannot be
+    /// unprotected. In any of these cases, the security context is discarded, and will be
+    /// established again at the next request.
+    pub async fn request<T: ClientTransport, R>(
+        &mut self,
+        transport: &mut T,
+        peer: &lakers::Credential,
+        build_request: impl FnOnce(&mut liboscore::ProtectedMessage),
+        process_response: impl FnOnce(&liboscore::ProtectedMessage) -> R,
+    ) -> Result<R, ClientError<T::Error>> {
+        let state = match self.pool.lookup(|c| c.is_for(peer), core::mem::take) {
+            Some(state) => state,
+            None => self.run_edhoc(transport, peer).await?,
+        };
+
+        let ClientContextState {
+            peer: Some(peer),
+            stage:
+                ClientContextStage::Oscore {
+                    mut context,
+                    message_3,
+                    own_id,
+                    mut material,
+                    key_update,
+                    _crypto,
+                },
+        } = state
+        else {
+            unreachable!("only established contexts are looked up or created");
+        };
+
+        // Keys are updated only once the server has processed message 3.
+        let request_update = (message_3.is_none()
+            && material.is_some()
+            && (key_update
+                || context.sender_sequence_number() >= crate::kudos::SEQUENCE_NUMBER_THRESHOLD))
+            .then(|| {
+                let mut crypto = (self.crypto_factory)();
+                let mut nonce = [0; crate::kudos::NONCE_LEN];
+                for byte in &mut nonce {
+                    *byte = crypto.get_random_byte();
+                }
+                KeyUpdate::new(nonce)
+            });
+        // A request starting a key update is protected with an intermediate context.
+        let mut intermediate_context = match (&request_update, &material) {
+            (Some(request_update), Some(material)) => Some(
+                request_update
+                    .intermediate(material)
+                    .and_then(|intermediate| intermediate.derive().ok())
+                    .ok_or(ClientError::Oscore)?,
+            ),
+            _ => None,
+        };
+
+        let mut request_code = 0;
+        let mut request_buffer = [0u8; CLIENT_MESSAGE_BUFFER_SIZE];
+        let mut request = Message::new(&mut request_code, &mut request_buffer[..]);
+        let (mut correlation, ()) = liboscore::protect_request(
+            &mut request,
+            intermediate_context.as_mut().unwrap_or(&mut context),
+            build_request,
+        )
+        .map_err(|_| {
+            error!("Request could not be protected.");
+            ClientError::Oscore
+        })?;
+
+        let mut combined_code = 0;
+        let mut combined_buffer = [0u8; CLIENT_MESSAGE_BUFFER_SIZE];
+        let mut combined = Message::new(&mut combined_code, &mut combined_buffer[..]);
+        let request = if let Some(message_3) = &message_3 {
+            trace!("Sending EDHOC message 3 along with the OSCORE request");
+            with_edhoc_option(&request, message_3.as_slice(), &mut combined)?;
+            &combined
+        } else if let Some(request_update) = &request_update {
+            trace!("Updating the keys of the security context with the OSCORE request");
+            crate::kudos::copy_with_key_update(&request, request_update, &mut combined)
+                .map_err(|()| ClientError::MessageTooLarge)?;
+            &combined
+        } else {
+            &request
+        };
+
+        let mut response_code = 0;
+        let mut response_buffer = [0u8; CLIENT_MESSAGE_BUFFER_SIZE];
+        let mut response = Message::new(&mut response_code, &mut response_buffer[..]);
+        transport
+            .exchange(request, &mut response)
+            .await
+            .map_err(ClientError::Transport)?;
+
+        let oscore_option: Option<OscoreOption> = response
+            .options()
+            .find(|o| o.number() == coap_numbers::option::OSCORE)
+            .and_then(|o| o.value().try_into().ok());
+        let Some(oscore_option) = oscore_option else {
+            // Most likely an error from the server that could not find our context; starting over
+            // with EDHOC at the next request.
+            error!("Response is not protected with OSCORE, discarding security context.");
+            return Err(ClientError::Oscore);
+        };
+        let (oscore_option, response_update) =
+            crate::kudos::split_option(&oscore_option).map_err(|_| {
+                error!("OSCORE option uses unsupported extensions");
+                ClientError::Oscore
+            })?;
+        match (&request_update, response_update) {
+            (None, None) => (),
+            (Some(request_update), Some(response_update)) => {
+                // The response is protected with the new context already.
+                let updated = material
+                    .as_ref()
+                    .and_then(|old| KeyUpdate::combined(request_update, &response_update, old))
+                    .ok_or(ClientError::Oscore)?;
+                context = updated.derive().map_err(|_| ClientError::Oscore)?;
+                material = Some(updated);
+                debug!("Updated the keys of the security context.");
+            }
+            _ => {
+                error!("Response does not match the key update, discarding security context.");
+                return Err(ClientError::Oscore);
+            }
+        }
+        let oscore_option = liboscore::OscoreOption::parse(&oscore_option).map_err(|_| {
+            error!("OSCORE option could not be parsed");
+            ClientError::Oscore
+        })?;
+
+        let output = liboscore::unprotect_response(
+            &mut response,
+            &mut context,
+            oscore_option,
+            &mut correlation,
+            process_response,
+        )
+        .map_err(|_| {
+            error!("Response could not be unprotected, discarding security context.");
+            ClientError::Oscore
+        })?;
+
+        // The server has processed message 3 (and completed any key update) once it produced a
+        // protected response.
+        let _evicted = self.pool.force_insert(ClientContextState {
+            peer: Some(peer),
+            stage: ClientContextStage::Oscore {
+                context,
+                message_3: None,
+                own_id,
+                material,
+                key_update: false,
+                _crypto,
+            },
+        });
+
+        Ok(output)
+    }
+
+    /// Updates the keys of the security context established with the server presenting the `peer`
+    /// credential, along with the next request sent to it.
+    ///
+    /// This happens on its own once the sender sequence number of the context reaches
+    /// [`SEQUENCE_NUMBER_THRESHOLD`](crate::kudos::SEQUENCE_NUMBER_THRESHOLD). It returns false if
+    /// there is no such context, or its keys can not be updated.
+    pub fn update_key(&mut self, peer: &lakers::Credential) -> bool {
+        self.pool
+            .lookup(
+                |c| c.is_for(peer),
+                |c| match &mut c.stage {
+                    ClientContextStage::Oscore {
+                        material: Some(_),
+                        key_update,
+                        ..
+                    } => {
+                        *key_update = true;
+                        true
+                    }
+                    _ => false,
+                },
+            )
+            .unwrap_or(false)
+    }
+
+    /// Runs EDHOC as initiator with the server presenting the `peer` credential, up to producing
+    /// message 3, and returns the resulting security context.
+    async fn run_edhoc<T: ClientTransport>(
+        &mut self,
+        transport: &mut T,
+        peer: &lakers::Credential,
+    ) -> Result<ClientContextState<Crypto>, ClientError<T::Error>> {
+        let c_i = COwn::not_in_iter(self.pool.iter().filter_map(|entry| entry.own_id()));
+
+        let (hkdf_number, aead_number) =
+            oscore_algorithms(self.cipher_suite).ok_or(ClientError::Edhoc)?;
+
+        trace!("Starting EDHOC as initiator");
+        let (initiator, message_1) = lakers::EdhocInitiator::new(
+            (self.crypto_factory)(),
+            lakers::EDHOCMethod::StatStat,
+            self.cipher_suite,
+        )
+        .prepare_message_1(Some(c_i.into()), &None)
+        .map_err(edhoc_error)?;
+
+        let mut request_code = 0;
+        let mut request_buffer = [0u8; CLIENT_MESSAGE_BUFFER_SIZE];
+        let mut request = Message::new(&mut request_code, &mut request_buffer[..]);
+        request.set_code(coap_numbers::code::POST);
+        request
+            .add_option(coap_numbers::option::URI_PATH, b".well-known")
+            .and_then(|()| request.add_option(coap_numbers::option::URI_PATH, b"edhoc"))
+            .map_err(|_| ClientError::MessageTooLarge)?;
+        // Forward flow: message 1 is prefixed with CBOR `true` instead of a connection identifier.
+        let payload = request
+            .payload_mut_with_len(1 + message_1.as_slice().len())
+            .map_err(|_| ClientError::MessageTooLarge)?;
+        let (first_byte, rest) = payload
+            .split_first_mut()
+            .expect("length was requested to be at least 1");
+        *first_byte = 0xf5;
+        rest.copy_from_slice(message_1.as_slice());
+
+        let mut response_code = 0;
+        let mut response_buffer = [0u8; CLIENT_MESSAGE_BUFFER_SIZE];
+        let mut response = Message::new(&mut response_code, &mut response_buffer[..]);
+        transport
+            .exchange(&request, &mut response)
+            .await
+            .map_err(ClientError::Transport)?;
+
+        let code: u8 = response.code().into();
+        if code != coap_numbers::code::CHANGED {
+            error!("EDHOC message 1 was answered with code {}", code);
+            return Err(ClientError::EdhocRejected { code });
+        }
+        let message_2 = lakers::EdhocMessageBuffer::new_from_slice(response.payload())
+            .map_err(|_| ClientError::MessageTooLarge)?;
+
+        let (mut initiator, c_r, id_cred_r, ead_2) =
+            initiator.parse_message_2(&message_2).map_err(edhoc_error)?;
+        if ead_2.is_some_and(|e| e.is_critical) {
+            error!("Critical EAD2 item received, aborting");
+            return Err(ClientError::Edhoc);
+        }
+
+        let cred_r =
+            lakers::credential_check_or_fetch(Some(*peer), id_cred_r).map_err(edhoc_error)?;
+        initiator
+            .set_identity(self.own_credential.1, self.own_credential.0)
+            .map_err(edhoc_error)?;
+        let initiator = initiator.verify_message_2(cred_r).map_err(edhoc_error)?;
+
+        // Sending our credential by reference, as the server is expected to know it (that is the
+        // only way it would authorize us anyway).
+        let (initiator, message_3, _prk_out) = initiator
+            .prepare_message_3(lakers::CredentialTransfer::ByReference, &None)
+            .map_err(edhoc_error)?;
+        let mut initiator = initiator
+            .completed_without_message_4()
+            .map_err(edhoc_error)?;
+
+        let oscore_secret = initiator.edhoc_exporter(0u8, &[], 16); // label is 0
+        let oscore_salt = initiator.edhoc_exporter(1u8, &[], 8); // label is 1
+        let oscore_secret = &oscore_secret[..16];
+        let oscore_salt = &oscore_salt[..8];
+
+        let hkdf = liboscore::HkdfAlg::from_number(hkdf_number).map_err(|_| ClientError::Oscore)?;
+        let aead = liboscore::AeadAlg::from_number(aead_number).map_err(|_| ClientError::Oscore)?;
+
+        let immutables = liboscore::PrimitiveImmutables::derive(
+            hkdf,
+            oscore_secret,
+            oscore_salt,
+            None,
+            aead,
+            c_r.as_slice(),
+            c_i.as_slice(),
+        )
+        .map_err(|_| ClientError::Oscore)?;
+
+        let material = ContextMaterial::new(
+            hkdf_number,
+            aead_number,
+            oscore_secret,
+            oscore_salt,
+            c_r.as_slice(),
+            c_i,
+        );
+
+        debug!(
+            "Established OSCORE context with sender ID {:?} through EDHOC",
+            c_r.as_slice()
+        );
+
+        Ok(ClientContextState {
+            peer: Some(*peer),
+            stage: ClientContextStage::Oscore {
+                context: liboscore::PrimitiveContext::new_from_fresh_material(immutables),
+                message_3: Some(message_3),
+                own_id: c_i,
+                material,
+                key_update: false,
+                _crypto: core::marker::PhantomData,
+            },
+        })
+    }
+}
+
+/// Returns the OSCORE HKDF and AEAD algorithms (as COSE algorithm numbers) that go with an EDHOC
+/// cipher suite: the suite's application AEAD, and HKDF with its hash algorithm.
+fn oscore_algorithms(cipher_suite: lakers::EDHOCSuite) -> Option<(i32, i32)> {
+    // Suite 2 is AES-CCM-16-64-128 and SHA-256 (RFC9528 Section 10.2).
+    matches!(cipher_suite, lakers::EDHOCSuite::CipherSuite2).then_some((
+        crate::iana::cose_alg::HKDF_HMAC256256,
+        crate::iana::cose_alg::AES_CCM_16_64_128,
+    ))
+}
+
+/// Copies the OSCORE `request` into `combined`, adding the EDHOC option and prefixing the payload
+/// with EDHOC message 3 as per RFC9668 Section 3.2.
+fn with_edhoc_option<E>(
+    request: &Message<'_>,
+    message_3: &[u8],
+    combined: &mut Message<'_>,
+) -> Result<(), ClientError<E>> {
+    combined.set_code(request.code().into());
+
+    let mut edhoc_added = false;
+    for opt in request.options() {
+        if !edhoc_added && opt.number() > coap_numbers::option::EDHOC {
+            combined
+                .add_option(coap_numbers::option::EDHOC, b"")
+                .map_err(|_| ClientError::MessageTooLarge)?;
+            edhoc_added = true;
+        }
+        combined
+            .add_option(opt.number(), opt.value())
+            .map_err(|_| ClientError::MessageTooLarge)?;
+    }
+    if !edhoc_added {
+        combined
+            .add_option(coap_numbers::option::EDHOC, b"")
+            .map_err(|_| ClientError::MessageTooLarge)?;
+    }
+
+    // EDHOC message 3 is a CBOR byte string itself, so the OSCORE payload can follow right away.
+    let oscore_payload = request.payload();
+    let payload = combined
+        .payload_mut_with_len(message_3.len() + oscore_payload.len())
+        .map_err(|_| ClientError::MessageTooLarge)?;
+    let (payload_message_3, payload_oscore) = payload.split_at_mut(message_3.len());
+    payload_message_3.copy_from_slice(message_3);
+    payload_oscore.copy_from_slice(oscore_payload);
+
+    Ok(())
+}
+
+/// Logs a [`lakers::EDHOCError`] and converts it into a [`ClientError`].
+#[track_caller]
+#[expect(
+    clippy::needless_pass_by_value,
+    reason = "ergonomics at the call sites need this"
+)]
+fn edhoc_error<E>(e: lakers::EDHOCError) -> ClientError<E> {
+    error!(
+        "EDHOC as initiator failed: {:?}",
+        defmt_or_log::Debug2Format(&e)
+    );
+    ClientError::Edhoc
+}
+
+#[cfg(test)]
+mod tests {
+    use coap_handler::Handler;
+    use coap_message::{
+        MinimalWritableMessage, MutableWritableMessage, ReadableMessage,
+        error::RenderableOnMinimal as _,
+    };
+    use coap_message_implementations::inmemory_write::Message;
+    use embassy_futures::block_on;
+    use hexlit::hex;
+
+    use super::{ClientTransport, OscoreEdhocClient};
+
+    /// Credential of the server (the demo device credential of Ariel OS).
+    const SERVER_CREDENTIAL: &[u8] = &hex!(
+        "A2026008A101A5010202410A2001215820BBC34960526EA4D32E940CAD2A234148DDC21791A12AFBCBAC93622046DD44F02258204519E257236B2A0CE2023F0931F1F386CA7AFDA64FCDE0108C224C51EABF6072"
+    );
+    const SERVER_KEY: [u8; 32] =
+        hex!("72cc4761dbd4c78f758931aa589d348d1ef874a7e303ede2f140dcf3e6aa4aac");
+    /// Credential of the client (the demo administrator credential of Ariel OS).
+    const CLIENT_CREDENTIAL: &[u8] = &hex!(
+        "A2027734322D35302D33312D46462D45462D33372D33322D333908A101A5010202412B2001215820AC75E9ECE3E50BFC8ED60399889522405C47BF16DF96660A41298CB4307F7EB62258206E5DE611388A4B8A8211334AC7D37ECB52A387D257E6DB3C2A93DF21FF3AFFC8"
+    );
+    const CLIENT_KEY: [u8; 32] =
+        hex!("fb13adeb6518cee5f88417660841142e830a81fe334380a953406a1305e8706b");
+
+    /// Deterministic RNG; good enough for exercising the protocol.
+    struct TestRng(u64);
+
+    impl rand_core::RngCore for TestRng {
+        fn next_u32(&mut self) -> u32 {
+            rand_core::impls::next_u32_via_fill(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            // xorshift64
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            rand_core::impls::fill_bytes_via_next(self, dest);
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl rand_core::CryptoRng for TestRng {}
+
+    /// Resource responding to any request with a fixed payload.
+    struct Hello;
+
+    impl Handler for Hello {
+        type RequestData = ();
+        type ExtractRequestError = coap_message_utils::Error;
+        type BuildResponseError<M: MinimalWritableMessage> = M::UnionError;
+
+        fn extract_request_data<M: ReadableMessage>(
+            &mut self,
+            _request: &M,
+        ) -> Result<(), Self::ExtractRequestError> {
+            Ok(())
+        }
+
+        fn estimate_length(&mut self, _request: &()) -> usize {
+            16
+        }
+
+        fn build_response<M: MutableWritableMessage>(
+            &mut self,
+            response: &mut M,
+            _request: (),
+        ) -> Result<(), Self::BuildResponseError<M>> {
+            response.set_code(M::Code::new(coap_numbers::code::CONTENT)?);
+            response.set_payload(b"Hello")?;
+            Ok(())
+        }
+    }
+
+    /// Transport passing requests right into a server side handler.
+    struct Loopback<H>(H);
+
+    impl<H: Handler> ClientTransport for Loopback<H> {
+        type Error = ();
+
+        async fn exchange(
+            &mut self,
+            request: &Message<'_>,
+            response: &mut Message<'_>,
+        ) -> Result<(), ()> {
+            match self.0.extract_request_data(request) {
+                Ok(data) => self.0.build_response(response, data).map_err(|_| ()),
+                Err(e) => e.render(response).map_err(|_| ()),
+            }
+        }
+    }
+
+    /// Transport that can lose a response, after which the request is sent again.
+    struct Retransmitting<H> {
+        inner: Loopback<H>,
+        lose_next_response: bool,
+    }
+
+    impl<H: Handler> ClientTransport for Retransmitting<H> {
+        type Error = ();
+
+        async fn exchange(
+            &mut self,
+            request: &Message<'_>,
+            response: &mut Message<'_>,
+        ) -> Result<(), ()> {
+            if core::mem::take(&mut self.lose_next_response) {
+                let mut lost_code = 0;
+                let mut lost_buffer = [0u8; super::CLIENT_MESSAGE_BUFFER_SIZE];
+                let mut lost = Message::new(&mut lost_code, &mut lost_buffer[..]);
+                self.inner.exchange(request, &mut lost).await?;
+            }
+            self.inner.exchange(request, response).await
+        }
+    }
+
+    #[test]
+    fn request_through_edhoc_and_oscore() {
+        let server_credential = lakers::Credential::parse_ccs(SERVER_CREDENTIAL).unwrap();
+        let client_credential = lakers::Credential::parse_ccs(CLIENT_CREDENTIAL).unwrap();
+
+        let config = crate::seccfg::ConfigBuilder::new()
+            .with_own_edhoc_credential(server_credential, SERVER_KEY)
+            .with_known_edhoc_credential(client_credential, crate::scope::AllowAll.into());
+        let mut transport = Loopback(crate::OscoreEdhocHandler::new(
+            Hello,
+            config,
+            || lakers_crypto_rustcrypto::Crypto::new(TestRng(1)),
+            TestRng(2),
+            crate::time::TimeUnknown,
+        ));
+
+        let mut client = OscoreEdhocClient::new(client_credential, CLIENT_KEY, || {
+            lakers_crypto_rustcrypto::Crypto::new(TestRng(3))
+        });
+
+        block_on(async {
+            // The first request carries EDHOC message 3, the second uses the established
+            // context only. The third updates the keys through KUDOS, and the fourth uses the
+            // updated context.
+            for i in 0..4 {
+                if i == 2 {
+                    assert!(client.update_key(&server_credential));
+                }
+                let (code, payload) = client
+                    .request(
+                        &mut transport,
+                        &server_credential,
+                        |request| request.set_code(coap_numbers::code::GET),
+                        |response| {
+                            (
+                                response.code(),
+                                heapless::Vec::<u8, 16>::from_slice(response.payload()).unwrap(),
+                            )
+                        },
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(code, coap_numbers::code::CONTENT);
+                assert_eq!(&payload[..], b"Hello");
+            }
+        });
+    }
+
+    #[test]
+    fn key_update_survives_lost_response() {
+        let server_credential = lakers::Credential::parse_ccs(SERVER_CREDENTIAL).unwrap();
+        let client_credential = lakers::Credential::parse_ccs(CLIENT_CREDENTIAL).unwrap();
+
+        let config = crate::seccfg::ConfigBuilder::new()
+            .with_own_edhoc_credential(server_credential, SERVER_KEY)
+            .with_known_edhoc_credential(client_credential, crate::scope::AllowAll.into());
+        let mut transport = Retransmitting {
+            inner: Loopback(crate::OscoreEdhocHandler::new(
+                Hello,
+                config,
+                || lakers_crypto_rustcrypto::Crypto::new(TestRng(1)),
+                TestRng(2),
+                crate::time::TimeUnknown,
+            )),
+            lose_next_response: false,
+        };
+
+        let mut client = OscoreEdhocClient::new(client_credential, CLIENT_KEY, || {
+            lakers_crypto_rustcrypto::Crypto::new(TestRng(3))
+        });
+
+        block_on(async {
+            // The server processes the request updating the keys twice, and only its second
+            // response reaches the client; both that and the next request need the server to
+            // still accept the update from the keys the client holds.
+            for i in 0..4 {
+                if i == 2 {
+                    assert!(client.update_key(&server_credential));
+                    transport.lose_next_response = true;
+                }
+                let code = client
+                    .request(
+                        &mut transport,
+                        &server_credential,
+                        |request| request.set_code(coap_numbers::code::GET),
+                        |response| response.code(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(code, coap_numbers::code::CONTENT);
+            }
+        });
+    }
+
+    /// Set once the claims of [`Revocable`] are revoked.
+    static REVOKED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+    /// Claims that can be revoked through [`REVOKED`].
+    #[derive(Debug)]
+    struct RevocableClaims(crate::seccfg::ConfigBuilderClaims);
+
+    impl crate::GeneralClaims for RevocableClaims {
+        type Scope = crate::scope::UnionScope;
+
+        fn scope(&self) -> &Self::Scope {
+            &self.0.scope
+        }
+
+        fn time_constraint(&self) -> crate::time::TimeConstraint {
+            self.0.time_constraint
+        }
+
+        fn is_revoked(&self) -> bool {
+            REVOKED.load(core::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    /// Configuration handing out [`RevocableClaims`], which it persists as empty claims.
+    struct Revocable(crate::seccfg::ConfigBuilder);
+
+    impl crate::seccfg::ServerSecurityConfig for Revocable {
+        const PARSES_TOKENS: bool = false;
+        const HAS_EDHOC: bool = true;
+        type GeneralClaims = RevocableClaims;
+
+        fn own_edhoc_credential(&self) -> Option<(lakers::Credential, lakers::BytesP256ElemLen)> {
+            self.0.own_edhoc_credential()
+        }
+
+        fn expand_id_cred_x(
+            &self,
+            id_cred_x: lakers::IdCred,
+        ) -> Option<(lakers::Credential, RevocableClaims)> {
+            self.0
+                .expand_id_cred_x(id_cred_x)
+                .map(|(credential, claims)| (credential, RevocableClaims(claims)))
+        }
+
+        fn encode_claims(&self, _claims: &RevocableClaims, _buffer: &mut [u8]) -> Option<usize> {
+            Some(0)
+        }
+    }
+
+    #[test]
+    fn revoked_context_is_discarded() {
+        use crate::persist::PersistenceAction;
+
+        let server_credential = lakers::Credential::parse_ccs(SERVER_CREDENTIAL).unwrap();
+        let client_credential = lakers::Credential::parse_ccs(CLIENT_CREDENTIAL).unwrap();
+
+        let config = crate::seccfg::ConfigBuilder::new()
+            .with_own_edhoc_credential(server_credential, SERVER_KEY)
+            .with_known_edhoc_credential(client_credential, crate::scope::AllowAll.into());
+        let mut transport = Loopback(crate::OscoreEdhocHandler::new(
+            Hello,
+            Revocable(config),
+            || lakers_crypto_rustcrypto::Crypto::new(TestRng(1)),
+            TestRng(2),
+            crate::time::TimeUnknown,
+        ));
+
+        let mut client = OscoreEdhocClient::new(client_credential, CLIENT_KEY, || {
+            lakers_crypto_rustcrypto::Crypto::new(TestRng(3))
+        });
+
+        block_on(async {
+            let get = |request: &mut liboscore::ProtectedMessage| {
+                request.set_code(coap_numbers::code::GET);
+            };
+
+            let code = client
+                .request(&mut transport, &server_credential, get, |response| {
+                    response.code()
+                })
+                .await
+                .unwrap();
+            assert_eq!(code, coap_numbers::code::CONTENT);
+
+            let stored = transport.0.next_persistence_request().unwrap();
+            assert!(matches!(stored.action(), PersistenceAction::Store { .. }));
+            let slot = stored.slot();
+            transport.0.persisted(stored);
+            assert!(transport.0.next_persistence_request().is_none());
+
+            REVOKED.store(true, core::sync::atomic::Ordering::Relaxed);
+
+            // The context is removed from storage even while the peer does not use it ...
+            let removed = transport.0.next_persistence_request().unwrap();
+            assert!(matches!(removed.action(), PersistenceAction::Remove));
+            assert_eq!(removed.slot(), slot);
+            transport.0.persisted(removed);
+            assert!(transport.0.next_persistence_request().is_none());
+
+            // ... and is not usable any more.
+            assert!(
+                client
+                    .request(&mut transport, &server_credential, get, |response| {
+                        response.code()
+                    })
+                    .await
+                    .is_err()
+            );
+        });
+    }
+}
@@ -0,0 +1,793 @@
+//! EDHOC credentials of peers beyond CWT Claims Sets (CCS): X.509 certificates and CWTs.
+//!
+//! Peers can indicate these credentials in their `ID_CRED_x`:
+//!
+//! * An X.509 certificate by value (`x5chain`,
+//!   [RFC9360](https://www.rfc-editor.org/rfc/rfc9360)) or by its hash (`x5t`) is recognized if
+//!   it is known in advance: a credential built from it by [`certificate_credential()`] can be
+//!   configured like any other known credential (e.g. through
+//!   [`ConfigBuilder::with_known_edhoc_credential()`](crate::seccfg::ConfigBuilder::with_known_edhoc_credential)).
+//! * An X.509 certificate (`x5chain`) or a CWT (`kcwt`) by value is accepted if it is signed by a
+//!   [`TrustedIssuer`] (e.g. configured through
+//!   [`ConfigBuilder::with_trusted_edhoc_issuer()`](crate::seccfg::ConfigBuilder::with_trusted_edhoc_issuer)).
+//!   This allows authenticating peers of an existing PKI without registering each of their keys.
+//!
+//! Only P-256 keys and ES256 (ECDSA with SHA-256) signatures are supported. Certificates need to
+//! be issued by the trusted issuer directly; further certificates of a chain are not evaluated.
+//! Certificates of certification authorities (whose basic constraints have `cA` set) are rejected;
+//! other extensions are not evaluated. The validity period of a certificate (or the expiry time of
+//! a CWT) limits the time for which the peer's security context is valid, as evaluated against the
+//! server's time provider.
+//!
+//! As with any credential, credentials (and `ID_CRED_x` values carrying them) need to fit into
+//! Lakers' credential buffers.
+
+use defmt_or_log::trace;
+use sha2::Digest as _;
+
+use crate::error::{CredentialError, CredentialErrorDetail};
+use crate::iana::{cose_alg, cose_header};
+use crate::time::TimeConstraint;
+
+/// DER tags used in X.509 certificates.
+mod tag {
+    pub(super) const INTEGER: u8 = 0x02;
+    pub(super) const BOOLEAN: u8 = 0x01;
+    pub(super) const BIT_STRING: u8 = 0x03;
+    pub(super) const OCTET_STRING: u8 = 0x04;
+    pub(super) const OBJECT_IDENTIFIER: u8 = 0x06;
+    pub(super) const UTC_TIME: u8 = 0x17;
+    pub(super) const GENERALIZED_TIME: u8 = 0x18;
+    pub(super) const SEQUENCE: u8 = 0x30;
+    /// The explicitly tagged version of a certificate.
+    pub(super) const VERSION: u8 = 0xa0;
+    /// The implicitly tagged issuer unique identifier of a certificate.
+    pub(super) const ISSUER_UNIQUE_ID: u8 = 0x81;
+    /// The implicitly tagged subject unique identifier of a certificate.
+    pub(super) const SUBJECT_UNIQUE_ID: u8 = 0x82;
+    /// The explicitly tagged extensions of a certificate.
+    pub(super) const EXTENSIONS: u8 = 0xa3;
+}
+
+/// Encoded object identifier of `ecdsa-with-SHA256` (1.2.840.10045.4.3.2).
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+/// Encoded object identifier of `id-ecPublicKey` (1.2.840.10045.2.1).
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// Encoded object identifier of `prime256v1` (1.2.840.10045.3.1.7).
+const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// Encoded object identifier of `id-ce-basicConstraints` (2.5.29.19).
+const BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+
+/// An issuer whose ES256 signatures on X.509 certificates and CWTs are trusted.
+#[derive(Clone, Debug)]
+pub struct TrustedIssuer {
+    key: p256::ecdsa::VerifyingKey,
+}
+
+impl TrustedIssuer {
+    /// Creates an issuer from the coordinates of its P-256 public key.
+    ///
+    /// # Errors
+    ///
+    /// This errs if the coordinates do not describe a point on the curve.
+    pub fn new(x: &[u8; 32], y: &[u8; 32]) -> Result<Self, CredentialError> {
+        let key = p256::ecdsa::VerifyingKey::from_encoded_point(
+            &p256::EncodedPoint::from_affine_coordinates(x.into(), y.into(), false),
+        )
+        .map_err(|_| CredentialErrorDetail::InconsistentDetails)?;
+        Ok(Self { key })
+    }
+
+    /// Expands an `ID_CRED_x` that carries a certificate or CWT signed by this issuer into the
+    /// peer's credential, along with the time during which the credential is valid.
+    ///
+    /// Returns `None` if the `ID_CRED_x` carries no such credential.
+    #[must_use]
+    pub fn expand(
+        &self,
+        id_cred_x: &lakers::IdCred,
+    ) -> Option<(lakers::Credential, TimeConstraint)> {
+        self.expand_full_value(id_cred_x.as_full_value())
+    }
+
+    /// Like [`Self::expand()`], but working on the full (map) value of the `ID_CRED_x`.
+    fn expand_full_value(&self, id_cred_x: &[u8]) -> Option<(lakers::Credential, TimeConstraint)> {
+        let (x, time_constraint, credential) = match Reference::parse(id_cred_x)? {
+            Reference::Certificate(certificate) => {
+                let parsed = Certificate::parse(certificate)?;
+                parsed.verify(&self.key)?;
+                let (x, _) = parsed.public_key_coordinates();
+                let time_constraint = TimeConstraint::between(parsed.not_before, parsed.not_after);
+                (x, time_constraint, bstr_wrapped(certificate)?)
+            }
+            Reference::Cwt(cwt) => {
+                let (x, expiry) = crate::ace::verify_cwt_credential(cwt, &self.key)
+                    .inspect_err(|_| {
+                        trace!("CWT is not signed by the trusted issuer, or unusable.")
+                    })
+                    .ok()?;
+                let mut credential = lakers::BufferCred::new();
+                credential.extend_from_slice(cwt).ok()?;
+                let time_constraint =
+                    expiry.map_or_else(TimeConstraint::unbounded, TimeConstraint::until);
+                (x, time_constraint, credential)
+            }
+            Reference::Thumbprint { .. } => return None,
+        };
+        Some((lakers::Credential::new_ccs(credential, x), time_constraint))
+    }
+}
+
+/// Builds an EDHOC credential from a DER encoded X.509 certificate.
+///
+/// The credential is recognized when a peer indicates the certificate by value or by its hash.
+///
+/// # Errors
+///
+/// This errs if the certificate can not be parsed, does not contain a P-256 key, is the
+/// certificate of a certification authority, or is too large.
+pub fn certificate_credential(certificate: &[u8]) -> Result<lakers::Credential, CredentialError> {
+    let parsed =
+        Certificate::parse(certificate).ok_or(CredentialErrorDetail::UnsupportedExtension)?;
+    let (x, _) = parsed.public_key_coordinates();
+    let bytes = bstr_wrapped(certificate).ok_or(CredentialErrorDetail::ConstraintExceeded)?;
+    Ok(lakers::Credential::new_ccs(bytes, x))
+}
+
+/// Checks whether the `ID_CRED_x` indicates the certificate from which `credential` was built by
+/// [`certificate_credential()`].
+pub(crate) fn identifies_certificate(
+    id_cred_x: &lakers::IdCred,
+    credential: &lakers::Credential,
+) -> bool {
+    // CRED_x of a certificate is the certificate wrapped in a byte string.
+    let Ok(certificate) = minicbor::Decoder::new(credential.bytes.as_slice()).bytes() else {
+        return false;
+    };
+    Reference::parse(id_cred_x.as_full_value())
+        .is_some_and(|reference| reference.identifies(certificate))
+}
+
+/// Wraps a certificate in a CBOR byte string, as which it is used as `CRED_x` (RFC9528 Section
+/// 3.5.2).
+fn bstr_wrapped(certificate: &[u8]) -> Option<lakers::BufferCred> {
+    let mut wrapped = lakers::BufferCred::new();
+    let mut head = heapless::Vec::<u8, 9>::new();
+    minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut head))
+        .bytes_len(u64::try_from(certificate.len()).ok()?)
+        .ok()?;
+    wrapped.extend_from_slice(&head).ok()?;
+    wrapped.extend_from_slice(certificate).ok()?;
+    Some(wrapped)
+}
+
+/// A credential as indicated in an `ID_CRED_x` map.
+enum Reference<'a> {
+    /// The DER encoded end-entity certificate of an `x5chain`.
+    Certificate(&'a [u8]),
+    /// The algorithm and hash value of an `x5t`.
+    Thumbprint { alg: i32, hash: &'a [u8] },
+    /// The encoded CWT of a `kcwt`.
+    Cwt(&'a [u8]),
+}
+
+impl<'a> Reference<'a> {
+    /// Parses the full (map) value of an `ID_CRED_x`.
+    fn parse(id_cred_x: &'a [u8]) -> Option<Self> {
+        let mut decoder = minicbor::Decoder::new(id_cred_x);
+        if decoder.map().ok()? != Some(1) {
+            return None;
+        }
+        match decoder.i64().ok()? {
+            cose_header::X5CHAIN => {
+                // Either a single certificate, or an array starting with the end-entity one.
+                if decoder.datatype().ok()? == minicbor::data::Type::Array {
+                    decoder.array().ok()?;
+                }
+                decoder.bytes().ok().map(Self::Certificate)
+            }
+            cose_header::X5T => {
+                if decoder.array().ok()? != Some(2) {
+                    return None;
+                }
+                let alg = decoder.i32().ok()?;
+                let hash = decoder.bytes().ok()?;
+                Some(Self::Thumbprint { alg, hash })
+            }
+            cose_header::KCWT => {
+                let start = decoder.position();
+                decoder.skip().ok()?;
+                id_cred_x.get(start..decoder.position()).map(Self::Cwt)
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks whether this references the given DER encoded certificate.
+    fn identifies(&self, certificate: &[u8]) -> bool {
+        match self {
+            Self::Certificate(given) => *given == certificate,
+            Self::Thumbprint { alg, hash } => {
+                let expected_len = match *alg {
+                    cose_alg::SHA_256 => 32,
+                    cose_alg::SHA_256_64 => 8,
+                    _ => return false,
+                };
+                hash.len() == expected_len && sha2::Sha256::digest(certificate).starts_with(hash)
+            }
+            Self::Cwt(_) => false,
+        }
+    }
+}
+
+/// The parts of an X.509 certificate that are evaluated.
+struct Certificate<'a> {
+    /// The encoded `tbsCertificate`, over which the signature is made.
+    tbs: &'a [u8],
+    /// Start of the validity period, in seconds since the Unix epoch.
+    not_before: u64,
+    /// End of the validity period, in seconds since the Unix epoch.
+    not_after: u64,
+    public_key: p256::ecdsa::VerifyingKey,
+    signature: p256::ecdsa::Signature,
+}
+
+impl<'a> Certificate<'a> {
+    /// Parses a DER encoded certificate with a P-256 key and an ES256 signature.
+    ///
+    /// Certificates of certification authorities are rejected, as they are not meant to
+    /// authenticate peers.
+    fn parse(der: &'a [u8]) -> Option<Self> {
+        let (_, certificate, _) = tlv(der, tag::SEQUENCE)?;
+        let (tbs, tbs_fields, rest) = tlv(certificate, tag::SEQUENCE)?;
+        let (_, algorithm, rest) = tlv(rest, tag::SEQUENCE)?;
+        let (_, signature, _) = tlv(rest, tag::BIT_STRING)?;
+        let (_, algorithm, _) = tlv(algorithm, tag::OBJECT_IDENTIFIER)?;
+        if algorithm != ECDSA_WITH_SHA256 {
+            trace!("Certificate is not signed with ES256.");
+            return None;
+        }
+        let signature = ecdsa_signature(signature)?;
+
+        let mut fields = tbs_fields;
+        if fields.first() == Some(&tag::VERSION) {
+            (_, _, fields) = tlv(fields, tag::VERSION)?;
+        }
+        let (_, _serial_number, fields) = tlv(fields, tag::INTEGER)?;
+        let (_, _signature, fields) = tlv(fields, tag::SEQUENCE)?;
+        let (_, _issuer, fields) = tlv(fields, tag::SEQUENCE)?;
+        let (_, validity, fields) = tlv(fields, tag::SEQUENCE)?;
+        let (_, _subject, fields) = tlv(fields, tag::SEQUENCE)?;
+        let (_, subject_public_key_info, fields) = tlv(fields, tag::SEQUENCE)?;
+
+        if is_certification_authority(fields)? {
+            trace!("Certificate is that of a certification authority.");
+            return None;
+        }
+
+        let (not_before, validity) = time(validity)?;
+        let (not_after, _) = time(validity)?;
+
+        let (_, algorithm, subject_public_key_info) = tlv(subject_public_key_info, tag::SEQUENCE)?;
+        let (_, key_type, parameters) = tlv(algorithm, tag::OBJECT_IDENTIFIER)?;
+        let (_, curve, _) = tlv(parameters, tag::OBJECT_IDENTIFIER)?;
+        if key_type != EC_PUBLIC_KEY || curve != PRIME256V1 {
+            trace!("Certificate does not contain a P-256 key.");
+            return None;
+        }
+        let (_, public_key, _) = tlv(subject_public_key_info, tag::BIT_STRING)?;
+        // Keys are whole bytes, so the number of unused bits is 0.
+        let public_key = public_key.strip_prefix(&[0])?;
+        let public_key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key).ok()?;
+
+        Some(Self {
+            tbs,
+            not_before,
+            not_after,
+            public_key,
+            signature,
+        })
+    }
+
+    /// Checks that the certificate was signed by the issuer's `key`.
+    fn verify(&self, key: &p256::ecdsa::VerifyingKey) -> Option<()> {
+        use p256::ecdsa::signature::Verifier as _;
+
+        key.verify(self.tbs, &self.signature)
+            .inspect_err(|_| trace!("Certificate is not signed by the trusted issuer."))
+            .ok()
+    }
+
+    /// Returns the coordinates of the certificate's public key.
+    fn public_key_coordinates(&self) -> (lakers::BytesP256ElemLen, lakers::BytesP256ElemLen) {
+        let point = self.public_key.to_encoded_point(false);
+        let coordinate = |c: Option<&p256::FieldBytes>| {
+            c.map(|c| (*c).into())
+                .expect("uncompressed points have both coordinates")
+        };
+        (coordinate(point.x()), coordinate(point.y()))
+    }
+}
+
+/// Checks whether the basic constraints among the optional fields at the end of a
+/// `tbsCertificate` mark the certificate as that of a certification authority.
+///
+/// Returns `None` if the fields can not be parsed.
+fn is_certification_authority(mut fields: &[u8]) -> Option<bool> {
+    for unique_id in [tag::ISSUER_UNIQUE_ID, tag::SUBJECT_UNIQUE_ID] {
+        if fields.first() == Some(&unique_id) {
+            (_, _, fields) = tlv(fields, unique_id)?;
+        }
+    }
+    if fields.is_empty() {
+        return Some(false);
+    }
+    let (_, extensions, _) = tlv(fields, tag::EXTENSIONS)?;
+    let (_, mut extensions, _) = tlv(extensions, tag::SEQUENCE)?;
+    while !extensions.is_empty() {
+        let (_, extension, rest) = tlv(extensions, tag::SEQUENCE)?;
+        extensions = rest;
+        let (_, id, mut extension) = tlv(extension, tag::OBJECT_IDENTIFIER)?;
+        if id != BASIC_CONSTRAINTS {
+            continue;
+        }
+        if extension.first() == Some(&tag::BOOLEAN) {
+            (_, _, extension) = tlv(extension, tag::BOOLEAN)?;
+        }
+        let (_, value, _) = tlv(extension, tag::OCTET_STRING)?;
+        let (_, constraints, _) = tlv(value, tag::SEQUENCE)?;
+        // cA defaults to false, and is then absent.
+        return Some(match tlv(constraints, tag::BOOLEAN) {
+            Some((_, ca, _)) => ca.iter().any(|&b| b != 0),
+            None => false,
+        });
+    }
+    Some(false)
+}
+
+/// Reads a DER item with the given `tag` at the start of `data`.
+///
+/// Returns the whole encoded item, its content, and the data after it.
+fn tlv(data: &[u8], tag: u8) -> Option<(&[u8], &[u8], &[u8])> {
+    let (&found, rest) = data.split_first()?;
+    if found != tag {
+        return None;
+    }
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0..0x80 => (usize::from(first), rest),
+        0x81 => {
+            let (&len, rest) = rest.split_first()?;
+            (usize::from(len), rest)
+        }
+        0x82 => {
+            let (len, rest) = rest.split_first_chunk::<2>()?;
+            (usize::from(u16::from_be_bytes(*len)), rest)
+        }
+        // Longer items are not expected in certificates of constrained devices.
+        _ => return None,
+    };
+    let (content, rest) = rest.split_at_checked(len)?;
+    let encoded = data.get(..data.len() - rest.len())?;
+    Some((encoded, content, rest))
+}
+
+/// Parses the signature value of a certificate (a bit string containing an `Ecdsa-Sig-Value`).
+fn ecdsa_signature(bit_string: &[u8]) -> Option<p256::ecdsa::Signature> {
+    let encoded = bit_string.strip_prefix(&[0])?;
+    let (_, value, _) = tlv(encoded, tag::SEQUENCE)?;
+    let (_, r, rest) = tlv(value, tag::INTEGER)?;
+    let (_, s, _) = tlv(rest, tag::INTEGER)?;
+
+    let mut rs = [0u8; 64];
+    let (r_out, s_out) = rs.split_at_mut(32);
+    for (integer, out) in [(r, r_out), (s, s_out)] {
+        // DER integers are minimal, but have a leading zero if their high bit is set.
+        let start = integer
+            .iter()
+            .position(|b| *b != 0)
+            .unwrap_or(integer.len());
+        let integer = integer.get(start..)?;
+        let offset = out.len().checked_sub(integer.len())?;
+        out.get_mut(offset..)?.copy_from_slice(integer);
+    }
+    p256::ecdsa::Signature::from_slice(&rs).ok()
+}
+
+/// Reads a `UTCTime` or `GeneralizedTime` (in the forms required by RFC5280 Section 4.1.2.5) at
+/// the start of `data`.
+///
+/// Returns the time in seconds since the Unix epoch, and the data after it.
+fn time(data: &[u8]) -> Option<(u64, &[u8])> {
+    let (year, fields, rest) = if let Some((_, time, rest)) = tlv(data, tag::UTC_TIME) {
+        let (year, fields) = time.split_first_chunk::<2>()?;
+        let year = decimal(year)?;
+        // Two-digit years are in the range from 1950 to 2049.
+        let century = if year >= 50 { 1900 } else { 2000 };
+        (century + year, fields, rest)
+    } else {
+        let (_, time, rest) = tlv(data, tag::GENERALIZED_TIME)?;
+        let (year, fields) = time.split_first_chunk::<4>()?;
+        (decimal(year)?, fields, rest)
+    };
+
+    let [month, day, hour, minute, second] = match fields {
+        [m1, m2, d1, d2, h1, h2, i1, i2, s1, s2, b'Z'] => [
+            decimal(&[*m1, *m2])?,
+            decimal(&[*d1, *d2])?,
+            decimal(&[*h1, *h2])?,
+            decimal(&[*i1, *i2])?,
+            decimal(&[*s1, *s2])?,
+        ],
+        _ => return None,
+    };
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let days = days_since_epoch(year, month, day)?;
+    Some((days * 86400 + hour * 3600 + minute * 60 + second, rest))
+}
+
+/// Parses ASCII digits.
+fn decimal(digits: &[u8]) -> Option<u64> {
+    digits.iter().try_fold(0, |value, digit| {
+        digit
+            .is_ascii_digit()
+            .then(|| value * 10 + u64::from(digit - b'0'))
+    })
+}
+
+/// Counts the days from 1970-01-01 to the given date (which must not be earlier).
+fn days_since_epoch(year: u64, month: u64, day: u64) -> Option<u64> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Counting years from March on puts the leap day at the end of the year.
+    let (year, month) = if month > 2 {
+        (year, month - 3)
+    } else {
+        (year.checked_sub(1)?, month + 9)
+    };
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let days = year * 365 + year / 4 - year / 100 + year / 400 + day_of_year;
+    // Days from 0000-03-01 to 1970-01-01
+    days.checked_sub(719_468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Buffer = heapless::Vec<u8, 512>;
+
+    /// Appends a DER item.
+    fn push_tlv(buffer: &mut Buffer, tag: u8, content: &[u8]) {
+        buffer.push(tag).unwrap();
+        let len = u16::try_from(content.len()).unwrap();
+        match u8::try_from(len) {
+            Ok(len @ 0..0x80) => buffer.push(len).unwrap(),
+            Ok(len) => buffer.extend_from_slice(&[0x81, len]).unwrap(),
+            Err(_) => {
+                buffer.push(0x82).unwrap();
+                buffer.extend_from_slice(&len.to_be_bytes()).unwrap();
+            }
+        }
+        buffer.extend_from_slice(content).unwrap();
+    }
+
+    fn der(tag: u8, content: &[u8]) -> Buffer {
+        let mut buffer = Buffer::new();
+        push_tlv(&mut buffer, tag, content);
+        buffer
+    }
+
+    fn concat(items: &[&[u8]]) -> Buffer {
+        let mut buffer = Buffer::new();
+        for item in items {
+            buffer.extend_from_slice(item).unwrap();
+        }
+        buffer
+    }
+
+    /// Builds a certificate for `subject`'s key, signed by `issuer`, valid from 2024-01-01 until
+    /// 2030-01-01.
+    fn certificate(subject: &p256::ecdsa::SigningKey, issuer: &p256::ecdsa::SigningKey) -> Buffer {
+        certificate_with_extensions(subject, issuer, &[])
+    }
+
+    /// Like [`certificate()`], with the given encoded extensions (if not empty).
+    fn certificate_with_extensions(
+        subject: &p256::ecdsa::SigningKey,
+        issuer: &p256::ecdsa::SigningKey,
+        extensions: &[u8],
+    ) -> Buffer {
+        use p256::ecdsa::signature::Signer as _;
+
+        let algorithm = der(
+            tag::SEQUENCE,
+            &der(tag::OBJECT_IDENTIFIER, ECDSA_WITH_SHA256),
+        );
+        let name = der(tag::SEQUENCE, &[]);
+        let validity = der(
+            tag::SEQUENCE,
+            &concat(&[
+                &der(tag::UTC_TIME, b"240101000000Z"),
+                &der(tag::GENERALIZED_TIME, b"20300101000000Z"),
+            ]),
+        );
+        let mut key = Buffer::new();
+        key.push(0).unwrap();
+        key.extend_from_slice(subject.verifying_key().to_encoded_point(false).as_bytes())
+            .unwrap();
+        let public_key_info = der(
+            tag::SEQUENCE,
+            &concat(&[
+                &der(
+                    tag::SEQUENCE,
+                    &concat(&[
+                        &der(tag::OBJECT_IDENTIFIER, EC_PUBLIC_KEY),
+                        &der(tag::OBJECT_IDENTIFIER, PRIME256V1),
+                    ]),
+                ),
+                &der(tag::BIT_STRING, &key),
+            ]),
+        );
+        let tbs = der(
+            tag::SEQUENCE,
+            &concat(&[
+                &der(tag::VERSION, &der(tag::INTEGER, &[2])),
+                &der(tag::INTEGER, &[1]),
+                &algorithm,
+                &name,
+                &validity,
+                &name,
+                &public_key_info,
+                &if extensions.is_empty() {
+                    Buffer::new()
+                } else {
+                    der(tag::EXTENSIONS, &der(tag::SEQUENCE, extensions))
+                },
+            ]),
+        );
+
+        let signature: p256::ecdsa::Signature = issuer.sign(&tbs);
+        let (r, s) = signature.split_bytes();
+        let integer = |value: &[u8]| {
+            // Always prefixing a zero is not minimal, but accepted.
+            der(tag::INTEGER, &concat(&[&[0], value]))
+        };
+        let mut signature_value = Buffer::new();
+        signature_value.push(0).unwrap();
+        signature_value
+            .extend_from_slice(&der(tag::SEQUENCE, &concat(&[&integer(&r), &integer(&s)])))
+            .unwrap();
+
+        der(
+            tag::SEQUENCE,
+            &concat(&[&tbs, &algorithm, &der(tag::BIT_STRING, &signature_value)]),
+        )
+    }
+
+    fn keys() -> (p256::ecdsa::SigningKey, p256::ecdsa::SigningKey) {
+        (
+            p256::ecdsa::SigningKey::from_bytes((&[0x11; 32]).into()).unwrap(),
+            p256::ecdsa::SigningKey::from_bytes((&[0x22; 32]).into()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(days_since_epoch(1970, 1, 1), Some(0));
+        assert_eq!(days_since_epoch(2000, 3, 1), Some(11_017));
+        assert_eq!(days_since_epoch(1969, 12, 31), None);
+        assert_eq!(
+            time(&der(tag::GENERALIZED_TIME, b"20300101000000Z")).map(|(time, _)| time),
+            Some(1_893_456_000)
+        );
+        assert_eq!(
+            time(&der(tag::UTC_TIME, b"991231235959Z")).map(|(time, _)| time),
+            Some(946_684_799)
+        );
+        assert!(time(&der(tag::UTC_TIME, b"991231235959")).is_none());
+    }
+
+    #[test]
+    fn certificate_by_issuer() {
+        let (subject, issuer) = keys();
+        let certificate = certificate(&subject, &issuer);
+
+        let parsed = Certificate::parse(&certificate).unwrap();
+        assert_eq!(parsed.public_key, *subject.verifying_key());
+        assert_eq!(parsed.not_before, 1_704_067_200);
+        assert_eq!(parsed.not_after, 1_893_456_000);
+        assert!(parsed.verify(issuer.verifying_key()).is_some());
+        assert!(parsed.verify(subject.verifying_key()).is_none());
+
+        let mut tampered = certificate.clone();
+        // The serial number
+        *tampered.get_mut(13).unwrap() ^= 0x01;
+        let tampered = Certificate::parse(&tampered).unwrap();
+        assert!(tampered.verify(issuer.verifying_key()).is_none());
+    }
+
+    /// Encodes a basic constraints extension, marked critical.
+    fn basic_constraints(ca: bool) -> Buffer {
+        let constraints = if ca {
+            der(tag::SEQUENCE, &der(tag::BOOLEAN, &[0xff]))
+        } else {
+            der(tag::SEQUENCE, &[])
+        };
+        der(
+            tag::SEQUENCE,
+            &concat(&[
+                &der(tag::OBJECT_IDENTIFIER, BASIC_CONSTRAINTS),
+                &der(tag::BOOLEAN, &[0xff]),
+                &der(tag::OCTET_STRING, &constraints),
+            ]),
+        )
+    }
+
+    #[test]
+    fn certification_authority() {
+        let (subject, issuer) = keys();
+        let other_extension = der(
+            tag::SEQUENCE,
+            &concat(&[
+                // keyUsage (2.5.29.15), not evaluated
+                &der(tag::OBJECT_IDENTIFIER, &[0x55, 0x1d, 0x0f]),
+                &der(tag::OCTET_STRING, &der(tag::BIT_STRING, &[0x07, 0x80])),
+            ]),
+        );
+
+        let end_entity = certificate_with_extensions(
+            &subject,
+            &issuer,
+            &concat(&[&other_extension, &basic_constraints(false)]),
+        );
+        let parsed = Certificate::parse(&end_entity).unwrap();
+        assert!(parsed.verify(issuer.verifying_key()).is_some());
+
+        let authority = certificate_with_extensions(
+            &subject,
+            &issuer,
+            &concat(&[&other_extension, &basic_constraints(true)]),
+        );
+        assert!(Certificate::parse(&authority).is_none());
+        assert!(certificate_credential(&authority).is_err());
+    }
+
+    #[test]
+    fn validity_period() {
+        struct Now(u64);
+
+        impl crate::time::TimeProvider for Now {
+            fn now(&mut self) -> (u64, Option<u64>) {
+                (self.0, Some(self.0))
+            }
+        }
+
+        let (subject, issuer) = keys();
+        let certificate = certificate(&subject, &issuer);
+        let mut x5chain = Buffer::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut x5chain))
+            .map(1)
+            .and_then(|e| e.i64(cose_header::X5CHAIN))
+            .and_then(|e| e.bytes(&certificate))
+            .unwrap();
+
+        let issuer = TrustedIssuer {
+            key: *issuer.verifying_key(),
+        };
+        let (_, time_constraint) = issuer.expand_full_value(&x5chain).unwrap();
+        assert!(!time_constraint.is_valid_with(&mut Now(1_704_067_199)));
+        assert!(time_constraint.is_valid_with(&mut Now(1_704_067_200)));
+        assert!(time_constraint.is_valid_with(&mut Now(1_893_455_999)));
+        assert!(!time_constraint.is_valid_with(&mut Now(1_893_456_000)));
+        // Without knowledge of the time, only the expiry can be evaluated.
+        assert!(time_constraint.is_valid_with(&mut crate::time::TimeUnknown));
+    }
+
+    #[test]
+    fn references() {
+        let (subject, issuer) = keys();
+        let certificate = certificate(&subject, &issuer);
+        let other = certificate_of_other();
+
+        let mut x5chain = Buffer::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut x5chain))
+            .map(1)
+            .and_then(|e| e.i64(cose_header::X5CHAIN))
+            .and_then(|e| e.bytes(&certificate))
+            .unwrap();
+        let reference = Reference::parse(&x5chain).unwrap();
+        assert!(reference.identifies(&certificate));
+        assert!(!reference.identifies(&other));
+
+        let hash = sha2::Sha256::digest(&certificate);
+        let mut x5t = Buffer::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut x5t))
+            .map(1)
+            .and_then(|e| e.i64(cose_header::X5T))
+            .and_then(|e| e.array(2))
+            .and_then(|e| e.i32(cose_alg::SHA_256_64))
+            .and_then(|e| e.bytes(hash.get(..8).unwrap()))
+            .unwrap();
+        let reference = Reference::parse(&x5t).unwrap();
+        assert!(reference.identifies(&certificate));
+        assert!(!reference.identifies(&other));
+    }
+
+    fn certificate_of_other() -> Buffer {
+        let (_, issuer) = keys();
+        let other = p256::ecdsa::SigningKey::from_bytes((&[0x33; 32]).into()).unwrap();
+        certificate(&other, &issuer)
+    }
+
+    #[test]
+    fn cwt_by_issuer() {
+        use p256::ecdsa::signature::Signer as _;
+
+        let (subject, issuer) = keys();
+        let point = subject.verifying_key().to_encoded_point(false);
+
+        let mut protected = Buffer::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut protected))
+            .map(1)
+            .and_then(|e| e.u8(1))
+            .and_then(|e| e.i32(cose_alg::ES256))
+            .unwrap();
+        let mut payload = Buffer::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut payload))
+            .map(2)
+            .and_then(|e| e.u8(4))
+            .and_then(|e| e.u64(1_893_456_000))
+            .and_then(|e| e.u8(8))
+            .and_then(|e| e.map(1))
+            .and_then(|e| e.u8(1))
+            .and_then(|e| e.map(4))
+            .and_then(|e| e.u8(1))
+            .and_then(|e| e.u8(2))
+            .and_then(|e| e.i8(-1))
+            .and_then(|e| e.u8(1))
+            .and_then(|e| e.i8(-2))
+            .and_then(|e| e.bytes(point.x().unwrap()))
+            .and_then(|e| e.i8(-3))
+            .and_then(|e| e.bytes(point.y().unwrap()))
+            .unwrap();
+        let mut signed = Buffer::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut signed))
+            .array(4)
+            .and_then(|e| e.str("Signature1"))
+            .and_then(|e| e.bytes(&protected))
+            .and_then(|e| e.bytes(&[]))
+            .and_then(|e| e.bytes(&payload))
+            .unwrap();
+        let signature: p256::ecdsa::Signature = issuer.sign(&signed);
+
+        let mut cwt = Buffer::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut cwt))
+            .tag(minicbor::data::Tag::new(18))
+            .and_then(|e| e.array(4))
+            .and_then(|e| e.bytes(&protected))
+            .and_then(|e| e.map(0))
+            .and_then(|e| e.bytes(&payload))
+            .and_then(|e| e.bytes(&signature.to_bytes()))
+            .unwrap();
+
+        let (x, expiry) = crate::ace::verify_cwt_credential(&cwt, issuer.verifying_key()).unwrap();
+        assert_eq!(x.as_slice(), point.x().unwrap().as_slice());
+        assert_eq!(expiry, Some(1_893_456_000));
+        assert!(crate::ace::verify_cwt_credential(&cwt, subject.verifying_key()).is_err());
+
+        let mut kcwt = Buffer::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut kcwt))
+            .map(1)
+            .and_then(|e| e.i64(cose_header::KCWT))
+            .unwrap();
+        kcwt.extend_from_slice(&cwt).unwrap();
+        assert!(matches!(
+            Reference::parse(&kcwt),
+            Some(Reference::Cwt(parsed)) if parsed == cwt.as_slice()
+        ));
+    }
+}
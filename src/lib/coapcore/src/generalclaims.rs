@@ -39,6 +39,16 @@ pub trait GeneralClaims: core::fmt::Debug {
     fn is_important(&self) -> bool {
         false
     }
+
+    /// Access whether the claims were withdrawn after the security context was established.
+    ///
+    /// Unlike the time constraint, this can change at any time (eg. when the peer's credential is
+    /// removed from a list of known peers). Security contexts whose claims are revoked are
+    /// discarded when they are next used, and are removed from persistent storage (see
+    /// [`crate::persist`]).
+    fn is_revoked(&self) -> bool {
+        false
+    }
 }
 
 impl GeneralClaims for core::convert::Infallible {
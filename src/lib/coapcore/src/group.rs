@@ -0,0 +1,1087 @@
+//! Group OSCORE, following
+//! [draft-ietf-core-oscore-groupcomm](https://datatracker.ietf.org/doc/draft-ietf-core-oscore-groupcomm/).
+//!
+//! Group OSCORE protects requests that are sent to several servers at once (typically over IP
+//! multicast), along with their responses. All members of an [`OscoreGroup`] share a master
+//! secret, and each member signs its messages with its own private key, so that members can not
+//! impersonate each other.
+//!
+//! Both modes of Group OSCORE are supported:
+//!
+//! * In the group mode, messages are encrypted with keys derived from the shared master secret,
+//!   and signed by their sender. This is what requests to the whole group use.
+//! * In the pairwise mode, messages are encrypted with keys derived from an ECDH shared secret of
+//!   the two members, which authenticates them without a signature. This is what members use for
+//!   requests to a single other member.
+//!
+//! The [`OscoreEdhocHandler`] processes requests of the group configured in its
+//! [`ServerSecurityConfig`] (typically through
+//! [`ConfigBuilder::with_oscore_group()`](crate::seccfg::ConfigBuilder::with_oscore_group)), and
+//! authorizes them with the scope given there. Responses are protected in the mode of the request.
+//! They do not carry a Partial IV of their own, so the server does not need to keep a sender
+//! sequence number (nor persist it). Observations through the group are not supported.
+//!
+//! The replay windows of the members are kept in RAM. As long as the window of a member is
+//! unknown (i.e., after startup), its requests are not processed, but answered with a 4.01
+//! Unauthorized response carrying an Echo option ([RFC9175](https://www.rfc-editor.org/rfc/rfc9175));
+//! only a request repeating the echoed value is processed, and the member's window starts there.
+//! As the repeated request is integrity protected, the challenge is sent unprotected.
+//!
+//! Only the algorithms most commonly used with Group OSCORE are supported: AES-CCM-16-64-128 as
+//! group encryption algorithm and AEAD algorithm, ES256 as signature algorithm, ECDH-SS +
+//! HKDF-256 for the pairwise key agreement, and HKDF SHA-256 for key derivation. Credentials are
+//! CWT Claims Sets (CCS) with an EC2 P-256 key; there is no Group Manager.
+//!
+//! [`OscoreEdhocHandler`]: crate::OscoreEdhocHandler
+//! [`ServerSecurityConfig`]: crate::seccfg::ServerSecurityConfig
+
+use coap_message::{MessageOption as _, ReadableMessage};
+use defmt_or_log::{debug, trace};
+use p256::ecdsa::signature::{DigestSigner as _, DigestVerifier as _};
+use sha2::Digest as _;
+
+use crate::iana::cose_alg;
+use crate::persist::ECHO_LEN;
+
+/// Maximum number of members (other than the server itself) of an [`OscoreGroup`].
+pub const MAX_MEMBERS: usize = 4;
+
+/// Maximum length of a credential of a group member.
+pub const MAX_CREDENTIAL_LEN: usize = 128;
+
+/// Maximum length of a Group Identifier (Gid).
+const MAX_GID_LEN: usize = 8;
+
+/// Maximum length of the master secret and of the master salt.
+const MAX_SECRET_LEN: usize = 32;
+
+/// Key length of AES-CCM-16-64-128.
+const KEY_LEN: usize = 16;
+/// Nonce length of AES-CCM-16-64-128.
+const NONCE_LEN: usize = 13;
+/// Tag length of AES-CCM-16-64-128.
+const TAG_LEN: usize = 8;
+/// Length of an ES256 signature.
+const SIGNATURE_LEN: usize = 64;
+
+/// Maximum length of a Sender ID with the nonce length of AES-CCM-16-64-128 (RFC8613 Section
+/// 3.3).
+const MAX_ID_LEN: usize = NONCE_LEN - 6;
+/// Maximum length of a Partial IV.
+const MAX_PIV_LEN: usize = 5;
+
+/// Space a protected message takes up beyond its plaintext.
+pub(crate) const PROTECTION_OVERHEAD: usize = TAG_LEN + SIGNATURE_LEN;
+
+/// Maximum encoded length of the `external_aad` array.
+const MAX_EXTERNAL_AAD_LEN: usize = 224;
+/// Maximum encoded length of the `Enc_structure`, which is the AAD of the encryption.
+const MAX_AAD_LEN: usize = MAX_EXTERNAL_AAD_LEN + 16;
+/// Maximum encoded length of the `info` of a key derivation.
+const MAX_INFO_LEN: usize = 32;
+
+/// Flags in the first byte of the OSCORE option.
+const FLAG_EXTENSION: u8 = 0x80;
+const FLAG_RESERVED: u8 = 0x40;
+const FLAG_GROUP: u8 = 0x20;
+const FLAG_KID_CONTEXT: u8 = 0x10;
+const FLAG_KID: u8 = 0x08;
+const MASK_PIV_LEN: u8 = 0x07;
+
+type Aes128Ccm = ccm::Ccm<aes::Aes128, ccm::consts::U8, ccm::consts::U13>;
+type Key = [u8; KEY_LEN];
+type Id = heapless::Vec<u8, MAX_ID_LEN>;
+type Credential = heapless::Vec<u8, MAX_CREDENTIAL_LEN>;
+
+/// Error type indicating that an [`OscoreGroup`] could not be configured.
+///
+/// This happens when identifiers or secrets exceed the supported lengths, when credentials are
+/// not CCSs with a P-256 key, when the own key does not match the own credential, or when too
+/// many members are added.
+#[derive(Debug, Copy, Clone)]
+pub struct InvalidGroup;
+
+/// Reasons for which a request could not be unprotected.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum UnprotectError {
+    /// The OSCORE option is malformed, or does not indicate this group.
+    InvalidOption,
+    /// The request's sender is not a member of the group.
+    UnknownSender,
+    /// The signature or the ciphertext did not verify.
+    VerifyFailed,
+}
+
+/// The mode in which a message is protected.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Mode {
+    Group,
+    Pairwise,
+}
+
+/// A member of an [`OscoreGroup`], along with the keys derived for it.
+struct Member {
+    id: Id,
+    credential: Credential,
+    public_key: p256::ecdsa::VerifyingKey,
+    /// The Recipient Key of the group mode.
+    key: Key,
+    /// The Pairwise Recipient Key.
+    pairwise_recipient_key: Key,
+    /// The Pairwise Sender Key used in responses to this member.
+    pairwise_sender_key: Key,
+}
+
+/// A Group OSCORE security context, as configured through
+/// [`ConfigBuilder::with_oscore_group()`](crate::seccfg::ConfigBuilder::with_oscore_group).
+///
+/// This holds the immutable parts of the context (along with the keys derived from them); the
+/// replay windows are kept by the handler. See the [module level documentation](self) for the
+/// supported algorithms.
+pub struct OscoreGroup {
+    gid: heapless::Vec<u8, MAX_GID_LEN>,
+    secret: heapless::Vec<u8, MAX_SECRET_LEN>,
+    salt: heapless::Vec<u8, MAX_SECRET_LEN>,
+    sender_id: Id,
+    credential: Credential,
+    signing_key: p256::ecdsa::SigningKey,
+    sender_key: Key,
+    common_iv: [u8; NONCE_LEN],
+    signature_encryption_key: Key,
+    members: heapless::Vec<Member, MAX_MEMBERS>,
+}
+
+impl OscoreGroup {
+    /// Sets up a group with the given Group Identifier (Gid, which is sent as ID Context), the
+    /// shared master secret and salt, and the server's own Sender ID, credential (a CCS) and
+    /// private key.
+    ///
+    /// # Errors
+    ///
+    /// This fails if any of the inputs is not supported (see [`InvalidGroup`]).
+    pub fn new(
+        group_id: &[u8],
+        master_secret: &[u8],
+        master_salt: &[u8],
+        sender_id: &[u8],
+        credential: &[u8],
+        private_key: [u8; 32],
+    ) -> Result<Self, InvalidGroup> {
+        let signing_key =
+            p256::ecdsa::SigningKey::from_bytes((&private_key).into()).map_err(|_| InvalidGroup)?;
+        if credential_key(credential).as_ref() != Some(signing_key.verifying_key()) {
+            debug!("Own group credential does not match the private key.");
+            return Err(InvalidGroup);
+        }
+
+        let gid = heapless::Vec::from_slice(group_id).map_err(|_| InvalidGroup)?;
+        let hkdf = hkdf::Hkdf::<sha2::Sha256>::new(Some(master_salt), master_secret);
+        let sender_key = expand(&hkdf, &info(sender_id, &gid, "Key", KEY_LEN)?)?;
+        let common_iv = expand(&hkdf, &info(&[], &gid, "IV", NONCE_LEN)?)?;
+        let signature_encryption_key = expand(&hkdf, &info(&[], &gid, "SEKey", KEY_LEN)?)?;
+
+        Ok(Self {
+            gid,
+            secret: heapless::Vec::from_slice(master_secret).map_err(|_| InvalidGroup)?,
+            salt: heapless::Vec::from_slice(master_salt).map_err(|_| InvalidGroup)?,
+            sender_id: heapless::Vec::from_slice(sender_id).map_err(|_| InvalidGroup)?,
+            credential: heapless::Vec::from_slice(credential).map_err(|_| InvalidGroup)?,
+            signing_key,
+            sender_key,
+            common_iv,
+            signature_encryption_key,
+            members: heapless::Vec::new(),
+        })
+    }
+
+    /// Adds a member of the group that the server accepts requests from, identified by its
+    /// Sender ID and its credential (a CCS).
+    ///
+    /// # Errors
+    ///
+    /// This fails if the member can not be added (see [`InvalidGroup`]), in particular if a member
+    /// with the same Sender ID is already present.
+    pub fn with_member(
+        mut self,
+        sender_id: &[u8],
+        credential: &[u8],
+    ) -> Result<Self, InvalidGroup> {
+        if sender_id == self.sender_id.as_slice()
+            || self.members.iter().any(|m| m.id.as_slice() == sender_id)
+        {
+            return Err(InvalidGroup);
+        }
+        let public_key = credential_key(credential).ok_or(InvalidGroup)?;
+
+        let hkdf = hkdf::Hkdf::<sha2::Sha256>::new(Some(self.salt.as_slice()), &self.secret);
+        let key = expand(&hkdf, &info(sender_id, &self.gid, "Key", KEY_LEN)?)?;
+
+        // Pairwise keys (Section 2.5.1 of the draft)
+        let shared_secret = p256::ecdh::diffie_hellman(
+            self.signing_key.as_nonzero_scalar(),
+            public_key.as_affine(),
+        );
+        let pairwise_key =
+            |key: &Key, id: &[u8], first: &[u8], second: &[u8]| -> Result<Key, InvalidGroup> {
+                let mut ikm = heapless::Vec::<u8, { 2 * MAX_CREDENTIAL_LEN + 32 }>::new();
+                ikm.extend_from_slice(first)
+                    .and_then(|()| ikm.extend_from_slice(second))
+                    .and_then(|()| ikm.extend_from_slice(shared_secret.raw_secret_bytes()))
+                    .map_err(|()| InvalidGroup)?;
+                let hkdf = hkdf::Hkdf::<sha2::Sha256>::new(Some(key), &ikm);
+                expand(&hkdf, &info(id, &self.gid, "Key", KEY_LEN)?)
+            };
+        let pairwise_sender_key = pairwise_key(
+            &self.sender_key,
+            self.sender_id.as_slice(),
+            self.credential.as_slice(),
+            credential,
+        )?;
+        let pairwise_recipient_key =
+            pairwise_key(&key, sender_id, credential, self.credential.as_slice())?;
+
+        self.members
+            .push(Member {
+                id: heapless::Vec::from_slice(sender_id).map_err(|_| InvalidGroup)?,
+                credential: heapless::Vec::from_slice(credential).map_err(|_| InvalidGroup)?,
+                public_key,
+                key,
+                pairwise_recipient_key,
+                pairwise_sender_key,
+            })
+            .map_err(|_| InvalidGroup)?;
+        Ok(self)
+    }
+
+    /// Verifies and decrypts a request into `buffer`.
+    ///
+    /// On success, this returns what is needed to protect the response, the request's sequence
+    /// number and the plaintext (starting with the code, followed by options and payload as in a
+    /// CoAP message).
+    pub(crate) fn unprotect_request<'b>(
+        &self,
+        option: &[u8],
+        payload: &[u8],
+        buffer: &'b mut [u8],
+    ) -> Result<(Correlation, u64, &'b [u8]), UnprotectError> {
+        let ParsedOption {
+            mode,
+            piv,
+            kid_context,
+            kid,
+        } = ParsedOption::parse(option).ok_or(UnprotectError::InvalidOption)?;
+        if piv.is_empty() || kid_context != Some(self.gid.as_slice()) {
+            return Err(UnprotectError::InvalidOption);
+        }
+        let kid = kid.ok_or(UnprotectError::InvalidOption)?;
+        let (index, member) = self
+            .members
+            .iter()
+            .enumerate()
+            .find(|(_, m)| m.id.as_slice() == kid)
+            .ok_or(UnprotectError::UnknownSender)?;
+
+        let external_aad = self
+            .external_aad(kid, piv, option, &member.credential)
+            .map_err(|_| UnprotectError::InvalidOption)?;
+        let (key, verifying_key) = match mode {
+            Mode::Group => (&member.key, Some(&member.public_key)),
+            Mode::Pairwise => (&member.pairwise_recipient_key, None),
+        };
+        let len = self.open(
+            key,
+            &self.nonce(kid, piv),
+            &external_aad,
+            verifying_key.map(|k| (k, kid, piv, true)),
+            payload,
+            buffer,
+        )?;
+        let plaintext = buffer.get(..len).ok_or(UnprotectError::VerifyFailed)?;
+        if plaintext.is_empty() {
+            // There is not even a code
+            return Err(UnprotectError::VerifyFailed);
+        }
+
+        let sequence_number = piv
+            .iter()
+            .fold(0, |number, byte| (number << 8) | u64::from(*byte));
+        Ok((
+            Correlation {
+                member: index,
+                mode,
+                request_piv: heapless::Vec::from_slice(piv)
+                    .map_err(|()| UnprotectError::InvalidOption)?,
+            },
+            sequence_number,
+            plaintext,
+        ))
+    }
+
+    /// Encrypts (and in the group mode, signs) the response plaintext in the first
+    /// `plaintext_len` bytes of `buffer` in place.
+    ///
+    /// On success, this returns the value of the OSCORE option of the response, and its payload.
+    /// `buffer` needs to have [`PROTECTION_OVERHEAD`] bytes to spare.
+    pub(crate) fn protect_response<'b>(
+        &self,
+        correlation: &Correlation,
+        buffer: &'b mut [u8],
+        plaintext_len: usize,
+    ) -> Result<(heapless::Vec<u8, { 1 + MAX_ID_LEN }>, &'b [u8]), ()> {
+        let member = self.members.get(correlation.member).ok_or(())?;
+        let piv = correlation.request_piv.as_slice();
+
+        // Responses carry the Sender ID, but use the request's nonce rather than a Partial IV of
+        // their own.
+        let flags = match correlation.mode {
+            Mode::Group => FLAG_GROUP | FLAG_KID,
+            Mode::Pairwise => FLAG_KID,
+        };
+        let mut option = heapless::Vec::new();
+        option.push(flags).map_err(|_| ())?;
+        option.extend_from_slice(&self.sender_id)?;
+
+        let external_aad = self.external_aad(&member.id, piv, &option, &self.credential)?;
+        let key = match correlation.mode {
+            Mode::Group => &self.sender_key,
+            Mode::Pairwise => &member.pairwise_sender_key,
+        };
+        let len = self.seal(
+            key,
+            &self.nonce(&member.id, piv),
+            &external_aad,
+            (correlation.mode == Mode::Group).then_some((self.sender_id.as_slice(), piv, false)),
+            buffer,
+            plaintext_len,
+        )?;
+        Ok((option, buffer.get(..len).ok_or(())?))
+    }
+
+    /// Builds the AEAD nonce from the Sender ID and the Partial IV that the nonce is formed from
+    /// (RFC8613 Section 5.2).
+    fn nonce(&self, id: &[u8], piv: &[u8]) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        // The ID and the Partial IV are left-padded to the end of their fields; their lengths are
+        // checked when configuring members and parsing options.
+        let (id_field, piv_field) = nonce.split_at_mut(NONCE_LEN - MAX_PIV_LEN);
+        for (n, i) in id_field.iter_mut().rev().zip(id.iter().rev()) {
+            *n = *i;
+        }
+        for (n, p) in piv_field.iter_mut().rev().zip(piv.iter().rev()) {
+            *n = *p;
+        }
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "ID is at most MAX_ID_LEN long"
+        )]
+        let id_len = id.len() as u8;
+        nonce[0] = id_len;
+        for (n, iv) in nonce.iter_mut().zip(self.common_iv) {
+            *n ^= iv;
+        }
+        nonce
+    }
+
+    /// Encodes the `external_aad` of a message of this group (Section 4.3 of the draft).
+    ///
+    /// The `request_*` values are those of the request the message is (or responds to);
+    /// `credential` is that of the message's sender.
+    fn external_aad(
+        &self,
+        request_kid: &[u8],
+        request_piv: &[u8],
+        option: &[u8],
+        credential: &[u8],
+    ) -> Result<heapless::Vec<u8, MAX_EXTERNAL_AAD_LEN>, ()> {
+        let mut encoded = heapless::Vec::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut encoded))
+            .array(9)
+            .and_then(|e| e.u8(1))
+            .and_then(|e| e.array(4))
+            .and_then(|e| e.i32(cose_alg::AES_CCM_16_64_128))
+            .and_then(|e| e.i32(cose_alg::ES256))
+            .and_then(|e| e.i32(cose_alg::AES_CCM_16_64_128))
+            .and_then(|e| e.i32(cose_alg::ECDH_SS_HKDF_256))
+            .and_then(|e| e.bytes(request_kid))
+            .and_then(|e| e.bytes(request_piv))
+            // No class I options
+            .and_then(|e| e.bytes(&[]))
+            .and_then(|e| e.bytes(&self.gid))
+            .and_then(|e| e.bytes(option))
+            .and_then(|e| e.bytes(credential))
+            // There is no Group Manager
+            .and_then(|e| e.null())
+            .map_err(|_| ())?;
+        Ok(encoded)
+    }
+
+    /// Computes the keystream with which the signature of a message is encrypted.
+    ///
+    /// `id` is the Sender ID of the message's sender, and `piv` the Partial IV its nonce is formed
+    /// from.
+    fn signature_keystream(
+        &self,
+        id: &[u8],
+        piv: &[u8],
+        is_request: bool,
+    ) -> Result<[u8; SIGNATURE_LEN], ()> {
+        let mut info = heapless::Vec::<u8, MAX_INFO_LEN>::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut info))
+            .array(4)
+            .and_then(|e| e.bytes(id))
+            .and_then(|e| e.bytes(&self.gid))
+            .and_then(|e| e.bool(is_request))
+            .and_then(|e| e.u64(SIGNATURE_LEN as u64))
+            .map_err(|_| ())?;
+        let hkdf = hkdf::Hkdf::<sha2::Sha256>::new(Some(piv), &self.signature_encryption_key);
+        let mut keystream = [0; SIGNATURE_LEN];
+        hkdf.expand(&info, &mut keystream).map_err(|_| ())?;
+        Ok(keystream)
+    }
+
+    /// Encrypts the first `len` bytes of `buffer` in place, and in the group mode (when
+    /// `signature` gives the sender's ID, the Partial IV and the direction for the keystream)
+    /// appends the encrypted signature.
+    ///
+    /// Returns the length of the protected payload.
+    fn seal(
+        &self,
+        key: &Key,
+        nonce: &[u8; NONCE_LEN],
+        external_aad: &[u8],
+        signature: Option<(&[u8], &[u8], bool)>,
+        buffer: &mut [u8],
+        len: usize,
+    ) -> Result<usize, ()> {
+        use ccm::KeyInit as _;
+        use ccm::aead::AeadInPlace as _;
+
+        let (plaintext, tail) = buffer.split_at_mut_checked(len).ok_or(())?;
+        let tag = Aes128Ccm::new(key.into())
+            .encrypt_in_place_detached(nonce.into(), &enc_structure(external_aad)?, plaintext)
+            .map_err(|_| ())?;
+        tail.get_mut(..TAG_LEN).ok_or(())?.copy_from_slice(&tag);
+        let len = len + TAG_LEN;
+
+        let Some((id, piv, is_request)) = signature else {
+            return Ok(len);
+        };
+        let ciphertext = buffer.get(..len).ok_or(())?;
+        let signature: p256::ecdsa::Signature = self
+            .signing_key
+            .sign_digest(signature_digest(external_aad, ciphertext));
+        let keystream = self.signature_keystream(id, piv, is_request)?;
+        let target = buffer.get_mut(len..len + SIGNATURE_LEN).ok_or(())?;
+        for ((t, s), k) in target.iter_mut().zip(signature.to_bytes()).zip(keystream) {
+            *t = s ^ k;
+        }
+        Ok(len + SIGNATURE_LEN)
+    }
+
+    /// Verifies `payload` (in the group mode, when `signature` gives the sender's verifying key,
+    /// ID, the Partial IV and the direction for the keystream) and decrypts it into `buffer`.
+    ///
+    /// Returns the length of the plaintext.
+    fn open(
+        &self,
+        key: &Key,
+        nonce: &[u8; NONCE_LEN],
+        external_aad: &[u8],
+        signature: Option<(&p256::ecdsa::VerifyingKey, &[u8], &[u8], bool)>,
+        payload: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize, UnprotectError> {
+        use ccm::KeyInit as _;
+        use ccm::aead::AeadInPlace as _;
+
+        let ciphertext = if let Some((verifying_key, id, piv, is_request)) = signature {
+            let (ciphertext, encrypted_signature) = payload
+                .split_at_checked(
+                    payload
+                        .len()
+                        .checked_sub(SIGNATURE_LEN)
+                        .ok_or(UnprotectError::VerifyFailed)?,
+                )
+                .ok_or(UnprotectError::VerifyFailed)?;
+            let keystream = self
+                .signature_keystream(id, piv, is_request)
+                .map_err(|()| UnprotectError::InvalidOption)?;
+            let mut signature = [0; SIGNATURE_LEN];
+            for ((s, e), k) in signature.iter_mut().zip(encrypted_signature).zip(keystream) {
+                *s = e ^ k;
+            }
+            let signature = p256::ecdsa::Signature::from_slice(&signature)
+                .map_err(|_| UnprotectError::VerifyFailed)?;
+            verifying_key
+                .verify_digest(signature_digest(external_aad, ciphertext), &signature)
+                .map_err(|_| {
+                    debug!("Group OSCORE signature did not verify.");
+                    UnprotectError::VerifyFailed
+                })?;
+            ciphertext
+        } else {
+            payload
+        };
+
+        let len = ciphertext
+            .len()
+            .checked_sub(TAG_LEN)
+            .ok_or(UnprotectError::VerifyFailed)?;
+        let (ciphertext, tag) = ciphertext.split_at(len);
+        let plaintext = buffer.get_mut(..len).ok_or(UnprotectError::VerifyFailed)?;
+        plaintext.copy_from_slice(ciphertext);
+        let aad = enc_structure(external_aad).map_err(|()| UnprotectError::InvalidOption)?;
+        Aes128Ccm::new(key.into())
+            .decrypt_in_place_detached(nonce.into(), &aad, plaintext, ccm::Tag::from_slice(tag))
+            .map_err(|_| {
+                debug!("Group OSCORE decryption failed.");
+                UnprotectError::VerifyFailed
+            })?;
+        Ok(len)
+    }
+}
+
+/// Returns true if an OSCORE option value carries an ID Context, which indicates a Group OSCORE
+/// request (as none of the other contexts of this crate use one).
+pub(crate) fn is_group_option(option: &[u8]) -> bool {
+    option
+        .first()
+        .is_some_and(|flags| flags & FLAG_KID_CONTEXT != 0)
+}
+
+/// What is needed to protect the response to a group request.
+#[derive(Debug)]
+pub(crate) struct Correlation {
+    member: usize,
+    mode: Mode,
+    request_piv: heapless::Vec<u8, MAX_PIV_LEN>,
+}
+
+impl Correlation {
+    /// The index of the request's sender among the members of the group.
+    pub(crate) fn member(&self) -> usize {
+        self.member
+    }
+}
+
+/// The verdict of a [`Replay`] window on a request.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Freshness {
+    /// The request is to be processed.
+    Fresh,
+    /// The request was already processed, or is too old to tell.
+    Replay,
+    /// The window is unknown, and the request did not repeat the Echo value sent last.
+    Unknown,
+}
+
+/// Replay protection state of a group member (RFC8613 Section 7.4), as kept by the handler.
+#[derive(Debug, Default)]
+pub(crate) struct Replay {
+    /// The highest accepted sequence number, along with a mask of which of the 32 sequence numbers
+    /// below it were accepted (or are too old to tell).
+    ///
+    /// This is `None` until a request repeated an Echo value.
+    window: Option<(u64, u32)>,
+    /// The Echo value sent last while the window is unknown.
+    echo: Option<[u8; ECHO_LEN]>,
+}
+
+impl Replay {
+    /// Checks whether a verified request with the given sequence number is to be processed.
+    pub(crate) fn check(&self, sequence_number: u64, request: &impl ReadableMessage) -> Freshness {
+        match self.window {
+            None => {
+                let repeated = self.echo.as_ref().is_some_and(|echo| {
+                    request
+                        .options()
+                        .any(|o| o.number() == coap_numbers::option::ECHO && o.value() == echo)
+                });
+                if repeated {
+                    Freshness::Fresh
+                } else {
+                    Freshness::Unknown
+                }
+            }
+            Some((highest, _)) if sequence_number > highest => Freshness::Fresh,
+            Some((highest, seen)) => {
+                if window_bit(highest - sequence_number) & !seen == 0 {
+                    Freshness::Replay
+                } else {
+                    Freshness::Fresh
+                }
+            }
+        }
+    }
+
+    /// Records that a request with the given sequence number was processed.
+    pub(crate) fn accept(&mut self, sequence_number: u64) {
+        self.window = Some(match self.window {
+            None => {
+                // Requests sent before the one that repeated the Echo value may be replays.
+                self.echo = None;
+                (sequence_number, u32::MAX)
+            }
+            Some((highest, seen)) if sequence_number > highest => {
+                let shift = sequence_number - highest;
+                let seen = u32::try_from(shift)
+                    .ok()
+                    .and_then(|shift| seen.checked_shl(shift))
+                    .unwrap_or(0);
+                (sequence_number, seen | window_bit(shift))
+            }
+            Some((highest, seen)) => (highest, seen | window_bit(highest - sequence_number)),
+        });
+    }
+
+    /// Picks a new Echo value to challenge the member with.
+    pub(crate) fn challenge(&mut self, rng: &mut impl rand_core::RngCore) {
+        let mut value = [0; ECHO_LEN];
+        rng.fill_bytes(&mut value);
+        self.echo = Some(value);
+    }
+
+    /// Returns the Echo value to send along with a 4.01 Unauthorized response, if any.
+    pub(crate) fn echo(&self) -> Option<&[u8; ECHO_LEN]> {
+        self.echo.as_ref()
+    }
+}
+
+/// Returns the bit of a [`Replay`] window mask that represents the sequence number `distance`
+/// below the highest one, or 0 if it is not in the window.
+fn window_bit(distance: u64) -> u32 {
+    match distance.checked_sub(1) {
+        Some(bit) if bit < 32 => 1 << bit,
+        _ => 0,
+    }
+}
+
+/// The fields of an OSCORE option value as used in Group OSCORE.
+struct ParsedOption<'a> {
+    mode: Mode,
+    piv: &'a [u8],
+    kid_context: Option<&'a [u8]>,
+    kid: Option<&'a [u8]>,
+}
+
+impl<'a> ParsedOption<'a> {
+    fn parse(option: &'a [u8]) -> Option<Self> {
+        let (&flags, rest) = option.split_first()?;
+        if flags & (FLAG_EXTENSION | FLAG_RESERVED) != 0 {
+            return None;
+        }
+        let piv_len = usize::from(flags & MASK_PIV_LEN);
+        if piv_len > MAX_PIV_LEN {
+            return None;
+        }
+        let (piv, mut rest) = rest.split_at_checked(piv_len)?;
+        let kid_context = if flags & FLAG_KID_CONTEXT != 0 {
+            let (&s, tail) = rest.split_first()?;
+            let (kid_context, tail) = tail.split_at_checked(usize::from(s))?;
+            rest = tail;
+            Some(kid_context)
+        } else {
+            None
+        };
+        let kid = if flags & FLAG_KID != 0 {
+            if rest.len() > MAX_ID_LEN {
+                return None;
+            }
+            Some(rest)
+        } else if rest.is_empty() {
+            None
+        } else {
+            return None;
+        };
+        Some(Self {
+            mode: if flags & FLAG_GROUP != 0 {
+                Mode::Group
+            } else {
+                Mode::Pairwise
+            },
+            piv,
+            kid_context,
+            kid,
+        })
+    }
+}
+
+/// Encodes the `info` of the key derivation of a Sender, Recipient or Signature Encryption Key,
+/// or of the Common IV (RFC8613 Section 3.2.1).
+fn info(
+    id: &[u8],
+    gid: &[u8],
+    kind: &str,
+    len: usize,
+) -> Result<heapless::Vec<u8, MAX_INFO_LEN>, InvalidGroup> {
+    let mut info = heapless::Vec::new();
+    minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut info))
+        .array(5)
+        .and_then(|e| e.bytes(id))
+        .and_then(|e| e.bytes(gid))
+        .and_then(|e| e.i32(cose_alg::AES_CCM_16_64_128))
+        .and_then(|e| e.str(kind))
+        .and_then(|e| e.u64(len as u64))
+        .map_err(|_| InvalidGroup)?;
+    Ok(info)
+}
+
+/// Runs the HKDF-Expand step of a key derivation.
+fn expand<const N: usize>(
+    hkdf: &hkdf::Hkdf<sha2::Sha256>,
+    info: &[u8],
+) -> Result<[u8; N], InvalidGroup> {
+    let mut output = [0; N];
+    hkdf.expand(info, &mut output).map_err(|_| InvalidGroup)?;
+    Ok(output)
+}
+
+/// Encodes the `Enc_structure` whose encoding is the AAD of the encryption.
+fn enc_structure(external_aad: &[u8]) -> Result<heapless::Vec<u8, MAX_AAD_LEN>, ()> {
+    let mut encoded = heapless::Vec::new();
+    minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut encoded))
+        .array(3)
+        .and_then(|e| e.str("Encrypt0"))
+        .and_then(|e| e.bytes(&[]))
+        .and_then(|e| e.bytes(external_aad))
+        .map_err(|_| ())?;
+    Ok(encoded)
+}
+
+/// Hashes the `Sig_structure` that is signed in the group mode.
+///
+/// The signed payload is the ciphertext, including the tag.
+fn signature_digest(external_aad: &[u8], ciphertext: &[u8]) -> sha2::Sha256 {
+    let mut head = heapless::Vec::<u8, { 1 + 11 + 1 + 3 + MAX_EXTERNAL_AAD_LEN }>::new();
+    // The buffer is sized for the longest external_aad.
+    let _ = minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut head))
+        .array(4)
+        .and_then(|e| e.str("Signature1"))
+        .and_then(|e| e.bytes(&[]))
+        .and_then(|e| e.bytes(external_aad));
+
+    let mut digest = sha2::Sha256::new();
+    digest.update(&head);
+    digest.update(bstr_head(ciphertext.len()));
+    digest.update(ciphertext);
+    digest
+}
+
+/// Encodes the head of a CBOR byte string of the given length.
+fn bstr_head(len: usize) -> heapless::Vec<u8, 9> {
+    let mut head = heapless::Vec::new();
+    // The lengths are so short that none of this fails.
+    let _ = minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut head)).u64(len as u64);
+    if let Some(first) = head.first_mut() {
+        // Turn the unsigned integer (major type 0) into a byte string head (major type 2)
+        *first |= 0x40;
+    }
+    head
+}
+
+/// Extracts the P-256 public key from a CCS.
+fn credential_key(credential: &[u8]) -> Option<p256::ecdsa::VerifyingKey> {
+    /// Moves the decoder to the value of the integer key `label` in the map at its position.
+    fn enter(decoder: &mut minicbor::Decoder<'_>, label: i64) -> Option<()> {
+        use minicbor::data::Type;
+
+        for _ in 0..decoder.map().ok()?? {
+            let found = match decoder.datatype().ok()? {
+                Type::U8
+                | Type::U16
+                | Type::U32
+                | Type::U64
+                | Type::I8
+                | Type::I16
+                | Type::I32
+                | Type::I64 => decoder.i64().ok()? == label,
+                _ => {
+                    decoder.skip().ok()?;
+                    false
+                }
+            };
+            if found {
+                return Some(());
+            }
+            decoder.skip().ok()?;
+        }
+        None
+    }
+
+    let mut decoder = minicbor::Decoder::new(credential);
+    // cnf / COSE_Key
+    enter(&mut decoder, 8)?;
+    enter(&mut decoder, 1)?;
+    let key: crate::ace::CoseKey = decoder.decode().ok()?;
+    trace!("Group credential key: {:?}", key);
+    // EC2 on P-256
+    if key.kty != 2 || key.crv != Some(1) {
+        return None;
+    }
+    let x: &[u8; 32] = key.x?.try_into().ok()?;
+    let y: &[u8; 32] = key.y?.try_into().ok()?;
+    p256::ecdsa::VerifyingKey::from_encoded_point(&p256::EncodedPoint::from_affine_coordinates(
+        x.into(),
+        y.into(),
+        false,
+    ))
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use coap_message::MinimalWritableMessage as _;
+    use coap_message_implementations::inmemory_write::Message;
+
+    use super::*;
+
+    fn kccs(key: &p256::ecdsa::SigningKey) -> Credential {
+        let point = key.verifying_key().to_encoded_point(false);
+        let mut encoded = Credential::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut encoded))
+            .map(2)
+            .and_then(|e| e.u8(2))
+            .and_then(|e| e.str("member"))
+            .and_then(|e| e.u8(8))
+            .and_then(|e| e.map(1))
+            .and_then(|e| e.u8(1))
+            .and_then(|e| e.map(4))
+            .and_then(|e| e.u8(1))
+            .and_then(|e| e.u8(2))
+            .and_then(|e| e.i8(-1))
+            .and_then(|e| e.u8(1))
+            .and_then(|e| e.i8(-2))
+            .and_then(|e| e.bytes(point.x().unwrap()))
+            .and_then(|e| e.i8(-3))
+            .and_then(|e| e.bytes(point.y().unwrap()))
+            .unwrap();
+        encoded
+    }
+
+    /// Sets up the two views of a group with members 0x01 and 0x02.
+    fn groups() -> (OscoreGroup, OscoreGroup) {
+        let private_1 = [0x11; 32];
+        let private_2 = [0x22; 32];
+        let credential_1 = kccs(&p256::ecdsa::SigningKey::from_bytes((&private_1).into()).unwrap());
+        let credential_2 = kccs(&p256::ecdsa::SigningKey::from_bytes((&private_2).into()).unwrap());
+        let view = |id: u8, credential: &[u8], private, other: u8, other_credential: &[u8]| {
+            OscoreGroup::new(
+                b"lights",
+                &[0x42; 16],
+                &[0x23; 8],
+                &[id],
+                credential,
+                private,
+            )
+            .unwrap()
+            .with_member(&[other], other_credential)
+            .unwrap()
+        };
+        (
+            view(0x01, &credential_1, private_1, 0x02, &credential_2),
+            view(0x02, &credential_2, private_2, 0x01, &credential_1),
+        )
+    }
+
+    /// Protects a request from `client`'s view, as a client implementation would.
+    fn protect_request(
+        client: &OscoreGroup,
+        mode: Mode,
+        piv: &[u8],
+        plaintext: &[u8],
+        buffer: &mut [u8],
+    ) -> (heapless::Vec<u8, 32>, usize) {
+        let mut option = heapless::Vec::<u8, 32>::new();
+        let flags = match mode {
+            Mode::Group => FLAG_GROUP,
+            Mode::Pairwise => 0,
+        };
+        option
+            .push(flags | FLAG_KID_CONTEXT | FLAG_KID | u8::try_from(piv.len()).unwrap())
+            .unwrap();
+        option.extend_from_slice(piv).unwrap();
+        option
+            .push(u8::try_from(client.gid.len()).unwrap())
+            .unwrap();
+        option.extend_from_slice(&client.gid).unwrap();
+        option.extend_from_slice(&client.sender_id).unwrap();
+
+        let server = client.members.first().unwrap();
+        let key = match mode {
+            Mode::Group => &client.sender_key,
+            Mode::Pairwise => &server.pairwise_sender_key,
+        };
+        let external_aad = client
+            .external_aad(&client.sender_id, piv, &option, &client.credential)
+            .unwrap();
+        buffer
+            .get_mut(..plaintext.len())
+            .unwrap()
+            .copy_from_slice(plaintext);
+        let len = client
+            .seal(
+                key,
+                &client.nonce(&client.sender_id, piv),
+                &external_aad,
+                (mode == Mode::Group).then_some((client.sender_id.as_slice(), piv, true)),
+                buffer,
+                plaintext.len(),
+            )
+            .unwrap();
+        (option, len)
+    }
+
+    /// Unprotects a response in `client`'s view, as a client implementation would.
+    fn unprotect_response(
+        client: &OscoreGroup,
+        mode: Mode,
+        piv: &[u8],
+        option: &[u8],
+        payload: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<usize, UnprotectError> {
+        let server = client.members.first().unwrap();
+        let parsed = ParsedOption::parse(option).unwrap();
+        assert_eq!(parsed.mode, mode);
+        assert_eq!(parsed.kid, Some(server.id.as_slice()));
+        let external_aad = client
+            .external_aad(&client.sender_id, piv, option, &server.credential)
+            .unwrap();
+        let key = match mode {
+            Mode::Group => &server.key,
+            Mode::Pairwise => &server.pairwise_recipient_key,
+        };
+        client.open(
+            key,
+            &client.nonce(&client.sender_id, piv),
+            &external_aad,
+            (mode == Mode::Group).then_some((&server.public_key, server.id.as_slice(), piv, false)),
+            payload,
+            buffer,
+        )
+    }
+
+    fn roundtrip(mode: Mode) {
+        let (client, server) = groups();
+        // GET /light
+        let request = [0x01, 0xb5, b'l', b'i', b'g', b'h', b't'];
+        let mut buffer = [0; 256];
+        let (option, len) = protect_request(&client, mode, &[0x05], &request, &mut buffer);
+
+        let mut plaintext = [0; 256];
+        let (correlation, sequence_number, decrypted) = server
+            .unprotect_request(&option, buffer.get(..len).unwrap(), &mut plaintext)
+            .unwrap();
+        assert_eq!(decrypted, &request);
+        assert_eq!(sequence_number, 5);
+        assert_eq!(correlation.member(), 0);
+
+        // 2.05 Content "on"
+        let response = [0x45, 0xff, b'o', b'n'];
+        let mut buffer = [0; 256];
+        buffer
+            .get_mut(..response.len())
+            .unwrap()
+            .copy_from_slice(&response);
+        let (option, payload) = server
+            .protect_response(&correlation, &mut buffer, response.len())
+            .unwrap();
+
+        let mut plaintext = [0; 256];
+        let len =
+            unprotect_response(&client, mode, &[0x05], &option, payload, &mut plaintext).unwrap();
+        assert_eq!(plaintext.get(..len).unwrap(), &response);
+    }
+
+    #[test]
+    fn group_mode_roundtrip() {
+        roundtrip(Mode::Group);
+    }
+
+    #[test]
+    fn pairwise_mode_roundtrip() {
+        roundtrip(Mode::Pairwise);
+    }
+
+    #[test]
+    fn tampered_requests_fail() {
+        let (client, server) = groups();
+        let mut buffer = [0; 256];
+        let (option, len) = protect_request(&client, Mode::Group, &[0x07], &[0x01], &mut buffer);
+        let protected = buffer.get(..len).unwrap();
+        let mut plaintext = [0; 256];
+
+        // Flipping a bit of the ciphertext breaks the signature
+        let mut tampered = buffer;
+        tampered[0] ^= 1;
+        assert!(matches!(
+            server.unprotect_request(&option, tampered.get(..len).unwrap(), &mut plaintext),
+            Err(UnprotectError::VerifyFailed)
+        ));
+
+        // The Partial IV is part of the nonce and the AAD
+        let mut other_option = option.clone();
+        *other_option.get_mut(1).unwrap() = 0x08;
+        assert!(
+            server
+                .unprotect_request(&other_option, protected, &mut plaintext)
+                .is_err()
+        );
+
+        // Members can not claim to be someone else
+        let mut unknown = option.clone();
+        *unknown.last_mut().unwrap() = 0x03;
+        assert!(matches!(
+            server.unprotect_request(&unknown, protected, &mut plaintext),
+            Err(UnprotectError::UnknownSender)
+        ));
+
+        assert!(
+            server
+                .unprotect_request(&option, protected, &mut plaintext)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn mismatched_own_key_is_rejected() {
+        let credential = kccs(&p256::ecdsa::SigningKey::from_bytes((&[0x11; 32]).into()).unwrap());
+        assert!(
+            OscoreGroup::new(b"g", &[0x42; 16], &[], &[0x01], &credential, [0x22; 32]).is_err()
+        );
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut code = 0;
+        let mut buffer = [0; 32];
+        let request = Message::new(&mut code, &mut buffer[..]);
+
+        let mut replay = Replay::default();
+        assert_eq!(replay.check(10, &request), Freshness::Unknown);
+        replay.echo = Some([7; ECHO_LEN]);
+        assert_eq!(replay.check(10, &request), Freshness::Unknown);
+
+        let mut code = 0;
+        let mut buffer = [0; 32];
+        let mut repeated = Message::new(&mut code, &mut buffer[..]);
+        repeated
+            .add_option(coap_numbers::option::ECHO, &[7; ECHO_LEN])
+            .unwrap();
+        assert_eq!(replay.check(11, &repeated), Freshness::Fresh);
+        replay.accept(11);
+        assert!(replay.echo().is_none());
+
+        // Anything before the verified request may be a replay
+        assert_eq!(replay.check(11, &request), Freshness::Replay);
+        assert_eq!(replay.check(3, &request), Freshness::Replay);
+        assert_eq!(replay.check(13, &request), Freshness::Fresh);
+        replay.accept(13);
+        assert_eq!(replay.check(12, &request), Freshness::Fresh);
+        replay.accept(12);
+        assert_eq!(replay.check(12, &request), Freshness::Replay);
+        replay.accept(100);
+        assert_eq!(replay.check(99, &request), Freshness::Fresh);
+        assert_eq!(replay.check(13, &request), Freshness::Replay);
+    }
+}
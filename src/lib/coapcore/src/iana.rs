@@ -22,4 +22,29 @@ pub(crate) mod cose_alg {
 
     /// AES-CCM-16-64-128
     pub(crate) const AES_CCM_16_64_128: i32 = 10;
+
+    /// ES256 (ECDSA w/ SHA-256)
+    pub(crate) const ES256: i32 = -7;
+
+    /// ECDH-SS + HKDF-256
+    pub(crate) const ECDH_SS_HKDF_256: i32 = -27;
+
+    /// SHA-256 (from COSE Algorithms)
+    pub(crate) const SHA_256: i32 = -16;
+
+    /// SHA-256 truncated to 64 bits (from COSE Algorithms)
+    pub(crate) const SHA_256_64: i32 = -15;
+}
+
+/// The [COSE Header Parameters](https://www.iana.org/assignments/cose/cose.xhtml#header-parameters)
+/// registry, as far as its labels are used in EDHOC `ID_CRED_x` maps
+pub(crate) mod cose_header {
+    /// A CWT containing a COSE_Key in a `cnf` claim
+    pub(crate) const KCWT: i64 = 13;
+
+    /// An ordered chain of X.509 certificates
+    pub(crate) const X5CHAIN: i64 = 33;
+
+    /// Hash of an X.509 certificate
+    pub(crate) const X5T: i64 = 34;
 }
@@ -0,0 +1,372 @@
+//! Key update for OSCORE (KUDOS), following
+//! [draft-ietf-core-oscore-key-update](https://datatracker.ietf.org/doc/draft-ietf-core-oscore-key-update/).
+//!
+//! KUDOS derives a new OSCORE security context from an established one and nonces contributed by
+//! both peers, without running EDHOC again. This allows long-lived contexts to be rekeyed before
+//! their sequence numbers run out, or before the policy under which they were established would
+//! demand new keys.
+//!
+//! This implements the forward message flow, which the client initiates:
+//!
+//! * The client sends a request protected with an intermediate context, which it derives from the
+//!   old context and a nonce N1. It announces N1 (along with the X1 byte) in the request's OSCORE
+//!   option.
+//! * The server derives the same intermediate context to unprotect the request, and then the new
+//!   context from both its own nonce N2 and N1. It responds with the new context, announcing N2
+//!   in the response's OSCORE option, and discards the old context.
+//! * The client derives the new context as well, and uses it from then on.
+//!
+//! Neither the reverse message flow nor the "no forward secrecy" mode are supported, and
+//! observations are not preserved across key updates.
+//!
+//! On the server side, the [`OscoreEdhocHandler`] takes part in key updates of all contexts it
+//! established, as long as their replay window is known to be accurate (see [`crate::persist`]).
+//! A context that was updated is evicted first until the client has used it.
+//!
+//! On the client side, the [`OscoreEdhocClient`] updates the keys of a context with its next
+//! request once its sender sequence number reaches [`SEQUENCE_NUMBER_THRESHOLD`], or when asked
+//! to through [`OscoreEdhocClient::update_key()`].
+//!
+//! [`OscoreEdhocHandler`]: crate::OscoreEdhocHandler
+//! [`OscoreEdhocClient`]: crate::OscoreEdhocClient
+//! [`OscoreEdhocClient::update_key()`]: crate::OscoreEdhocClient::update_key
+
+use coap_message::{MessageOption as _, MinimalWritableMessage as _, ReadableMessage as _};
+use coap_message_implementations::inmemory_write::Message;
+use hmac::Mac as _;
+
+use crate::persist::ContextMaterial;
+
+/// Sender sequence number from which an [`OscoreEdhocClient`](crate::OscoreEdhocClient) updates
+/// the keys of a context before sending further requests.
+///
+/// This is well below the limit of 2^40 imposed by the length of OSCORE's Partial IV, so that
+/// there is ample room for the key update to be retried.
+pub const SEQUENCE_NUMBER_THRESHOLD: u64 = 1 << 32;
+
+/// Length of the nonces chosen by this implementation.
+pub(crate) const NONCE_LEN: usize = 8;
+
+/// Maximum length of a nonce, as expressible in the X byte.
+const MAX_NONCE_LEN: usize = 16;
+
+/// Maximum length of an OSCORE option value processed by this crate.
+pub(crate) const MAX_OPTION_LEN: usize = 32;
+
+/// An OSCORE option value.
+pub(crate) type OscoreOption = heapless::Vec<u8, MAX_OPTION_LEN>;
+
+/// Flags in the first byte of the OSCORE option.
+const FLAG_EXTENSION: u8 = 0x80;
+const FLAG_KID_CONTEXT: u8 = 0x10;
+const FLAG_KID: u8 = 0x08;
+const MASK_PIV_LEN: u8 = 0x07;
+
+/// The `d` flag in the second byte of the OSCORE option, indicating the presence of X and N.
+const FLAG_KEY_UPDATE: u8 = 0x01;
+
+/// The bits of X that encode the length of the nonce (minus one).
+const MASK_NONCE_LEN: u8 = 0x0f;
+/// The bits of X that request the reverse message flow or the "no forward secrecy" mode, or are
+/// reserved.
+///
+/// The `p` bit (preserving observations) is ignored, as observations do not survive key updates.
+const MASK_UNSUPPORTED: u8 = 0xe0;
+
+/// Label of the key derivation, following the "oscore " prefix of `KUDOS-Expand-Label`.
+const LABEL: &[u8] = b"oscore key update";
+
+/// Error type indicating that an OSCORE option is malformed or uses unsupported extensions.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct InvalidOption;
+
+/// The key update fields of an OSCORE option: the X byte and the nonce.
+#[derive(Debug, Clone)]
+pub(crate) struct KeyUpdate {
+    x: u8,
+    nonce: heapless::Vec<u8, MAX_NONCE_LEN>,
+}
+
+impl KeyUpdate {
+    /// Creates the fields announcing a nonce chosen by this implementation.
+    pub(crate) fn new(nonce: [u8; NONCE_LEN]) -> Self {
+        #[expect(
+            clippy::cast_possible_truncation,
+            reason = "NONCE_LEN is less than MAX_NONCE_LEN"
+        )]
+        let x = (NONCE_LEN - 1) as u8;
+        Self {
+            x,
+            nonce: heapless::Vec::from_slice(&nonce).expect("NONCE_LEN is less than MAX_NONCE_LEN"),
+        }
+    }
+
+    /// Returns true if the peer requested only what this implementation supports.
+    pub(crate) fn is_supported(&self) -> bool {
+        self.x & MASK_UNSUPPORTED == 0
+    }
+
+    /// Derives the intermediate context material `CTX_1` a request announcing these fields is
+    /// protected with.
+    pub(crate) fn intermediate(&self, old: &ContextMaterial) -> Option<ContextMaterial> {
+        update(old, &[self.x], &self.nonce)
+    }
+
+    /// Derives the new context material `CTX_NEW` from the fields of the request and of the
+    /// response.
+    pub(crate) fn combined(
+        request: &Self,
+        response: &Self,
+        old: &ContextMaterial,
+    ) -> Option<ContextMaterial> {
+        let x = cbor_sequence(&[request.x], &[response.x])?;
+        let n = cbor_sequence(&request.nonce, &response.nonce)?;
+        update(old, &x, &n)
+    }
+}
+
+/// Implements `updateCtx(X, N, CTX_OLD)`.
+///
+/// The new master secret is derived from the old one through `KUDOS-Expand-Label`, with the
+/// encoded X and N as context; N becomes the new master salt.
+fn update(old: &ContextMaterial, x: &[u8], n: &[u8]) -> Option<ContextMaterial> {
+    if old.hkdf() != crate::iana::cose_alg::HKDF_HMAC256256 {
+        return None;
+    }
+    let old_secret = old.master_secret();
+
+    let mut x_n = heapless::Vec::<u8, { 2 * (2 + 2 * (2 + MAX_NONCE_LEN)) }>::new();
+    push_bstr(&mut x_n, x)?;
+    push_bstr(&mut x_n, n)?;
+
+    // HKDF-Expand for a single block, which is all that is needed for secrets of at most the
+    // hash length; HMAC zero-pads the shorter PRKs used as OSCORE master secrets.
+    let length = u16::try_from(old_secret.len()).ok()?;
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(old_secret).ok()?;
+    mac.update(&length.to_be_bytes());
+    mac.update(&[u8::try_from(LABEL.len()).ok()?]);
+    mac.update(LABEL);
+    mac.update(&[u8::try_from(x_n.len()).ok()?]);
+    mac.update(&x_n);
+    mac.update(&[1]);
+    let block = mac.finalize().into_bytes();
+
+    old.with_master(block.get(..old_secret.len())?, n)
+}
+
+/// Encodes two byte strings as a CBOR sequence (the content of `comb()`).
+fn cbor_sequence(a: &[u8], b: &[u8]) -> Option<heapless::Vec<u8, { 2 * (2 + MAX_NONCE_LEN) }>> {
+    let mut sequence = heapless::Vec::new();
+    push_bstr(&mut sequence, a)?;
+    push_bstr(&mut sequence, b)?;
+    Some(sequence)
+}
+
+/// Appends `bytes` as a CBOR byte string of less than 256 bytes.
+fn push_bstr<const N: usize>(out: &mut heapless::Vec<u8, N>, bytes: &[u8]) -> Option<()> {
+    let len = u8::try_from(bytes.len()).ok()?;
+    if len < 24 {
+        out.push(0x40 | len).ok()?;
+    } else {
+        out.extend_from_slice(&[0x58, len]).ok()?;
+    }
+    out.extend_from_slice(bytes).ok()
+}
+
+/// Splits the key update fields off an OSCORE option value.
+///
+/// The returned option value is in the form of RFC8613, as libOSCORE processes it.
+pub(crate) fn split_option(
+    option: &[u8],
+) -> Result<(OscoreOption, Option<KeyUpdate>), InvalidOption> {
+    let Some((&flags, rest)) = option.split_first() else {
+        return Ok((OscoreOption::new(), None));
+    };
+    if flags & FLAG_EXTENSION == 0 {
+        return Ok((
+            OscoreOption::from_slice(option).map_err(|_| InvalidOption)?,
+            None,
+        ));
+    }
+    let (&flags_1, rest) = rest.split_first().ok_or(InvalidOption)?;
+    if flags_1 & !FLAG_KEY_UPDATE != 0 {
+        return Err(InvalidOption);
+    }
+
+    let mut plain = OscoreOption::new();
+    let mut push = |bytes: &[u8]| plain.extend_from_slice(bytes).map_err(|_| InvalidOption);
+    push(&[flags & !FLAG_EXTENSION])?;
+    let (piv, mut rest) = rest
+        .split_at_checked(usize::from(flags & MASK_PIV_LEN))
+        .ok_or(InvalidOption)?;
+    push(piv)?;
+    if flags & FLAG_KID_CONTEXT != 0 {
+        let (&s, tail) = rest.split_first().ok_or(InvalidOption)?;
+        let (kid_context, tail) = tail.split_at_checked(usize::from(s)).ok_or(InvalidOption)?;
+        push(&[s])?;
+        push(kid_context)?;
+        rest = tail;
+    }
+    let key_update = if flags_1 & FLAG_KEY_UPDATE != 0 {
+        let (&x, tail) = rest.split_first().ok_or(InvalidOption)?;
+        let (nonce, tail) = tail
+            .split_at_checked(usize::from(x & MASK_NONCE_LEN) + 1)
+            .ok_or(InvalidOption)?;
+        rest = tail;
+        Some(KeyUpdate {
+            x,
+            nonce: heapless::Vec::from_slice(nonce).map_err(|_| InvalidOption)?,
+        })
+    } else {
+        None
+    };
+    if flags & FLAG_KID == 0 && !rest.is_empty() {
+        return Err(InvalidOption);
+    }
+    push(rest)?;
+
+    if plain == [0] {
+        // All flags are zero, which is expressed as an empty option.
+        plain.clear();
+    }
+    Ok((plain, key_update))
+}
+
+/// Adds key update fields to an OSCORE option value in the form of RFC8613.
+pub(crate) fn join_option(
+    option: &[u8],
+    key_update: &KeyUpdate,
+) -> Result<OscoreOption, InvalidOption> {
+    let (flags, rest) = option.split_first().map_or((0, &[][..]), |(f, r)| (*f, r));
+    if flags & FLAG_EXTENSION != 0 {
+        return Err(InvalidOption);
+    }
+
+    let mut joined = OscoreOption::new();
+    let mut push = |bytes: &[u8]| joined.extend_from_slice(bytes).map_err(|_| InvalidOption);
+    push(&[flags | FLAG_EXTENSION, FLAG_KEY_UPDATE])?;
+    let (piv, mut rest) = rest
+        .split_at_checked(usize::from(flags & MASK_PIV_LEN))
+        .ok_or(InvalidOption)?;
+    push(piv)?;
+    if flags & FLAG_KID_CONTEXT != 0 {
+        let s = *rest.first().ok_or(InvalidOption)?;
+        let (kid_context, tail) = rest
+            .split_at_checked(1 + usize::from(s))
+            .ok_or(InvalidOption)?;
+        push(kid_context)?;
+        rest = tail;
+    }
+    push(&[key_update.x])?;
+    push(&key_update.nonce)?;
+    push(rest)?;
+    Ok(joined)
+}
+
+/// Copies a protected `message` into `target`, adding the key update fields to its OSCORE option.
+///
+/// This fails if the OSCORE option already has extensions, or if `target` is too small.
+pub(crate) fn copy_with_key_update(
+    message: &Message<'_>,
+    key_update: &KeyUpdate,
+    target: &mut Message<'_>,
+) -> Result<(), ()> {
+    target.set_code(message.code().into());
+    for opt in message.options() {
+        let joined;
+        let value = if opt.number() == coap_numbers::option::OSCORE {
+            joined = join_option(opt.value(), key_update).map_err(|_| ())?;
+            joined.as_slice()
+        } else {
+            opt.value()
+        };
+        target.add_option(opt.number(), value).map_err(|_| ())?;
+    }
+    target.set_payload(message.payload()).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::COwn;
+
+    #[test]
+    fn option_roundtrip() {
+        let key_update = KeyUpdate::new([0x11; NONCE_LEN]);
+        // Partial IV 0x05 and KID 0x37
+        let plain = [0x09, 0x05, 0x37];
+        let joined = join_option(&plain, &key_update).unwrap();
+        assert_eq!(
+            joined.as_slice(),
+            &[
+                0x89, 0x01, 0x05, 0x07, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x37
+            ]
+        );
+
+        let (split, found) = split_option(&joined).unwrap();
+        assert_eq!(split.as_slice(), &plain);
+        let found = found.unwrap();
+        assert_eq!(found.x, key_update.x);
+        assert_eq!(found.nonce, key_update.nonce);
+
+        // Responses usually carry no KID
+        let joined = join_option(&[0x01, 0x00], &key_update).unwrap();
+        let (split, found) = split_option(&joined).unwrap();
+        assert_eq!(split.as_slice(), &[0x01, 0x00]);
+        assert!(found.is_some());
+    }
+
+    #[test]
+    fn plain_options_pass() {
+        let (split, found) = split_option(&[0x09, 0x05, 0x37]).unwrap();
+        assert_eq!(split.as_slice(), &[0x09, 0x05, 0x37]);
+        assert!(found.is_none());
+        assert!(split_option(&[]).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn malformed_options_fail() {
+        // Truncated nonce
+        assert!(split_option(&[0x80, 0x01, 0x07, 0x11]).is_err());
+        // Unknown flag in the second byte
+        assert!(split_option(&[0x80, 0x02]).is_err());
+        // Trailing data without KID flag
+        assert!(split_option(&[0x80, 0x01, 0x00, 0x11, 0x37]).is_err());
+    }
+
+    #[test]
+    fn both_peers_derive_the_same_context() {
+        let old = ContextMaterial::new(
+            crate::iana::cose_alg::HKDF_HMAC256256,
+            crate::iana::cose_alg::AES_CCM_16_64_128,
+            &[0x42; 16],
+            &[0x23; 8],
+            &[0x01],
+            COwn::from_kid(&[0x37]).unwrap(),
+        )
+        .unwrap();
+        let request = KeyUpdate::new([0x11; NONCE_LEN]);
+        let response = KeyUpdate::new([0x22; NONCE_LEN]);
+
+        let intermediate = request.intermediate(&old).unwrap();
+        let new = KeyUpdate::combined(&request, &response, &old).unwrap();
+        assert_ne!(intermediate.master_secret(), old.master_secret());
+        assert_ne!(new.master_secret(), intermediate.master_secret());
+        assert_eq!(new.master_secret().len(), old.master_secret().len());
+
+        // A peer receiving the fields derives the same
+        let (_, received) =
+            split_option(&join_option(&[0x09, 0x00, 0x37], &request).unwrap()).unwrap();
+        let received = received.unwrap();
+        assert_eq!(
+            received.intermediate(&old).unwrap().master_secret(),
+            intermediate.master_secret()
+        );
+        assert_eq!(
+            KeyUpdate::combined(&received, &response, &old)
+                .unwrap()
+                .master_secret(),
+            new.master_secret()
+        );
+    }
+}
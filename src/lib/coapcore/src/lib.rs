@@ -1,9 +1,9 @@
 //! A CoAP security tool for embedded devices, supporting OSCORE/EDHOC and managing credentials.
 //!
 //! This crate is under active development; breaking changes will be made as necessary. It
-//! currently only handles the server side of CoAP exchanges. At runtime, there is more copying of
-//! messages than is generally preferred; those result from limitations of underlying tools and are
-//! being addressed there.
+//! primarily handles the server side of CoAP exchanges, with a client side counterpart for
+//! OSCORE/EDHOC. At runtime, there is more copying of messages than is generally preferred; those
+//! result from limitations of underlying tools and are being addressed there.
 //!
 //! This crate builds on several components technically and logically:
 //!
@@ -27,6 +27,25 @@
 //!
 //! The arguments passed to the [`OscoreEdhocHandler`] at construction guide its behavior.
 //!
+//! Resources can be observed if the stack cooperates with the handler as described in the
+//! [`observe`] module.
+//!
+//! Security contexts can be kept across reboots if the application stores them as described in
+//! the [`persist`] module. Their keys can be updated without running EDHOC again, as described in
+//! the [`kudos`] module.
+//!
+//! Requests sent to a multicast group can be processed when they are protected with Group OSCORE,
+//! as described in the [`group`] module.
+//!
+//! Besides CWT Claims Sets, peers can be authenticated by X.509 certificates and CWTs, as
+//! described in the [`credentials`] module.
+//!
+//! Requests and security events are counted, and requests can be reported to an access log, as
+//! described in the [`metrics`] module.
+//!
+//! On the client side, an [`OscoreEdhocClient`] establishes security contexts with servers whose
+//! credentials it knows, and protects requests sent through a [`ClientTransport`].
+//!
 //! # Logging
 //!
 //! Extensive logging is available in this crate through [`defmt_or_log`], depending on features
@@ -71,6 +90,18 @@ pub mod scope;
 pub use generalclaims::GeneralClaims;
 pub mod seccfg;
 
+pub mod credentials;
+
+pub mod observe;
+
+pub mod persist;
+
+pub mod kudos;
+
+pub mod group;
+
+pub mod metrics;
+
 // Might warrant a standalone crate at some point
 //
 // This is pub only to make the doctests run (but the crate's pub-ness needs a major overhaul
@@ -80,5 +111,8 @@ pub mod oluru;
 mod seccontext;
 pub use seccontext::*;
 
+mod client;
+pub use client::{CLIENT_MESSAGE_BUFFER_SIZE, ClientError, ClientTransport, OscoreEdhocClient};
+
 mod error;
 pub use error::CredentialError;
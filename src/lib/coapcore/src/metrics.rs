@@ -0,0 +1,182 @@
+//! Counters and access logging of the requests processed by an [`OscoreEdhocHandler`].
+//!
+//! Rejected requests and failed EDHOC handshakes are otherwise only visible in the log (and the
+//! details of those not at all to the peer). To allow monitoring a server, the handler keeps
+//! [`Metrics`], available through [`OscoreEdhocHandler::metrics()`]: the requests checked
+//! against the peer's authorization (by their code, and by whether they were allowed), completed
+//! and failed EDHOC handshakes, rejected replays, and security contexts evicted to make room for
+//! new ones. All counters saturate at [`u32::MAX`].
+//!
+//! Additionally, every request that is checked against the peer's authorization is reported to
+//! the [`AccessLog`] set through [`OscoreEdhocHandler::with_access_log()`], along with the
+//! [`Peer`] it came from and its [`Outcome`]. Requests that are rejected before that (e.g.
+//! because they could not be decrypted) are only counted.
+//!
+//! For requests protected with OSCORE, the request passed to the access log and counted by its
+//! code is the decrypted one, not the outer POST or FETCH request.
+//!
+//! [`OscoreEdhocHandler`]: crate::OscoreEdhocHandler
+//! [`OscoreEdhocHandler::metrics()`]: crate::OscoreEdhocHandler::metrics
+//! [`OscoreEdhocHandler::with_access_log()`]: crate::OscoreEdhocHandler::with_access_log
+
+use coap_message::ReadableMessage;
+
+/// Number of request codes counted individually: the methods GET (1) to iPATCH (7), with all
+/// other codes counted in the first slot.
+const COUNTED_CODES: usize = 8;
+
+/// Counters of the requests and security events processed by an
+/// [`OscoreEdhocHandler`][crate::OscoreEdhocHandler]; see the [module documentation](self).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Metrics {
+    requests: [u32; COUNTED_CODES],
+    /// Requests that were not allowed by the peer's authorization, and were answered through
+    /// [`ServerSecurityConfig::render_not_allowed()`].
+    ///
+    /// [`ServerSecurityConfig::render_not_allowed()`]: crate::seccfg::ServerSecurityConfig::render_not_allowed
+    pub not_allowed: u32,
+    /// Requests that were answered with an Echo challenge because they may have been replays to
+    /// a restored security context (see [`crate::persist`]) or to a group member whose replay
+    /// window is unknown (see [`crate::group`]).
+    pub freshness_challenges: u32,
+    /// EDHOC handshakes that completed, resulting in a new OSCORE security context.
+    pub edhoc_completed: u32,
+    /// EDHOC messages that could not be processed, aborting their handshake.
+    pub edhoc_failed: u32,
+    /// OSCORE requests that could not be decrypted. This includes replays, which libOSCORE does
+    /// not tell apart from other failures.
+    pub oscore_rejected: u32,
+    /// Group OSCORE requests that were rejected as replays.
+    pub replays_rejected: u32,
+    /// Security contexts that were evicted to make room for new ones.
+    pub contexts_evicted: u32,
+}
+
+impl Metrics {
+    /// Slot in which requests with the given code are counted.
+    fn code_index(code: u8) -> usize {
+        match code {
+            1..=7 => code.into(),
+            _ => 0,
+        }
+    }
+
+    /// Returns the number of requests with the given code that were checked against the peer's
+    /// authorization.
+    ///
+    /// Codes other than the methods GET (1) to iPATCH (7) are counted together: asking for any
+    /// of them returns the number of all of them.
+    #[must_use]
+    pub fn requests(&self, code: u8) -> u32 {
+        self.requests
+            .get(Self::code_index(code))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of all requests that were checked against the peer's authorization.
+    #[must_use]
+    pub fn total_requests(&self) -> u32 {
+        self.requests
+            .iter()
+            .fold(0, |total, count| total.saturating_add(*count))
+    }
+
+    /// Increments a single counter.
+    pub(crate) fn count(counter: &mut u32) {
+        *counter = counter.saturating_add(1);
+    }
+
+    /// Counts a request with the given code that was checked with the given outcome.
+    pub(crate) fn count_request(&mut self, code: u8, outcome: Outcome) {
+        if let Some(counter) = self.requests.get_mut(Self::code_index(code)) {
+            Self::count(counter);
+        }
+        match outcome {
+            Outcome::Allowed => (),
+            Outcome::NotAllowed => Self::count(&mut self.not_allowed),
+            Outcome::NotFresh => Self::count(&mut self.freshness_challenges),
+        }
+    }
+}
+
+/// The peer a request was received from, as reported to an [`AccessLog`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Peer<'a> {
+    /// The request was not protected, and is authorized by the server's policy for
+    /// unauthenticated requests.
+    Unprotected,
+    /// The request was protected with an OSCORE security context established through EDHOC or
+    /// ACE, identified by its Recipient ID (which is the KID the peer sends).
+    Oscore {
+        /// Recipient ID of the security context.
+        recipient_id: &'a [u8],
+    },
+    /// The request was protected with Group OSCORE (see [`crate::group`]).
+    GroupMember {
+        /// Position of the member in the group, in the sequence in which members were added
+        /// through [`OscoreGroup::with_member()`][crate::group::OscoreGroup::with_member].
+        index: usize,
+    },
+}
+
+/// The outcome of checking a request against the peer's authorization, as reported to an
+/// [`AccessLog`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Outcome {
+    /// The request was passed on to the resource.
+    Allowed,
+    /// The request was answered through
+    /// [`ServerSecurityConfig::render_not_allowed()`][crate::seccfg::ServerSecurityConfig::render_not_allowed].
+    NotAllowed,
+    /// The request was answered with an Echo challenge because it may have been a replay.
+    NotFresh,
+}
+
+/// A recipient of the requests checked by an [`OscoreEdhocHandler`][crate::OscoreEdhocHandler].
+///
+/// The unit type `()` is an access log that discards all records, and is the default of a
+/// handler.
+pub trait AccessLog {
+    /// Records that `request` from `peer` was checked against the peer's authorization, with the
+    /// given `outcome`.
+    ///
+    /// The resource can be found in the request's Uri-Path options. This is called during
+    /// request processing, and should thus not take long.
+    fn record<M: ReadableMessage>(&mut self, peer: Peer<'_>, request: &M, outcome: Outcome);
+}
+
+impl AccessLog for () {
+    fn record<M: ReadableMessage>(&mut self, _peer: Peer<'_>, _request: &M, _outcome: Outcome) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counting() {
+        let mut metrics = Metrics::default();
+        metrics.count_request(coap_numbers::code::GET, Outcome::Allowed);
+        metrics.count_request(coap_numbers::code::GET, Outcome::NotAllowed);
+        metrics.count_request(coap_numbers::code::POST, Outcome::NotFresh);
+        // Not a method; counted with all other codes
+        metrics.count_request(coap_numbers::code::CONTENT, Outcome::Allowed);
+
+        assert_eq!(metrics.requests(coap_numbers::code::GET), 2);
+        assert_eq!(metrics.requests(coap_numbers::code::POST), 1);
+        assert_eq!(metrics.requests(coap_numbers::code::PUT), 0);
+        assert_eq!(metrics.requests(coap_numbers::code::EMPTY), 1);
+        assert_eq!(metrics.total_requests(), 4);
+        assert_eq!(metrics.not_allowed, 1);
+        assert_eq!(metrics.freshness_challenges, 1);
+
+        metrics.contexts_evicted = u32::MAX;
+        Metrics::count(&mut metrics.contexts_evicted);
+        assert_eq!(metrics.contexts_evicted, u32::MAX);
+    }
+}
@@ -0,0 +1,471 @@
+//! Support for observing resources ([RFC7641](https://www.rfc-editor.org/rfc/rfc7641)).
+//!
+//! The [`OscoreEdhocHandler`] keeps a bounded set of observations: A GET request with an Observe
+//! option of 0 that is allowed and answered successfully registers one, and a GET request with an
+//! Observe option of 1 removes it again.
+//!
+//! As the [`coap_handler::Handler`] interface carries neither the token nor the addresses of a
+//! request, the CoAP transport has to cooperate:
+//!
+//! * Before passing a request to the handler, it reports the request's [`Origin`] through
+//!   [`OscoreEdhocHandler::set_request_origin()`].
+//! * When the application indicates that a resource changed, it calls
+//!   [`OscoreEdhocHandler::notify()`], and then sends all the messages produced by
+//!   [`OscoreEdhocHandler::build_notification()`] to their origin.
+//! * When a notification is rejected (by a Reset message) or a confirmable notification is not
+//!   acknowledged, it calls [`OscoreEdhocHandler::cancel_observation()`].
+//!
+//! Each notification is authorized anew against the observer's current authorization (which may
+//! have expired in the meantime), and is protected with the security context through which the
+//! observation was registered. When that context is gone, the observation silently ends.
+//!
+//! [`OscoreEdhocHandler`]: crate::OscoreEdhocHandler
+//! [`OscoreEdhocHandler::set_request_origin()`]: crate::OscoreEdhocHandler::set_request_origin
+//! [`OscoreEdhocHandler::notify()`]: crate::OscoreEdhocHandler::notify
+//! [`OscoreEdhocHandler::build_notification()`]: crate::OscoreEdhocHandler::build_notification
+//! [`OscoreEdhocHandler::cancel_observation()`]: crate::OscoreEdhocHandler::cancel_observation
+
+use coap_message::{MessageOption as _, MinimalWritableMessage as _, ReadableMessage};
+use coap_message_implementations::inmemory_write::Message;
+
+use crate::helpers::COwn;
+
+/// Number of observations a handler keeps at a time.
+///
+/// Registrations beyond that are answered without an Observe option, which clients understand as
+/// the observation not being established.
+pub const MAX_OBSERVATIONS: usize = 4;
+
+/// Maximum length of a CoAP token.
+pub const MAX_TOKEN_LEN: usize = 8;
+
+/// Space for the options of the request that are needed to build notifications from it.
+const OBSERVED_OPTIONS_LEN: usize = 48;
+
+/// Every this many notifications, one is sent as confirmable to see whether the client is still
+/// interested (RFC7641 Section 4.5).
+const CON_INTERVAL: u32 = 8;
+
+/// Size of the buffer into which requests are reconstructed for notifications.
+pub(crate) const REQUEST_COPY_LEN: usize = OBSERVED_OPTIONS_LEN + 8;
+
+/// The observe sequence number is sent in 3 bytes.
+const SEQUENCE_MASK: u32 = 0x00ff_ffff;
+
+/// Transport level identification of the request that is being processed: the addresses it was
+/// exchanged between, and its token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    /// Address of the client.
+    pub remote: core::net::SocketAddr,
+    /// Address at which the request was received.
+    pub local: core::net::SocketAddr,
+    /// Token of the request.
+    pub token: heapless::Vec<u8, MAX_TOKEN_LEN>,
+}
+
+/// A notification built by [`OscoreEdhocHandler::build_notification()`].
+///
+/// [`OscoreEdhocHandler::build_notification()`]: crate::OscoreEdhocHandler::build_notification
+#[derive(Debug)]
+pub struct Notification {
+    /// Where (and with which token) the notification is to be sent.
+    pub origin: Origin,
+    /// Whether the notification should be sent as a confirmable message.
+    ///
+    /// If the transport does not receive an acknowledgement, it should cancel the observation.
+    pub confirmable: bool,
+    /// Whether the observation ended with this notification (typically because an error is being
+    /// reported).
+    pub last: bool,
+}
+
+/// How notifications for an observation are protected.
+#[derive(Copy, Clone)]
+pub(crate) enum Protection {
+    /// Not at all.
+    Plain,
+    /// Using the OSCORE security context with that recipient ID, continuing the request with the
+    /// given correlation data.
+    Oscore {
+        kid: COwn,
+        correlation: liboscore::raw::oscore_requestid_t,
+    },
+}
+
+impl Protection {
+    fn kid(&self) -> Option<COwn> {
+        match self {
+            Protection::Plain => None,
+            Protection::Oscore { kid, .. } => Some(*kid),
+        }
+    }
+}
+
+/// The parts of a request relevant to observation.
+#[derive(Debug)]
+pub(crate) enum ObserveRequest {
+    /// A GET request with Observe: 0, whose options are stored for rebuilding it later.
+    Register(heapless::Vec<u8, OBSERVED_OPTIONS_LEN>),
+    /// A GET request with Observe: 1.
+    Deregister,
+}
+
+impl ObserveRequest {
+    /// Returns the observation related action the request asks for, if any.
+    ///
+    /// Requests whose options needed for rebuilding them do not fit are not registered.
+    pub(crate) fn from_request(request: &impl ReadableMessage) -> Option<Self> {
+        if u8::from(request.code()) != coap_numbers::code::GET {
+            return None;
+        }
+
+        let mut observe = None;
+        let mut options = heapless::Vec::new();
+        for option in request.options() {
+            match option.number() {
+                coap_numbers::option::OBSERVE => observe = Some(option.value_uint::<u32>()),
+                coap_numbers::option::URI_PATH
+                | coap_numbers::option::URI_QUERY
+                | coap_numbers::option::ACCEPT => {
+                    let value = option.value();
+                    let len = u8::try_from(value.len()).ok()?;
+                    options
+                        .extend_from_slice(&option.number().to_be_bytes())
+                        .ok()?;
+                    options.push(len).ok()?;
+                    options.extend_from_slice(value).ok()?;
+                }
+                _ => (),
+            }
+        }
+
+        match observe? {
+            Some(0) => Some(ObserveRequest::Register(options)),
+            Some(1) => Some(ObserveRequest::Deregister),
+            _ => None,
+        }
+    }
+}
+
+/// Iterates over the (number, value) pairs stored in an [`ObserveRequest::Register`].
+fn stored_options(mut options: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    core::iter::from_fn(move || {
+        let ([number_high, number_low, len], rest) = options.split_first_chunk::<3>()?;
+        let (value, rest) = rest.split_at_checked((*len).into())?;
+        options = rest;
+        Some((u16::from_be_bytes([*number_high, *number_low]), value))
+    })
+}
+
+/// A registered observation.
+pub(crate) struct Observation {
+    pub(crate) origin: Origin,
+    pub(crate) protection: Protection,
+    options: heapless::Vec<u8, OBSERVED_OPTIONS_LEN>,
+    /// Number of notifications sent since registration.
+    sent: u32,
+    /// Set when the observed resource has changed, and cleared when a notification is built.
+    pending: bool,
+}
+
+impl Observation {
+    /// Rebuilds the registering request (without the Observe option) in a message backed by
+    /// `buffer`.
+    pub(crate) fn rebuild_request<'b>(
+        &self,
+        code: &'b mut u8,
+        buffer: &'b mut [u8; REQUEST_COPY_LEN],
+    ) -> Message<'b> {
+        let mut request = Message::new(code, &mut buffer[..]);
+        request.set_code(coap_numbers::code::GET);
+        for (number, value) in stored_options(&self.options) {
+            request
+                .add_option(number, value)
+                .expect("buffer is sized to fit all stored options");
+        }
+        request
+    }
+
+    /// Decides whether the next notification is sent as confirmable, and counts it.
+    pub(crate) fn next_is_confirmable(&mut self) -> bool {
+        self.sent = self.sent.wrapping_add(1);
+        self.sent % CON_INTERVAL == 0
+    }
+}
+
+/// The observation state of an [`OscoreEdhocHandler`](crate::OscoreEdhocHandler).
+pub(crate) struct Observations {
+    slots: [Option<Observation>; MAX_OBSERVATIONS],
+    /// Origin of the request that is currently being processed.
+    current_origin: Option<Origin>,
+    /// Registration found in the request currently being processed, which is completed once its
+    /// response was built successfully.
+    candidate: Option<(Origin, heapless::Vec<u8, OBSERVED_OPTIONS_LEN>)>,
+    /// Source of Observe option values; shared across observations, this is increasing for each
+    /// of them.
+    sequence: u32,
+}
+
+impl Observations {
+    pub(crate) fn new() -> Self {
+        Self {
+            slots: Default::default(),
+            current_origin: None,
+            candidate: None,
+            sequence: 0,
+        }
+    }
+
+    pub(crate) fn set_request_origin(&mut self, origin: Origin) {
+        self.current_origin = Some(origin);
+    }
+
+    /// Resets the per-request state at the start of processing a request, and returns the
+    /// request's origin if the transport provided one.
+    pub(crate) fn start_request(&mut self) -> Option<Origin> {
+        self.candidate = None;
+        self.current_origin.take()
+    }
+
+    /// Processes the observation aspects of a request that was authorized, and which was
+    /// received through the security context identified by `kid` (or none).
+    ///
+    /// Requests without an origin are not considered for observation.
+    pub(crate) fn process_request(
+        &mut self,
+        origin: Option<Origin>,
+        request: Option<ObserveRequest>,
+        kid: Option<COwn>,
+    ) {
+        let Some(origin) = origin else {
+            return;
+        };
+        // Any request with the same token replaces an earlier observation, as does an explicit
+        // deregistration. Only the security context that registered an observation can end it
+        // that way.
+        self.cancel_where(|o| o.origin == origin && o.protection.kid() == kid);
+        if let Some(ObserveRequest::Register(options)) = request {
+            self.candidate = Some((origin, options));
+        }
+    }
+
+    /// Returns the Observe option value for the response if the current request is about to
+    /// register an observation.
+    pub(crate) fn candidate_sequence(&mut self) -> Option<u32> {
+        self.candidate.as_ref()?;
+        if !self.slots.iter().any(Option::is_none) {
+            defmt_or_log::debug!("No free observation slot, responding without Observe.");
+            self.candidate = None;
+            return None;
+        }
+        Some(self.next_sequence())
+    }
+
+    /// Completes a registration after its response was built successfully.
+    pub(crate) fn register(&mut self, protection: Protection) {
+        let Some((origin, options)) = self.candidate.take() else {
+            return;
+        };
+        if let Some(slot) = self.slots.iter_mut().find(|s| s.is_none()) {
+            defmt_or_log::debug!("Registered observation.");
+            *slot = Some(Observation {
+                origin,
+                protection,
+                options,
+                sent: 0,
+                pending: false,
+            });
+        }
+    }
+
+    pub(crate) fn next_sequence(&mut self) -> u32 {
+        self.sequence = self.sequence.wrapping_add(1) & SEQUENCE_MASK;
+        self.sequence
+    }
+
+    /// Marks all observations of the resource at `path` (e.g. `/sensors/temp`) as needing a
+    /// notification.
+    pub(crate) fn notify(&mut self, path: &str) {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        for observation in self.slots.iter_mut().flatten() {
+            let mut observed = stored_options(&observation.options)
+                .filter(|(number, _)| *number == coap_numbers::option::URI_PATH)
+                .map(|(_, value)| value);
+            let mut requested = path.split('/').filter(|s| !s.is_empty());
+            let matches = loop {
+                match (observed.next(), requested.next()) {
+                    (None, None) => break true,
+                    (Some(o), Some(r)) if o == r.as_bytes() => (),
+                    _ => break false,
+                }
+            };
+            observation.pending |= matches;
+        }
+    }
+
+    /// Takes out the next observation that needs a notification; the caller puts it back with
+    /// [`Self::put_back()`] unless the observation ended.
+    pub(crate) fn take_pending(&mut self) -> Option<Observation> {
+        self.slots
+            .iter_mut()
+            .find(|s| s.as_ref().is_some_and(|o| o.pending))?
+            .take()
+            .map(|mut o| {
+                o.pending = false;
+                o
+            })
+    }
+
+    pub(crate) fn put_back(&mut self, observation: Observation) {
+        if let Some(slot) = self.slots.iter_mut().find(|s| s.is_none()) {
+            *slot = Some(observation);
+        }
+    }
+
+    pub(crate) fn cancel_where(&mut self, mut predicate: impl FnMut(&Observation) -> bool) {
+        for slot in &mut self.slots {
+            if slot.as_ref().is_some_and(&mut predicate) {
+                defmt_or_log::debug!("Removed observation.");
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// Copies a `message` into `target`, adding an Observe option with the given sequence number
+/// if any.
+///
+/// This fails if `target` is too small.
+pub(crate) fn copy_with_observe<M: coap_message::MutableWritableMessage>(
+    message: &Message<'_>,
+    mut sequence: Option<u32>,
+    target: &mut M,
+) -> Result<(), ()> {
+    use coap_message::{Code as _, OptionNumber as _};
+
+    let observe = M::OptionNumber::new(coap_numbers::option::OBSERVE).map_err(|_| ())?;
+    target.set_code(M::Code::new(message.code().into()).map_err(|_| ())?);
+    for opt in message.options() {
+        // Options are added in ascending order.
+        if opt.number() > coap_numbers::option::OBSERVE {
+            if let Some(sequence) = sequence.take() {
+                target.add_option_uint(observe, sequence).map_err(|_| ())?;
+            }
+        }
+        target
+            .add_option(
+                M::OptionNumber::new(opt.number()).map_err(|_| ())?,
+                opt.value(),
+            )
+            .map_err(|_| ())?;
+    }
+    if let Some(sequence) = sequence {
+        target.add_option_uint(observe, sequence).map_err(|_| ())?;
+    }
+    target.set_payload(message.payload()).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use coap_message::{MessageOption as _, MinimalWritableMessage as _, ReadableMessage as _};
+
+    use super::*;
+
+    #[test]
+    fn stored_options_roundtrip() {
+        let mut code = 0;
+        let mut buffer = [0; REQUEST_COPY_LEN];
+        let mut request = Message::new(&mut code, &mut buffer[..]);
+        request.set_code(coap_numbers::code::GET);
+        request
+            .add_option(coap_numbers::option::OBSERVE, b"")
+            .unwrap();
+        request
+            .add_option(coap_numbers::option::URI_PATH, b"sensors")
+            .unwrap();
+        request
+            .add_option(coap_numbers::option::URI_PATH, b"temp")
+            .unwrap();
+
+        let Some(ObserveRequest::Register(options)) = ObserveRequest::from_request(&request) else {
+            panic!("Request was not recognized as registration");
+        };
+
+        let mut observations = Observations::new();
+        let origin = Origin {
+            remote: "[2001:db8::1]:5683".parse().unwrap(),
+            local: "[2001:db8::2]:5683".parse().unwrap(),
+            token: heapless::Vec::from_slice(b"tok").unwrap(),
+        };
+        observations.set_request_origin(origin.clone());
+        let current = observations.start_request();
+        observations.process_request(current, Some(ObserveRequest::Register(options)), None);
+        assert!(observations.candidate_sequence().is_some());
+        observations.register(Protection::Plain);
+
+        observations.notify("/sensors");
+        assert!(observations.take_pending().is_none());
+        observations.notify("/sensors/temp");
+        let observation = observations.take_pending().unwrap();
+        assert_eq!(observation.origin, origin);
+
+        let mut code = 0;
+        let mut buffer = [0; REQUEST_COPY_LEN];
+        let rebuilt = observation.rebuild_request(&mut code, &mut buffer);
+        let path: heapless::Vec<&[u8], 4> = rebuilt
+            .options()
+            .filter(|o| o.number() == coap_numbers::option::URI_PATH)
+            .map(|o| o.value())
+            .collect();
+        assert_eq!(&path[..], &[&b"sensors"[..], &b"temp"[..]]);
+
+        observations.put_back(observation);
+        observations.set_request_origin(origin);
+        let current = observations.start_request();
+        observations.process_request(current, Some(ObserveRequest::Deregister), None);
+        observations.notify("/sensors/temp");
+        assert!(observations.take_pending().is_none());
+    }
+
+    #[test]
+    fn observe_is_copied_in_order() {
+        let mut code = 0;
+        let mut buffer = [0; 64];
+        let mut response = Message::new(&mut code, &mut buffer[..]);
+        response.set_code(coap_numbers::code::CONTENT);
+        response
+            .add_option(coap_numbers::option::ETAG, b"\x01")
+            .unwrap();
+        response
+            .add_option(coap_numbers::option::CONTENT_FORMAT, b"\x3c")
+            .unwrap();
+        response.set_payload(b"23").unwrap();
+
+        let mut copy_code = 0;
+        let mut copy_buffer = [0; 64];
+        let mut copy = Message::new(&mut copy_code, &mut copy_buffer[..]);
+        copy_with_observe(&response, Some(5), &mut copy).unwrap();
+
+        let numbers: heapless::Vec<u16, 4> = copy.options().map(|o| o.number()).collect();
+        assert_eq!(
+            &numbers[..],
+            &[
+                coap_numbers::option::ETAG,
+                coap_numbers::option::OBSERVE,
+                coap_numbers::option::CONTENT_FORMAT
+            ]
+        );
+        assert_eq!(copy.payload(), b"23");
+
+        let mut plain_code = 0;
+        let mut plain_buffer = [0; 64];
+        let mut plain = Message::new(&mut plain_code, &mut plain_buffer[..]);
+        copy_with_observe(&response, None, &mut plain).unwrap();
+        assert!(
+            plain
+                .options()
+                .all(|o| o.number() != coap_numbers::option::OBSERVE)
+        );
+    }
+}
@@ -0,0 +1,442 @@
+//! Persistence of OSCORE security contexts across reboots, following [RFC8613 Appendix
+//! B.1](https://www.rfc-editor.org/rfc/rfc8613#appendix-B.1).
+//!
+//! Security contexts established through EDHOC or ACE are kept in RAM by the
+//! [`OscoreEdhocHandler`]. So that clients do not need to establish new ones after every reboot,
+//! the handler describes what is to be written to persistent storage as [`PersistenceRequest`]s:
+//!
+//! * When a context is established, its input material and claims are stored, along with a sender
+//!   sequence number that is [`SEQUENCE_NUMBER_STEP`] ahead of the current one.
+//! * When the sender sequence number gets close to the stored one, a higher one is stored.
+//!   Sequence numbers beyond the stored one are not used: Responses that would need them fail
+//!   until the storage has caught up.
+//! * When a context is evicted, or its claims are
+//!   [revoked](crate::GeneralClaims::is_revoked), it is removed from storage.
+//!
+//! The application applies the requests one at a time (as obtained from
+//! [`OscoreEdhocHandler::next_persistence_request()`]), and reports each one that was applied
+//! through [`OscoreEdhocHandler::persisted()`]. At startup, the stored contexts are passed to
+//! [`OscoreEdhocHandler::restore_context()`] before any request is processed, followed by applying
+//! the requests produced then.
+//!
+//! A restored context continues with the stored sender sequence number. As its replay window is
+//! lost, the first request of a restored context is answered with a 4.01 Unauthorized response
+//! carrying an Echo option ([RFC9175](https://www.rfc-editor.org/rfc/rfc9175)), and the request is
+//! not processed. Only a request repeating the echoed value is processed; from then on, requests
+//! with a sequence number lower than that one are rejected as replays.
+//!
+//! Only contexts whose claims the [`ServerSecurityConfig`] can
+//! [encode][ServerSecurityConfig::encode_claims] are persisted.
+//!
+//! [`OscoreEdhocHandler`]: crate::OscoreEdhocHandler
+//! [`OscoreEdhocHandler::next_persistence_request()`]: crate::OscoreEdhocHandler::next_persistence_request
+//! [`OscoreEdhocHandler::persisted()`]: crate::OscoreEdhocHandler::persisted
+//! [`OscoreEdhocHandler::restore_context()`]: crate::OscoreEdhocHandler::restore_context
+//! [`ServerSecurityConfig`]: crate::seccfg::ServerSecurityConfig
+//! [ServerSecurityConfig::encode_claims]: crate::seccfg::ServerSecurityConfig::encode_claims
+
+use crate::helpers::COwn;
+
+/// Number of sender sequence numbers a context may use beyond what was stored before (the `K` of
+/// RFC8613 Appendix B.1.1).
+///
+/// Larger values reduce the number of writes to storage, at the cost of skipping more sequence
+/// numbers on every reboot.
+pub const SEQUENCE_NUMBER_STEP: u64 = 64;
+
+/// Number of security contexts that can be persisted at the same time.
+///
+/// Each context is assigned a slot (a number below this) in which it is stored.
+pub const SLOTS: usize = crate::seccontext::MAX_CONTEXTS;
+
+/// Maximum length of the encoded input material of a context.
+pub const MAX_MATERIAL_LEN: usize = 88;
+
+/// Maximum length of the encoded claims of a context, as produced by
+/// [`ServerSecurityConfig::encode_claims()`](crate::seccfg::ServerSecurityConfig::encode_claims).
+///
+/// This leaves room for an [`AifValue`](crate::scope::AifValue) along with an expiry time;
+/// contexts whose claims hold larger AIF values are not persisted.
+pub const MAX_CLAIMS_LEN: usize = crate::scope::AIF_SCOPE_MAX_LEN + 16;
+
+/// Maximum length of a master secret that is persisted.
+const MAX_SECRET_LEN: usize = 32;
+
+/// Maximum length of a master salt that is persisted.
+///
+/// This accommodates the salts built by ACE-OSCORE from the nonces.
+const MAX_SALT_LEN: usize = 32;
+
+/// Maximum length of an OSCORE Sender ID with the 13 byte nonce of AES-CCM-16-64-128 (RFC8613
+/// Section 3.3).
+const MAX_ID_LEN: usize = 7;
+
+/// Length of the Echo option values sent to clients of restored contexts.
+pub(crate) const ECHO_LEN: usize = 8;
+
+/// Error type indicating that a persisted security context could not be restored.
+#[derive(Debug, Copy, Clone)]
+pub struct InvalidRecord;
+
+/// A change to the persisted security contexts; see the [module level documentation](self).
+#[derive(Debug)]
+pub struct PersistenceRequest {
+    pub(crate) slot: usize,
+    pub(crate) kid: COwn,
+    pub(crate) action: PersistenceAction,
+}
+
+impl PersistenceRequest {
+    /// The slot the request applies to.
+    #[must_use]
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// What is to be stored in the slot.
+    #[must_use]
+    pub fn action(&self) -> &PersistenceAction {
+        &self.action
+    }
+}
+
+/// The change requested by a [`PersistenceRequest`].
+#[derive(Debug)]
+pub enum PersistenceAction {
+    /// Stores a newly established context, replacing whatever was stored in the slot.
+    ///
+    /// The items are to be stored atomically, and are to be passed back to
+    /// [`OscoreEdhocHandler::restore_context()`](crate::OscoreEdhocHandler::restore_context)
+    /// unmodified.
+    Store {
+        /// The input material from which the context is derived.
+        material: heapless::Vec<u8, MAX_MATERIAL_LEN>,
+        /// The claims of the context.
+        claims: heapless::Vec<u8, MAX_CLAIMS_LEN>,
+        /// The sender sequence number up to which the context may be used.
+        sequence_number: u64,
+    },
+    /// Replaces the stored sender sequence number of the context in the slot.
+    Advance {
+        /// The sender sequence number up to which the context may be used.
+        sequence_number: u64,
+    },
+    /// Removes the context stored in the slot.
+    Remove,
+}
+
+/// The input from which an OSCORE security context is derived, as needed to derive it again
+/// after a reboot, or to [update its keys](crate::kudos).
+///
+/// The ID Context is not recorded, as none of the contexts established by this crate use one.
+#[derive(Debug, Clone)]
+pub(crate) struct ContextMaterial {
+    hkdf: i32,
+    aead: i32,
+    secret: heapless::Vec<u8, MAX_SECRET_LEN>,
+    salt: heapless::Vec<u8, MAX_SALT_LEN>,
+    sender_id: heapless::Vec<u8, MAX_ID_LEN>,
+    recipient_id: COwn,
+}
+
+impl ContextMaterial {
+    /// Records the arguments to [`liboscore::PrimitiveImmutables::derive()`].
+    ///
+    /// Returns `None` if any of them is too long to be persisted.
+    pub(crate) fn new(
+        hkdf: i32,
+        aead: i32,
+        secret: &[u8],
+        salt: &[u8],
+        sender_id: &[u8],
+        recipient_id: COwn,
+    ) -> Option<Self> {
+        Some(Self {
+            hkdf,
+            aead,
+            secret: heapless::Vec::from_slice(secret).ok()?,
+            salt: heapless::Vec::from_slice(salt).ok()?,
+            sender_id: heapless::Vec::from_slice(sender_id).ok()?,
+            recipient_id,
+        })
+    }
+
+    pub(crate) fn recipient_id(&self) -> COwn {
+        self.recipient_id
+    }
+
+    /// Encodes the material as a CBOR array.
+    pub(crate) fn encode(&self) -> heapless::Vec<u8, MAX_MATERIAL_LEN> {
+        let mut encoded = heapless::Vec::new();
+        minicbor::Encoder::new(minicbor_adapters::WriteToHeapless(&mut encoded))
+            .array(6)
+            .and_then(|e| e.i32(self.hkdf))
+            .and_then(|e| e.i32(self.aead))
+            .and_then(|e| e.bytes(&self.secret))
+            .and_then(|e| e.bytes(&self.salt))
+            .and_then(|e| e.bytes(&self.sender_id))
+            .and_then(|e| e.bytes(self.recipient_id.as_slice()))
+            .expect("MAX_MATERIAL_LEN accommodates the longest fields");
+        encoded
+    }
+
+    /// Decodes material produced by [`Self::encode()`].
+    pub(crate) fn decode(encoded: &[u8]) -> Result<Self, InvalidRecord> {
+        let mut decoder = minicbor::Decoder::new(encoded);
+        if decoder.array().map_err(|_| InvalidRecord)? != Some(6) {
+            return Err(InvalidRecord);
+        }
+        let hkdf = decoder.i32().map_err(|_| InvalidRecord)?;
+        let aead = decoder.i32().map_err(|_| InvalidRecord)?;
+        let secret = decoder.bytes().map_err(|_| InvalidRecord)?;
+        let salt = decoder.bytes().map_err(|_| InvalidRecord)?;
+        let sender_id = decoder.bytes().map_err(|_| InvalidRecord)?;
+        let recipient_id =
+            COwn::from_kid(decoder.bytes().map_err(|_| InvalidRecord)?).ok_or(InvalidRecord)?;
+        Self::new(hkdf, aead, secret, salt, sender_id, recipient_id).ok_or(InvalidRecord)
+    }
+
+    /// Derives the security context again, continuing with the given sender sequence number.
+    pub(crate) fn restore(
+        &self,
+        sequence_number: u64,
+    ) -> Result<liboscore::PrimitiveContext, InvalidRecord> {
+        Ok(liboscore::PrimitiveContext::new_from_stored_material(
+            self.immutables()?,
+            sequence_number,
+        ))
+    }
+
+    /// Derives the security context as a new one, whose sequence numbers start at zero.
+    pub(crate) fn derive(&self) -> Result<liboscore::PrimitiveContext, InvalidRecord> {
+        Ok(liboscore::PrimitiveContext::new_from_fresh_material(
+            self.immutables()?,
+        ))
+    }
+
+    fn immutables(&self) -> Result<liboscore::PrimitiveImmutables, InvalidRecord> {
+        let hkdf = liboscore::HkdfAlg::from_number(self.hkdf).map_err(|_| InvalidRecord)?;
+        let aead = liboscore::AeadAlg::from_number(self.aead).map_err(|_| InvalidRecord)?;
+        liboscore::PrimitiveImmutables::derive(
+            hkdf,
+            &self.secret,
+            &self.salt,
+            None,
+            aead,
+            &self.sender_id,
+            self.recipient_id.as_slice(),
+        )
+        .map_err(|_| InvalidRecord)
+    }
+
+    /// The HKDF algorithm of the context.
+    pub(crate) fn hkdf(&self) -> i32 {
+        self.hkdf
+    }
+
+    /// The master secret of the context.
+    pub(crate) fn master_secret(&self) -> &[u8] {
+        &self.secret
+    }
+
+    /// Returns the same material with a different master secret and salt.
+    ///
+    /// Returns `None` if either of them is too long to be persisted.
+    pub(crate) fn with_master(&self, secret: &[u8], salt: &[u8]) -> Option<Self> {
+        Some(Self {
+            secret: heapless::Vec::from_slice(secret).ok()?,
+            salt: heapless::Vec::from_slice(salt).ok()?,
+            ..self.clone()
+        })
+    }
+}
+
+/// How far a request of a security context can be trusted not to be a replay.
+#[derive(Debug)]
+enum Freshness {
+    /// The context was established since startup, so libOSCORE's replay window is accurate.
+    Fresh,
+    /// The context was restored, and no request was verified to be fresh yet.
+    ///
+    /// Requests are processed only if they carry the Echo value sent last.
+    Challenged(Option<[u8; ECHO_LEN]>),
+    /// The context was restored, and a request with the given sequence number was verified to be
+    /// fresh; requests with lower sequence numbers may be replays.
+    Above(u64),
+}
+
+/// Persistence state of a security context.
+#[derive(Debug)]
+pub(crate) struct Persistence {
+    /// Sender sequence numbers below this one may be used; this is `None` as long as the context
+    /// was never (requested to be) stored.
+    pub(crate) limit: Option<u64>,
+    freshness: Freshness,
+}
+
+impl Persistence {
+    /// Creates the state for a context that was just established.
+    pub(crate) fn fresh() -> Self {
+        Self {
+            limit: None,
+            freshness: Freshness::Fresh,
+        }
+    }
+
+    /// Creates the state for a context that was restored with the given sender sequence number.
+    pub(crate) fn restored(sequence_number: u64) -> Self {
+        Self {
+            limit: Some(sequence_number),
+            freshness: Freshness::Challenged(None),
+        }
+    }
+
+    /// Returns true if the context was established since startup.
+    ///
+    /// Only then the replay window of the context is known to be accurate.
+    pub(crate) fn is_fresh(&self) -> bool {
+        matches!(self.freshness, Freshness::Fresh)
+    }
+
+    /// Returns the sender sequence number to store for `context`, allowing it to use the next
+    /// [`SEQUENCE_NUMBER_STEP`] ones.
+    pub(crate) fn next_limit(&self, context: &liboscore::PrimitiveContext) -> u64 {
+        sender_sequence_number(context) + SEQUENCE_NUMBER_STEP
+    }
+
+    /// Returns true if `context` may use another sender sequence number.
+    pub(crate) fn may_send(&self, context: &liboscore::PrimitiveContext) -> bool {
+        self.limit
+            .is_none_or(|limit| sender_sequence_number(context) < limit)
+    }
+
+    /// Returns true if `context` uses sender sequence numbers close enough to the stored one that
+    /// a higher one should be stored.
+    pub(crate) fn needs_advance(&self, context: &liboscore::PrimitiveContext) -> bool {
+        self.limit.is_some_and(|limit| {
+            sender_sequence_number(context) + SEQUENCE_NUMBER_STEP / 2 >= limit
+        })
+    }
+
+    /// Returns true if a decrypted request with the given sequence number is to be processed, and
+    /// false if it is to be answered with a 4.01 Unauthorized response.
+    pub(crate) fn accepts(
+        &self,
+        request: &impl coap_message::ReadableMessage,
+        sequence_number: Option<u64>,
+    ) -> bool {
+        use coap_message::MessageOption as _;
+
+        match &self.freshness {
+            Freshness::Fresh => true,
+            Freshness::Challenged(None) => false,
+            Freshness::Challenged(Some(echo)) => request
+                .options()
+                .any(|o| o.number() == coap_numbers::option::ECHO && o.value() == echo),
+            Freshness::Above(floor) => sequence_number.is_some_and(|n| n > *floor),
+        }
+    }
+
+    /// Records that a request with the given sequence number was accepted.
+    pub(crate) fn record_accepted(&mut self, sequence_number: Option<u64>) {
+        if let (Freshness::Challenged(_), Some(sequence_number)) =
+            (&self.freshness, sequence_number)
+        {
+            self.freshness = Freshness::Above(sequence_number);
+        }
+    }
+
+    /// Picks a new Echo value if the context still awaits a request that is known to be fresh.
+    pub(crate) fn challenge(&mut self, rng: &mut impl rand_core::RngCore) {
+        if let Freshness::Challenged(echo) = &mut self.freshness {
+            let mut value = [0; ECHO_LEN];
+            rng.fill_bytes(&mut value);
+            *echo = Some(value);
+        }
+    }
+
+    /// Returns the Echo value to send along with a 4.01 Unauthorized response, if any.
+    pub(crate) fn echo(&self) -> Option<&[u8; ECHO_LEN]> {
+        match &self.freshness {
+            Freshness::Challenged(echo) => echo.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the sender sequence number the context uses next.
+fn sender_sequence_number(context: &liboscore::PrimitiveContext) -> u64 {
+    context.sender_sequence_number()
+}
+
+/// Returns the sequence number (the Partial IV) from the value of a request's OSCORE option
+/// ([RFC8613 Section 6.1](https://www.rfc-editor.org/rfc/rfc8613#section-6.1)).
+pub(crate) fn request_sequence_number(option: &[u8]) -> Option<u64> {
+    let (flags, rest) = option.split_first()?;
+    let partial_iv = rest.get(..usize::from(flags & 0x07))?;
+    if partial_iv.is_empty() || partial_iv.len() > 5 {
+        return None;
+    }
+    Some(
+        partial_iv
+            .iter()
+            .fold(0, |number, byte| (number << 8) | u64::from(*byte)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn material_roundtrip() {
+        let material = ContextMaterial::new(
+            crate::iana::cose_alg::HKDF_HMAC256256,
+            crate::iana::cose_alg::AES_CCM_16_64_128,
+            &[0x11; MAX_SECRET_LEN],
+            &[0x22; MAX_SALT_LEN],
+            &[0x33; MAX_ID_LEN],
+            COwn::from_kid(&[0x37]).unwrap(),
+        )
+        .unwrap();
+
+        let encoded = material.encode();
+        let decoded = ContextMaterial::decode(&encoded).unwrap();
+        assert_eq!(decoded.hkdf, material.hkdf);
+        assert_eq!(decoded.aead, material.aead);
+        assert_eq!(decoded.secret, material.secret);
+        assert_eq!(decoded.salt, material.salt);
+        assert_eq!(decoded.sender_id, material.sender_id);
+        assert_eq!(decoded.recipient_id, material.recipient_id);
+
+        assert!(ContextMaterial::decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn oversized_material_is_not_recorded() {
+        assert!(
+            ContextMaterial::new(
+                crate::iana::cose_alg::HKDF_HMAC256256,
+                crate::iana::cose_alg::AES_CCM_16_64_128,
+                &[0; MAX_SECRET_LEN + 1],
+                &[],
+                &[],
+                COwn::from_kid(&[0]).unwrap(),
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn sequence_number_from_option() {
+        // Partial IV 0x0125, with a KID
+        assert_eq!(
+            request_sequence_number(&[0x0a, 0x01, 0x25, 0x37]),
+            Some(0x0125)
+        );
+        // No Partial IV, as in responses
+        assert_eq!(request_sequence_number(&[]), None);
+        // Truncated
+        assert_eq!(request_sequence_number(&[0x03, 0x01]), None);
+    }
+}
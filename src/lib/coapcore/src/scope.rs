@@ -1,6 +1,9 @@
 //! Expressions for access policy as evaluated for a particular security context.
 //!
-//! This module provides the [`Scope`] trait, and generic implementations thereof.
+//! This module provides the [`Scope`] trait, and generic implementations thereof. Scopes can be
+//! combined through [`Intersection`]; [`UnionScope::Intersection`] does that for two AIF values
+//! without knowing their combination at build time (eg. a token's scope limited by a policy
+//! configured through [`ConfigBuilder::with_token_scope_limit()`][crate::seccfg::ConfigBuilder::with_token_scope_limit]).
 
 use coap_message::{MessageOption, ReadableMessage};
 
@@ -45,16 +48,52 @@ impl Scope for DenyAll {
     }
 }
 
-const AIF_SCOPE_MAX_LEN: usize = 64;
+/// A scope expression that allows requests only if both its component scopes allow them.
+///
+/// This allows combining scopes from different sources, eg. limiting what an ACE token allows by a
+/// locally configured policy, rather than picking one of them.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone)]
+pub struct Intersection<A, B>(pub A, pub B);
+
+impl<A: Scope, B: Scope> Scope for Intersection<A, B> {
+    fn request_is_allowed<M: ReadableMessage>(&self, request: &M) -> bool {
+        self.0.request_is_allowed(request) && self.1.request_is_allowed(request)
+    }
+}
+
+/// Length of the encoded AIF scopes that fit into an [`AifValue`].
+///
+/// This is also the default length of the AIF values in a [`UnionScope`] and in a
+/// [`ConfigBuilder`][crate::seccfg::ConfigBuilder]; other lengths are picked through their `N`
+/// parameter.
+pub const AIF_SCOPE_MAX_LEN: usize = 64;
 
 /// A representation of an RFC9237 using the REST-specific model.
 ///
-/// It is arbitrarily limited in length; future versions may give more flexibility, eg. by
-/// referring to data in storage.
+/// It is limited in length to [`AIF_SCOPE_MAX_LEN`]; [`GenericAifValue`] offers other lengths.
+/// Future versions may give more flexibility, eg. by referring to data in storage.
+pub type AifValue = GenericAifValue<AIF_SCOPE_MAX_LEN>;
+
+/// A representation of an RFC9237 using the REST-specific model, stored in a buffer of `N` bytes.
+///
+/// This is usually used through its alias [`AifValue`], which is also what [`UnionScope`] holds by
+/// default; applications that need larger scopes can pick a larger `N`.
 ///
 /// This type is constrained to valid CBOR representations of the REST-specific model; it may panic
 /// if that constraint is not upheld.
 ///
+/// ## Extensions
+///
+/// Beyond the paths of the REST-specific model, two extensions are understood in the `Toid`:
+///
+/// * A path whose last segment is `*` (e.g. `/ariel/*`) is a prefix: it covers the path before the
+///   wildcard and all paths below it. A `*` in any other place is matched literally.
+/// * A path followed by a query (e.g. `/light?mode=on&mode=off`) only covers requests whose
+///   Uri-Query options are all listed among its `&`-separated items; items ending in `*` (e.g.
+///   `level=*`) cover any option that starts with what precedes the `*`. Without a query, any
+///   Uri-Query options are allowed.
+///
 /// ## Caveats
 ///
 /// Using this is not very efficient; worst case, it iterates over all options for all AIF entries.
@@ -64,9 +103,9 @@ const AIF_SCOPE_MAX_LEN: usize = 64;
 /// the AIF. This could be mitigated by switching to a CRI based model.
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug, Clone)]
-pub struct AifValue([u8; AIF_SCOPE_MAX_LEN]);
+pub struct GenericAifValue<const N: usize>([u8; N]);
 
-impl AifValue {
+impl<const N: usize> GenericAifValue<N> {
     /// Ingests an AIF scope, verifying that it satisfies the constraints of this type.
     ///
     /// # Errors
@@ -74,7 +113,7 @@ impl AifValue {
     /// This produces errors if the input (which is typically received from the network) is
     /// malformed or contains unsupported items.
     pub fn parse(bytes: &[u8]) -> Result<Self, InvalidScope> {
-        let mut buffer = [0; AIF_SCOPE_MAX_LEN];
+        let mut buffer = [0; N];
 
         buffer
             .get_mut(..bytes.len())
@@ -94,9 +133,19 @@ impl AifValue {
 
         Ok(Self(buffer))
     }
+
+    /// Returns the CBOR encoded AIF array this was parsed from.
+    #[must_use]
+    pub fn encoded(&self) -> &[u8] {
+        let mut decoder = minicbor::Decoder::new(&self.0);
+        decoder.skip().expect("validated at construction");
+        self.0
+            .get(..decoder.position())
+            .expect("decoder position is within the buffer")
+    }
 }
 
-impl Scope for AifValue {
+impl<const N: usize> Scope for GenericAifValue<N> {
     fn request_is_allowed<M: ReadableMessage>(&self, request: &M) -> bool {
         let code: u8 = request.code().into();
         let (codebit, false) = 1u32.overflowing_shl(
@@ -107,41 +156,18 @@ impl Scope for AifValue {
             return false;
         };
         let mut decoder = minicbor::Decoder::new(&self.0);
-        'outer: for item in decoder.array_iter::<(&str, u32)>().unwrap() {
-            let (path, perms) = item.unwrap();
+        for item in decoder.array_iter::<(&str, u32)>().unwrap() {
+            let (toid, perms) = item.unwrap();
             if perms & codebit == 0 {
                 continue;
             }
-            // BIG FIXME: We're iterating over options without checking for critical options. If the
-            // resource handler router consumes any different set of options, that disagreement might
-            // give us a security issue.
-            let mut pathopts = request
-                .options()
-                .filter(|o| o.number() == coap_numbers::option::URI_PATH)
-                .peekable();
-            if path == "/" && pathopts.peek().is_none() {
-                // Special case: For consistency should be a single empty option.
-                return true;
-            }
-            assert!(path.starts_with('/'), "Invalid AIF");
-            let mut remainder = &path[1..];
-            while !remainder.is_empty() {
-                let (next_part, next_remainder) = match remainder.split_once('/') {
-                    Some((next_part, next_remainder)) => (next_part, next_remainder),
-                    None => (remainder, ""),
-                };
-                let Some(this_opt) = pathopts.next() else {
-                    // Request path is shorter than this AIF record
-                    continue 'outer;
-                };
-                if this_opt.value() != next_part.as_bytes() {
-                    // Request path is just different from this AIF record
-                    continue 'outer;
-                }
-                remainder = next_remainder;
-            }
-            if pathopts.next().is_none() {
-                // Request path is longer than this AIF record
+            let (path, query) = match toid.split_once('?') {
+                Some((path, query)) => (path, Some(query)),
+                None => (toid, None),
+            };
+            if path_matches(path, request)
+                && query.is_none_or(|query| query_matches(query, request))
+            {
                 return true;
             }
         }
@@ -150,6 +176,53 @@ impl Scope for AifValue {
     }
 }
 
+/// Checks whether the Uri-Path options of the request match the path of an AIF record.
+fn path_matches<M: ReadableMessage>(path: &str, request: &M) -> bool {
+    // BIG FIXME: We're iterating over options without checking for critical options. If the
+    // resource handler router consumes any different set of options, that disagreement might
+    // give us a security issue.
+    let mut pathopts = request
+        .options()
+        .filter(|o| o.number() == coap_numbers::option::URI_PATH)
+        .peekable();
+    if path == "/" && pathopts.peek().is_none() {
+        // Special case: For consistency should be a single empty option.
+        return true;
+    }
+    let mut remainder = path.strip_prefix('/').expect("Invalid AIF");
+    while !remainder.is_empty() {
+        let (next_part, next_remainder) = remainder.split_once('/').unwrap_or((remainder, ""));
+        if next_part == "*" && next_remainder.is_empty() {
+            // Wildcard covering any further segments
+            return true;
+        }
+        let Some(this_opt) = pathopts.next() else {
+            // Request path is shorter than this AIF record
+            return false;
+        };
+        if this_opt.value() != next_part.as_bytes() {
+            // Request path is just different from this AIF record
+            return false;
+        }
+        remainder = next_remainder;
+    }
+    // Unless the request path is longer than this AIF record, it matches.
+    pathopts.next().is_none()
+}
+
+/// Checks whether all Uri-Query options of the request are listed in the query of an AIF record.
+fn query_matches<M: ReadableMessage>(query: &str, request: &M) -> bool {
+    request
+        .options()
+        .filter(|o| o.number() == coap_numbers::option::URI_QUERY)
+        .all(|option| {
+            query.split('&').any(|item| match item.strip_suffix('*') {
+                Some(prefix) => option.value().starts_with(prefix.as_bytes()),
+                None => option.value() == item.as_bytes(),
+            })
+        })
+}
+
 /// A scope that can use multiple backends, erasing its type.
 ///
 /// (Think "`dyn Scope`" but without requiring dyn compatibility).
@@ -157,47 +230,180 @@ impl Scope for AifValue {
 /// This is useful when combining multiple authentication methods, eg. allowing ACE tokens (that
 /// need an [`AifValue`] to express their arbitrary scopes) as well as a configured admin key (that
 /// has "all" permission, which are not expressible in an [`AifValue`].
+///
+/// The AIF values it holds are stored in buffers of `N` bytes.
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug, Clone)]
-pub enum UnionScope {
+pub enum UnionScope<const N: usize = AIF_SCOPE_MAX_LEN> {
     /// Contains an [`AifValue`].
-    AifValue(AifValue),
+    AifValue(GenericAifValue<N>),
+    /// Allows requests that both [`AifValue`]s allow.
+    Intersection(GenericAifValue<N>, GenericAifValue<N>),
     /// Allows all requests.
     AllowAll,
     /// Denies all requests.
     DenyAll,
 }
 
-impl Scope for UnionScope {
+impl<const N: usize> Scope for UnionScope<N> {
     fn request_is_allowed<M: ReadableMessage>(&self, request: &M) -> bool {
         match self {
             UnionScope::AifValue(v) => v.request_is_allowed(request),
+            UnionScope::Intersection(a, b) => {
+                a.request_is_allowed(request) && b.request_is_allowed(request)
+            }
             UnionScope::AllowAll => AllowAll.request_is_allowed(request),
             UnionScope::DenyAll => DenyAll.request_is_allowed(request),
         }
     }
 }
 
-impl From<AifValue> for UnionScope {
-    fn from(value: AifValue) -> Self {
+impl<const N: usize> From<GenericAifValue<N>> for UnionScope<N> {
+    fn from(value: GenericAifValue<N>) -> Self {
         UnionScope::AifValue(value)
     }
 }
 
-impl From<AllowAll> for UnionScope {
+impl<const N: usize> From<AllowAll> for UnionScope<N> {
     fn from(_value: AllowAll) -> Self {
         UnionScope::AllowAll
     }
 }
 
-impl From<DenyAll> for UnionScope {
+impl<const N: usize> From<DenyAll> for UnionScope<N> {
     fn from(_value: DenyAll) -> Self {
         UnionScope::DenyAll
     }
 }
 
-impl From<core::convert::Infallible> for UnionScope {
+impl<const N: usize> From<core::convert::Infallible> for UnionScope<N> {
     fn from(value: core::convert::Infallible) -> Self {
         match value {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use coap_message::MinimalWritableMessage as _;
+    use coap_message_implementations::inmemory_write::Message;
+    use minicbor::encode::write::Cursor;
+
+    use super::*;
+
+    /// Checks whether `scope` allows a request with the given code, path segments and queries.
+    fn allows(scope: &impl Scope, code: u8, path: &[&str], query: &[&str]) -> bool {
+        let mut request_code = 0;
+        let mut buffer = [0; 64];
+        let mut request = Message::new(&mut request_code, &mut buffer[..]);
+        request.set_code(code);
+        for segment in path {
+            request
+                .add_option(coap_numbers::option::URI_PATH, segment.as_bytes())
+                .unwrap();
+        }
+        for item in query {
+            request
+                .add_option(coap_numbers::option::URI_QUERY, item.as_bytes())
+                .unwrap();
+        }
+        scope.request_is_allowed(&request)
+    }
+
+    /// Encodes and parses AIF records.
+    fn aif<const N: usize>(records: &[(&str, u32)]) -> GenericAifValue<N> {
+        let mut encoded = [0; N];
+        let mut encoder = minicbor::Encoder::new(Cursor::new(&mut encoded[..]));
+        encoder.array(records.len().try_into().unwrap()).unwrap();
+        for (toid, tperm) in records {
+            encoder
+                .array(2)
+                .unwrap()
+                .str(toid)
+                .unwrap()
+                .u32(*tperm)
+                .unwrap();
+        }
+        let len = encoder.into_writer().position();
+        GenericAifValue::parse(encoded.get(..len).unwrap()).unwrap()
+    }
+
+    const GET: u8 = coap_numbers::code::GET;
+    const PUT: u8 = coap_numbers::code::PUT;
+
+    #[test]
+    fn paths() {
+        let scope: AifValue = aif(&[("/", 1), ("/hello", 1), ("/ariel/*", 1 | 4)]);
+        assert!(allows(&scope, GET, &[], &[]));
+        assert!(allows(&scope, GET, &["hello"], &[]));
+        assert!(!allows(&scope, GET, &["hello", "world"], &[]));
+        assert!(!allows(&scope, PUT, &["hello"], &[]));
+        assert!(allows(&scope, GET, &["ariel"], &[]));
+        assert!(allows(&scope, PUT, &["ariel", "peers"], &[]));
+        assert!(allows(&scope, GET, &["ariel", "ota", "state"], &[]));
+        assert!(!allows(&scope, GET, &["arielle"], &[]));
+    }
+
+    #[test]
+    fn queries() {
+        let scope: AifValue = aif(&[("/light?mode=on&mode=off&level=*", 4), ("/info", 1)]);
+        assert!(allows(&scope, PUT, &["light"], &[]));
+        assert!(allows(&scope, PUT, &["light"], &["mode=on"]));
+        assert!(allows(&scope, PUT, &["light"], &["mode=off", "level=3"]));
+        assert!(!allows(&scope, PUT, &["light"], &["mode=blink"]));
+        assert!(!allows(&scope, PUT, &["light"], &["mode=on", "color=red"]));
+        // Without a query in the record, any query is allowed.
+        assert!(allows(&scope, GET, &["info"], &["verbose"]));
+    }
+
+    #[test]
+    fn larger_scopes() {
+        let records = [
+            ("/sensors/temperature", 1),
+            ("/sensors/humidity", 1),
+            ("/actuators/valve", 4),
+            ("/actuators/pump", 4),
+        ];
+        // Too long for the default buffer
+        let mut encoded = [0; 128];
+        let mut encoder = minicbor::Encoder::new(Cursor::new(&mut encoded[..]));
+        encoder.encode(records).unwrap();
+        let len = encoder.into_writer().position();
+        let encoded = encoded.get(..len).unwrap();
+        assert!(AifValue::parse(encoded).is_err());
+
+        let scope = GenericAifValue::<128>::parse(encoded).unwrap();
+        assert_eq!(scope.encoded(), encoded);
+        assert!(allows(&scope, PUT, &["actuators", "pump"], &[]));
+
+        let scope: UnionScope<128> = scope.into();
+        assert!(allows(&scope, PUT, &["actuators", "pump"], &[]));
+        assert!(!allows(&scope, PUT, &["sensors", "humidity"], &[]));
+    }
+
+    #[test]
+    fn intersection() {
+        let token: AifValue = aif(&[("/light", 1 | 4), ("/ariel/*", 1)]);
+        let local: AifValue = aif(&[("/light", 1 | 4), ("/ariel/info", 1 | 4)]);
+        let scope = Intersection(token, local);
+        assert!(allows(&scope, PUT, &["light"], &[]));
+        assert!(allows(&scope, GET, &["ariel", "info"], &[]));
+        assert!(!allows(&scope, PUT, &["ariel", "info"], &[]));
+        assert!(!allows(&scope, GET, &["ariel", "peers"], &[]));
+
+        assert!(!allows(&Intersection(AllowAll, DenyAll), GET, &[], &[]));
+        assert!(allows(
+            &Intersection(AllowAll, UnionScope::<AIF_SCOPE_MAX_LEN>::AllowAll),
+            GET,
+            &[],
+            &[]
+        ));
+
+        let token: AifValue = aif(&[("/light", 1 | 4), ("/ariel/*", 1)]);
+        let local: AifValue = aif(&[("/light", 1 | 4), ("/ariel/info", 1 | 4)]);
+        let scope = UnionScope::Intersection(token, local);
+        assert!(allows(&scope, PUT, &["light"], &[]));
+        assert!(allows(&scope, GET, &["ariel", "info"], &[]));
+        assert!(!allows(&scope, PUT, &["ariel", "info"], &[]));
+        assert!(!allows(&scope, GET, &["ariel", "peers"], &[]));
+    }
+}
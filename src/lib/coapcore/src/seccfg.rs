@@ -9,9 +9,11 @@ use defmt_or_log::{debug, error, trace};
 use crate::ace::HeaderMap;
 use crate::error::{CredentialError, CredentialErrorDetail};
 use crate::generalclaims::{GeneralClaims, Unlimited};
+use crate::scope::{AIF_SCOPE_MAX_LEN, GenericAifValue, UnionScope};
 use crate::time::TimeConstraint;
 
-pub(crate) const MAX_AUD_SIZE: usize = 8;
+/// Maximum length of the audience that tokens signed by an ES256 AS are checked against.
+pub const MAX_AUD_SIZE: usize = 8;
 
 /// Error type of [`ServerSecurityConfig::render_not_allowed`].
 ///
@@ -38,6 +40,12 @@ pub trait ServerSecurityConfig {
     /// paths.
     const HAS_EDHOC: bool;
 
+    /// True if the type will at any time provide an [`oscore_group()`](Self::oscore_group).
+    ///
+    /// This is used by the handler implementation to shortcut through some message processing
+    /// paths.
+    const HAS_OSCORE_GROUP: bool = false;
+
     /// The way scopes issued with this system as audience by this AS are expressed here.
     type GeneralClaims: GeneralClaims;
 
@@ -135,6 +143,15 @@ pub trait ServerSecurityConfig {
         None
     }
 
+    /// The Group OSCORE context whose requests the server processes, along with the
+    /// authorizations of its members.
+    ///
+    /// All members of the group share the same authorizations; their time aspect is typically
+    /// unbounded.
+    fn oscore_group(&self) -> Option<(&crate::group::OscoreGroup, Self::GeneralClaims)> {
+        None
+    }
+
     /// Generates the scope representing unauthenticated access.
     ///
     /// Their time aspect is typically unbounded.
@@ -162,6 +179,29 @@ pub trait ServerSecurityConfig {
     ) -> Result<(), NotAllowedRenderingFailed> {
         Err(NotAllowedRenderingFailed)
     }
+
+    /// Encodes the claims of a security context into `buffer`, so that the context can be
+    /// persisted (see [`crate::persist`]).
+    ///
+    /// Returns the encoded length, or `None` if the claims can not be encoded. By default, no
+    /// claims are encoded, and thus no security contexts are persisted.
+    #[allow(
+        unused_variables,
+        reason = "Names are human visible part of API description"
+    )]
+    fn encode_claims(&self, claims: &Self::GeneralClaims, buffer: &mut [u8]) -> Option<usize> {
+        None
+    }
+
+    /// Decodes claims encoded by [`Self::encode_claims()`] when a persisted security context is
+    /// restored.
+    #[allow(
+        unused_variables,
+        reason = "Names are human visible part of API description"
+    )]
+    fn decode_claims(&self, encoded: &[u8]) -> Option<Self::GeneralClaims> {
+        None
+    }
 }
 
 /// The default empty configuration that denies all access.
@@ -197,7 +237,10 @@ impl ServerSecurityConfig for AllowAll {
 /// Lacking better sources of information, the scope's imporatance is chosen by source: Only
 /// preconfigured EDHOC keys are regarded as important, and thus kept around even in the presence
 /// of multiple competing token based contexts.
-pub struct ConfigBuilder {
+///
+/// The AIF values of its scopes are stored in buffers of `N` bytes; a builder with a non-default
+/// `N` is started from its [`Default`] implementation rather than from [`ConfigBuilder::new()`].
+pub struct ConfigBuilder<const N: usize = AIF_SCOPE_MAX_LEN> {
     /// Symmetric used when tokens are symmetrically encrypted with AES-CCM-16-128-256
     as_key_31: Option<[u8; 32]>,
     /// Asymmetric key used when tokens are signed with ES256
@@ -205,18 +248,23 @@ pub struct ConfigBuilder {
     /// Alogn with the key, this also holds the audience value of this RS (as signed tokens only
     /// make sense when the same signing key is used with multiple recipients).
     as_key_neg7: Option<([u8; 32], [u8; 32], heapless::String<MAX_AUD_SIZE>)>,
-    unauthenticated_scope: Option<crate::scope::UnionScope>,
+    /// Local policy that limits the scopes of all tokens.
+    token_scope_limit: Option<GenericAifValue<N>>,
+    unauthenticated_scope: Option<UnionScope<N>>,
     own_edhoc_credential: Option<(lakers::Credential, lakers::BytesP256ElemLen)>,
-    known_edhoc_clients: Option<(lakers::Credential, crate::scope::UnionScope)>,
+    known_edhoc_clients: Option<(lakers::Credential, UnionScope<N>)>,
+    trusted_edhoc_issuer: Option<(crate::credentials::TrustedIssuer, UnionScope<N>)>,
     request_creation_hints: &'static [u8],
+    oscore_group: Option<(crate::group::OscoreGroup, UnionScope<N>)>,
 }
 
-impl ServerSecurityConfig for ConfigBuilder {
+impl<const N: usize> ServerSecurityConfig for ConfigBuilder<N> {
     // We can't know at build time, assume yes
     const PARSES_TOKENS: bool = true;
     const HAS_EDHOC: bool = true;
+    const HAS_OSCORE_GROUP: bool = true;
 
-    type GeneralClaims = ConfigBuilderClaims;
+    type GeneralClaims = ConfigBuilderClaims<N>;
 
     fn decrypt_symmetric_token<'buf>(
         &self,
@@ -271,10 +319,7 @@ impl ServerSecurityConfig for ConfigBuilder {
         let claims: crate::ace::CwtClaimsSet = minicbor::decode(ciphertext)
             .map_err(|_| CredentialErrorDetail::UnsupportedExtension)?;
 
-        // FIXME: Consider moving into general parser.
-        let scope = crate::scope::AifValue::parse(claims.scope)
-            .map_err(|_| CredentialErrorDetail::UnsupportedExtension)?
-            .into();
+        let scope = self.token_scope(claims.scope)?;
         let time_constraint = crate::time::TimeConstraint::from_claims_set(&claims);
 
         Ok((
@@ -324,10 +369,7 @@ impl ServerSecurityConfig for ConfigBuilder {
             return Err(CredentialErrorDetail::VerifyFailed.into());
         }
 
-        // FIXME: Consider moving into general parser.
-        let scope = crate::scope::AifValue::parse(claims.scope)
-            .map_err(|_| CredentialErrorDetail::UnsupportedExtension)?
-            .into();
+        let scope = self.token_scope(claims.scope)?;
         let time_constraint = crate::time::TimeConstraint::from_claims_set(&claims);
 
         Ok((
@@ -350,6 +392,19 @@ impl ServerSecurityConfig for ConfigBuilder {
             })
     }
 
+    fn oscore_group(&self) -> Option<(&crate::group::OscoreGroup, Self::GeneralClaims)> {
+        self.oscore_group.as_ref().map(|(group, scope)| {
+            (
+                group,
+                ConfigBuilderClaims {
+                    scope: scope.clone(),
+                    time_constraint: TimeConstraint::unbounded(),
+                    is_important: false,
+                },
+            )
+        })
+    }
+
     fn own_edhoc_credential(&self) -> Option<(lakers::Credential, lakers::BytesP256ElemLen)> {
         #[expect(
             clippy::clone_on_copy,
@@ -367,12 +422,39 @@ impl ServerSecurityConfig for ConfigBuilder {
             id_cred_x.as_full_value()
         );
 
+        if let Some((issuer, scope)) = &self.trusted_edhoc_issuer {
+            if let Some((credential, time_constraint)) = issuer.expand(&id_cred_x) {
+                debug!("Peer presented a credential signed by the trusted issuer.");
+                return Some((
+                    credential,
+                    ConfigBuilderClaims {
+                        scope: scope.clone(),
+                        time_constraint,
+                        // Any number of peers may present such credentials.
+                        is_important: false,
+                    },
+                ));
+            }
+        }
+
         #[expect(
             clippy::single_element_loop,
             reason = "Expected to be extended to actual loop soon"
         )]
         for (credential, scope) in &[self.known_edhoc_clients.as_ref()?] {
             trace!("Comparing to {=[u8]:02x}", credential.bytes.as_slice()); // :02x could be :cbor
+            if crate::credentials::identifies_certificate(&id_cred_x, credential) {
+                debug!("Peer indicated use of the one preconfigured certificate.");
+                #[expect(clippy::clone_on_copy, reason = "Lakers items are overly copy happy")]
+                return Some((
+                    credential.clone(),
+                    ConfigBuilderClaims {
+                        scope: scope.clone(),
+                        time_constraint: TimeConstraint::unbounded(),
+                        is_important: true,
+                    },
+                ));
+            }
             if id_cred_x.reference_only() {
                 // ad Ok: If our credential has no KID, it can't be recognized in this branch
                 if credential.by_kid().as_ref() == Ok(&id_cred_x) {
@@ -441,9 +523,19 @@ impl ServerSecurityConfig for ConfigBuilder {
     }
 }
 
-impl Default for ConfigBuilder {
+impl<const N: usize> Default for ConfigBuilder<N> {
     fn default() -> Self {
-        ConfigBuilder::new()
+        Self {
+            as_key_31: None,
+            as_key_neg7: None,
+            token_scope_limit: None,
+            unauthenticated_scope: None,
+            known_edhoc_clients: None,
+            trusted_edhoc_issuer: None,
+            own_edhoc_credential: None,
+            request_creation_hints: &[],
+            oscore_group: None,
+        }
     }
 }
 
@@ -453,21 +545,28 @@ impl ConfigBuilder {
     /// Without any additional building steps, this is equivalent to [`DenyAll`].
     #[must_use]
     pub fn new() -> Self {
-        Self {
-            as_key_31: None,
-            as_key_neg7: None,
-            unauthenticated_scope: None,
-            known_edhoc_clients: None,
-            own_edhoc_credential: None,
-            request_creation_hints: &[],
-        }
+        Self::default()
+    }
+}
+
+impl<const N: usize> ConfigBuilder<N> {
+    /// Builds the scope of a token's claims from its encoded AIF `scope`, limited to what the
+    /// local policy allows.
+    fn token_scope(&self, scope: &[u8]) -> Result<UnionScope<N>, CredentialError> {
+        // FIXME: Consider moving into general parser.
+        let scope = GenericAifValue::<N>::parse(scope)
+            .map_err(|_| CredentialErrorDetail::UnsupportedExtension)?;
+        Ok(match &self.token_scope_limit {
+            Some(limit) => UnionScope::Intersection(scope, limit.clone()),
+            None => scope.into(),
+        })
     }
 
     /// Sets a single Authorization Server recognized by a shared `AES-16-128-256` (COSE algorithm
     /// 31) key.
     ///
     /// Scopes are accepted as given by the AS using the AIF REST model as understood by
-    /// [`crate::scope::AifValue`].
+    /// [`GenericAifValue`], limited by [`Self::with_token_scope_limit`] if configured.
     ///
     /// # Caveats and evolution
     ///
@@ -497,7 +596,7 @@ impl ConfigBuilder {
     /// have that audience.
     ///
     /// Scopes are accepted as given by the AS using the AIF REST model as understood by
-    /// [`crate::scope::AifValue`].
+    /// [`GenericAifValue`], limited by [`Self::with_token_scope_limit`] if configured.
     ///
     /// # Caveats and evolution
     ///
@@ -516,11 +615,42 @@ impl ConfigBuilder {
         }
     }
 
+    /// Limits the scopes granted by tokens of any Authorization Server to what the given local
+    /// policy allows.
+    ///
+    /// Requests are then only allowed if both the token's scope and the `limit` allow them; without
+    /// this, tokens are accepted with the scope given by the AS.
+    ///
+    /// # Panics
+    ///
+    /// When debug assertions are enabled, this panics if a limit has already been configured.
+    #[must_use]
+    pub fn with_token_scope_limit(self, limit: GenericAifValue<N>) -> Self {
+        debug_assert!(
+            self.token_scope_limit.is_none(),
+            "Overwriting previously configured token scope limit"
+        );
+        Self {
+            token_scope_limit: Some(limit),
+            ..self
+        }
+    }
+
+    /// Returns the local policy configured through [`Self::with_token_scope_limit`], if any.
+    #[must_use]
+    pub fn token_scope_limit(&self) -> Option<&GenericAifValue<N>> {
+        self.token_scope_limit.as_ref()
+    }
+
     /// Allow use of the server within the limits of the given scope by EDHOC clients provided they
     /// present the given credential.
     ///
     /// Unlike many ACE tokens, this credential is accepted without any limitations on time.
     ///
+    /// Credentials built from X.509 certificates by
+    /// [`certificate_credential()`](crate::credentials::certificate_credential) are recognized both
+    /// when the peer sends the certificate and when it indicates its hash.
+    ///
     /// # Caveats and evolution
     ///
     /// Currently, this type just supports a single credential; it should therefore only be called
@@ -530,7 +660,7 @@ impl ConfigBuilder {
     pub fn with_known_edhoc_credential(
         self,
         credential: lakers::Credential,
-        scope: crate::scope::UnionScope,
+        scope: UnionScope<N>,
     ) -> Self {
         Self {
             known_edhoc_clients: Some((credential, scope)),
@@ -538,6 +668,32 @@ impl ConfigBuilder {
         }
     }
 
+    /// Allows EDHOC clients that present an X.509 certificate or a CWT signed by the `issuer` use
+    /// of the server within the limits of the given scope.
+    ///
+    /// The client's security context is valid until its certificate or CWT expires. See the
+    /// [`credentials`](crate::credentials) module for which credentials are supported.
+    ///
+    /// # Panics
+    ///
+    /// When debug assertions are enabled, this panics if a trusted issuer has already been
+    /// configured.
+    #[must_use]
+    pub fn with_trusted_edhoc_issuer(
+        self,
+        issuer: crate::credentials::TrustedIssuer,
+        scope: UnionScope<N>,
+    ) -> Self {
+        debug_assert!(
+            self.trusted_edhoc_issuer.is_none(),
+            "Overwriting previously configured trusted issuer"
+        );
+        Self {
+            trusted_edhoc_issuer: Some((issuer, scope)),
+            ..self
+        }
+    }
+
     /// Configures an EDHOC credential and private key to be presented by this server.
     ///
     /// # Panics
@@ -567,7 +723,7 @@ impl ConfigBuilder {
     /// When debug assertions are enabled, this panics if an unauthenticated scope has already been
     /// configured.
     #[must_use]
-    pub fn allow_unauthenticated(self, scope: crate::scope::UnionScope) -> Self {
+    pub fn allow_unauthenticated(self, scope: UnionScope<N>) -> Self {
         debug_assert!(
             self.unauthenticated_scope.is_none(),
             "Overwriting previously configured unauthenticated scope"
@@ -595,24 +751,45 @@ impl ConfigBuilder {
             ..self
         }
     }
+
+    /// Processes requests protected with the given Group OSCORE context, allowing their senders
+    /// use of the server within the limits of the given scope.
+    ///
+    /// See the [`group`](crate::group) module for how those requests are processed.
+    ///
+    /// # Panics
+    ///
+    /// When debug assertions are enabled, this panics if a group has already been configured.
+    #[must_use]
+    pub fn with_oscore_group(self, group: crate::group::OscoreGroup, scope: UnionScope<N>) -> Self {
+        debug_assert!(
+            self.oscore_group.is_none(),
+            "Overwriting previously configured OSCORE group"
+        );
+        Self {
+            oscore_group: Some((group, scope)),
+            ..self
+        }
+    }
 }
 
 /// An implementation of [`GeneralClaims`] for [`ConfigBuilder`].
 ///
-/// It stores a [`UnionScope`][crate::scope::UnionScope] (effectively a
-/// [`AifValue`][crate::scope::AifValue]), a [`TimeConstraint`], and a flag for importance.
+/// It stores a [`UnionScope`] (effectively a [`AifValue`][crate::scope::AifValue], or an
+/// intersection of two when tokens are limited by [`ConfigBuilder::with_token_scope_limit()`]), a
+/// [`TimeConstraint`], and a flag for importance.
 #[derive(Debug)]
-pub struct ConfigBuilderClaims {
+pub struct ConfigBuilderClaims<const N: usize = AIF_SCOPE_MAX_LEN> {
     /// The scope of the claims (providing [`GeneralClaims::scope()`]).
-    pub scope: crate::scope::UnionScope,
+    pub scope: UnionScope<N>,
     /// Time constraints on the claims (providing [`GeneralClaims::time_constraint()`]).
     pub time_constraint: crate::time::TimeConstraint,
     /// Importance of the security context (providing [`GeneralClaims::is_important()`], see there).
     pub is_important: bool,
 }
 
-impl GeneralClaims for ConfigBuilderClaims {
-    type Scope = crate::scope::UnionScope;
+impl<const N: usize> GeneralClaims for ConfigBuilderClaims<N> {
+    type Scope = UnionScope<N>;
 
     fn scope(&self) -> &Self::Scope {
         &self.scope
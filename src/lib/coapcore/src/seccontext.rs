@@ -7,26 +7,31 @@
 use core::marker::PhantomData;
 
 use coap_message::{
-    Code, MessageOption, MinimalWritableMessage, MutableWritableMessage, ReadableMessage,
-    error::RenderableOnMinimal,
+    Code, MessageOption, MinimalWritableMessage, MutableWritableMessage, OptionNumber as _,
+    ReadableMessage, error::RenderableOnMinimal,
 };
 use coap_message_utils::{Error as CoAPError, OptionsExt as _};
 use defmt_or_log::{Debug2Format, debug, error, trace};
 
 use crate::generalclaims::{self, GeneralClaims as _};
+use crate::group::{Freshness, UnprotectError};
 use crate::helpers::COwn;
+use crate::kudos::{KeyUpdate, OscoreOption};
+use crate::metrics::{AccessLog, Metrics, Outcome, Peer};
+use crate::observe::{Notification, Observation, Observations, ObserveRequest, Origin, Protection};
+use crate::persist::{ContextMaterial, Persistence, PersistenceAction, PersistenceRequest};
 use crate::scope::Scope;
 use crate::seccfg::ServerSecurityConfig;
 
 use crate::time::TimeProvider;
 
-const MAX_CONTEXTS: usize = 4;
+pub(crate) const MAX_CONTEXTS: usize = 4;
 const _MAX_CONTEXTS_CHECK: () = assert!(MAX_CONTEXTS <= COwn::GENERATABLE_VALUES);
 
 /// Helper for cutting branches that can not be reached; could be a provided function of the
 /// [`ServerSecurityConfig`], but we need it const.
 const fn has_oscore<SSC: ServerSecurityConfig>() -> bool {
-    SSC::HAS_EDHOC || SSC::PARSES_TOKENS
+    SSC::HAS_EDHOC || SSC::PARSES_TOKENS || SSC::HAS_OSCORE_GROUP
 }
 
 /// Space allocated for the message into which an EDHOC request is copied to remove EDHOC option
@@ -49,15 +54,22 @@ const EDHOC_COPY_BUFFER_SIZE: usize = 1152;
 type SecContextPool<Crypto, Claims> =
     crate::oluru::OrderedPool<SecContextState<Crypto, Claims>, MAX_CONTEXTS, LEVEL_COUNT>;
 
-/// Copy of the OSCORE option
-type OscoreOption = heapless::Vec<u8, 16>;
-
 struct SecContextState<Crypto: lakers::Crypto, GeneralClaims: generalclaims::GeneralClaims> {
     // FIXME: Updating this should also check the timeout.
 
     // This is Some(...) unless the stage is unusable.
     authorization: Option<GeneralClaims>,
     protocol_stage: SecContextStage<Crypto>,
+    /// Present on OSCORE contexts that can be persisted (see [`crate::persist`]).
+    persistence: Option<Persistence>,
+    /// Input material of OSCORE contexts that can be persisted or [updated][crate::kudos].
+    material: Option<ContextMaterial>,
+    /// Material of OSCORE contexts from before they were updated through KUDOS, kept until the
+    /// peer used the updated context.
+    ///
+    /// If the response to the request that updated the keys is lost, the peer still holds this
+    /// material, and retries the update starting from it.
+    previous_material: Option<ContextMaterial>,
 }
 
 impl<Crypto: lakers::Crypto, GeneralClaims: generalclaims::GeneralClaims> Default
@@ -67,6 +79,9 @@ impl<Crypto: lakers::Crypto, GeneralClaims: generalclaims::GeneralClaims> Defaul
         Self {
             authorization: None,
             protocol_stage: SecContextStage::Empty,
+            persistence: None,
+            material: None,
+            previous_material: None,
         }
     }
 }
@@ -124,6 +139,10 @@ impl<Crypto: lakers::Crypto, GeneralClaims: generalclaims::GeneralClaims>
                 // even that)
                 LEVEL_ONGOING
             }
+            SecContextStage::Oscore(_) if self.previous_material.is_some() => {
+                // The peer did not show yet that it completed the key update.
+                LEVEL_ONGOING
+            }
             SecContextStage::Oscore(_) => {
                 if self
                     .authorization
@@ -166,6 +185,7 @@ pub struct OscoreEdhocHandler<
     SSC: ServerSecurityConfig,
     RNG: rand_core::RngCore + rand_core::CryptoRng,
     TP: TimeProvider,
+    AL: AccessLog = (),
 > {
     // It'd be tempted to have sharing among multiple handlers for multiple CoAP stacks, but
     // locks for such sharing could still be acquired in a factory (at which point it may make
@@ -187,8 +207,19 @@ pub struct OscoreEdhocHandler<
 
     time: TP,
 
+    observations: Observations,
+
+    /// Recipient IDs of the contexts stored in each persistence slot.
+    persisted: [Option<COwn>; MAX_CONTEXTS],
+
+    /// Replay windows of the members of the OSCORE group (see [`crate::group`]).
+    group_replay: [crate::group::Replay; crate::group::MAX_MEMBERS],
+
     crypto_factory: CryptoFactory,
     rng: RNG,
+
+    metrics: Metrics,
+    access_log: AL,
 }
 
 impl<
@@ -229,18 +260,526 @@ impl<
             authorities,
             rng,
             time,
+            observations: Observations::new(),
+            persisted: [None; MAX_CONTEXTS],
+            group_replay: Default::default(),
+            metrics: Metrics::default(),
+            access_log: (),
+        }
+    }
+}
+
+impl<
+    H: coap_handler::Handler,
+    Crypto: lakers::Crypto,
+    CryptoFactory: Fn() -> Crypto,
+    SSC: ServerSecurityConfig,
+    RNG: rand_core::RngCore + rand_core::CryptoRng,
+    TP: TimeProvider,
+    AL: AccessLog,
+> OscoreEdhocHandler<H, Crypto, CryptoFactory, SSC, RNG, TP, AL>
+{
+    /// Reports every request that is checked against the peer's authorization to `access_log`
+    /// (see the [`metrics`][crate::metrics] module), replacing any previously set access log.
+    #[must_use]
+    pub fn with_access_log<AL2: AccessLog>(
+        self,
+        access_log: AL2,
+    ) -> OscoreEdhocHandler<H, Crypto, CryptoFactory, SSC, RNG, TP, AL2> {
+        OscoreEdhocHandler {
+            pool: self.pool,
+            authorities: self.authorities,
+            inner: self.inner,
+            time: self.time,
+            observations: self.observations,
+            persisted: self.persisted,
+            group_replay: self.group_replay,
+            crypto_factory: self.crypto_factory,
+            rng: self.rng,
+            metrics: self.metrics,
+            access_log,
         }
     }
 
+    /// Returns the counters of the requests and security events processed so far; see the
+    /// [`metrics`][crate::metrics] module.
+    #[must_use]
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Informs the handler of the transport level origin of the next request.
+    ///
+    /// Only requests for which this was called can register observations; see the
+    /// [`observe`][crate::observe] module for details.
+    pub fn set_request_origin(&mut self, origin: Origin) {
+        self.observations.set_request_origin(origin);
+    }
+
+    /// Marks all observations of the resource at `path` (e.g. `"/sensors/temp"`) as needing a
+    /// notification.
+    ///
+    /// The notifications are then produced by [`Self::build_notification()`].
+    pub fn notify(&mut self, path: &str) {
+        self.observations.notify(path);
+    }
+
+    /// Ends any observation that was registered by a request with the given origin.
+    pub fn cancel_observation(&mut self, origin: &Origin) {
+        self.observations.cancel_where(|o| &o.origin == origin);
+    }
+
+    /// Restores a security context that was persisted in `slot` before a reboot.
+    ///
+    /// This is to be called with the data of all completed [`PersistenceAction::Store`] requests
+    /// (with the sequence number of the latest [`PersistenceAction::Advance`], if any) before
+    /// the first request is processed; see the [`persist`][crate::persist] module for details.
+    ///
+    /// # Errors
+    ///
+    /// This fails if the stored data is not understood, or if the slot or the context's recipient
+    /// ID are already in use. The slot is then best cleared from storage.
+    pub fn restore_context(
+        &mut self,
+        slot: usize,
+        material: &[u8],
+        claims: &[u8],
+        sequence_number: u64,
+    ) -> Result<(), crate::persist::InvalidRecord> {
+        use crate::persist::InvalidRecord;
+
+        if self.persisted.get(slot) != Some(&None) {
+            return Err(InvalidRecord);
+        }
+        let material = ContextMaterial::decode(material)?;
+        let kid = material.recipient_id();
+        if self.persisted.contains(&Some(kid))
+            || self
+                .pool
+                .iter()
+                .any(|entry| entry.corresponding_cown() == Some(kid))
+        {
+            return Err(InvalidRecord);
+        }
+        let claims = self
+            .authorities
+            .decode_claims(claims)
+            .ok_or(InvalidRecord)?;
+        let context = material.restore(sequence_number)?;
+
+        // There are no more slots than fit into the pool, so this does not evict other restored
+        // contexts.
+        let _ = self.pool.force_insert(SecContextState {
+            protocol_stage: SecContextStage::Oscore(context),
+            authorization: Some(claims),
+            persistence: Some(Persistence::restored(sequence_number)),
+            material: Some(material),
+            previous_material: None,
+        });
+        if let Some(persisted) = self.persisted.get_mut(slot) {
+            *persisted = Some(kid);
+        }
+        Ok(())
+    }
+
+    /// Discards all security contexts whose claims were
+    /// [revoked](generalclaims::GeneralClaims::is_revoked).
+    fn evict_revoked(&mut self) {
+        while self
+            .pool
+            .lookup(
+                |entry| {
+                    entry
+                        .authorization
+                        .as_ref()
+                        .is_some_and(generalclaims::GeneralClaims::is_revoked)
+                },
+                |entry| *entry = SecContextState::default(),
+            )
+            .is_some()
+        {
+            debug!("Discarded security context with revoked claims.");
+        }
+    }
+
+    /// Produces the next change that needs to be applied to persistent storage, if any.
+    ///
+    /// Once the change is applied, this needs to be reported through [`Self::persisted()`].
+    /// Changes can become due after any request or notification was processed, so it is
+    /// suitable to call this after each until it returns `None`.
+    pub fn next_persistence_request(&mut self) -> Option<PersistenceRequest> {
+        self.evict_revoked();
+
+        // Contexts that are gone are removed first, so that their slots become available.
+        let removed = self.persisted.iter().enumerate().find_map(|(slot, kid)| {
+            let kid = (*kid)?;
+            (!self.pool.iter().any(|entry| {
+                entry.persistence.is_some() && entry.corresponding_cown() == Some(kid)
+            }))
+            .then_some((slot, kid))
+        });
+        if let Some((slot, kid)) = removed {
+            return Some(PersistenceRequest {
+                slot,
+                kid,
+                action: PersistenceAction::Remove,
+            });
+        }
+
+        // Contexts are stored when they were established or updated, in the slot they already
+        // occupy if any.
+        loop {
+            let persisted = &self.persisted;
+            let authorities = &self.authorities;
+            let free = persisted.iter().position(Option::is_none);
+            let slot_for = |kid| persisted.iter().position(|p| *p == Some(kid)).or(free);
+            let store = self.pool.lookup(
+                |entry| {
+                    entry.corresponding_cown().is_some_and(|kid| {
+                        entry.persistence.as_ref().is_some_and(|persistence| {
+                            persistence.limit.is_none() || !persisted.contains(&Some(kid))
+                        }) && slot_for(kid).is_some()
+                    })
+                },
+                |entry| {
+                    let (Some(kid), SecContextStage::Oscore(context), Some(material)) = (
+                        entry.corresponding_cown(),
+                        &entry.protocol_stage,
+                        &entry.material,
+                    ) else {
+                        entry.persistence = None;
+                        return None;
+                    };
+                    let mut buffer = [0; crate::persist::MAX_CLAIMS_LEN];
+                    let claims = entry
+                        .authorization
+                        .as_ref()
+                        .and_then(|claims| authorities.encode_claims(claims, &mut buffer))
+                        .and_then(|len| buffer.get(..len))
+                        .and_then(|claims| heapless::Vec::from_slice(claims).ok());
+                    let (Some(claims), Some(persistence)) = (claims, entry.persistence.as_mut())
+                    else {
+                        debug!("Claims can not be persisted, not persisting context.");
+                        entry.persistence = None;
+                        return None;
+                    };
+                    // Until the context is stored, it may use any sequence number: If it is not
+                    // stored, it is gone after a reboot.
+                    let sequence_number = persistence.next_limit(context);
+                    persistence.limit = Some(sequence_number);
+                    Some(PersistenceRequest {
+                        slot: slot_for(kid).expect("checked when looking up the entry"),
+                        kid,
+                        action: PersistenceAction::Store {
+                            material: material.encode(),
+                            claims,
+                            sequence_number,
+                        },
+                    })
+                },
+            );
+            match store {
+                // Nothing to store
+                None => break,
+                Some(Some(request)) => return Some(request),
+                // The context turned out not to be persistable; look further
+                Some(None) => (),
+            }
+        }
+
+        self.pool.iter().find_map(|entry| {
+            let persistence = entry.persistence.as_ref()?;
+            let SecContextStage::Oscore(context) = &entry.protocol_stage else {
+                return None;
+            };
+            let kid = entry.corresponding_cown()?;
+            let slot = self.persisted.iter().position(|p| *p == Some(kid))?;
+            persistence
+                .needs_advance(context)
+                .then(|| PersistenceRequest {
+                    slot,
+                    kid,
+                    action: PersistenceAction::Advance {
+                        sequence_number: persistence.next_limit(context),
+                    },
+                })
+        })
+    }
+
+    /// Reports that a request obtained from [`Self::next_persistence_request()`] was applied to
+    /// persistent storage.
+    pub fn persisted(&mut self, request: PersistenceRequest) {
+        let PersistenceRequest { slot, kid, action } = request;
+        let Some(persisted) = self.persisted.get_mut(slot) else {
+            return;
+        };
+        match action {
+            PersistenceAction::Store { .. } => *persisted = Some(kid),
+            PersistenceAction::Remove => {
+                if *persisted == Some(kid) {
+                    *persisted = None;
+                }
+            }
+            PersistenceAction::Advance { sequence_number } => {
+                self.pool.lookup(
+                    |entry| entry.corresponding_cown() == Some(kid),
+                    |entry| {
+                        if let Some(persistence) = &mut entry.persistence {
+                            persistence.limit = persistence.limit.max(Some(sequence_number));
+                        }
+                    },
+                );
+            }
+        }
+    }
+
+    /// Builds the next pending notification into `response`.
+    ///
+    /// This returns `None` if no notifications are pending. Otherwise, `response` contains the
+    /// code, options and payload of a notification that is to be sent to the returned origin.
+    ///
+    /// Observations that can not be served any more (because their security context is gone or
+    /// their authorization expired) are ended without producing a notification; if the observed
+    /// resource produces an error or is not accessible any more, that error is the last
+    /// notification.
+    pub fn build_notification(
+        &mut self,
+        response: &mut coap_message_implementations::inmemory_write::Message<'_>,
+    ) -> Option<Notification> {
+        loop {
+            let mut observation = self.observations.take_pending()?;
+            let sequence = self.observations.next_sequence();
+            let built = match observation.protection {
+                Protection::Plain => {
+                    self.build_plain_notification(&observation, response, sequence)
+                }
+                Protection::Oscore {
+                    kid,
+                    mut correlation,
+                } => {
+                    let built = self.build_oscore_notification(
+                        &observation,
+                        kid,
+                        &mut correlation,
+                        response,
+                        sequence,
+                    );
+                    observation.protection = Protection::Oscore { kid, correlation };
+                    built
+                }
+            };
+
+            let Some(continues) = built else {
+                debug!("Observation ended without notification.");
+                continue;
+            };
+            let notification = Notification {
+                origin: observation.origin.clone(),
+                confirmable: continues && observation.next_is_confirmable(),
+                last: !continues,
+            };
+            if continues {
+                self.observations.put_back(observation);
+            }
+            return Some(notification);
+        }
+    }
+
+    /// Builds a notification for an observation that was registered without security.
+    ///
+    /// Returns whether the observation continues, or `None` if nothing was built.
+    fn build_plain_notification(
+        &mut self,
+        observation: &Observation,
+        response: &mut coap_message_implementations::inmemory_write::Message<'_>,
+        sequence: u32,
+    ) -> Option<bool> {
+        let mut code = 0;
+        let mut buffer = [0; crate::observe::REQUEST_COPY_LEN];
+        let request = observation.rebuild_request(&mut code, &mut buffer);
+
+        if !self.authorities.nosec_authorization().is_some_and(|s| {
+            s.scope().request_is_allowed(&request)
+                && s.time_constraint().is_valid_with(&mut self.time)
+        }) {
+            if self.authorities.render_not_allowed(response).is_err() {
+                response.set_code(coap_numbers::code::UNAUTHORIZED);
+            }
+            return Some(false);
+        }
+
+        Some(Self::build_inner_notification(
+            &mut self.inner,
+            &request,
+            response,
+            sequence,
+        ))
+    }
+
+    /// Builds a notification for an observation that was registered through OSCORE.
+    ///
+    /// Returns whether the observation continues, or `None` if nothing was built.
+    fn build_oscore_notification(
+        &mut self,
+        observation: &Observation,
+        kid: COwn,
+        correlation: &mut liboscore::raw::oscore_requestid_t,
+        response: &mut coap_message_implementations::inmemory_write::Message<'_>,
+        sequence: u32,
+    ) -> Option<bool> {
+        let mut code = 0;
+        let mut buffer = [0; crate::observe::REQUEST_COPY_LEN];
+        let request = observation.rebuild_request(&mut code, &mut buffer);
+
+        let time = &mut self.time;
+        let inner = &mut self.inner;
+        let authorities = &self.authorities;
+        self.pool
+            .lookup(
+                |c| c.corresponding_cown() == Some(kid),
+                |matched| {
+                    let SecContextState {
+                        protocol_stage: SecContextStage::Oscore(oscore_context),
+                        authorization: Some(authorization),
+                        persistence,
+                        ..
+                    } = matched
+                    else {
+                        return None;
+                    };
+                    if !authorization.time_constraint().is_valid_with(time) {
+                        debug!("Security context of observation expired.");
+                        return None;
+                    }
+                    if authorization.is_revoked() {
+                        debug!("Security context of observation was revoked.");
+                        return None;
+                    }
+                    if !persistence
+                        .as_ref()
+                        .is_none_or(|p| p.may_send(oscore_context))
+                    {
+                        debug!("Sender sequence numbers are exhausted until more are persisted.");
+                        return None;
+                    }
+
+                    // The outer code of notifications is Content (RFC8613 Section 4.2).
+                    response.set_code(coap_numbers::code::CONTENT);
+                    liboscore::protect_response(response, oscore_context, correlation, |response| {
+                        if authorization.scope().request_is_allowed(&request) {
+                            Self::build_inner_notification(inner, &request, response, sequence)
+                        } else {
+                            if authorities.render_not_allowed(response).is_err() {
+                                response.set_code(coap_numbers::code::UNAUTHORIZED);
+                            }
+                            false
+                        }
+                    })
+                    .map_err(|_| error!("Notification could not be protected."))
+                    .ok()
+                },
+            )
+            .flatten()
+    }
+
+    /// Builds a notification from the inner handler into `response` (which may be the plaintext
+    /// of an OSCORE message), and returns whether the observation continues.
+    fn build_inner_notification<M: MutableWritableMessage>(
+        inner: &mut H,
+        request: &coap_message_implementations::inmemory_write::Message<'_>,
+        response: &mut M,
+        sequence: u32,
+    ) -> bool {
+        let internal_server_error = |response: &mut M| {
+            if let Ok(code) = M::Code::new(coap_numbers::code::INTERNAL_SERVER_ERROR) {
+                response.set_code(code);
+            }
+        };
+
+        match inner.extract_request_data(request) {
+            Ok(extracted) => Self::build_observed_response(inner, extracted, response, sequence),
+            Err(e) => {
+                debug!("Observed resource errs with {:?}", Debug2Format(&e));
+                if e.render(response).is_err() {
+                    internal_server_error(response);
+                }
+                false
+            }
+        }
+    }
+
+    /// Builds the inner handler's response into `response`, carrying an Observe option with the
+    /// given sequence number if it is successful, and returns whether it was.
+    ///
+    /// Only successful (2.xx) responses may carry the option and keep the observation going
+    /// (RFC7641 Section 4.2), but the option precedes most options the inner handler adds. Thus,
+    /// the response is built into a scratch message first, and copied over with the option.
+    #[inline(never)]
+    fn build_observed_response<M: MutableWritableMessage>(
+        inner: &mut H,
+        extracted: H::RequestData,
+        response: &mut M,
+        sequence: u32,
+    ) -> bool {
+        let internal_server_error = |response: &mut M| {
+            if let Ok(code) = M::Code::new(coap_numbers::code::INTERNAL_SERVER_ERROR) {
+                response.set_code(code);
+            }
+        };
+
+        let mut scratch_code = 0;
+        let mut scratch_buffer = [0u8; EDHOC_COPY_BUFFER_SIZE];
+        let mut scratch = coap_message_implementations::inmemory_write::Message::new(
+            &mut scratch_code,
+            &mut scratch_buffer[..],
+        );
+        if let Err(e) = inner.build_response(&mut scratch, extracted) {
+            error!(
+                "Building observed response failed with {:?}",
+                Debug2Format(&e)
+            );
+            // Rendered into the response, which is still untouched.
+            if e.render(response).is_err() {
+                internal_server_error(response);
+            }
+            return false;
+        }
+
+        let code: u8 = scratch.code().into();
+        // 2.xx
+        let successful = code >> 5 == 2;
+        if crate::observe::copy_with_observe(&scratch, successful.then_some(sequence), response)
+            .is_err()
+        {
+            error!("Observed response could not be copied.");
+            // FIXME rewind message
+            internal_server_error(response);
+            return false;
+        }
+        successful
+    }
+
     /// Produces a [`COwn`] (as a recipient identifier) that is both available and not equal to the
     /// peer's recipient identifier.
     fn cown_but_not(&self, c_peer: &[u8]) -> COwn {
+        Self::cown_but_not_in(&self.pool, &self.persisted, c_peer)
+    }
+
+    /// Like [`Self::cown_but_not()`], but only borrowing the pool and the persistence slots, so
+    /// that other fields can be borrowed independently.
+    fn cown_but_not_in(
+        pool: &SecContextPool<Crypto, SSC::GeneralClaims>,
+        persisted: &[Option<COwn>; MAX_CONTEXTS],
+        c_peer: &[u8],
+    ) -> COwn {
         // Let's pick one now already: this allows us to use the identifier in our
         // request data.
         COwn::not_in_iter(
-            self.pool
-                .iter()
+            pool.iter()
                 .filter_map(|entry| entry.corresponding_cown())
+                // Contexts that are still stored keep their identifier until they are removed
+                // from storage, lest a new context gets mistaken for them.
+                .chain(persisted.iter().flatten().copied())
                 // C_R does not only need to be unique, it also must not be identical
                 // to C_I. If it is not expressible as a COwn (as_slice gives []),
                 // that's fine and we don't have to consider it.
@@ -248,6 +787,42 @@ impl<
         )
     }
 
+    /// Inserts a security context into the pool, counting the context it evicts (if any) in the
+    /// metrics.
+    fn insert_context(
+        &mut self,
+        state: SecContextState<Crypto, SSC::GeneralClaims>,
+    ) -> Option<SecContextState<Crypto, SSC::GeneralClaims>> {
+        let evicted = self.pool.force_insert(state);
+        if !matches!(
+            evicted,
+            Some(SecContextState {
+                protocol_stage: SecContextStage::Empty,
+                ..
+            }) | None
+        ) {
+            debug!("Evicted a security context to make room for a new one.");
+            Metrics::count(&mut self.metrics.contexts_evicted);
+        }
+        evicted
+    }
+
+    /// Counts a request that was checked against the peer's authorization, and reports it to the
+    /// access log.
+    ///
+    /// This only borrows the metrics and the access log, so that it can be called while other
+    /// fields are borrowed.
+    fn record_access<M: ReadableMessage>(
+        metrics: &mut Metrics,
+        access_log: &mut AL,
+        peer: Peer<'_>,
+        request: &M,
+        outcome: Outcome,
+    ) {
+        metrics.count_request(request.code().into(), outcome);
+        access_log.record(peer, request, outcome);
+    }
+
     /// Processes a CoAP request containing a message sent to /.well-known/edhoc.
     ///
     /// The caller has already checked Uri-Path and all other critical options, and that the
@@ -300,13 +875,16 @@ impl<
 
             let c_r = self.cown_but_not(c_i.as_slice());
 
-            let _evicted = self.pool.force_insert(SecContextState {
+            let _evicted = self.insert_context(SecContextState {
                 protocol_stage: SecContextStage::EdhocResponderProcessedM1 {
                     c_r,
                     c_i,
                     responder,
                 },
                 authorization: self.authorities.nosec_authorization(),
+                persistence: None,
+                material: None,
+                previous_material: None,
             });
 
             Ok(OwnRequestData::EdhocOkSend2(c_r))
@@ -345,6 +923,7 @@ impl<
                             responder: taken,
                         },
                     authorization,
+                    ..
                 } = taken
                 else {
                     todo!();
@@ -370,6 +949,9 @@ impl<
                         c_r,
                     },
                     authorization,
+                    persistence: None,
+                    material: None,
+                    previous_material: None,
                 };
                 Ok(message_2)
             },
@@ -378,6 +960,7 @@ impl<
         let message_2 = match message_2 {
             Some(Ok(m)) => m,
             Some(Err(e)) => {
+                Metrics::count(&mut self.metrics.edhoc_failed);
                 render_error(e).render(response).map_err(Err)?;
                 return Ok(());
             }
@@ -417,11 +1000,20 @@ impl<
         request: &M,
         oscore_option: &OscoreOption,
         with_edhoc: bool,
+        origin: Option<Origin>,
     ) -> Result<OwnRequestData<Result<H::RequestData, H::ExtractRequestError>>, CoAPError> {
         let payload = request.payload();
 
+        let (plain_option, request_update) =
+            crate::kudos::split_option(oscore_option).map_err(|_| {
+                error!("OSCORE option uses unsupported extensions");
+                CoAPError::bad_option(coap_numbers::option::OSCORE)
+            })?;
+
+        let sequence_number = crate::persist::request_sequence_number(&plain_option);
+
         // We know this to not fail b/c we only got here due to its presence
-        let oscore_option = liboscore::OscoreOption::parse(oscore_option).map_err(|_| {
+        let oscore_option = liboscore::OscoreOption::parse(&plain_option).map_err(|_| {
             error!("OSCORE option could not be parsed");
             CoAPError::bad_option(coap_numbers::option::OSCORE)
         })?;
@@ -452,7 +1044,8 @@ impl<
                     "In this variant, that option is not consumed so the argument is always false"
                 );
             }
-            self.process_edhoc_in_payload(payload, taken)?
+            self.process_edhoc_in_payload(payload, taken)
+                .inspect_err(|_| Metrics::count(&mut self.metrics.edhoc_failed))?
         } else {
             (taken, 0)
         };
@@ -460,6 +1053,9 @@ impl<
         let SecContextState {
             protocol_stage: SecContextStage::Oscore(mut oscore_context),
             authorization: Some(authorization),
+            mut persistence,
+            mut material,
+            mut previous_material,
         } = taken
         else {
             // FIXME: How'd we even get there? Should this be unreachable?
@@ -483,6 +1079,40 @@ impl<
             return Err(CoAPError::bad_request());
         }
 
+        if authorization.is_revoked() {
+            // Same as for expired tokens, the context is discarded completely.
+            debug!("Discarding revoked context");
+            return Err(CoAPError::bad_request());
+        }
+
+        // A request starting a key update is protected with an intermediate context; the
+        // requirement on the persistence state ensures that its fresh replay window is all that
+        // counts. While the peer did not use an updated context yet, it may be retrying the
+        // update, which then starts from the material from before.
+        let mut intermediate_context = None;
+        if let Some(request_update) = &request_update {
+            intermediate_context = previous_material
+                .as_ref()
+                .or(material.as_ref())
+                .filter(|_| {
+                    request_update.is_supported()
+                        && persistence.as_ref().is_none_or(Persistence::is_fresh)
+                })
+                .and_then(|material| request_update.intermediate(material))
+                .and_then(|intermediate| intermediate.derive().ok());
+            if intermediate_context.is_none() {
+                debug!("Key update requested on a context that can not be updated.");
+                let _ = self.insert_context(SecContextState {
+                    protocol_stage: SecContextStage::Oscore(oscore_context),
+                    authorization: Some(authorization),
+                    persistence,
+                    material,
+                    previous_material,
+                });
+                return Err(CoAPError::unauthorized());
+            }
+        }
+
         // See comment on EDHOC_COPY_BUFFER_SIZE
         let mut read_copy = [0u8; EDHOC_COPY_BUFFER_SIZE];
         let mut code_copy = 0;
@@ -503,8 +1133,14 @@ impl<
             if opt.number() == coap_numbers::option::EDHOC {
                 continue;
             }
+            let value = if opt.number() == coap_numbers::option::OSCORE {
+                // Without any KUDOS fields
+                plain_option.as_slice()
+            } else {
+                opt.value()
+            };
             copied_message
-                .add_option(opt.number(), opt.value())
+                .add_option(opt.number(), value)
                 .map_err(|_| {
                     error!("Options produced in unexpected sequence.");
                     CoAPError::internal_server_error()
@@ -518,27 +1154,83 @@ impl<
                 CoAPError::internal_server_error()
             })?;
 
+        let mut observe_request = None;
         let decrypted = liboscore::unprotect_request(
             &mut copied_message,
             oscore_option,
-            &mut oscore_context,
+            intermediate_context.as_mut().unwrap_or(&mut oscore_context),
             |request| {
-                if authorization.scope().request_is_allowed(request) {
+                let checked = if persistence
+                    .as_ref()
+                    .is_some_and(|p| !p.accepts(request, sequence_number))
+                {
+                    AuthorizationChecked::NotFresh
+                } else if authorization.scope().request_is_allowed(request) {
+                    observe_request = ObserveRequest::from_request(request);
                     AuthorizationChecked::Allowed(self.inner.extract_request_data(request))
                 } else {
                     AuthorizationChecked::NotAllowed
-                }
+                };
+                Self::record_access(
+                    &mut self.metrics,
+                    &mut self.access_log,
+                    Peer::Oscore {
+                        recipient_id: kid.as_slice(),
+                    },
+                    request,
+                    checked.outcome(),
+                );
+                checked
             },
         );
 
+        if let (Some(persistence), Ok((_, extracted))) = (persistence.as_mut(), &decrypted) {
+            if let AuthorizationChecked::NotFresh = extracted {
+                persistence.challenge(&mut self.rng);
+            } else {
+                persistence.record_accepted(sequence_number);
+            }
+        }
+
+        let mut response_update = None;
+        if let (Ok(_), Some(request_update)) = (&decrypted, &request_update) {
+            let mut nonce = [0; crate::kudos::NONCE_LEN];
+            self.rng.fill_bytes(&mut nonce);
+            let update = KeyUpdate::new(nonce);
+            let updated = previous_material
+                .as_ref()
+                .or(material.as_ref())
+                .and_then(|old| KeyUpdate::combined(request_update, &update, old))
+                .and_then(|new| Some((new.derive().ok()?, new)));
+            if let Some((new_context, new_material)) = updated {
+                debug!("Updated the keys of security context {:?}.", kid);
+                oscore_context = new_context;
+                let old_material = material.replace(new_material);
+                // A retried update replaces an update the peer never learned of.
+                previous_material = previous_material.or(old_material);
+                persistence = persistence.map(|_| Persistence::fresh());
+                response_update = Some(update);
+                // Observations are not preserved across key updates.
+                self.observations.cancel_where(|o| {
+                    matches!(o.protection, Protection::Oscore { kid: observed, .. } if observed == kid)
+                });
+            }
+        } else if decrypted.is_ok() {
+            // The peer used the context, so it completed any key update.
+            previous_material = None;
+        }
+
         // With any luck, this never moves out.
         //
         // Storing it even on decryption failure to avoid DoS from the first message (but
         // FIXME, should we increment an error count and lower priority?)
         #[allow(clippy::used_underscore_binding, reason = "used only in debug asserts")]
-        let _evicted = self.pool.force_insert(SecContextState {
+        let _evicted = self.insert_context(SecContextState {
             protocol_stage: SecContextStage::Oscore(oscore_context),
             authorization: Some(authorization),
+            persistence,
+            material,
+            previous_material,
         });
         debug_assert!(
             matches!(
@@ -554,13 +1246,109 @@ impl<
         let Ok((correlation, extracted)) = decrypted else {
             // FIXME is that the right code?
             error!("Decryption failure");
+            Metrics::count(&mut self.metrics.oscore_rejected);
             return Err(CoAPError::unauthorized());
         };
 
+        if request_update.is_some() && response_update.is_none() {
+            error!("Key update could not be completed.");
+            return Err(CoAPError::internal_server_error());
+        }
+
+        self.observations
+            .process_request(origin, observe_request, Some(kid));
+
         Ok(OwnRequestData::EdhocOscoreRequest {
             kid,
             correlation,
             extracted,
+            key_update: response_update,
+        })
+    }
+
+    /// Processes a CoAP request protected with the OSCORE group of the [`ServerSecurityConfig`]
+    /// (see [`crate::group`]).
+    ///
+    /// # Errors
+    ///
+    /// This produces errors if the request is not from a member of the group, does not verify, or
+    /// is a replay.
+    #[allow(
+        clippy::type_complexity,
+        reason = "type is subset of RequestData that has no alias in the type"
+    )]
+    fn extract_group<M: ReadableMessage>(
+        &mut self,
+        request: &M,
+        oscore_option: &OscoreOption,
+    ) -> Result<OwnRequestData<Result<H::RequestData, H::ExtractRequestError>>, CoAPError> {
+        let Some((group, authorization)) = self.authorities.oscore_group() else {
+            debug!("Group OSCORE request received, but no group is configured.");
+            return Err(CoAPError::unauthorized());
+        };
+
+        let mut plaintext = [0u8; EDHOC_COPY_BUFFER_SIZE];
+        let (correlation, sequence_number, plaintext) = group
+            .unprotect_request(oscore_option, request.payload(), &mut plaintext)
+            .map_err(|e| match e {
+                UnprotectError::InvalidOption => {
+                    error!("Group OSCORE option could not be processed.");
+                    CoAPError::bad_option(coap_numbers::option::OSCORE)
+                }
+                UnprotectError::UnknownSender => {
+                    debug!("Group OSCORE request from an unknown member.");
+                    CoAPError::unauthorized()
+                }
+                UnprotectError::VerifyFailed => {
+                    error!("Group OSCORE request could not be verified.");
+                    CoAPError::bad_request()
+                }
+            })?;
+        // Never fails: the plaintext was checked to contain at least the code.
+        let (&code, tail) = plaintext.split_first().ok_or_else(CoAPError::bad_request)?;
+        let plaintext = coap_message_implementations::inmemory::Message::new(code, tail);
+
+        let replay = self
+            .group_replay
+            .get_mut(correlation.member())
+            .ok_or_else(CoAPError::internal_server_error)?;
+        let extracted = match replay.check(sequence_number, &plaintext) {
+            Freshness::Unknown => {
+                debug!("Replay window of group member is unknown, challenging.");
+                replay.challenge(&mut self.rng);
+                AuthorizationChecked::NotFresh
+            }
+            Freshness::Replay => {
+                debug!("Group OSCORE request is a replay.");
+                Metrics::count(&mut self.metrics.replays_rejected);
+                return Err(CoAPError::unauthorized());
+            }
+            Freshness::Fresh => {
+                replay.accept(sequence_number);
+                if authorization
+                    .time_constraint()
+                    .is_valid_with(&mut self.time)
+                    && authorization.scope().request_is_allowed(&plaintext)
+                {
+                    AuthorizationChecked::Allowed(self.inner.extract_request_data(&plaintext))
+                } else {
+                    AuthorizationChecked::NotAllowed
+                }
+            }
+        };
+        Self::record_access(
+            &mut self.metrics,
+            &mut self.access_log,
+            Peer::GroupMember {
+                index: correlation.member(),
+            },
+            &plaintext,
+            extracted.outcome(),
+        );
+
+        Ok(OwnRequestData::GroupRequest {
+            correlation,
+            extracted,
         })
     }
 
@@ -577,7 +1365,7 @@ impl<
     /// This panics if cipher suite negotiation passed for a suite whose algorithms are unsupported
     /// in libOSCORE.
     fn process_edhoc_in_payload(
-        &self,
+        &mut self,
         payload: &[u8],
         sec_context_state: SecContextState<Crypto, SSC::GeneralClaims>,
     ) -> Result<(SecContextState<Crypto, SSC::GeneralClaims>, usize), CoAPError> {
@@ -614,7 +1402,7 @@ impl<
             let mut cred_i_and_authorization = None;
 
             if let Some(lakers::EADItem { label: crate::iana::edhoc_ead::ACETOKEN, value: Some(value), .. }) = ead_3.take() {
-                match crate::ace::process_edhoc_token(value.as_slice(), &self.authorities) {
+                match crate::ace::process_edhoc_token(value.as_slice(), &self.authorities, &mut self.time) {
                     Ok(ci_and_a) => cred_i_and_authorization = Some(ci_and_a),
                     Err(e) => {
                         error!("Received unprocessable token {=[u8]:02x}, error: {}", value.as_slice(), Debug2Format(&e)); // :02x could be :cbor
@@ -646,6 +1434,7 @@ impl<
                 responder.verify_message_3(cred_i).map_err(render_error)?;
 
             let mut responder = responder.completed_without_message_4().map_err(render_error)?;
+            Metrics::count(&mut self.metrics.edhoc_completed);
 
             // Once this gets updated beyond Lakers 0.7.2 (likely to 0.8), this will be needed:
             // let mut responder = responder.completed_without_message_4()
@@ -677,9 +1466,21 @@ impl<
 
             let context = liboscore::PrimitiveContext::new_from_fresh_material(immutables);
 
+            let material = ContextMaterial::new(
+                crate::iana::cose_alg::HKDF_HMAC256256,
+                crate::iana::cose_alg::AES_CCM_16_64_128,
+                oscore_secret,
+                oscore_salt,
+                sender_id,
+                c_r,
+            );
+
             SecContextState {
                 protocol_stage: SecContextStage::Oscore(context),
                 authorization: Some(authorization),
+                persistence: material.is_some().then(Persistence::fresh),
+                material,
+                previous_material: None,
             }
         } else {
             // Return the state. Best bet is that it was already advanced to an OSCORE
@@ -715,6 +1516,7 @@ impl<
         kid: COwn,
         mut correlation: liboscore::raw::oscore_requestid_t,
         extracted: AuthorizationChecked<Result<H::RequestData, H::ExtractRequestError>>,
+        key_update: Option<KeyUpdate>,
     ) -> Result<(), Result<CoAPError, M::UnionError>> {
         response.set_code(M::Code::new(coap_numbers::code::CHANGED).map_err(|x| Err(x.into()))?);
 
@@ -732,11 +1534,14 @@ impl<
         // async and the handler has a method to start writing to the message (which kind'a
         // implies rewinding)
 
+        let observe_sequence = self.observations.candidate_sequence();
+        let mut observed = false;
+
         self.pool
                     .lookup(|c| c.corresponding_cown() == Some(kid), |matched| {
                         // Not checking authorization any more: we don't even have access to the
                         // request any more, that check was done.
-                        let SecContextState { protocol_stage: SecContextStage::Oscore(oscore_context), .. } = matched else {
+                        let SecContextState { protocol_stage: SecContextStage::Oscore(oscore_context), persistence, .. } = matched else {
                             // State vanished before response was built.
                             //
                             // As it is, depending on the CoAP stack, there may be DoS if a peer
@@ -745,84 +1550,239 @@ impl<
                             return Err(CoAPError::internal_server_error());
                         };
 
+                        let echo = persistence.as_ref().and_then(|p| p.echo()).copied();
+                        if let AuthorizationChecked::NotFresh = extracted {
+                            // The request may be a replay, whose nonce must not be used with a
+                            // different response (RFC8613 Appendix B.1.2).
+                            correlation.is_first_use = false;
+                        }
+                        if key_update.is_some() {
+                            // The request's nonce was formed with the intermediate context.
+                            correlation.is_first_use = false;
+                        }
+                        if !correlation.is_first_use
+                            && !persistence.as_ref().is_none_or(|p| p.may_send(oscore_context))
+                        {
+                            debug!("Sender sequence numbers are exhausted until more are persisted.");
+                            return Err(CoAPError::service_unavailable());
+                        }
+
                         let response = coap_message_implementations::inmemory_write::Message::downcast_from(response)
                             .expect("OSCORE handler currently requires a response message implementation that is of fixed type");
 
                         response.set_code(coap_numbers::code::CHANGED);
 
-                        if liboscore::protect_response(
-                            response,
-                            // SECURITY BIG FIXME: How do we make sure that our correlation is really for
-                            // what we find in the pool and not for what wound up there by the time we send
-                            // the response? (Can't happen with the current stack, but conceptually there
-                            // should be a tie; carry the OSCORE context in an owned way?).
-                            oscore_context,
-                            &mut correlation,
-                            |response| match extracted {
-                                AuthorizationChecked::Allowed(Ok(extracted)) => match self.inner.build_response(response, extracted) {
+                        let build = |response: &mut liboscore::ProtectedMessage| match extracted {
+                            AuthorizationChecked::Allowed(Ok(extracted)) => {
+                                if let Some(sequence) = observe_sequence {
+                                    observed = Self::build_observed_response(&mut self.inner, extracted, response, sequence);
+                                    return;
+                                }
+                                match self.inner.build_response(response, extracted) {
+                                Ok(()) => {
+                                    // All fine, response was built
+                                },
+                                // One attempt to render rendering errors
+                                // FIXME rewind message
+                                Err(e) => {
+                                    error!("Rendering successful extraction failed with {:?}", Debug2Format(&e));
+                                    match e.render(response) {
+                                        Ok(()) => {
+                                            error!("Error rendered.");
+                                        },
+                                        Err(e2) => {
+                                            error!("Error could not be rendered: {:?}.", Debug2Format(&e2));
+                                            // FIXME rewind message
+                                            response.set_code(coap_numbers::code::INTERNAL_SERVER_ERROR);
+                                        }
+                                    }
+                                },
+                                }
+                            },
+                            AuthorizationChecked::Allowed(Err(inner_request_error)) => {
+                                error!("Extraction failed with {:?}.", Debug2Format(&inner_request_error));
+                                match inner_request_error.render(response) {
                                     Ok(()) => {
-                                        // All fine, response was built
+                                        error!("Original error rendered successfully.");
                                     },
-                                    // One attempt to render rendering errors
-                                    // FIXME rewind message
                                     Err(e) => {
-                                        error!("Rendering successful extraction failed with {:?}", Debug2Format(&e));
+                                        error!("Original error could not be rendered due to {:?}:", Debug2Format(&e));
+                                        // Two attempts to render extraction errors
+                                        // FIXME rewind message
                                         match e.render(response) {
                                             Ok(()) => {
-                                                error!("Error rendered.");
+                                                error!("Error was rendered fine.");
                                             },
                                             Err(e2) => {
-                                                error!("Error could not be rendered: {:?}.", Debug2Format(&e2));
+                                                error!("Rendering error caused {:?}.", Debug2Format(&e2));
                                                 // FIXME rewind message
-                                                response.set_code(coap_numbers::code::INTERNAL_SERVER_ERROR);
-                                            }
-                                        }
-                                    },
-                                },
-                                AuthorizationChecked::Allowed(Err(inner_request_error)) => {
-                                    error!("Extraction failed with {:?}.", Debug2Format(&inner_request_error));
-                                    match inner_request_error.render(response) {
-                                        Ok(()) => {
-                                            error!("Original error rendered successfully.");
-                                        },
-                                        Err(e) => {
-                                            error!("Original error could not be rendered due to {:?}:", Debug2Format(&e));
-                                            // Two attempts to render extraction errors
-                                            // FIXME rewind message
-                                            match e.render(response) {
-                                                Ok(()) => {
-                                                    error!("Error was rendered fine.");
-                                                },
-                                                Err(e2) => {
-                                                    error!("Rendering error caused {:?}.", Debug2Format(&e2));
-                                                    // FIXME rewind message
-                                                    response.set_code(
-                                                        coap_numbers::code::INTERNAL_SERVER_ERROR,
-                                                    );
-                                                }
+                                                response.set_code(
+                                                    coap_numbers::code::INTERNAL_SERVER_ERROR,
+                                                );
                                             }
                                         }
                                     }
                                 }
-                                AuthorizationChecked::NotAllowed => {
-                                    if self.authorities.render_not_allowed(response).is_err() {
-                                        // FIXME rewind message
-                                        response.set_code(coap_numbers::code::UNAUTHORIZED);
+                            }
+                            AuthorizationChecked::NotAllowed => {
+                                if self.authorities.render_not_allowed(response).is_err() {
+                                    // FIXME rewind message
+                                    response.set_code(coap_numbers::code::UNAUTHORIZED);
+                                }
+                            }
+                            AuthorizationChecked::NotFresh => {
+                                response.set_code(coap_numbers::code::UNAUTHORIZED);
+                                if let Some(echo) = &echo {
+                                    if response.add_option(coap_numbers::option::ECHO, echo).is_err() {
+                                        response.set_code(coap_numbers::code::INTERNAL_SERVER_ERROR);
                                     }
                                 }
-                            },
-                        )
-                        .is_err()
-                        {
+                            }
+                        };
+
+                        let protected = if let Some(key_update) = &key_update {
+                            // libOSCORE does not produce the KUDOS fields of the OSCORE option, so
+                            // the response is protected into a scratch message and copied over with
+                            // them added.
+                            let mut scratch_code = 0;
+                            let mut scratch_buffer = [0u8; EDHOC_COPY_BUFFER_SIZE];
+                            let mut scratch = coap_message_implementations::inmemory_write::Message::new(
+                                &mut scratch_code,
+                                &mut scratch_buffer[..],
+                            );
+                            liboscore::protect_response(&mut scratch, oscore_context, &mut correlation, build)
+                                .map_err(|_| ())
+                                .and_then(|_| crate::kudos::copy_with_key_update(&scratch, key_update, response))
+                        } else {
+                            liboscore::protect_response(
+                                response,
+                                // SECURITY BIG FIXME: How do we make sure that our correlation is really for
+                                // what we find in the pool and not for what wound up there by the time we send
+                                // the response? (Can't happen with the current stack, but conceptually there
+                                // should be a tie; carry the OSCORE context in an owned way?).
+                                oscore_context,
+                                &mut correlation,
+                                build,
+                            )
+                            .map_err(|_| ())
+                        };
+                        if protected.is_err() {
                             error!("Oups, responding with weird state");
                             // todo!("Thanks to the protect API we've lost access to our response");
                         }
                         Ok(())
                     })
                 .transpose().map_err(Ok)?;
+
+        if observed {
+            self.observations
+                .register(Protection::Oscore { kid, correlation });
+        }
+
+        Ok(())
+    }
+
+    /// Builds a response to a request that was received through the OSCORE group (see
+    /// [`crate::group`]).
+    ///
+    /// Unlike [`Self::build_oscore_response()`], this works on any response message type: the
+    /// plaintext is built in a scratch message, and the protected message is written generically.
+    fn build_group_response<M: MutableWritableMessage>(
+        &mut self,
+        response: &mut M,
+        correlation: &crate::group::Correlation,
+        extracted: AuthorizationChecked<Result<H::RequestData, H::ExtractRequestError>>,
+    ) -> Result<(), Result<CoAPError, M::UnionError>> {
+        if let AuthorizationChecked::NotFresh = extracted {
+            // The challenge is not protected: the request repeating its Echo value is.
+            response.set_code(
+                M::Code::new(coap_numbers::code::UNAUTHORIZED).map_err(|e| Err(e.into()))?,
+            );
+            if let Some(echo) = self
+                .group_replay
+                .get(correlation.member())
+                .and_then(|r| r.echo())
+            {
+                response
+                    .add_option(
+                        M::OptionNumber::new(coap_numbers::option::ECHO)
+                            .map_err(|e| Err(e.into()))?,
+                        echo,
+                    )
+                    .map_err(|e| Err(e.into()))?;
+            }
+            return Ok(());
+        }
+
+        // The plaintext is the code followed by the options and payload.
+        let mut buffer = [0u8; EDHOC_COPY_BUFFER_SIZE];
+        let [code, tail @ ..] = &mut buffer;
+        let (tail, _) = tail.split_at_mut(tail.len() - crate::group::PROTECTION_OVERHEAD);
+        let mut plaintext = coap_message_implementations::inmemory_write::Message::new(code, tail);
+        Self::build_inner_response(
+            &mut self.inner,
+            &self.authorities,
+            &mut plaintext,
+            extracted,
+        );
+        let plaintext_len = 1 + plaintext.finish();
+
+        let Some((group, _)) = self.authorities.oscore_group() else {
+            error!("OSCORE group vanished before response was built.");
+            return Err(Ok(CoAPError::internal_server_error()));
+        };
+        let (option, payload) = group
+            .protect_response(correlation, &mut buffer, plaintext_len)
+            .map_err(|()| {
+                error!("Group OSCORE response could not be protected.");
+                Ok(CoAPError::internal_server_error())
+            })?;
+
+        response.set_code(M::Code::new(coap_numbers::code::CHANGED).map_err(|e| Err(e.into()))?);
+        response
+            .add_option(
+                M::OptionNumber::new(coap_numbers::option::OSCORE).map_err(|e| Err(e.into()))?,
+                &option,
+            )
+            .map_err(|e| Err(e.into()))?;
+        response.set_payload(payload).map_err(|e| Err(e.into()))?;
         Ok(())
     }
 
+    /// Builds the inner handler's response to an authorized request (or the applicable error
+    /// response) into `response`, which is the plaintext of a Group OSCORE message.
+    fn build_inner_response(
+        inner: &mut H,
+        authorities: &SSC,
+        response: &mut coap_message_implementations::inmemory_write::Message<'_>,
+        extracted: AuthorizationChecked<Result<H::RequestData, H::ExtractRequestError>>,
+    ) {
+        match extracted {
+            AuthorizationChecked::Allowed(Ok(extracted)) => {
+                if let Err(e) = inner.build_response(response, extracted) {
+                    error!(
+                        "Rendering successful extraction failed with {:?}",
+                        Debug2Format(&e)
+                    );
+                    if e.render(response).is_err() {
+                        response.set_code(coap_numbers::code::INTERNAL_SERVER_ERROR);
+                    }
+                }
+            }
+            AuthorizationChecked::Allowed(Err(e)) => {
+                debug!("Extraction failed with {:?}.", Debug2Format(&e));
+                if e.render(response).is_err() {
+                    response.set_code(coap_numbers::code::INTERNAL_SERVER_ERROR);
+                }
+            }
+            AuthorizationChecked::NotAllowed | AuthorizationChecked::NotFresh => {
+                if authorities.render_not_allowed(response).is_err() {
+                    response.set_code(coap_numbers::code::UNAUTHORIZED);
+                }
+            }
+        }
+    }
+
     /// Processes a CoAP request containing an ACE token for /authz-info.
     ///
     /// This assumes that the content format was pre-checked to be application/ace+cbor, both in
@@ -840,19 +1800,26 @@ impl<
         let mut nonce2 = [0; crate::ace::OWN_NONCE_LEN];
         self.rng.fill_bytes(&mut nonce2);
 
-        let (response, oscore, generalclaims) =
-            crate::ace::process_acecbor_authz_info(payload, &self.authorities, nonce2, |nonce1| {
+        let pool = &self.pool;
+        let persisted = &self.persisted;
+        let (response, oscore, material, generalclaims) = crate::ace::process_acecbor_authz_info(
+            payload,
+            &self.authorities,
+            &mut self.time,
+            nonce2,
+            |nonce1| {
                 // This preferably (even exclusively) produces EDHOC-ideal recipient IDs, but as long
                 // as we're having more of those than slots, no point in not reusing the code.
-                self.cown_but_not(nonce1)
-            })
-            .map_err(|e| {
-                error!("Sending out error:");
-                error!("{}", Debug2Format(&e));
-                e.position
-                    // FIXME: Could also come from processing inner
-                    .map_or(CoAPError::bad_request(), CoAPError::bad_request_with_rbep)
-            })?;
+                Self::cown_but_not_in(pool, persisted, nonce1)
+            },
+        )
+        .map_err(|e| {
+            error!("Sending out error:");
+            error!("{}", Debug2Format(&e));
+            e.position
+                // FIXME: Could also come from processing inner
+                .map_or(CoAPError::bad_request(), CoAPError::bad_request_with_rbep)
+        })?;
 
         debug!(
             "Established OSCORE context with recipient ID {:?} and authorization {:?} through ACE-OSCORE",
@@ -861,9 +1828,12 @@ impl<
         );
         // FIXME: This should be flagged as "unconfirmed" for rapid eviction, as it could be part
         // of a replay.
-        let _evicted = self.pool.force_insert(SecContextState {
+        let _evicted = self.insert_context(SecContextState {
             protocol_stage: SecContextStage::Oscore(oscore),
             authorization: Some(generalclaims),
+            persistence: material.is_some().then(Persistence::fresh),
+            material,
+            previous_material: None,
         });
 
         Ok(response)
@@ -881,6 +1851,20 @@ pub enum AuthorizationChecked<I> {
     Allowed(I),
     /// Middleware checks failed, return a 4.01 Unauthorized
     NotAllowed,
+    /// The request may be a replay to a restored security context, return a 4.01 Unauthorized
+    /// with an Echo option (see [`crate::persist`])
+    NotFresh,
+}
+
+impl<I> AuthorizationChecked<I> {
+    /// The outcome of the checks, as reported to an [`AccessLog`].
+    fn outcome(&self) -> Outcome {
+        match self {
+            Self::Allowed(_) => Outcome::Allowed,
+            Self::NotAllowed => Outcome::NotAllowed,
+            Self::NotFresh => Outcome::NotFresh,
+        }
+    }
 }
 
 /// Request state created by an [`OscoreEdhocHandler`] for successful non-plaintext cases.
@@ -899,8 +1883,17 @@ pub enum OwnRequestData<I> {
         kid: COwn,
         correlation: liboscore::raw::oscore_requestid_t,
         extracted: AuthorizationChecked<I>,
+        /// The fields announcing the server's nonce if the request started a key update.
+        #[expect(private_interfaces, reason = "should be addressed eventually")]
+        key_update: Option<KeyUpdate>,
     },
     ProcessedToken(crate::ace::AceCborAuthzInfoResponse),
+    /// A request received through the OSCORE group (see [`crate::group`]).
+    GroupRequest {
+        #[expect(private_interfaces, reason = "should be addressed eventually")]
+        correlation: crate::group::Correlation,
+        extracted: AuthorizationChecked<I>,
+    },
 }
 
 // FIXME: It'd be tempting to implement Drop for Response to set the slot back to Empty -- but
@@ -1018,7 +2011,8 @@ impl<
     SSC: ServerSecurityConfig,
     RNG: rand_core::RngCore + rand_core::CryptoRng,
     TP: TimeProvider,
-> coap_handler::Handler for OscoreEdhocHandler<H, Crypto, CryptoFactory, SSC, RNG, TP>
+    AL: AccessLog,
+> coap_handler::Handler for OscoreEdhocHandler<H, Crypto, CryptoFactory, SSC, RNG, TP, AL>
 {
     type RequestData = OrInner<
         OwnRequestData<Result<H::RequestData, H::ExtractRequestError>>,
@@ -1126,6 +2120,8 @@ impl<
             .ignore_elective_others();
         let state = state.unwrap();
 
+        let origin = self.observations.start_request();
+
         if state.errors_handled_here() {
             if let Err(error) = extra_options {
                 // Critical options in all other cases are handled by the Unencrypted or Oscore
@@ -1144,10 +2140,27 @@ impl<
 
         match state {
             Start | WellKnown | Unencrypted => {
-                if self.authorities.nosec_authorization().is_some_and(|s| {
+                let allowed = self.authorities.nosec_authorization().is_some_and(|s| {
                     s.scope().request_is_allowed(request)
                         && s.time_constraint().is_valid_with(&mut self.time)
-                }) {
+                });
+                Self::record_access(
+                    &mut self.metrics,
+                    &mut self.access_log,
+                    Peer::Unprotected,
+                    request,
+                    if allowed {
+                        Outcome::Allowed
+                    } else {
+                        Outcome::NotAllowed
+                    },
+                );
+                if allowed {
+                    self.observations.process_request(
+                        origin,
+                        ObserveRequest::from_request(request),
+                        None,
+                    );
                     self.inner
                         .extract_request_data(request)
                         .map(|extracted| Inner(AuthorizationChecked::Allowed(extracted)))
@@ -1161,7 +2174,10 @@ impl<
                     unreachable!("State is not constructed");
                 }
                 require_post()?;
-                self.extract_edhoc(&request).map(Own).map_err(Own)
+                self.extract_edhoc(&request)
+                    .inspect_err(|_| Metrics::count(&mut self.metrics.edhoc_failed))
+                    .map(Own)
+                    .map_err(Own)
             }
             AuthzInfo(_) => {
                 if !SSC::PARSES_TOKENS {
@@ -1186,7 +2202,7 @@ impl<
                     // unreachable when HAS_EDHOC is not set.
                     unreachable!("State is not constructed");
                 }
-                self.extract_oscore_edhoc(&request, &oscore, true)
+                self.extract_oscore_edhoc(&request, &oscore, true, origin)
                     .map(Own)
                     .map_err(Own)
             }
@@ -1194,9 +2210,14 @@ impl<
                 if !has_oscore::<SSC>() {
                     unreachable!("State is not constructed");
                 }
-                self.extract_oscore_edhoc(&request, &oscore, false)
-                    .map(Own)
-                    .map_err(Own)
+                if SSC::HAS_OSCORE_GROUP && crate::group::is_group_option(&oscore) {
+                    // Observations are not supported through the group.
+                    self.extract_group(&request, &oscore).map(Own).map_err(Own)
+                } else {
+                    self.extract_oscore_edhoc(&request, &oscore, false, origin)
+                        .map(Own)
+                        .map_err(Own)
+                }
             }
         }
     }
@@ -1204,7 +2225,7 @@ impl<
         match req {
             OrInner::Own(_) => 2 + lakers::MAX_BUFFER_LEN,
             OrInner::Inner(AuthorizationChecked::Allowed(i)) => self.inner.estimate_length(i),
-            OrInner::Inner(AuthorizationChecked::NotAllowed) => 1,
+            OrInner::Inner(AuthorizationChecked::NotAllowed | AuthorizationChecked::NotFresh) => 1,
         }
     }
     fn build_response<M: MutableWritableMessage>(
@@ -1231,17 +2252,34 @@ impl<
                 kid,
                 correlation,
                 extracted,
+                key_update,
             }) => {
                 if !has_oscore::<SSC>() {
                     unreachable!("State is not constructed");
                 }
-                self.build_oscore_response(response, kid, correlation, extracted)
+                self.build_oscore_response(response, kid, correlation, extracted, key_update)
+                    .map_err(Own)?;
+            }
+            Own(OwnRequestData::GroupRequest {
+                correlation,
+                extracted,
+            }) => {
+                if !SSC::HAS_OSCORE_GROUP {
+                    unreachable!("State is not constructed");
+                }
+                self.build_group_response(response, &correlation, extracted)
                     .map_err(Own)?;
             }
             Inner(AuthorizationChecked::Allowed(i)) => {
-                self.inner.build_response(response, i).map_err(Inner)?;
+                if let Some(sequence) = self.observations.candidate_sequence() {
+                    if Self::build_observed_response(&mut self.inner, i, response, sequence) {
+                        self.observations.register(Protection::Plain);
+                    }
+                } else {
+                    self.inner.build_response(response, i).map_err(Inner)?;
+                }
             }
-            Inner(AuthorizationChecked::NotAllowed) => {
+            Inner(AuthorizationChecked::NotAllowed | AuthorizationChecked::NotFresh) => {
                 self.authorities
                     .render_not_allowed(response)
                     .map_err(|_| Own(Ok(CoAPError::unauthorized())))?;
@@ -54,8 +54,8 @@ impl TimeProvider for TimeUnknown {
 /// A processed set of token claims that limit it in time.
 #[derive(Copy, Clone, Debug)]
 pub struct TimeConstraint {
-    // iat would not go in here (that's only to feed a `TimeProvider::past_trusted`; nbf would go
-    // in here but we don't read that yet)
+    // iat would not go in here (that's only to feed a `TimeProvider::past_trusted`)
+    nbf: Option<u64>,
     exp: Option<u64>,
 }
 
@@ -63,7 +63,35 @@ impl TimeConstraint {
     /// Creates a [`TimeConstraint`] with no bounds; it is valid at any time.
     #[must_use]
     pub fn unbounded() -> Self {
-        Self { exp: None }
+        Self {
+            nbf: None,
+            exp: None,
+        }
+    }
+
+    /// Creates a [`TimeConstraint`] that is valid until the given expiry time.
+    #[must_use]
+    pub fn until(exp: u64) -> Self {
+        Self {
+            nbf: None,
+            exp: Some(exp),
+        }
+    }
+
+    /// Creates a [`TimeConstraint`] that is valid from the given start time until the given
+    /// expiry time (as are X.509 certificates).
+    #[must_use]
+    pub fn between(nbf: u64, exp: u64) -> Self {
+        Self {
+            nbf: Some(nbf),
+            exp: Some(exp),
+        }
+    }
+
+    /// Returns the expiry time of the constraint, if it has one.
+    #[must_use]
+    pub fn expiry(&self) -> Option<u64> {
+        self.exp
     }
 
     /// Extract time constraint from a claim.
@@ -73,6 +101,7 @@ impl TimeConstraint {
     #[must_use]
     pub fn from_claims_set(claims: &crate::ace::CwtClaimsSet<'_>) -> Self {
         TimeConstraint {
+            nbf: None,
             exp: Some(claims.exp),
         }
     }
@@ -81,11 +110,15 @@ impl TimeConstraint {
     ///
     /// Any uncertainty of the time provider is counted for the benefit of the client.
     pub(crate) fn is_valid_with(&self, time_provider: &mut impl TimeProvider) -> bool {
-        let Some(exp) = self.exp else {
+        if self.nbf.is_none() && self.exp.is_none() {
             return true;
-        };
-        let (now_early, _now_late) = time_provider.now();
+        }
+        let (now_early, now_late) = time_provider.now();
 
-        exp > now_early
+        let started = match (self.nbf, now_late) {
+            (Some(nbf), Some(now_late)) => nbf <= now_late,
+            _ => true,
+        };
+        started && self.exp.is_none_or(|exp| exp > now_early)
     }
 }